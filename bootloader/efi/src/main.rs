@@ -17,6 +17,7 @@ use core::cmp::max;
 use log::{debug, info};
 use uefi::prelude::*;
 use uefi::proto::console::gop::GraphicsOutput;
+use uefi::table::cfg::{ACPI2_GUID, ACPI_GUID};
 use x86_64::registers::control::{Cr0, Cr0Flags, Efer, EferFlags};
 use xmas_elf::ElfFile;
 
@@ -54,6 +55,9 @@ fn main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
     let graphic_info = init_graphic(bs, config.resolution);
     debug!("graphic_info {:#?}", graphic_info);
 
+    let rsdp_addr = find_rsdp(&system_table);
+    debug!("rsdp at {:#x?}", rsdp_addr);
+
     // Read kernel.
     let elf = {
         let mut file = fs::open_file(bs, config.kernel_path);
@@ -119,6 +123,7 @@ fn main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
         memory_map: memory_map,
         physical_memory_offset: config.physical_memory_offset,
         graphic_info,
+        rsdp_addr,
     };
 
     unsafe {
@@ -133,6 +138,16 @@ unsafe fn jump_to_entry(stack_top: u64, boot_info: *const BootInfo) -> ! {
     }
 }
 
+/// Find the ACPI RSDP in the configuration table, preferring the ACPI 2.0
+/// one.
+fn find_rsdp(system_table: &SystemTable<Boot>) -> Option<u64> {
+    let config = system_table.config_table();
+    let find = |guid| config.iter().find(|entry| entry.guid == guid);
+    find(ACPI2_GUID)
+        .or_else(|| find(ACPI_GUID))
+        .map(|entry| entry.address as u64)
+}
+
 /// If `resolution` is some, then set graphic mode matching the resolution.
 /// Return information of the final graphic mode.
 fn init_graphic(bs: &BootServices, resolution: Option<(usize, usize)>) -> GraphicInfo {
@@ -14,6 +14,8 @@ pub struct BootInfo {
     pub physical_memory_offset: u64,
     /// The graphic output information
     pub graphic_info: GraphicInfo,
+    /// Physical address of the ACPI RSDP, from the UEFI configuration table.
+    pub rsdp_addr: Option<u64>,
 }
 
 /// Graphic output information
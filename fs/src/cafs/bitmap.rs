@@ -1,102 +1,610 @@
 use super::cache::CacheManager;
-use crate::{BLOCK_BITS, BLOCK_SIZE};
+use super::layout::{BitmapBlock, Geometry};
+use crate::{Error, MAX_BLOCK_SIZE};
 use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::{Mutex, RwLock};
 
 pub struct Bitmap {
-    start_block_id: u64,
-    blocks: u64,
+    /// Where the bitmap is, changes when the filesystem is resized.
+    start_block_id: AtomicU64,
+    blocks: AtomicU64,
+    /// Bits held by one bitmap block.
+    block_bits: u64,
     cache_manager: Arc<CacheManager>,
+    /// Free bits of every bitmap block, loaded on first use.
+    free_counts: RwLock<Vec<u64>>,
+    /// Next-fit position used when the caller has no hint of its own.
+    next_fit: RwLock<u64>,
+    /// Held from finding free bits to setting them, and while bits are
+    /// freed, so two allocations never take the same run.
+    alloc_lock: Mutex<()>,
 }
 
-type BitmapBlock = [u64; BLOCK_SIZE as usize / 8];
-
 impl Bitmap {
     pub fn new(start_block_id: u64, blocks: u64, cache_manager: Arc<CacheManager>) -> Self {
         Self {
-            start_block_id,
-            blocks,
+            start_block_id: AtomicU64::new(start_block_id),
+            blocks: AtomicU64::new(blocks),
+            block_bits: Geometry::new(cache_manager.block_size()).bitmap_bits(),
             cache_manager,
+            free_counts: RwLock::new(Vec::new()),
+            next_fit: RwLock::new(0),
+            alloc_lock: Mutex::new(()),
         }
     }
 
+    pub fn start_block_id(&self) -> u64 {
+        self.start_block_id.load(Ordering::Acquire)
+    }
+
+    pub fn blocks(&self) -> u64 {
+        self.blocks.load(Ordering::Acquire)
+    }
+
     pub fn total_count(&self) -> u64 {
-        self.blocks * BLOCK_BITS
+        self.blocks() * self.block_bits
+    }
+
+    pub fn free_count(&self) -> Result<u64, Error> {
+        self.load_free_counts()?;
+        Ok(self.free_counts.read().iter().sum())
     }
 
-    pub fn free_count(&self) -> u64 {
-        let mut count = 0;
-        for block_id in 0..self.blocks {
-            count += unsafe {
+    /// Clear all bits of a freshly made bitmap and seal the checksum of its
+    /// blocks.
+    pub fn format(&self) {
+        for block_id in 0..self.blocks() {
+            unsafe {
                 self.cache_manager
-                    .get(block_id + self.start_block_id)
+                    .get(block_id + self.start_block_id())
+                    .write()
+                    .overwrite(0, |bitmap_block: &mut BitmapBlock| bitmap_block.fill(0));
+            }
+        }
+        self.free_counts.write().clear();
+    }
+
+    /// Allocate a single bit, starting from the bitmap-wide next-fit position.
+    pub fn alloc(&self) -> Result<Option<u64>, Error> {
+        let hint = *self.next_fit.read();
+        self.alloc_contiguous(1, hint)
+    }
+
+    /// Allocate `count` contiguous bits, searching forward from `hint` and
+    /// wrapping around to the start of the bitmap. Return the first bit.
+    pub fn alloc_contiguous(&self, count: u64, hint: u64) -> Result<Option<u64>, Error> {
+        let _guard = self.alloc_lock.lock();
+        self.alloc_contiguous_locked(count, hint)
+    }
+
+    fn alloc_contiguous_locked(&self, count: u64, hint: u64) -> Result<Option<u64>, Error> {
+        if count == 0 || count > self.free_count()? {
+            return Ok(None);
+        }
+        let hint = if hint < self.total_count() { hint } else { 0 };
+        let run = match self.find_run(hint, self.total_count(), count, count)? {
+            Some(run) => Some(run),
+            None => self.find_run(0, hint, count, count)?,
+        };
+        let Some((start, _)) = run else {
+            return Ok(None);
+        };
+        self.set_range(start, count, true)?;
+        *self.next_fit.write() = start + count;
+        Ok(Some(start))
+    }
+
+    /// Allocate `count` contiguous bits that all lie below `limit`, the
+    /// lowest such run. Return the first bit.
+    pub fn alloc_contiguous_below(&self, count: u64, limit: u64) -> Result<Option<u64>, Error> {
+        if count == 0 || count > limit {
+            return Ok(None);
+        }
+        let _guard = self.alloc_lock.lock();
+        let Some((start, _)) = self.find_run(0, limit - count + 1, count, count)? else {
+            return Ok(None);
+        };
+        self.set_range(start, count, true)?;
+        Ok(Some(start))
+    }
+
+    /// Allocate `count` bits, as contiguous as possible and close to `hint`.
+    /// Nothing is allocated if there are fewer than `count` free bits.
+    ///
+    /// Return the allocated bits as `(start, len)` extents in allocation order.
+    pub fn alloc_extents(&self, count: u64, hint: u64) -> Result<Option<Vec<(u64, u64)>>, Error> {
+        if count == 0 {
+            return Ok(Some(Vec::new()));
+        }
+        let _guard = self.alloc_lock.lock();
+        if count > self.free_count()? {
+            return Ok(None);
+        }
+        if let Some(start) = self.alloc_contiguous_locked(count, hint)? {
+            return Ok(Some(alloc::vec![(start, count)]));
+        }
+        // no single run is long enough, take the first free runs after hint
+        let mut extents = Vec::new();
+        let mut remain = count;
+        let mut pos = if hint < self.total_count() { hint } else { 0 };
+        while remain != 0 {
+            let run = match self.find_run(pos, self.total_count(), 1, remain)? {
+                Some(run) => Some(run),
+                None => self.find_run(0, pos, 1, remain)?,
+            };
+            let Some((start, len)) = run else {
+                // the free counts promised more, give back what was taken
+                for (start, len) in extents {
+                    self.set_range(start, len, false)?;
+                }
+                return Err(Error::Corrupted(self.start_block_id()));
+            };
+            self.set_range(start, len, true)?;
+            extents.push((start, len));
+            remain -= len;
+            pos = start + len;
+        }
+        *self.next_fit.write() = pos;
+        Ok(Some(extents))
+    }
+
+    pub fn dealloc(&self, bit: u64) -> Result<(), Error> {
+        self.dealloc_range(bit, 1)
+    }
+
+    /// Free `count` bits starting at `start`. Fails with `Error::Corrupted`,
+    /// changing nothing, if any bit of the range is not allocated.
+    pub fn dealloc_range(&self, start: u64, count: u64) -> Result<(), Error> {
+        let _guard = self.alloc_lock.lock();
+        self.set_range(start, count, false)
+    }
+
+    /// Return how many of the `count` bits starting at `start` are set.
+    pub fn used_count(&self, start: u64, count: u64) -> Result<u64, Error> {
+        assert!(start + count <= self.total_count());
+        let mut used = 0;
+        let mut bit = start;
+        let end = start + count;
+        while bit < end {
+            let (block_pos, _, _) = self.decompose(bit);
+            let block_end = end.min((block_pos + 1) * self.block_bits);
+            let bitmap_block = unsafe {
+                self.cache_manager
+                    .get(block_pos + self.start_block_id())
                     .read()
-                    .read(0, |bitmap_block: &BitmapBlock| {
+                    .read_checked(0, |bitmap_block: &BitmapBlock| *bitmap_block)?
+            };
+            while bit < block_end {
+                let (_, bits64_pos, inner_pos) = self.decompose(bit);
+                let len = (64 - inner_pos).min(block_end - bit);
+                used += (bitmap_block[bits64_pos] & Self::mask(inner_pos, len)).count_ones() as u64;
+                bit += len;
+            }
+        }
+        Ok(used)
+    }
+
+    /// Set every bit from `start` to the end of the bitmap, whatever its
+    /// state. Used to reserve the bits past the end of the area.
+    pub fn reserve_from(&self, start: u64) -> Result<(), Error> {
+        let _guard = self.alloc_lock.lock();
+        let mut bit = start;
+        while bit < self.total_count() {
+            let (block_pos, _, _) = self.decompose(bit);
+            let block_end = (block_pos + 1) * self.block_bits;
+            unsafe {
+                self.cache_manager
+                    .get(block_pos + self.start_block_id())
+                    .write()
+                    .modify_checked(0, |bitmap_block: &mut BitmapBlock| {
+                        while bit < block_end {
+                            let (_, bits64_pos, inner_pos) = self.decompose(bit);
+                            let len = (64 - inner_pos).min(block_end - bit);
+                            bitmap_block[bits64_pos] |= Self::mask(inner_pos, len);
+                            bit += len;
+                        }
+                    })?;
+            }
+        }
+        // recounted on next use
+        self.free_counts.write().clear();
+        Ok(())
+    }
+
+    /// Move the bitmap to `blocks` blocks at `start_block_id`. The bits that
+    /// still fit are kept, those of added blocks are free.
+    pub fn relocate(&self, start_block_id: u64, blocks: u64) -> Result<(), Error> {
+        if start_block_id == self.start_block_id() && blocks == self.blocks() {
+            return Ok(());
+        }
+        let _guard = self.alloc_lock.lock();
+        self.copy_to(start_block_id, blocks)?;
+        let mut free_counts = self.free_counts.write();
+        self.start_block_id.store(start_block_id, Ordering::Release);
+        self.blocks.store(blocks, Ordering::Release);
+        free_counts.clear();
+        Ok(())
+    }
+
+    /// Write the bitmap to `blocks` blocks at `start_block_id`, cut off or
+    /// padded with free bits.
+    pub fn copy_to(&self, start_block_id: u64, blocks: u64) -> Result<(), Error> {
+        let old_start_block_id = self.start_block_id();
+        let old_blocks = self.blocks();
+        for block_id in 0..blocks {
+            let bitmap_block = if block_id < old_blocks {
+                unsafe {
+                    self.cache_manager
+                        .get(block_id + old_start_block_id)
+                        .read()
+                        .read_checked(0, |bitmap_block: &BitmapBlock| *bitmap_block)?
+                }
+            } else {
+                [0; MAX_BLOCK_SIZE as usize / 8]
+            };
+            unsafe {
+                self.cache_manager
+                    .get(block_id + start_block_id)
+                    .write()
+                    .overwrite(0, |block: &mut BitmapBlock| *block = bitmap_block);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `bit` is set, bits past the end are not.
+    pub fn test(&self, bit: u64) -> Result<bool, Error> {
+        if bit >= self.total_count() {
+            return Ok(false);
+        }
+        let (block_pos, bits64_pos, inner_pos) = self.decompose(bit);
+        let bits64 = unsafe {
+            self.cache_manager
+                .get(block_pos + self.start_block_id())
+                .read()
+                .read_checked(0, |bitmap_block: &BitmapBlock| bitmap_block[bits64_pos])?
+        };
+        Ok(bits64 & (1 << inner_pos) != 0)
+    }
+
+    /// Set `bit`, which must be free.
+    pub fn alloc_at(&self, bit: u64) -> Result<(), Error> {
+        let _guard = self.alloc_lock.lock();
+        self.set_range(bit, 1, true)
+    }
+
+    /// Return all bits, bit `i` is bit `i % 64` of word `i / 64`.
+    pub fn words(&self) -> Result<Vec<u64>, Error> {
+        let words_per_block = (self.block_bits / 64) as usize;
+        let mut words = Vec::with_capacity(self.blocks() as usize * words_per_block);
+        for block_id in 0..self.blocks() {
+            unsafe {
+                self.cache_manager
+                    .get(block_id + self.start_block_id())
+                    .read()
+                    .read_checked(0, |bitmap_block: &BitmapBlock| {
+                        words.extend_from_slice(&bitmap_block[..words_per_block])
+                    })?;
+            }
+        }
+        Ok(words)
+    }
+
+    fn load_free_counts(&self) -> Result<(), Error> {
+        if !self.free_counts.read().is_empty() || self.blocks() == 0 {
+            return Ok(());
+        }
+        let mut free_counts = self.free_counts.write();
+        if !free_counts.is_empty() {
+            return Ok(());
+        }
+        let mut counts = Vec::with_capacity(self.blocks() as usize);
+        for block_id in 0..self.blocks() {
+            let used = unsafe {
+                self.cache_manager
+                    .get(block_id + self.start_block_id())
+                    .read()
+                    .read_checked(0, |bitmap_block: &BitmapBlock| {
                         bitmap_block
                             .iter()
-                            .map(|bits64| {
-                                let mut one_count = 0u64;
-                                let mut num = *bits64;
-                                while num != 0 {
-                                    if num & 1 == 1 {
-                                        one_count += 1;
-                                    }
-                                    num >>= 1;
-                                }
-                                64 - one_count
-                            })
+                            .take((self.block_bits / 64) as usize)
+                            .map(|bits64| bits64.count_ones() as u64)
                             .sum::<u64>()
-                    })
+                    })?
             };
+            counts.push(self.block_bits - used);
         }
-        count
-    }
-
-    pub fn alloc(&self) -> Option<u64> {
-        for block_id in 0..self.blocks {
-            let pos = unsafe {
-                let cache = self.cache_manager.get(block_id + self.start_block_id);
-                let mut cache = cache.write();
-                let id = cache.read(0, |bitmap_block: &BitmapBlock| {
-                    bitmap_block
-                        .iter()
-                        .enumerate()
-                        .find(|(_, bits64)| **bits64 != u64::MAX)
-                        .map(|(bits64_pos, bits64)| (bits64_pos, bits64.trailing_ones() as usize))
-                });
-                if let Some((bits64_pos, inner_pos)) = id {
-                    // modify cache
-                    cache.modify(0, |bitmap_block: &mut BitmapBlock| {
-                        bitmap_block[bits64_pos] |= 1u64 << inner_pos;
-                    });
-                    Some(block_id * BLOCK_BITS + (bits64_pos * 64 + inner_pos) as u64)
+        *free_counts = counts;
+        Ok(())
+    }
+
+    /// Find the first run of at least `min` free bits that starts in
+    /// `[from, to)`, and return its start with length capped to `max`.
+    fn find_run(
+        &self,
+        from: u64,
+        to: u64,
+        min: u64,
+        max: u64,
+    ) -> Result<Option<(u64, u64)>, Error> {
+        self.load_free_counts()?;
+        let mut run_start = from;
+        let mut run_len = 0u64;
+        let mut bit = from;
+        while bit < self.total_count() {
+            if run_len == 0 && bit >= to {
+                break;
+            }
+            let (block_pos, _, _) = self.decompose(bit);
+            if self.free_counts.read()[block_pos as usize] == 0 {
+                if run_len >= min {
+                    break;
+                }
+                run_len = 0;
+                bit = (block_pos + 1) * self.block_bits;
+                continue;
+            }
+            let bitmap_block = unsafe {
+                self.cache_manager
+                    .get(block_pos + self.start_block_id())
+                    .read()
+                    .read_checked(0, |bitmap_block: &BitmapBlock| *bitmap_block)?
+            };
+            let block_end = (block_pos + 1) * self.block_bits;
+            while bit < block_end {
+                let (_, bits64_pos, inner_pos) = self.decompose(bit);
+                let bits64 = bitmap_block[bits64_pos];
+                if inner_pos == 0 && bits64 == 0 {
+                    if run_len == 0 {
+                        if bit >= to {
+                            return Ok(None);
+                        }
+                        run_start = bit;
+                    }
+                    run_len += 64;
+                    bit += 64;
+                } else if inner_pos == 0 && bits64 == u64::MAX {
+                    if run_len >= min {
+                        return Ok(Some((run_start, run_len.min(max))));
+                    }
+                    run_len = 0;
+                    bit += 64;
+                } else if bits64 & (1u64 << inner_pos) == 0 {
+                    if run_len == 0 {
+                        if bit >= to {
+                            return Ok(None);
+                        }
+                        run_start = bit;
+                    }
+                    run_len += 1;
+                    bit += 1;
                 } else {
-                    None
+                    if run_len >= min {
+                        return Ok(Some((run_start, run_len.min(max))));
+                    }
+                    run_len = 0;
+                    bit += 1;
+                }
+                if run_len >= max {
+                    return Ok(Some((run_start, max)));
                 }
+            }
+        }
+        if run_len >= min {
+            Ok(Some((run_start, run_len.min(max))))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Set (`used == true`) or clear `count` bits starting at `start`, a whole
+    /// word at a time where possible. The caller holds `alloc_lock`. Fails
+    /// with `Error::Corrupted`, changing nothing, if a bit is already in
+    /// that state.
+    fn set_range(&self, start: u64, count: u64, used: bool) -> Result<(), Error> {
+        assert!(start + count <= self.total_count());
+        self.load_free_counts()?;
+        let end = start + count;
+        let mut bit = start;
+        while bit < end {
+            let (block_pos, _, _) = self.decompose(bit);
+            let block_end = end.min((block_pos + 1) * self.block_bits);
+            let block_id = block_pos + self.start_block_id();
+            let bitmap_block = unsafe {
+                self.cache_manager
+                    .get(block_id)
+                    .read()
+                    .read_checked(0, |bitmap_block: &BitmapBlock| *bitmap_block)?
             };
-            if pos.is_some() {
-                return pos;
+            while bit < block_end {
+                let (_, bits64_pos, inner_pos) = self.decompose(bit);
+                let len = (64 - inner_pos).min(block_end - bit);
+                let mask = Self::mask(inner_pos, len);
+                let expected = if used { 0 } else { mask };
+                if bitmap_block[bits64_pos] & mask != expected {
+                    return Err(Error::Corrupted(block_id));
+                }
+                bit += len;
+            }
+        }
+        let mut bit = start;
+        while bit < end {
+            let (block_pos, _, _) = self.decompose(bit);
+            let block_end = end.min((block_pos + 1) * self.block_bits);
+            let len = block_end - bit;
+            unsafe {
+                self.cache_manager
+                    .get(block_pos + self.start_block_id())
+                    .write()
+                    .modify_checked(0, |bitmap_block: &mut BitmapBlock| {
+                        while bit < block_end {
+                            let (_, bits64_pos, inner_pos) = self.decompose(bit);
+                            let len = (64 - inner_pos).min(block_end - bit);
+                            let mask = Self::mask(inner_pos, len);
+                            if used {
+                                bitmap_block[bits64_pos] |= mask;
+                            } else {
+                                bitmap_block[bits64_pos] &= !mask;
+                            }
+                            bit += len;
+                        }
+                    })?;
+            }
+            let mut free_counts = self.free_counts.write();
+            if used {
+                free_counts[block_pos as usize] -= len;
+            } else {
+                free_counts[block_pos as usize] += len;
             }
         }
-        None
+        Ok(())
     }
 
-    pub fn dealloc(&self, cache_manager: Arc<CacheManager>, bit: u64) {
-        let (block_pos, bits64_pos, inner_pos) = decompose(bit);
-        unsafe {
-            cache_manager
-                .get(block_pos + self.start_block_id)
-                .write()
-                .modify(0, |bitmap_block: &mut BitmapBlock| {
-                    assert!(bitmap_block[bits64_pos] & (1u64 << inner_pos) > 0);
-                    bitmap_block[bits64_pos] -= 1u64 << inner_pos;
-                });
+    /// `len` bits starting at `inner_pos` of a word.
+    fn mask(inner_pos: u64, len: u64) -> u64 {
+        if len == 64 {
+            u64::MAX
+        } else {
+            ((1u64 << len) - 1) << inner_pos
         }
     }
+
+    /// Return (block_pos, bits64_pos, inner_pos)
+    fn decompose(&self, mut bit: u64) -> (u64, usize, u64) {
+        let block_pos = bit / self.block_bits;
+        bit %= self.block_bits;
+        (block_pos, (bit / 64) as usize, bit % 64)
+    }
 }
 
-/// Return (block_pos, bits64_pos, inner_pos)
-fn decompose(mut bit: u64) -> (u64, usize, u64) {
-    let block_pos = bit / BLOCK_BITS;
-    bit = bit % BLOCK_BITS;
-    (block_pos, (bit / 64) as usize, bit % 64)
+#[cfg(test)]
+mod test {
+    use super::Bitmap;
+    use crate::cafs::cache::CacheManager;
+    use crate::cafs::layout::Geometry;
+    use crate::fake::Disk;
+    use crate::{Error, BLOCK_SIZE};
+    use spin::RwLock;
+    use std::sync::Arc;
+
+    const BLOCK_BITS: u64 = Geometry::new(BLOCK_SIZE).bitmap_bits();
+
+    fn fake_bitmap(blocks: u64) -> Bitmap {
+        let disk = Disk::new(blocks + 1);
+        let cache_manager = Arc::new(CacheManager::new(Arc::new(RwLock::new(disk)), BLOCK_SIZE));
+        let bitmap = Bitmap::new(1, blocks, cache_manager);
+        bitmap.format();
+        bitmap
+    }
+
+    #[test]
+    fn test_alloc_next_fit() {
+        let bitmap = fake_bitmap(2);
+        assert_eq!(bitmap.alloc().unwrap(), Some(0));
+        assert_eq!(bitmap.alloc().unwrap(), Some(1));
+        bitmap.dealloc(0).unwrap();
+        // next-fit does not go back to the freed bit until it wraps
+        assert_eq!(bitmap.alloc().unwrap(), Some(2));
+        assert_eq!(bitmap.free_count().unwrap(), 2 * BLOCK_BITS - 2);
+    }
+
+    #[test]
+    fn test_alloc_contiguous() {
+        let bitmap = fake_bitmap(2);
+        assert_eq!(bitmap.alloc_contiguous(10, 0).unwrap(), Some(0));
+        assert_eq!(bitmap.alloc_contiguous(100, 0).unwrap(), Some(10));
+        bitmap.dealloc_range(3, 4).unwrap();
+        // a run crossing the bitmap block boundary
+        assert_eq!(
+            bitmap.alloc_contiguous(200, BLOCK_BITS - 100).unwrap(),
+            Some(BLOCK_BITS - 100)
+        );
+        // the hole left by dealloc_range is too small, search wraps around
+        assert_eq!(
+            bitmap.alloc_contiguous(5, 2 * BLOCK_BITS - 2).unwrap(),
+            Some(110)
+        );
+        assert_eq!(bitmap.alloc_contiguous(4, 0).unwrap(), Some(3));
+        assert_eq!(bitmap.alloc_contiguous(2 * BLOCK_BITS, 0).unwrap(), None);
+        assert_eq!(bitmap.free_count().unwrap(), 2 * BLOCK_BITS - 315);
+    }
+
+    #[test]
+    fn test_alloc_extents() {
+        let bitmap = fake_bitmap(1);
+        assert_eq!(bitmap.alloc_contiguous(BLOCK_BITS, 0).unwrap(), Some(0));
+        bitmap.dealloc_range(10, 3).unwrap();
+        bitmap.dealloc_range(100, 70).unwrap();
+        bitmap.dealloc_range(1000, 5).unwrap();
+        assert_eq!(bitmap.alloc_extents(79, 500).unwrap(), None);
+        assert_eq!(
+            bitmap.alloc_extents(76, 500).unwrap(),
+            Some(vec![(1000, 5), (10, 3), (100, 68)])
+        );
+        assert_eq!(bitmap.free_count().unwrap(), 2);
+        assert_eq!(bitmap.alloc_contiguous(2, 0).unwrap(), Some(168));
+    }
+
+    #[test]
+    fn test_set_range_mismatch() {
+        let bitmap = fake_bitmap(2);
+        assert_eq!(
+            bitmap.alloc_contiguous(10, BLOCK_BITS - 5).unwrap(),
+            Some(BLOCK_BITS - 5)
+        );
+        // freeing bits that are not all allocated changes none of them
+        assert!(matches!(
+            bitmap.dealloc_range(BLOCK_BITS - 6, 4),
+            Err(Error::Corrupted(1))
+        ));
+        assert!(matches!(
+            bitmap.dealloc_range(BLOCK_BITS, 6),
+            Err(Error::Corrupted(2))
+        ));
+        assert!(matches!(
+            bitmap.alloc_at(BLOCK_BITS),
+            Err(Error::Corrupted(2))
+        ));
+        assert_eq!(bitmap.used_count(BLOCK_BITS - 6, 12).unwrap(), 10);
+        assert_eq!(bitmap.free_count().unwrap(), 2 * BLOCK_BITS - 10);
+    }
+
+    #[test]
+    fn test_concurrent_alloc() {
+        let bitmap = Arc::new(fake_bitmap(2));
+        let threads = (0..4)
+            .map(|_| {
+                let bitmap = bitmap.clone();
+                std::thread::spawn(move || {
+                    (0..50)
+                        .map(|_| bitmap.alloc_extents(3, 0).unwrap().unwrap())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut bits = threads
+            .into_iter()
+            .flat_map(|thread| thread.join().unwrap())
+            .flatten()
+            .flat_map(|(start, len)| start..start + len)
+            .collect::<Vec<_>>();
+        bits.sort_unstable();
+        bits.dedup();
+        assert_eq!(bits.len(), 4 * 50 * 3);
+        assert_eq!(bitmap.free_count().unwrap(), 2 * BLOCK_BITS - 600);
+    }
+
+    #[test]
+    fn test_corrupted() {
+        let disk = Arc::new(RwLock::new(Disk::new(2)));
+        let cache_manager = Arc::new(CacheManager::new(disk.clone(), BLOCK_SIZE));
+        Bitmap::new(1, 1, cache_manager.clone()).format();
+        cache_manager.flush();
+
+        disk.write().data[1][3] ^= 1;
+        let bitmap = Bitmap::new(1, 1, Arc::new(CacheManager::new(disk, BLOCK_SIZE)));
+        assert!(matches!(bitmap.alloc(), Err(Error::Corrupted(1))));
+    }
 }
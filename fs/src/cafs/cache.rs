@@ -1,26 +1,60 @@
+use super::crc::crc32c;
 use crate::BlockDevice;
-use crate::BLOCK_SIZE;
+use crate::{Error, BLOCK_SIZE, MAX_BLOCK_SIZE};
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use spin::RwLock;
 
 const CACHE_SIZE: usize = 32;
 
+/// An on-disk structure carrying a CRC32C of its own bytes.
+///
+/// The checksum is seeded with the id of the block holding the structure, so a
+/// valid structure read from the wrong block is caught as well.
+///
+/// # Safety
+/// `checksum_offset` and `len` must lie within the structure.
+pub unsafe trait Checksummed: Sized {
+    /// Offset of the little-endian `u32` checksum inside the structure.
+    fn checksum_offset(block_size: u64) -> usize;
+
+    /// Bytes of the structure covered by the checksum.
+    fn len(block_size: u64) -> usize {
+        core::mem::size_of::<Self>()
+    }
+}
+
+/// A filesystem block spanning `block_size / BLOCK_SIZE` device blocks.
+///
+/// The buffer is always `MAX_BLOCK_SIZE` long so that on-disk structures can
+/// be laid out for the largest block size. Only the first `block_size` bytes
+/// are loaded from and written back to the device; the rest stays zero.
 pub struct Cache {
-    cache: [u8; BLOCK_SIZE as usize],
+    cache: [u8; MAX_BLOCK_SIZE as usize],
     block_id: u64,
+    block_size: u64,
     block_device: Arc<RwLock<dyn BlockDevice>>,
     modified: bool,
 }
 
 impl Cache {
     /// Load a new BlockCache from disk.
-    pub fn new(block_id: u64, block_device: Arc<RwLock<dyn BlockDevice>>) -> Self {
-        let mut cache = [0u8; BLOCK_SIZE as usize];
-        block_device.read().read_block(block_id, &mut cache);
+    pub fn new(block_id: u64, block_size: u64, block_device: Arc<RwLock<dyn BlockDevice>>) -> Self {
+        let mut cache = [0u8; MAX_BLOCK_SIZE as usize];
+        let ratio = block_size / BLOCK_SIZE;
+        {
+            let device = block_device.read();
+            for (i, buf) in cache[..block_size as usize]
+                .chunks_exact_mut(BLOCK_SIZE as usize)
+                .enumerate()
+            {
+                device.read_block(block_id * ratio + i as u64, buf);
+            }
+        }
         Self {
             cache,
             block_id,
+            block_size,
             block_device,
             modified: false,
         }
@@ -35,7 +69,7 @@ impl Cache {
         T: Sized,
     {
         let type_size = core::mem::size_of::<T>();
-        assert!(offset + type_size <= BLOCK_SIZE as usize);
+        assert!(offset + type_size <= MAX_BLOCK_SIZE as usize);
         let addr = self.offset_addr(offset);
         &*(addr as *const T)
     }
@@ -45,7 +79,7 @@ impl Cache {
         T: Sized,
     {
         let type_size = core::mem::size_of::<T>();
-        assert!(offset + type_size <= BLOCK_SIZE as usize);
+        assert!(offset + type_size <= MAX_BLOCK_SIZE as usize);
         self.modified = true;
         let addr = self.offset_addr(offset);
         &mut *(addr as *mut T)
@@ -54,9 +88,14 @@ impl Cache {
     pub fn sync(&mut self) {
         if self.modified {
             self.modified = false;
-            self.block_device
-                .write()
-                .write_block(self.block_id, &self.cache);
+            let ratio = self.block_size / BLOCK_SIZE;
+            let mut device = self.block_device.write();
+            for (i, buf) in self.cache[..self.block_size as usize]
+                .chunks_exact(BLOCK_SIZE as usize)
+                .enumerate()
+            {
+                device.write_block(self.block_id * ratio + i as u64, buf);
+            }
         }
     }
 
@@ -67,6 +106,95 @@ impl Cache {
     pub unsafe fn modify<T, V>(&mut self, offset: usize, f: impl FnOnce(&mut T) -> V) -> V {
         f(self.get_mut(offset))
     }
+
+    /// Like `read`, but fail with `Error::Corrupted` if the checksum of the
+    /// structure does not match.
+    ///
+    /// # Safety
+    /// The bytes at `offset` must be laid out as a `T`.
+    pub unsafe fn read_checked<T: Checksummed, V>(
+        &self,
+        offset: usize,
+        f: impl FnOnce(&T) -> V,
+    ) -> Result<V, Error> {
+        self.verify::<T>(offset)?;
+        Ok(self.read(offset, f))
+    }
+
+    /// Like `modify`, but verify the structure first and update its checksum
+    /// afterwards.
+    ///
+    /// # Safety
+    /// The bytes at `offset` must be laid out as a `T`.
+    pub unsafe fn modify_checked<T: Checksummed, V>(
+        &mut self,
+        offset: usize,
+        f: impl FnOnce(&mut T) -> V,
+    ) -> Result<V, Error> {
+        self.verify::<T>(offset)?;
+        Ok(self.overwrite(offset, f))
+    }
+
+    /// Like `modify_checked`, but `f` changes a copy of the structure, which
+    /// replaces it only if `f` succeeds: a failed change leaves it as it was.
+    ///
+    /// # Safety
+    /// The bytes at `offset` must be laid out as a `T`.
+    pub unsafe fn try_modify_checked<T: Checksummed + Copy, V>(
+        &mut self,
+        offset: usize,
+        f: impl FnOnce(&mut T) -> Result<V, Error>,
+    ) -> Result<V, Error> {
+        self.verify::<T>(offset)?;
+        let mut copy = self.read(offset, |value: &T| *value);
+        let ret = f(&mut copy)?;
+        self.overwrite(offset, |value: &mut T| *value = copy);
+        Ok(ret)
+    }
+
+    /// Modify a structure without looking at its old contents, e.g. when it
+    /// is initialized, and update its checksum.
+    ///
+    /// # Safety
+    /// The bytes at `offset` must be laid out as a `T`.
+    pub unsafe fn overwrite<T: Checksummed, V>(
+        &mut self,
+        offset: usize,
+        f: impl FnOnce(&mut T) -> V,
+    ) -> V {
+        let ret = self.modify(offset, f);
+        let checksum = self.compute_checksum::<T>(offset);
+        let pos = offset + T::checksum_offset(self.block_size);
+        self.cache[pos..pos + 4].copy_from_slice(&checksum.to_le_bytes());
+        ret
+    }
+
+    /// CRC32C of the whole block, used for directory and file data blocks.
+    pub fn checksum(&self) -> u32 {
+        crc32c(
+            crc32c(0, &self.block_id.to_le_bytes()),
+            &self.cache[..self.block_size as usize],
+        )
+    }
+
+    fn verify<T: Checksummed>(&self, offset: usize) -> Result<(), Error> {
+        let pos = offset + T::checksum_offset(self.block_size);
+        let mut stored = [0u8; 4];
+        stored.copy_from_slice(&self.cache[pos..pos + 4]);
+        if u32::from_le_bytes(stored) == self.compute_checksum::<T>(offset) {
+            Ok(())
+        } else {
+            Err(Error::Corrupted(self.block_id))
+        }
+    }
+
+    fn compute_checksum<T: Checksummed>(&self, offset: usize) -> u32 {
+        let pos = offset + T::checksum_offset(self.block_size);
+        let end = offset + T::len(self.block_size);
+        let crc = crc32c(0, &self.block_id.to_le_bytes());
+        let crc = crc32c(crc, &self.cache[offset..pos]);
+        crc32c(crc, &self.cache[pos + 4..end])
+    }
 }
 
 impl Drop for Cache {
@@ -77,17 +205,25 @@ impl Drop for Cache {
 
 pub struct CacheManager {
     queue: RwLock<Vec<(u64, Arc<RwLock<Cache>>)>>,
+    block_size: u64,
     block_device: Arc<RwLock<dyn BlockDevice>>,
 }
 
 impl CacheManager {
-    pub fn new(block_device: Arc<RwLock<dyn BlockDevice>>) -> Self {
+    pub fn new(block_device: Arc<RwLock<dyn BlockDevice>>, block_size: u64) -> Self {
+        assert!(block_size.is_power_of_two());
+        assert!((BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size));
         Self {
             queue: RwLock::new(Vec::with_capacity(CACHE_SIZE)),
+            block_size,
             block_device,
         }
     }
 
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
     pub fn get(&self, block_id: u64) -> Arc<RwLock<Cache>> {
         let lock = self.queue.read();
         if let Some((_, cache)) = lock.iter().find(|(id, _)| *id == block_id) {
@@ -110,17 +246,22 @@ impl CacheManager {
                         panic!("Run out of BlockCache!");
                     }
                 }
-                let block_cache =
-                    Arc::new(RwLock::new(Cache::new(block_id, self.block_device.clone())));
+                let block_cache = Arc::new(RwLock::new(Cache::new(
+                    block_id,
+                    self.block_size,
+                    self.block_device.clone(),
+                )));
                 queue.push((block_id, block_cache.clone()));
                 block_cache
             }
         }
     }
 
+    /// Write back all modified blocks and flush the device.
     pub fn flush(&self) {
         for (_, cache) in &*self.queue.read() {
             cache.write().sync();
         }
+        self.block_device.write().flush();
     }
 }
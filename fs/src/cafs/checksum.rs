@@ -0,0 +1,125 @@
+use super::cache::{Cache, CacheManager};
+use super::layout::{ChecksumBlock, Geometry};
+use crate::fs::InodeType;
+use crate::{Error, MAX_BLOCK_SIZE};
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Checksums of the data area, one `u32` per data block.
+///
+/// Directory blocks are always checksummed, file data blocks only if the
+/// filesystem was made with `FEATURE_RO_COMPAT_DATA_CSUM`.
+pub struct ChecksumTable {
+    /// Where the table is, changes when the filesystem is resized.
+    start_block_id: AtomicU64,
+    blocks: AtomicU64,
+    data_area_start_block: u64,
+    /// Whether file data blocks are checksummed.
+    data: bool,
+    cache_manager: Arc<CacheManager>,
+}
+
+impl ChecksumTable {
+    pub fn new(
+        start_block_id: u64,
+        blocks: u64,
+        data_area_start_block: u64,
+        data: bool,
+        cache_manager: Arc<CacheManager>,
+    ) -> Self {
+        Self {
+            start_block_id: AtomicU64::new(start_block_id),
+            blocks: AtomicU64::new(blocks),
+            data_area_start_block,
+            data,
+            cache_manager,
+        }
+    }
+
+    /// Clear a freshly made table and seal the checksum of its blocks.
+    pub fn format(&self) {
+        let start_block_id = self.start_block_id.load(Ordering::Acquire);
+        for block_id in 0..self.blocks.load(Ordering::Acquire) {
+            unsafe {
+                self.cache_manager
+                    .get(block_id + start_block_id)
+                    .write()
+                    .overwrite(0, |block: &mut ChecksumBlock| block.fill(0));
+            }
+        }
+    }
+
+    /// Move the table to `blocks` blocks at `start_block_id`, keeping the
+    /// entries that still fit.
+    pub fn relocate(&self, start_block_id: u64, blocks: u64) -> Result<(), Error> {
+        let old_start_block_id = self.start_block_id.load(Ordering::Acquire);
+        let old_blocks = self.blocks.load(Ordering::Acquire);
+        if start_block_id == old_start_block_id && blocks == old_blocks {
+            return Ok(());
+        }
+        for block_id in 0..blocks {
+            let checksum_block = if block_id < old_blocks {
+                unsafe {
+                    self.cache_manager
+                        .get(block_id + old_start_block_id)
+                        .read()
+                        .read_checked(0, |block: &ChecksumBlock| *block)?
+                }
+            } else {
+                [0; MAX_BLOCK_SIZE as usize / 4]
+            };
+            unsafe {
+                self.cache_manager
+                    .get(block_id + start_block_id)
+                    .write()
+                    .overwrite(0, |block: &mut ChecksumBlock| *block = checksum_block);
+            }
+        }
+        self.start_block_id.store(start_block_id, Ordering::Release);
+        self.blocks.store(blocks, Ordering::Release);
+        Ok(())
+    }
+
+    /// Whether data blocks of an inode of type `type_` are checksummed.
+    pub fn covers(&self, type_: InodeType) -> bool {
+        type_ == InodeType::Dir || self.data
+    }
+
+    /// Record the checksum of data block `block_id`, whose contents are `cache`.
+    pub fn update(&self, block_id: u64, cache: &Cache) -> Result<(), Error> {
+        let checksum = cache.checksum();
+        let (table_block_id, index) = self.pos_of(block_id);
+        unsafe {
+            self.cache_manager
+                .get(table_block_id)
+                .write()
+                .modify_checked(0, |block: &mut ChecksumBlock| block[index] = checksum)
+        }
+    }
+
+    /// Check the contents `cache` of data block `block_id` against the table.
+    pub fn verify(&self, block_id: u64, cache: &Cache) -> Result<(), Error> {
+        let (table_block_id, index) = self.pos_of(block_id);
+        let checksum = unsafe {
+            self.cache_manager
+                .get(table_block_id)
+                .read()
+                .read_checked(0, |block: &ChecksumBlock| block[index])?
+        };
+        if checksum == cache.checksum() {
+            Ok(())
+        } else {
+            Err(Error::Corrupted(block_id))
+        }
+    }
+
+    /// Return (table block, entry) holding the checksum of data block `block_id`.
+    fn pos_of(&self, block_id: u64) -> (u64, usize) {
+        let per_block = Geometry::new(self.cache_manager.block_size()).checksums_per_block();
+        let id = block_id - self.data_area_start_block;
+        (
+            self.start_block_id.load(Ordering::Acquire) + id / per_block,
+            (id % per_block) as usize,
+        )
+    }
+}
@@ -1,62 +1,441 @@
-use super::cache::CacheManager;
+use super::cache::{CacheManager, Checksummed};
+use super::crc::crc32c;
 use crate::fs::InodeType;
-use crate::BLOCK_SIZE;
+use crate::{Error, BLOCK_SIZE, MAX_BLOCK_SIZE};
+use alloc::string::{String, ToString};
 use alloc::sync::Arc;
 use alloc::vec;
 use alloc::vec::Vec;
 
 const FS_MAGIC: u32 = 0x5138;
+/// On-disk format revision, bumped on incompatible layout changes.
+pub const FS_VERSION: u32 = 5;
 
+/// Block holding the primary copy of the `SuperBlock`.
+pub const SUPER_BLOCK_ID: u64 = 0;
+/// Block holding the backup copy of the `SuperBlock`.
+pub const BACKUP_SUPER_BLOCK_ID: u64 = 1;
+/// Blocks in front of the inode bitmap.
+pub const RESERVED_BLOCKS: u64 = 2;
+
+pub const LABEL_LENGTH_LIMIT: usize = 63;
+
+/// Inode areas a filesystem can gather by growing.
+pub const INODE_AREA_LIMIT: usize = 8;
+
+/// Snapshots a filesystem can hold at once.
+pub const SNAPSHOT_LIMIT: usize = 4;
+pub const SNAPSHOT_NAME_LIMIT: usize = 31;
+
+pub const XATTR_NAME_LIMIT: usize = 255;
+
+/// Bytes of a compressed file compressed together, the unit of reading.
+pub const CHUNK_SIZE: u64 = 16 << 10;
+
+/// Features an implementation may ignore and still read and write.
+pub const FEATURE_COMPAT_SUPP: u64 = 0;
+/// Features an implementation must understand to mount at all.
+pub const FEATURE_INCOMPAT_SUPP: u64 = FEATURE_INCOMPAT_INLINE_DATA | FEATURE_INCOMPAT_COMPRESSION;
+/// Features an implementation must understand to mount read-write.
+pub const FEATURE_RO_COMPAT_SUPP: u64 = FEATURE_RO_COMPAT_DATA_CSUM;
+
+/// Small files and directories keep their data in `Meta`.
+pub const FEATURE_INCOMPAT_INLINE_DATA: u64 = 1 << 0;
+/// Files may be stored LZ4 compressed, chunk by chunk.
+pub const FEATURE_INCOMPAT_COMPRESSION: u64 = 1 << 1;
+
+/// File data blocks are checksummed too, not only metadata and directories.
+pub const FEATURE_RO_COMPAT_DATA_CSUM: u64 = 1 << 0;
+
+pub const STATE_CLEAN: u32 = 1;
+pub const STATE_DIRTY: u32 = 2;
+
+/// A run of `blocks` blocks starting at block `start`.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[repr(C)]
+pub struct Extent {
+    pub start: u64,
+    pub blocks: u64,
+}
+
+impl Extent {
+    pub const fn new(start: u64, blocks: u64) -> Self {
+        Self { start, blocks }
+    }
+
+    pub const fn end(&self) -> u64 {
+        self.start + self.blocks
+    }
+}
+
+/// Where the areas of a CAFS are. Everything but the start of the data area
+/// may move when the filesystem is resized, so metadata moved into the data
+/// area is marked as used in the data bitmap.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(C)]
+pub struct Regions {
+    pub inode_bitmap: Extent,
+    /// Inode numbers run through the inode areas in order, unused areas are
+    /// empty.
+    pub inode_areas: [Extent; INODE_AREA_LIMIT],
+    /// Inodes in use or free, the rest of the inode bitmap is reserved.
+    pub inode_count: u64,
+    pub data_bitmap: Extent,
+    /// Checksums of data blocks.
+    pub checksum_table: Extent,
+    /// The rest of the data bitmap past the data area is reserved.
+    pub data_area: Extent,
+}
+
+// size: 4 + 4 + 8 * 2 + 200 + 8 * 3 + 16 + 64 + 8 * 4 + 4 + 4 + 8
+#[derive(Copy, Clone)]
 #[repr(C)]
 pub struct SuperBlock {
     magic: u32,
+    pub version: u32,
+    pub block_size: u64,
     pub total_blocks: u64,
-    pub inode_bitmap_blocks: u64,
-    pub inode_area_blocks: u64,
-    pub data_bitmap_blocks: u64,
-    pub data_area_blocks: u64,
+    pub regions: Regions,
+    pub feature_compat: u64,
+    pub feature_incompat: u64,
+    pub feature_ro_compat: u64,
+    pub uuid: [u8; 16],
+    pub label: [u8; LABEL_LENGTH_LIMIT + 1],
+    pub mkfs_time: u64,
+    pub mount_time: u64,
+    pub write_time: u64,
+    pub mount_count: u64,
+    pub state: u32,
+    checksum: u32,
+    /// Block of the `SnapshotTable`, 0 until the first snapshot is taken.
+    pub snapshot_table: u64,
 }
 
 impl SuperBlock {
+    #[allow(clippy::too_many_arguments)]
     pub fn initialize(
         &mut self,
+        block_size: u64,
         total_blocks: u64,
-        inode_bitmap_blocks: u64,
-        inode_area_blocks: u64,
-        data_bitmap_blocks: u64,
-        data_area_blocks: u64,
+        regions: Regions,
+        feature_incompat: u64,
+        feature_ro_compat: u64,
+        uuid: [u8; 16],
+        label: [u8; LABEL_LENGTH_LIMIT + 1],
+        now: u64,
     ) {
         *self = Self {
             magic: FS_MAGIC,
+            version: FS_VERSION,
+            block_size,
             total_blocks,
-            inode_bitmap_blocks,
-            inode_area_blocks,
-            data_bitmap_blocks,
-            data_area_blocks,
-        }
+            regions,
+            feature_compat: 0,
+            feature_incompat,
+            feature_ro_compat,
+            uuid,
+            label,
+            mkfs_time: now,
+            mount_time: 0,
+            write_time: now,
+            mount_count: 0,
+            state: STATE_CLEAN,
+            checksum: 0,
+            snapshot_table: 0,
+        };
+        self.update_checksum();
     }
+
     pub fn is_valid(&self) -> bool {
         self.magic == FS_MAGIC
+            && self.version == FS_VERSION
+            && self.checksum == self.compute_checksum()
+            && self.block_size.is_power_of_two()
+            && (BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&self.block_size)
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.state == STATE_CLEAN
+    }
+
+    /// Must be called after any field changes and before the block is synced.
+    pub fn update_checksum(&mut self) {
+        self.checksum = self.compute_checksum();
+    }
+
+    fn compute_checksum(&self) -> u32 {
+        let mut copy = *self;
+        copy.checksum = 0;
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                &copy as *const Self as *const u8,
+                core::mem::size_of::<Self>(),
+            )
+        };
+        crc32c(0, bytes)
+    }
+}
+
+/// A read-only image of the inodes, sharing data and index blocks with the
+/// live files until they change.
+// size: 32 + 8 + 8 + 16 * 3
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[repr(C)]
+pub struct SnapshotEntry {
+    pub name: [u8; SNAPSHOT_NAME_LIMIT + 1],
+    pub time: u64,
+    /// Inodes covered by `inode_bitmap`.
+    pub inode_count: u64,
+    /// Copy of the inode bitmap.
+    pub inode_bitmap: Extent,
+    /// `Meta` of the inodes in use, packed in inode number order.
+    pub inodes: Extent,
+    /// Copy of the data bitmap, data blocks that must not change while the
+    /// snapshot exists. It covers the snapshot's own blocks too.
+    pub data_bitmap: Extent,
+}
+
+impl SnapshotEntry {
+    pub fn is_used(&self) -> bool {
+        self.data_bitmap.blocks != 0
+    }
+
+    pub fn name(&self) -> String {
+        let len = self
+            .name
+            .iter()
+            .position(|&x| x == 0)
+            .unwrap_or(self.name.len());
+        String::from_utf8_lossy(&self.name[..len]).to_string()
     }
 }
 
-const DIRECT_COUNT: usize = 36;
-// size: 8 + 8 * 36 + 8 + 8 + 200
+#[repr(C)]
+pub struct SnapshotTable {
+    pub entries: [SnapshotEntry; SNAPSHOT_LIMIT],
+    checksum: u32,
+}
+
+unsafe impl Checksummed for SnapshotTable {
+    fn checksum_offset(_block_size: u64) -> usize {
+        core::mem::size_of::<SnapshotEntry>() * SNAPSHOT_LIMIT
+    }
+}
+
+/// Extended attributes of an inode, records of `name length: u8, value
+/// length: u16, name, value` packed after the header.
+#[repr(C)]
+pub struct XattrBlock {
+    /// Bytes of records.
+    len: u32,
+    checksum: u32,
+    records: [u8; MAX_BLOCK_SIZE as usize - 8],
+}
+
+unsafe impl Checksummed for XattrBlock {
+    fn checksum_offset(_block_size: u64) -> usize {
+        4
+    }
+
+    fn len(block_size: u64) -> usize {
+        block_size as usize
+    }
+}
+
+impl XattrBlock {
+    /// Whether `xattrs` fit in a block of `block_size` bytes.
+    pub fn fits(xattrs: &[(String, Vec<u8>)], block_size: u64) -> bool {
+        let len: usize = xattrs
+            .iter()
+            .map(|(name, value)| 3 + name.len() + value.len())
+            .sum();
+        len <= block_size as usize - 8
+    }
+
+    pub fn xattrs(&self) -> Vec<(String, Vec<u8>)> {
+        let mut xattrs = vec![];
+        let mut records = &self.records[..self.len as usize];
+        while records.len() >= 3 {
+            let name_len = records[0] as usize;
+            let value_len = u16::from_le_bytes([records[1], records[2]]) as usize;
+            let (name, value) = records[3..3 + name_len + value_len].split_at(name_len);
+            xattrs.push((String::from_utf8_lossy(name).to_string(), value.to_vec()));
+            records = &records[3 + name_len + value_len..];
+        }
+        xattrs
+    }
+
+    /// `xattrs` must fit, see `fits`.
+    pub fn set_xattrs(&mut self, xattrs: &[(String, Vec<u8>)]) {
+        let mut len = 0;
+        for (name, value) in xattrs {
+            let record = &mut self.records[len..len + 3 + name.len() + value.len()];
+            record[0] = name.len() as u8;
+            record[1..3].copy_from_slice(&(value.len() as u16).to_le_bytes());
+            record[3..3 + name.len()].copy_from_slice(name.as_bytes());
+            record[3 + name.len()..].copy_from_slice(value);
+            len += record.len();
+        }
+        self.len = len as u32;
+    }
+}
+
+/// Sizes of the on-disk layout derived from the block size chosen at mkfs time.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Geometry {
+    block_size: u64,
+}
+
+impl Geometry {
+    pub const fn new(block_size: u64) -> Self {
+        Self { block_size }
+    }
+
+    pub const fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    pub const fn block_bits(&self) -> u64 {
+        self.block_size * 8
+    }
+
+    /// num of entries in a indirect block, 63 for 512 B blocks
+    pub const fn indirect_len(&self) -> u64 {
+        self.block_size / 8 - 1
+    }
+
+    /// Bits held by one bitmap block, the last word holds the checksum.
+    pub const fn bitmap_bits(&self) -> u64 {
+        self.block_bits() - 64
+    }
+
+    /// Data blocks covered by one block of the checksum table, the last
+    /// entry holds the checksum of the table block itself.
+    pub const fn checksums_per_block(&self) -> u64 {
+        self.block_size / 4 - 1
+    }
+
+    pub const fn inodes_per_block(&self) -> u64 {
+        self.block_size / core::mem::size_of::<Meta>() as u64
+    }
+
+    /// Return block number correspond to size.
+    pub const fn data_blocks(&self, size: u64) -> u64 {
+        (size + self.block_size - 1) / self.block_size
+    }
+
+    /// Data blocks a chunk of a compressed file spans.
+    pub const fn chunk_blocks(&self) -> u64 {
+        CHUNK_SIZE / self.block_size
+    }
+
+    // ~ 18 KB for 512 B blocks
+    pub const fn direct_max(&self) -> u64 {
+        DIRECT_COUNT as u64 * self.block_size
+    }
+
+    pub const fn block_table_indirect_max(&self) -> u64 {
+        self.indirect_len() * self.block_size
+    }
+
+    // ~ 50 KB for 512 B blocks
+    pub const fn block_table_max(&self) -> u64 {
+        self.direct_max() + self.block_table_indirect_max()
+    }
+
+    pub const fn block_directory_indirect_max(&self) -> u64 {
+        self.indirect_len() * self.block_table_indirect_max()
+    }
+
+    // ~ 2 MB for 512 B blocks
+    pub const fn block_directory_max(&self) -> u64 {
+        self.direct_max() + self.block_directory_indirect_max()
+    }
+
+    pub const fn l3_indirect_max(&self) -> u64 {
+        self.indirect_len() * self.block_directory_indirect_max()
+    }
+
+    // ~ 122 MB for 512 B blocks
+    pub const fn l3_max(&self) -> u64 {
+        self.direct_max() + self.l3_indirect_max()
+    }
+
+    pub const fn l4_indirect_max(&self) -> u64 {
+        self.indirect_len() * self.l3_indirect_max()
+    }
+
+    // ~ 7 GB for 512 B blocks, ~ 249 TB for 4 KB blocks
+    pub const fn l4_max(&self) -> u64 {
+        self.direct_max() + self.l4_indirect_max()
+    }
+}
+
+/// A block of the data bitmap or the inode bitmap.
+pub type BitmapBlock = [u64; MAX_BLOCK_SIZE as usize / 8];
+
+unsafe impl Checksummed for BitmapBlock {
+    fn checksum_offset(block_size: u64) -> usize {
+        block_size as usize - 8
+    }
+
+    fn len(block_size: u64) -> usize {
+        block_size as usize
+    }
+}
+
+/// A block of the checksum table, one entry per data block.
+pub type ChecksumBlock = [u32; MAX_BLOCK_SIZE as usize / 4];
+
+unsafe impl Checksummed for ChecksumBlock {
+    fn checksum_offset(block_size: u64) -> usize {
+        block_size as usize - 4
+    }
+
+    fn len(block_size: u64) -> usize {
+        block_size as usize
+    }
+}
+
+const DIRECT_COUNT: usize = 35;
+/// Bytes of data a `Meta` can hold in place of the direct pointers.
+pub const INLINE_LIMIT: u64 = 8 * DIRECT_COUNT as u64;
+/// Set in `Meta::type_` when the data is inline.
+const INLINE_DATA: u32 = 1 << 31;
+/// Set in `Meta::type_` when the data blocks hold compressed chunks.
+///
+/// Chunk `i` spans data blocks `[i, i + 1) * chunk_blocks`. It is a hole if
+/// all of them are, stored as is if none of them are, and otherwise its
+/// first blocks hold the compressed length as `u32` LE and the LZ4 block.
+const COMPRESSED: u32 = 1 << 30;
+
+// size: 8 + 8 * 35 + 8 + 8 + 4 + 4 + 200
+#[derive(Copy, Clone)]
 #[repr(C)]
 pub struct Meta {
     size: u64,
     direct: [u64; DIRECT_COUNT],
     indirect: u64,
-    type_: InodeType,
+    /// `XattrBlock` of the inode, 0 if it has no extended attributes.
+    xattr: u64,
+    type_: u32,
+    checksum: u32,
     name: [u8; 199 + 1],
 }
 
+unsafe impl Checksummed for Meta {
+    fn checksum_offset(_block_size: u64) -> usize {
+        8 + 8 * DIRECT_COUNT + 8 + 8 + 4
+    }
+}
+
 impl Meta {
     pub fn init(&mut self, type_: InodeType, name: [u8; 200]) {
         self.size = 0;
         self.direct.iter_mut().for_each(|v| *v = 0);
         self.indirect = 0;
-        self.type_ = type_;
+        self.xattr = 0;
+        self.type_ = type_ as u32;
         self.name = name;
     }
 
@@ -64,8 +443,14 @@ impl Meta {
         self.size
     }
 
+    /// Only valid on a verified `Meta`, any type other than a directory reads
+    /// as a file.
     pub fn type_(&self) -> InodeType {
-        self.type_
+        if self.type_ & !(INLINE_DATA | COMPRESSED) == InodeType::Dir as u32 {
+            InodeType::Dir
+        } else {
+            InodeType::File
+        }
     }
 
     pub fn direct(&self) -> &[u64] {
@@ -80,80 +465,138 @@ impl Meta {
         self.name
     }
 
+    /// Whether the data is kept in `direct` rather than in data blocks.
+    pub fn is_inline(&self) -> bool {
+        self.type_ & INLINE_DATA != 0
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.type_ & COMPRESSED != 0
+    }
+
+    /// Only flips the flag, the data must be rewritten to match.
+    pub fn set_compressed(&mut self, compressed: bool) {
+        if compressed {
+            self.type_ |= COMPRESSED;
+        } else {
+            self.type_ &= !COMPRESSED;
+        }
+    }
+
+    pub fn inline_data(&self) -> Vec<u8> {
+        assert!(self.is_inline());
+        let mut data = self
+            .direct
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect::<Vec<_>>();
+        data.truncate(self.size as usize);
+        data
+    }
+
+    /// Keep `data` inline, the file must have no blocks.
+    pub fn set_inline_data(&mut self, data: &[u8]) {
+        assert!(data.len() as u64 <= INLINE_LIMIT && self.indirect == 0);
+        let mut bytes = [0; INLINE_LIMIT as usize];
+        bytes[..data.len()].copy_from_slice(data);
+        for (word, chunk) in self.direct.iter_mut().zip(bytes.chunks(8)) {
+            *word = u64::from_le_bytes(chunk.try_into().unwrap());
+        }
+        self.size = data.len() as u64;
+        self.type_ |= INLINE_DATA;
+    }
+
+    /// Return the inline data and turn the file into an empty one using
+    /// blocks.
+    pub fn take_inline_data(&mut self) -> Vec<u8> {
+        if !self.is_inline() {
+            return vec![];
+        }
+        let data = self.inline_data();
+        self.direct.fill(0);
+        self.size = 0;
+        self.type_ &= !INLINE_DATA;
+        data
+    }
+
+    pub fn xattr(&self) -> u64 {
+        self.xattr
+    }
+
+    pub fn set_xattr(&mut self, block_id: u64) {
+        self.xattr = block_id;
+    }
+
     pub fn is_dir(&self) -> bool {
-        self.type_ == InodeType::Dir
+        self.type_() == InodeType::Dir
     }
 
     pub fn is_file(&self) -> bool {
-        self.type_ == InodeType::File
+        self.type_() == InodeType::File
     }
 
-    pub fn get_block_id(&self, inner_id: u64, cache_manager: Arc<CacheManager>) -> Option<u64> {
-        unsafe {
-            if ((self.size + BLOCK_SIZE - 1) / BLOCK_SIZE) <= inner_id {
-                None
-            } else if inner_id < DIRECT_COUNT as u64 {
-                Some(self.direct[inner_id as usize])
-            } else {
-                let cache = cache_manager.get(self.indirect);
-                let cache = cache.read();
-                Some(
-                    cache
-                        .get_ref::<IndirectBlock>(0)
-                        .get_block_id(inner_id - DIRECT_COUNT as u64, cache_manager),
-                )
-            }
+    pub fn get_block_id(
+        &self,
+        inner_id: u64,
+        cache_manager: Arc<CacheManager>,
+    ) -> Result<Option<u64>, Error> {
+        let geometry = Geometry::new(cache_manager.block_size());
+        if self.is_inline() || geometry.data_blocks(self.size) <= inner_id {
+            Ok(None)
+        } else if inner_id < DIRECT_COUNT as u64 {
+            Ok(Some(self.direct[inner_id as usize]))
+        } else {
+            IndirectBlock::get_block_id(
+                self.indirect,
+                inner_id - DIRECT_COUNT as u64,
+                cache_manager,
+            )
+            .map(Some)
         }
     }
 
     /// return (index, blocks)
-    pub fn blocks(&self, cache_manager: Arc<CacheManager>) -> (Vec<u64>, Vec<u64>) {
+    ///
+    /// `blocks` has one entry per data block in file order, 0 for a hole.
+    /// Both are empty for inline data.
+    pub fn blocks(&self, cache_manager: Arc<CacheManager>) -> Result<(Vec<u64>, Vec<u64>), Error> {
+        if self.is_inline() {
+            return Ok((vec![], vec![]));
+        }
+        let data_blocks = self.data_blocks(Geometry::new(cache_manager.block_size())) as usize;
         let mut blocks = self
             .direct
             .iter()
-            .filter(|x| **x != 0)
-            .map(|x| *x)
+            .take(data_blocks)
+            .copied()
             .collect::<Vec<_>>();
         let mut index = vec![];
         if self.indirect != 0 {
             index.push(self.indirect);
-            let (mut index_ids, mut data_ids) = unsafe {
-                cache_manager
-                    .get(self.indirect)
-                    .read()
-                    .read(0, |block: &IndirectBlock| {
-                        block.to_vec(cache_manager.clone(), None)
-                    })
-            };
+            let (mut index_ids, mut data_ids) =
+                IndirectBlock::to_vec(self.indirect, cache_manager, None)?;
             blocks.append(&mut data_ids);
             index.append(&mut index_ids);
         }
+        blocks.truncate(data_blocks);
         index.sort();
-        (index, blocks)
+        Ok((index, blocks))
     }
 
     /// Return block number correspond to size.
-    pub fn data_blocks(&self) -> u64 {
-        Self::_data_blocks(self.size)
-    }
-
-    pub fn _data_blocks(size: u64) -> u64 {
-        (size + BLOCK_SIZE - 1) / BLOCK_SIZE
+    pub fn data_blocks(&self, geometry: Geometry) -> u64 {
+        geometry.data_blocks(self.size)
     }
 
     /// Return number of blocks needed including indirect block
-    pub fn index_blocks(size: u64) -> LevelInfo {
-        let indirect_size = if size >= DIRECT_MAX {
-            size - DIRECT_MAX
-        } else {
-            0
-        };
-        let block_table_len =
-            (indirect_size + BLOCK_TABLE_INDIRECT_MAX - 1) / BLOCK_TABLE_INDIRECT_MAX;
-        let block_directory_len =
-            (indirect_size + BLOCK_DIRECTORY_INDIRECT_MAX - 1) / BLOCK_DIRECTORY_INDIRECT_MAX;
-        let l3_len = (indirect_size + L3_INDIRECT_MAX - 1) / L3_INDIRECT_MAX;
-        let (l4, l3, block_directory, block_table, direct) = if size > L3_MAX {
+    pub fn index_blocks(size: u64, geometry: Geometry) -> LevelInfo {
+        let indirect_size = size.saturating_sub(geometry.direct_max());
+        let block_table_len = (indirect_size + geometry.block_table_indirect_max() - 1)
+            / geometry.block_table_indirect_max();
+        let block_directory_len = (indirect_size + geometry.block_directory_indirect_max() - 1)
+            / geometry.block_directory_indirect_max();
+        let l3_len = (indirect_size + geometry.l3_indirect_max() - 1) / geometry.l3_indirect_max();
+        let (l4, l3, block_directory, block_table, direct) = if size > geometry.l3_max() {
             (
                 1,
                 l3_len,
@@ -161,7 +604,7 @@ impl Meta {
                 block_table_len,
                 DIRECT_COUNT as u64,
             )
-        } else if size > BLOCK_DIRECTORY_MAX {
+        } else if size > geometry.block_directory_max() {
             (
                 0,
                 1,
@@ -169,12 +612,12 @@ impl Meta {
                 block_table_len,
                 DIRECT_COUNT as u64,
             )
-        } else if size > BLOCK_TABLE_MAX {
+        } else if size > geometry.block_table_max() {
             (0, 0, 1, block_table_len, DIRECT_COUNT as u64)
-        } else if size > DIRECT_MAX {
+        } else if size > geometry.direct_max() {
             (0, 0, 0, 1, DIRECT_COUNT as u64)
         } else {
-            (0, 0, 0, 0, (size + BLOCK_SIZE - 1) / BLOCK_SIZE)
+            (0, 0, 0, 0, geometry.data_blocks(size))
         };
         LevelInfo {
             l4,
@@ -192,6 +635,8 @@ impl Meta {
         data: Vec<u64>,
         cache_manager: Arc<CacheManager>,
     ) {
+        let geometry = Geometry::new(cache_manager.block_size());
+        let indirect_len = geometry.indirect_len() as usize;
         let mut index_iter = index.into_iter().chain(core::iter::repeat(0));
         let mut data_iter = data.into_iter().chain(core::iter::repeat(0));
 
@@ -204,11 +649,7 @@ impl Meta {
 
         let curr_level = level_info.root_level();
 
-        let indirect_size = if self.size >= DIRECT_MAX {
-            self.size - DIRECT_MAX
-        } else {
-            0
-        };
+        let indirect_size = self.size.saturating_sub(geometry.direct_max());
         if indirect_size != 0 {
             let l4_id = if level_info.l4 != 0 {
                 index_iter.next().unwrap()
@@ -236,16 +677,10 @@ impl Meta {
                     cache_manager
                         .get(l4_id)
                         .write()
-                        .modify(0, |block: &mut IndirectBlock| {
-                            block.type_ = IndirectBlockType::L4;
-                            block.entries = l3_ids
-                                .iter()
-                                .map(|x| *x)
-                                .chain(core::iter::repeat(0))
-                                .take(INDIRECT_LEN)
-                                .collect::<Vec<_>>()
-                                .try_into()
-                                .unwrap();
+                        .overwrite(0, |block: &mut IndirectBlock| {
+                            block.type_ = IndirectBlockType::L4 as u32;
+                            block.entries =
+                                IndirectBlock::entries_from(l3_ids.iter().copied(), indirect_len);
                         });
                 }
             }
@@ -255,14 +690,10 @@ impl Meta {
                     cache_manager
                         .get(*i)
                         .write()
-                        .modify(0, |block: &mut IndirectBlock| {
-                            block.type_ = IndirectBlockType::L3;
-                            block.entries = l3_entries
-                                .by_ref()
-                                .take(INDIRECT_LEN)
-                                .collect::<Vec<_>>()
-                                .try_into()
-                                .unwrap();
+                        .overwrite(0, |block: &mut IndirectBlock| {
+                            block.type_ = IndirectBlockType::L3 as u32;
+                            block.entries =
+                                IndirectBlock::entries_from(l3_entries.by_ref(), indirect_len);
                         });
                 }
             }
@@ -272,14 +703,10 @@ impl Meta {
                     cache_manager
                         .get(*i)
                         .write()
-                        .modify(0, |block: &mut IndirectBlock| {
-                            block.type_ = IndirectBlockType::BlockDirectory;
-                            block.entries = l2_entries
-                                .by_ref()
-                                .take(INDIRECT_LEN)
-                                .collect::<Vec<_>>()
-                                .try_into()
-                                .unwrap();
+                        .overwrite(0, |block: &mut IndirectBlock| {
+                            block.type_ = IndirectBlockType::BlockDirectory as u32;
+                            block.entries =
+                                IndirectBlock::entries_from(l2_entries.by_ref(), indirect_len);
                         });
                 }
             }
@@ -288,14 +715,10 @@ impl Meta {
                     cache_manager
                         .get(*i)
                         .write()
-                        .modify(0, |block: &mut IndirectBlock| {
-                            block.type_ = IndirectBlockType::BlockTable;
-                            block.entries = data_iter
-                                .by_ref()
-                                .take(INDIRECT_LEN)
-                                .collect::<Vec<_>>()
-                                .try_into()
-                                .unwrap();
+                        .overwrite(0, |block: &mut IndirectBlock| {
+                            block.type_ = IndirectBlockType::BlockTable as u32;
+                            block.entries =
+                                IndirectBlock::entries_from(data_iter.by_ref(), indirect_len);
                         });
                 }
             }
@@ -323,15 +746,15 @@ impl Meta {
         mut data_blocks: Vec<u64>,
         mut index_blocks: Vec<u64>,
         cache_manager: Arc<CacheManager>,
-    ) {
+    ) -> Result<(), Error> {
         assert!(new_size >= self.size);
+        let (mut index, mut data) = self.blocks(cache_manager.clone())?;
         self.size = new_size;
-
-        let (mut index, mut data) = self.blocks(cache_manager.clone());
         index.append(&mut index_blocks);
         data.append(&mut data_blocks);
 
         self.forward(new_info, index, data, cache_manager);
+        Ok(())
     }
 
     /// # Panic
@@ -340,58 +763,45 @@ impl Meta {
         &mut self,
         new_size: u64,
         cache_manager: Arc<CacheManager>,
-    ) -> (Vec<u64>, Vec<u64>) {
+    ) -> Result<(Vec<u64>, Vec<u64>), Error> {
         assert!(new_size <= self.size);
-        let prev_data_blocks = self.data_blocks();
-        let curr_info = Self::index_blocks(self.size);
-        let new_info = Self::index_blocks(new_size);
+        let geometry = Geometry::new(cache_manager.block_size());
+        let prev_data_blocks = self.data_blocks(geometry);
+        let curr_info = Self::index_blocks(self.size, geometry);
+        let new_info = Self::index_blocks(new_size, geometry);
+        let (mut index, mut data) = self.blocks(cache_manager.clone())?;
         self.size = new_size;
 
-        let (mut index, mut data) = self.blocks(cache_manager.clone());
         let mut collected_index_ids = vec![];
         let mut collected_data_ids = vec![];
         for i in 0..(curr_info.index_block_count() - new_info.index_block_count()) {
             collected_index_ids.push(index.pop().unwrap());
         }
-        for i in 0..(prev_data_blocks - Meta::_data_blocks(new_size)) {
-            collected_data_ids.push(data.pop().unwrap());
+        for i in 0..(prev_data_blocks - geometry.data_blocks(new_size)) {
+            let id = data.pop().unwrap();
+            if id != 0 {
+                collected_data_ids.push(id);
+            }
         }
 
         self.forward(new_info, index, data, cache_manager);
 
-        (collected_index_ids, collected_data_ids)
+        Ok((collected_index_ids, collected_data_ids))
     }
 
     /// Clear size to zero and return blocks that should be deallocated.
-    pub fn clear_size(&mut self, cache_manager: Arc<CacheManager>) -> Vec<u64> {
-        let (mut index, mut data) = self.blocks(cache_manager);
-        self.init(self.type_, self.name);
-        index.append(&mut data);
-        index
+    pub fn clear_size(&mut self, cache_manager: Arc<CacheManager>) -> Result<Vec<u64>, Error> {
+        let (mut index, data) = self.blocks(cache_manager)?;
+        let xattr = self.xattr;
+        self.init(self.type_(), self.name);
+        self.xattr = xattr;
+        index.extend(data.into_iter().filter(|id| *id != 0));
+        Ok(index)
     }
 }
 
-// num of entries in a indirect block = 511
-const INDIRECT_LEN: usize = (BLOCK_SIZE / 8) as usize - 1;
-// ~ 18 KB
-const DIRECT_MAX: u64 = DIRECT_COUNT as u64 * BLOCK_SIZE;
-
-const BLOCK_TABLE_INDIRECT_MAX: u64 = INDIRECT_LEN as u64 * BLOCK_SIZE;
-// ~ 50 KB
-const BLOCK_TABLE_MAX: u64 = DIRECT_MAX + BLOCK_TABLE_INDIRECT_MAX;
-
-const BLOCK_DIRECTORY_INDIRECT_MAX: u64 = (INDIRECT_LEN * INDIRECT_LEN) as u64 * BLOCK_SIZE;
-// ~ 2 MB
-const BLOCK_DIRECTORY_MAX: u64 = DIRECT_MAX + BLOCK_DIRECTORY_INDIRECT_MAX;
-
-const L3_INDIRECT_MAX: u64 = (INDIRECT_LEN * INDIRECT_LEN * INDIRECT_LEN) as u64 * BLOCK_SIZE;
-// ~ 122 MB
-const L3_MAX: u64 = DIRECT_MAX + L3_INDIRECT_MAX;
-
-const L4_INDIRECT_MAX: u64 =
-    (INDIRECT_LEN * INDIRECT_LEN * INDIRECT_LEN * INDIRECT_LEN) as u64 * BLOCK_SIZE;
-// ~ 7 GB
-const L4_MAX: u64 = DIRECT_MAX + L4_INDIRECT_MAX;
+// num of entries in a indirect block of the largest block size
+const MAX_INDIRECT_LEN: usize = (MAX_BLOCK_SIZE / 8) as usize - 1;
 
 #[derive(Debug, Eq, PartialEq)]
 pub struct LevelInfo {
@@ -433,14 +843,27 @@ impl LevelInfo {
     }
 }
 
+/// Only the first `Geometry::indirect_len` entries fit in a block, the rest
+/// are always zero.
 #[repr(C)]
 pub struct IndirectBlock {
-    pub entries: [u64; INDIRECT_LEN],
-    pub type_: IndirectBlockType,
+    pub type_: u32,
+    pub checksum: u32,
+    pub entries: [u64; MAX_INDIRECT_LEN],
+}
+
+unsafe impl Checksummed for IndirectBlock {
+    fn checksum_offset(_block_size: u64) -> usize {
+        4
+    }
+
+    fn len(block_size: u64) -> usize {
+        block_size as usize
+    }
 }
 
 #[derive(PartialEq, Copy, Clone, Debug)]
-#[repr(u64)]
+#[repr(u32)]
 pub enum IndirectBlockType {
     BlockTable,
     BlockDirectory,
@@ -449,108 +872,138 @@ pub enum IndirectBlockType {
 }
 
 impl IndirectBlockType {
+    pub fn from_raw(raw: u32) -> Option<Self> {
+        match raw {
+            0 => Some(Self::BlockTable),
+            1 => Some(Self::BlockDirectory),
+            2 => Some(Self::L3),
+            3 => Some(Self::L4),
+            _ => None,
+        }
+    }
+
     pub fn add(&self) -> Self {
-        if *self as u64 == Self::L4 as u64 {
-            return Self::L4;
+        match self {
+            Self::BlockTable => Self::BlockDirectory,
+            Self::BlockDirectory => Self::L3,
+            Self::L3 | Self::L4 => Self::L4,
         }
-        unsafe { *(&(*self as u64 + 1) as *const u64 as *const Self) }
     }
 
     pub fn decrease(&self) -> Self {
-        if *self as u64 == Self::BlockTable as u64 {
-            return Self::BlockTable;
+        match self {
+            Self::BlockTable | Self::BlockDirectory => Self::BlockTable,
+            Self::L3 => Self::BlockDirectory,
+            Self::L4 => Self::L3,
         }
-        unsafe { *(&(*self as u64 - 1) as *const u64 as *const Self) }
     }
 }
 
 impl<'block> IndirectBlock {
     fn read(&self, cache_manager: &CacheManager) {}
 
-    fn get_block_id(&self, inner_id: u64, cache_manager: Arc<CacheManager>) -> u64 {
-        unsafe {
-            match self.type_ {
-                IndirectBlockType::BlockTable => self.entries[inner_id as usize],
-                dir => {
-                    let divisor = match dir {
-                        IndirectBlockType::BlockTable => unreachable!(),
-                        IndirectBlockType::BlockDirectory => INDIRECT_LEN as u64,
-                        IndirectBlockType::L3 => (INDIRECT_LEN * INDIRECT_LEN) as u64,
-                        IndirectBlockType::L4 => {
-                            (INDIRECT_LEN * INDIRECT_LEN * INDIRECT_LEN) as u64
-                        }
-                    };
-                    let index = inner_id / divisor;
-                    let offset = inner_id % divisor;
-                    cache_manager
-                        .get(self.entries[index as usize])
-                        .read()
-                        .get_ref::<IndirectBlock>(0)
-                        .get_block_id(offset, cache_manager)
-                }
-            }
+    /// Take `len` entries from `ids`, padding with zero.
+    fn entries_from(ids: impl Iterator<Item = u64>, len: usize) -> [u64; MAX_INDIRECT_LEN] {
+        let mut entries = [0; MAX_INDIRECT_LEN];
+        for (entry, id) in entries.iter_mut().take(len).zip(ids) {
+            *entry = id;
         }
+        entries
+    }
+
+    /// Verify the index block `block_id` and return its type and entries.
+    fn load(
+        block_id: u64,
+        cache_manager: &CacheManager,
+    ) -> Result<(IndirectBlockType, [u64; MAX_INDIRECT_LEN]), Error> {
+        let (type_, entries) = unsafe {
+            cache_manager
+                .get(block_id)
+                .read()
+                .read_checked(0, |block: &IndirectBlock| (block.type_, block.entries))?
+        };
+        let type_ = IndirectBlockType::from_raw(type_).ok_or(Error::Corrupted(block_id))?;
+        Ok((type_, entries))
+    }
+
+    fn get_block_id(
+        block_id: u64,
+        inner_id: u64,
+        cache_manager: Arc<CacheManager>,
+    ) -> Result<u64, Error> {
+        let indirect_len = Geometry::new(cache_manager.block_size()).indirect_len();
+        let (type_, entries) = Self::load(block_id, &cache_manager)?;
+        let divisor = match type_ {
+            IndirectBlockType::BlockTable => return Ok(entries[inner_id as usize]),
+            IndirectBlockType::BlockDirectory => indirect_len,
+            IndirectBlockType::L3 => indirect_len * indirect_len,
+            IndirectBlockType::L4 => indirect_len * indirect_len * indirect_len,
+        };
+        let index = inner_id / divisor;
+        let offset = inner_id % divisor;
+        Self::get_block_id(entries[index as usize], offset, cache_manager)
     }
 
+    /// Return (index, data) blocks of the tree rooted at `block_id`, not
+    /// including `block_id` itself. Subtrees rooted in `filter` are skipped.
+    ///
+    /// Data blocks keep their position, holes and the unused tail of the
+    /// last block table read as 0.
     pub fn to_vec(
-        &self,
+        block_id: u64,
         cache_manager: Arc<CacheManager>,
         filter: Option<&Vec<u64>>,
-    ) -> (Vec<u64>, Vec<u64>) {
+    ) -> Result<(Vec<u64>, Vec<u64>), Error> {
         let mut data_ids = Vec::new();
         let mut index_ids = Vec::new();
-        self._to_vec(&mut data_ids, &mut index_ids, cache_manager.clone(), filter);
-        (index_ids, data_ids)
+        Self::_to_vec(
+            block_id,
+            &mut data_ids,
+            &mut index_ids,
+            cache_manager.clone(),
+            filter,
+        )?;
+        Ok((index_ids, data_ids))
     }
 
     fn _to_vec(
-        &self,
+        block_id: u64,
         data_ids: &mut Vec<u64>,
         index_ids: &mut Vec<u64>,
         cache_manager: Arc<CacheManager>,
         filter: Option<&Vec<u64>>,
-    ) {
-        unsafe {
-            match self.type_ {
-                IndirectBlockType::BlockTable => {
-                    for id in self.entries.iter().filter(|x| {
-                        **x != 0
-                            && (filter.is_none()
-                                || (filter.is_some() && !filter.unwrap().contains(*x)))
-                    }) {
-                        data_ids.push(*id)
-                    }
-                }
-                _ => {
-                    for id in self.entries.iter().filter(|x| {
-                        **x != 0
-                            && (filter.is_none()
-                                || (filter.is_some() && !filter.unwrap().contains(*x)))
-                    }) {
-                        index_ids.push(*id);
-                        cache_manager
-                            .get(*id)
-                            .read()
-                            .get_ref::<IndirectBlock>(0)
-                            ._to_vec(data_ids, index_ids, cache_manager.clone(), filter);
-                    }
+    ) -> Result<(), Error> {
+        let indirect_len = Geometry::new(cache_manager.block_size()).indirect_len() as usize;
+        let (type_, entries) = Self::load(block_id, &cache_manager)?;
+        let filtered = |x: &u64| *x != 0 && filter.is_some() && filter.unwrap().contains(x);
+        match type_ {
+            IndirectBlockType::BlockTable => data_ids.extend(
+                entries
+                    .into_iter()
+                    .take(indirect_len)
+                    .filter(|x| !filtered(x)),
+            ),
+            _ => {
+                for id in entries.into_iter().filter(|x| *x != 0 && !filtered(x)) {
+                    index_ids.push(id);
+                    Self::_to_vec(id, data_ids, index_ids, cache_manager.clone(), filter)?;
                 }
             }
         }
+        Ok(())
     }
 }
 
-pub type DataBlock = [u8; BLOCK_SIZE as usize];
+pub type DataBlock = [u8; MAX_BLOCK_SIZE as usize];
 
 #[cfg(test)]
 mod test {
     extern crate std;
 
     use super::{
-        IndirectBlock, IndirectBlockType, InodeType, LevelInfo, Meta, BLOCK_DIRECTORY_INDIRECT_MAX,
-        BLOCK_DIRECTORY_MAX, BLOCK_TABLE_INDIRECT_MAX, BLOCK_TABLE_MAX, DIRECT_COUNT, DIRECT_MAX,
-        INDIRECT_LEN, L3_INDIRECT_MAX, L3_MAX, L4_MAX,
+        Geometry, IndirectBlock, IndirectBlockType, InodeType, LevelInfo, Meta, DIRECT_COUNT,
     };
+    use crate::cafs::crc::crc32c;
     use alloc::vec;
     use alloc::vec::Vec;
     use spin::rwlock::RwLock;
@@ -563,6 +1016,17 @@ mod test {
     use super::super::cache::CacheManager;
     use crate::{BlockDevice, BLOCK_SIZE};
 
+    const G: Geometry = Geometry::new(BLOCK_SIZE);
+    const INDIRECT_LEN: usize = G.indirect_len() as usize;
+    const DIRECT_MAX: u64 = G.direct_max();
+    const BLOCK_TABLE_INDIRECT_MAX: u64 = G.block_table_indirect_max();
+    const BLOCK_TABLE_MAX: u64 = G.block_table_max();
+    const BLOCK_DIRECTORY_INDIRECT_MAX: u64 = G.block_directory_indirect_max();
+    const BLOCK_DIRECTORY_MAX: u64 = G.block_directory_max();
+    const L3_INDIRECT_MAX: u64 = G.l3_indirect_max();
+    const L3_MAX: u64 = G.l3_max();
+    const L4_MAX: u64 = G.l4_max();
+
     #[derive(Debug)]
     pub struct FakeDisk {
         pub total_blocks: u64,
@@ -601,10 +1065,26 @@ mod test {
         size: u64,
         direct: [u64; DIRECT_COUNT],
         indirect: u64,
-        type_: InodeType,
+        xattr: u64,
+        type_: u32,
+        checksum: u32,
         name: [u8; 199 + 1],
     }
 
+    /// Seal the checksum of `block` and write it to `block_id`.
+    fn write_indirect(fake_disk: &mut FakeDisk, block_id: u64, mut block: IndirectBlock) {
+        let bytes = |block: &IndirectBlock| unsafe {
+            &*slice_from_raw_parts(
+                block as *const IndirectBlock as *const u8,
+                BLOCK_SIZE as usize,
+            )
+        };
+        let crc = crc32c(0, &block_id.to_le_bytes());
+        let crc = crc32c(crc, &bytes(&block)[..4]);
+        block.checksum = crc32c(crc, &bytes(&block)[8..]);
+        fake_disk.write_block(block_id, bytes(&block));
+    }
+
     /// return (inode, index_ids, ids, cache_manager)
     fn fake_inode(size: u64) -> (Meta, Vec<u64>, Vec<u64>, Arc<CacheManager>, Range<u64>) {
         let mut fake_disk = FakeDisk::new(L4_MAX / BLOCK_SIZE);
@@ -628,14 +1108,12 @@ mod test {
                 .collect::<Vec<_>>();
             let l3_root = l4_entries[0];
             let l4 = IndirectBlock {
-                entries: l4_entries.clone().try_into().unwrap(),
-                type_: IndirectBlockType::L4,
+                entries: IndirectBlock::entries_from(l4_entries.iter().copied(), INDIRECT_LEN),
+                type_: IndirectBlockType::L4 as u32,
+                checksum: 0,
             };
             let l4_root = 11;
-            let addr = &l4 as *const IndirectBlock as *const u8;
-            fake_disk.write_block(l4_root, unsafe {
-                &*slice_from_raw_parts(addr, BLOCK_SIZE as usize)
-            });
+            write_indirect(&mut fake_disk, l4_root, l4);
 
             // L3
             let block_directory_counts =
@@ -688,35 +1166,38 @@ mod test {
                             (&mut blocks).take(INDIRECT_LEN).collect::<Vec<_>>();
 
                         let block_table = IndirectBlock {
-                            entries: block_table_entries_ids.try_into().unwrap(),
-                            type_: IndirectBlockType::BlockTable,
+                            entries: IndirectBlock::entries_from(
+                                block_table_entries_ids.into_iter(),
+                                INDIRECT_LEN,
+                            ),
+                            type_: IndirectBlockType::BlockTable as u32,
+                            checksum: 0,
                         };
-                        let addr = &block_table as *const IndirectBlock as *const u8;
-                        fake_disk.write_block(*block_directory_entry, unsafe {
-                            &*slice_from_raw_parts(addr, BLOCK_SIZE as usize)
-                        });
+                        write_indirect(&mut fake_disk, *block_directory_entry, block_table);
                     }
 
                     let block_directory = IndirectBlock {
-                        entries: block_directory_entries_ids.try_into().unwrap(),
-                        type_: IndirectBlockType::BlockDirectory,
+                        entries: IndirectBlock::entries_from(
+                            block_directory_entries_ids.iter().copied(),
+                            INDIRECT_LEN,
+                        ),
+                        type_: IndirectBlockType::BlockDirectory as u32,
+                        checksum: 0,
                     };
-                    let addr = &block_directory as *const IndirectBlock as *const u8;
-                    fake_disk.write_block(*l3_entry, unsafe {
-                        &*slice_from_raw_parts(addr, BLOCK_SIZE as usize)
-                    });
+                    write_indirect(&mut fake_disk, *l3_entry, block_directory);
                 }
 
                 let l3 = IndirectBlock {
-                    entries: l3_entries_ids.try_into().unwrap(),
-                    type_: IndirectBlockType::L3,
+                    entries: IndirectBlock::entries_from(
+                        l3_entries_ids.iter().copied(),
+                        INDIRECT_LEN,
+                    ),
+                    type_: IndirectBlockType::L3 as u32,
+                    checksum: 0,
                 };
-                let addr = &l3 as *const IndirectBlock as *const u8;
-                fake_disk.write_block(l4_entry, unsafe {
-                    &*slice_from_raw_parts(addr, BLOCK_SIZE as usize)
-                });
+                write_indirect(&mut fake_disk, l4_entry, l3);
             }
-            let (root, indexes) = if let Some(level) = Meta::index_blocks(size).root_level() {
+            let (root, indexes) = if let Some(level) = Meta::index_blocks(size, G).root_level() {
                 let mut indexes = vec![];
                 match level {
                     IndirectBlockType::BlockTable => {
@@ -767,14 +1248,19 @@ mod test {
                     .try_into()
                     .unwrap(),
                 indirect: root,
-                type_: InodeType::File,
+                xattr: 0,
+                type_: InodeType::File as u32,
+                checksum: 0,
                 name: [0; 200],
             })
         };
         let mut ids = inode.direct.to_vec();
         ids.append(&mut block_ids);
 
-        let cache_manager = Arc::new(CacheManager::new(Arc::new(RwLock::new(fake_disk))));
+        let cache_manager = Arc::new(CacheManager::new(
+            Arc::new(RwLock::new(fake_disk)),
+            BLOCK_SIZE,
+        ));
         index_ids.sort();
         (inode, index_ids, ids, cache_manager, id_iter)
     }
@@ -784,14 +1270,14 @@ mod test {
         let (small, index_ids, block_ids, cache_manager, _) = fake_inode(BLOCK_SIZE + 10);
 
         assert_eq!(
-            small.get_block_id(0, cache_manager.clone()),
+            small.get_block_id(0, cache_manager.clone()).unwrap(),
             Some(block_ids[0])
         );
         assert_eq!(
-            small.get_block_id(1, cache_manager.clone()),
+            small.get_block_id(1, cache_manager.clone()).unwrap(),
             Some(block_ids[1])
         );
-        assert_eq!(small.get_block_id(2, cache_manager.clone()), None);
+        assert_eq!(small.get_block_id(2, cache_manager.clone()).unwrap(), None);
     }
 
     #[test]
@@ -799,27 +1285,39 @@ mod test {
         let (medium, index_ids, block_ids, cache_manager, _) = fake_inode(DIRECT_MAX * 10);
 
         assert_eq!(
-            medium.get_block_id((DIRECT_COUNT - 1) as u64, cache_manager.clone()),
+            medium
+                .get_block_id((DIRECT_COUNT - 1) as u64, cache_manager.clone())
+                .unwrap(),
             Some(block_ids[DIRECT_COUNT - 1])
         );
         assert_eq!(
-            medium.get_block_id(DIRECT_COUNT as u64, cache_manager.clone()),
+            medium
+                .get_block_id(DIRECT_COUNT as u64, cache_manager.clone())
+                .unwrap(),
             Some(block_ids[DIRECT_COUNT])
         );
         assert_eq!(
-            medium.get_block_id(DIRECT_COUNT as u64 + 1, cache_manager.clone()),
+            medium
+                .get_block_id(DIRECT_COUNT as u64 + 1, cache_manager.clone())
+                .unwrap(),
             Some(block_ids[DIRECT_COUNT + 1])
         );
         assert_eq!(
-            medium.get_block_id((block_ids.len() - 1) as u64, cache_manager.clone()),
+            medium
+                .get_block_id((block_ids.len() - 1) as u64, cache_manager.clone())
+                .unwrap(),
             Some(block_ids[block_ids.len() - 1])
         );
         assert_eq!(
-            medium.get_block_id(block_ids.len() as u64, cache_manager.clone()),
+            medium
+                .get_block_id(block_ids.len() as u64, cache_manager.clone())
+                .unwrap(),
             None
         );
         assert_eq!(
-            medium.get_block_id((block_ids.len() + 1) as u64, cache_manager.clone()),
+            medium
+                .get_block_id((block_ids.len() + 1) as u64, cache_manager.clone())
+                .unwrap(),
             None
         );
     }
@@ -830,19 +1328,23 @@ mod test {
             fake_inode(L3_MAX + BLOCK_DIRECTORY_MAX);
 
         assert_eq!(
-            inode.get_block_id(100u64, cache_manager.clone()),
+            inode.get_block_id(100u64, cache_manager.clone()).unwrap(),
             Some(block_ids[100])
         );
         assert_eq!(
-            inode.get_block_id(10000u64, cache_manager.clone()),
+            inode.get_block_id(10000u64, cache_manager.clone()).unwrap(),
             Some(block_ids[10000])
         );
         assert_eq!(
-            inode.get_block_id((block_ids.len() - 1) as u64, cache_manager.clone()),
+            inode
+                .get_block_id((block_ids.len() - 1) as u64, cache_manager.clone())
+                .unwrap(),
             Some(*block_ids.last().unwrap())
         );
         assert_eq!(
-            inode.get_block_id(block_ids.len() as u64, cache_manager.clone()),
+            inode
+                .get_block_id(block_ids.len() as u64, cache_manager.clone())
+                .unwrap(),
             None
         );
     }
@@ -857,15 +1359,15 @@ mod test {
             fake_inode(2 * BLOCK_DIRECTORY_MAX);
 
         assert_eq!(
-            small.blocks(cache_manager_small),
+            small.blocks(cache_manager_small).unwrap(),
             (index_ids_small, block_ids_small)
         );
 
-        let blocks = medium.blocks(cache_manager_medium);
+        let blocks = medium.blocks(cache_manager_medium).unwrap();
         // blocks.sort();
         assert_eq!(blocks, (index_ids_medium, block_ids_medium));
 
-        let blocks = large.blocks(cache_manager_large);
+        let blocks = large.blocks(cache_manager_large).unwrap();
         // blocks.sort();
         assert_eq!(blocks, (index_ids_large, block_ids_large));
     }
@@ -873,7 +1375,7 @@ mod test {
     #[test]
     fn test_index_blocks() {
         assert_eq!(
-            Meta::index_blocks(0),
+            Meta::index_blocks(0, G),
             LevelInfo {
                 l4: 0,
                 l3: 0,
@@ -883,7 +1385,7 @@ mod test {
             }
         );
         assert_eq!(
-            Meta::index_blocks(10),
+            Meta::index_blocks(10, G),
             LevelInfo {
                 l4: 0,
                 l3: 0,
@@ -893,7 +1395,7 @@ mod test {
             }
         );
         assert_eq!(
-            Meta::index_blocks(BLOCK_SIZE - 1),
+            Meta::index_blocks(BLOCK_SIZE - 1, G),
             LevelInfo {
                 l4: 0,
                 l3: 0,
@@ -903,7 +1405,7 @@ mod test {
             }
         );
         assert_eq!(
-            Meta::index_blocks(BLOCK_SIZE),
+            Meta::index_blocks(BLOCK_SIZE, G),
             LevelInfo {
                 l4: 0,
                 l3: 0,
@@ -913,7 +1415,7 @@ mod test {
             }
         );
         assert_eq!(
-            Meta::index_blocks(BLOCK_SIZE + 1),
+            Meta::index_blocks(BLOCK_SIZE + 1, G),
             LevelInfo {
                 l4: 0,
                 l3: 0,
@@ -923,7 +1425,7 @@ mod test {
             }
         );
         assert_eq!(
-            Meta::index_blocks(DIRECT_MAX - 10),
+            Meta::index_blocks(DIRECT_MAX - 10, G),
             LevelInfo {
                 l4: 0,
                 l3: 0,
@@ -933,7 +1435,7 @@ mod test {
             }
         );
         assert_eq!(
-            Meta::index_blocks(DIRECT_MAX),
+            Meta::index_blocks(DIRECT_MAX, G),
             LevelInfo {
                 l4: 0,
                 l3: 0,
@@ -943,7 +1445,7 @@ mod test {
             }
         );
         assert_eq!(
-            Meta::index_blocks(DIRECT_MAX + 1),
+            Meta::index_blocks(DIRECT_MAX + 1, G),
             LevelInfo {
                 l4: 0,
                 l3: 0,
@@ -954,7 +1456,7 @@ mod test {
         );
         let size = BLOCK_TABLE_MAX;
         assert_eq!(
-            Meta::index_blocks(size),
+            Meta::index_blocks(size, G),
             LevelInfo {
                 l4: 0,
                 l3: 0,
@@ -965,7 +1467,7 @@ mod test {
         );
         let size = BLOCK_TABLE_MAX + 1;
         assert_eq!(
-            Meta::index_blocks(size),
+            Meta::index_blocks(size, G),
             LevelInfo {
                 l4: 0,
                 l3: 0,
@@ -976,7 +1478,7 @@ mod test {
         );
         let size = L3_MAX;
         assert_eq!(
-            Meta::index_blocks(size),
+            Meta::index_blocks(size, G),
             LevelInfo {
                 l4: 0,
                 l3: 1,
@@ -989,7 +1491,7 @@ mod test {
         );
         let size = L3_MAX + 1;
         assert_eq!(
-            Meta::index_blocks(size),
+            Meta::index_blocks(size, G),
             LevelInfo {
                 l4: 1,
                 l3: 2,
@@ -1011,17 +1513,19 @@ mod test {
                 .read()
                 .get_ref::<IndirectBlock>(0)
                 .entries[1];
-            let (filtered_index, filtered_data) = cache_manager
-                .get(inode.indirect)
-                .read()
-                .get_ref::<IndirectBlock>(0)
-                .to_vec(cache_manager.clone(), Some(&vec![block_directory_id]));
+            let (filtered_index, filtered_data) = IndirectBlock::to_vec(
+                inode.indirect,
+                cache_manager.clone(),
+                Some(&vec![block_directory_id]),
+            )
+            .unwrap();
+            let filtered_data = filtered_data.iter().filter(|id| **id != 0).count();
             assert_eq!(
-                inode.data_blocks(),
-                (filtered_data.len() + DIRECT_COUNT + INDIRECT_LEN * INDIRECT_LEN) as u64
+                inode.data_blocks(G),
+                (filtered_data + DIRECT_COUNT + INDIRECT_LEN * INDIRECT_LEN) as u64
             );
             assert_eq!(
-                Meta::index_blocks(inode.size).index_block_count(),
+                Meta::index_blocks(inode.size, G).index_block_count(),
                 1 + filtered_index.len() as u64 + 64
             );
         }
@@ -1059,20 +1563,20 @@ mod test {
 
         for new_size in shrink_size {
             println!("{} {}", prev_size, new_size);
-            let prev_info = Meta::index_blocks(prev_size);
-            let (index_ids, block_ids) = inode.blocks(cache_manager.clone());
+            let prev_info = Meta::index_blocks(prev_size, G);
+            let (index_ids, block_ids) = inode.blocks(cache_manager.clone()).unwrap();
             assert_eq!(prev_info.index_block_count(), index_ids.len() as u64);
 
             let prev_index_blocks = prev_info.index_block_count();
-            let prev_data_blocks = inode.data_blocks();
+            let prev_data_blocks = inode.data_blocks(G);
 
             let (dealloc_index_ids, dealloc_data_ids) =
-                inode.shrink(new_size, cache_manager.clone());
-            let new_info = Meta::index_blocks(inode.size);
+                inode.shrink(new_size, cache_manager.clone()).unwrap();
+            let new_info = Meta::index_blocks(inode.size, G);
             let new_index_blocks = new_info.index_block_count();
-            let new_data_blocks = inode.data_blocks();
+            let new_data_blocks = inode.data_blocks(G);
 
-            let (index, blocks) = inode.blocks(cache_manager.clone());
+            let (index, blocks) = inode.blocks(cache_manager.clone()).unwrap();
             assert_eq!(index.len() as u64, new_info.index_block_count());
             assert_eq!(blocks.len() as u64, new_data_blocks);
             assert_eq!(
@@ -1119,29 +1623,31 @@ mod test {
         ];
 
         for new_size in extend_size {
-            let curr_info = Meta::index_blocks(inode.size());
+            let curr_info = Meta::index_blocks(inode.size(), G);
 
-            let new_info = Meta::index_blocks(new_size);
+            let new_info = Meta::index_blocks(new_size, G);
             let new_index_blocks = new_info.index_block_count();
             let data_blocks = id_iter
                 .by_ref()
-                .take((Meta::_data_blocks(new_size) - inode.data_blocks()) as usize)
+                .take((G.data_blocks(new_size) - inode.data_blocks(G)) as usize)
                 .collect::<Vec<_>>();
             let index_blocks = id_iter
                 .by_ref()
                 .take((new_info.index_block_count() - curr_info.index_block_count()) as usize)
                 .collect::<Vec<_>>();
-            inode.extend(
-                new_size,
-                new_info,
-                data_blocks,
-                index_blocks,
-                cache_manager.clone(),
-            );
+            inode
+                .extend(
+                    new_size,
+                    new_info,
+                    data_blocks,
+                    index_blocks,
+                    cache_manager.clone(),
+                )
+                .unwrap();
 
-            let (index_ids, block_ids) = inode.blocks(cache_manager.clone());
+            let (index_ids, block_ids) = inode.blocks(cache_manager.clone()).unwrap();
             assert_eq!(new_index_blocks, index_ids.len() as u64);
-            assert_eq!(inode.data_blocks(), block_ids.len() as u64);
+            assert_eq!(inode.data_blocks(G), block_ids.len() as u64);
         }
     }
 
@@ -1149,7 +1655,7 @@ mod test {
     fn test_clear_size() {
         let (mut inode, index_ids, block_ids, cache_manager, _) = fake_inode(BLOCK_TABLE_MAX * 2);
 
-        let ids = inode.clear_size(cache_manager);
+        let ids = inode.clear_size(cache_manager).unwrap();
 
         assert_eq!(ids.len(), index_ids.len() + block_ids.len())
     }
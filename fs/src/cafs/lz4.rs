@@ -0,0 +1,142 @@
+//! The LZ4 block format, without the frame around it.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+const MIN_MATCH: usize = 4;
+/// A block ends with at least this many literals.
+const LAST_LITERALS: usize = 5;
+/// The last match starts at least this far from the end.
+const MF_LIMIT: usize = 12;
+const MAX_OFFSET: usize = u16::MAX as usize;
+const HASH_LOG: u32 = 12;
+
+fn read_u32(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap())
+}
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_LOG)) as usize
+}
+
+/// Append `len` in the 255-continued form following a token nibble of 15.
+fn push_length(out: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        out.push(255);
+        len -= 255;
+    }
+    out.push(len as u8);
+}
+
+fn push_sequence(out: &mut Vec<u8>, literals: &[u8], offset_and_len: Option<(usize, usize)>) {
+    let match_len = offset_and_len.map_or(0, |(_, len)| len - MIN_MATCH);
+    out.push(((literals.len().min(15) as u8) << 4) | match_len.min(15) as u8);
+    if literals.len() >= 15 {
+        push_length(out, literals.len() - 15);
+    }
+    out.extend_from_slice(literals);
+    if let Some((offset, _)) = offset_and_len {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        if match_len >= 15 {
+            push_length(out, match_len - 15);
+        }
+    }
+}
+
+/// Compress `input` greedily, matches are found through a hash table of
+/// the last position of every 4-byte sequence.
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() / 2 + 16);
+    let mut table = vec![0usize; 1 << HASH_LOG];
+    let mut anchor = 0;
+    let mut pos = 0;
+    while pos + MF_LIMIT < input.len() {
+        let sequence = read_u32(input, pos);
+        let candidate = core::mem::replace(&mut table[hash(sequence)], pos);
+        if candidate >= pos
+            || pos - candidate > MAX_OFFSET
+            || read_u32(input, candidate) != sequence
+        {
+            pos += 1;
+            continue;
+        }
+        let mut len = MIN_MATCH;
+        while pos + len < input.len() - LAST_LITERALS && input[candidate + len] == input[pos + len]
+        {
+            len += 1;
+        }
+        push_sequence(&mut out, &input[anchor..pos], Some((pos - candidate, len)));
+        pos += len;
+        anchor = pos;
+    }
+    push_sequence(&mut out, &input[anchor..], None);
+    out
+}
+
+fn read_length(input: &[u8], pos: &mut usize, mut len: usize) -> Option<usize> {
+    if len == 15 {
+        loop {
+            let byte = *input.get(*pos)?;
+            *pos += 1;
+            len += byte as usize;
+            if byte != 255 {
+                break;
+            }
+        }
+    }
+    Some(len)
+}
+
+/// Decompress `input` into exactly `len` bytes, `None` if it is malformed.
+pub fn decompress(input: &[u8], len: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(len);
+    let mut pos = 0;
+    loop {
+        let token = *input.get(pos)?;
+        pos += 1;
+        let literals = read_length(input, &mut pos, (token >> 4) as usize)?;
+        if out.len() + literals > len {
+            return None;
+        }
+        out.extend_from_slice(input.get(pos..pos + literals)?);
+        pos += literals;
+        if pos == input.len() {
+            break;
+        }
+        let offset = u16::from_le_bytes([*input.get(pos)?, *input.get(pos + 1)?]) as usize;
+        pos += 2;
+        let match_len = read_length(input, &mut pos, (token & 15) as usize)? + MIN_MATCH;
+        if offset == 0 || offset > out.len() || out.len() + match_len > len {
+            return None;
+        }
+        // the match may overlap the bytes it produces
+        let start = out.len() - offset;
+        for i in start..start + match_len {
+            out.push(out[i]);
+        }
+    }
+    (out.len() == len).then_some(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{compress, decompress};
+    use alloc::vec::Vec;
+
+    #[test]
+    fn test_lz4() {
+        let text = b"CAFS keeps small files inline and compresses big ones. ".repeat(100);
+        let noise = (0..5000u32)
+            .map(|x| (x.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect::<Vec<_>>();
+        for data in [&b""[..], b"a", &[7; 300], &text, &noise] {
+            let compressed = compress(data);
+            assert_eq!(decompress(&compressed, data.len()).unwrap(), data);
+        }
+        assert!(compress(&text).len() < text.len() / 10);
+        // a literal-only block from the reference implementation
+        assert_eq!(decompress(b"\x50hello", 5).unwrap(), b"hello");
+        assert!(decompress(&compress(&text), text.len() - 1).is_none());
+        assert!(decompress(b"\x1fa\x02\x00", 40).is_none());
+    }
+}
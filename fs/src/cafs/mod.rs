@@ -1,24 +1,38 @@
-use crate::fs::{Inode, InodeType, FS};
-use crate::{BlockDevice, BLOCK_BITS, BLOCK_SIZE};
+use crate::fs::{Inode, InodeType, Seek, FS};
+use crate::{now, BlockDevice, Error, BLOCK_SIZE, MAX_BLOCK_SIZE};
 use alloc::string::{String, ToString};
 use alloc::sync::Arc;
 use alloc::vec;
 use alloc::vec::Vec;
 use bitmap::Bitmap;
 use cache::CacheManager;
-use core::iter;
-use layout::{DataBlock, Meta, SuperBlock};
+use checksum::ChecksumTable;
+use core::ops::Range;
+use layout::{
+    DataBlock, Extent, Geometry, Meta, Regions, SuperBlock, XattrBlock, BACKUP_SUPER_BLOCK_ID,
+    CHUNK_SIZE, FEATURE_INCOMPAT_COMPRESSION, FEATURE_INCOMPAT_INLINE_DATA, FEATURE_INCOMPAT_SUPP,
+    FEATURE_RO_COMPAT_DATA_CSUM, FEATURE_RO_COMPAT_SUPP, INLINE_LIMIT, INODE_AREA_LIMIT,
+    LABEL_LENGTH_LIMIT, RESERVED_BLOCKS, STATE_CLEAN, STATE_DIRTY, SUPER_BLOCK_ID,
+    XATTR_NAME_LIMIT,
+};
+use log::warn;
+use snapshot::{Snapshot, SnapshotView};
 use spin::RwLock;
 
 mod bitmap;
 pub mod cache;
+mod checksum;
+mod crc;
 mod layout;
+mod lz4;
+mod snapshot;
 
 pub const NAME_LENGTH_LIMIT: usize = 199;
 
 // padding: 8 + 8 + 8 + 8 + 8 + 8 + 184 + 24
 pub struct CaInode {
     cache_manager: Arc<CacheManager>,
+    checksums: Arc<ChecksumTable>,
     inode_number: u64,
     type_: InodeType,
     size: u64,
@@ -26,11 +40,18 @@ pub struct CaInode {
     offset: usize,
     name: [u8; NAME_LENGTH_LIMIT + 1],
     blocks: Vec<u64>,
+    /// The data is in the `Meta`, `blocks` is empty.
+    inline: bool,
+    /// The data blocks hold compressed chunks.
+    compressed: bool,
+    /// Next-fit goal for the data blocks of this inode.
+    alloc_hint: u64,
 }
 
 impl CaInode {
     pub fn new(
         cache_manager: Arc<CacheManager>,
+        checksums: Arc<ChecksumTable>,
         inode_number: u64,
         type_: InodeType,
         block_id: u64,
@@ -45,10 +66,11 @@ impl CaInode {
             cache_manager
                 .get(block_id)
                 .write()
-                .modify(offset, |meta: &mut Meta| meta.init(type_, bytes));
+                .overwrite(offset, |meta: &mut Meta| meta.init(type_, bytes));
         }
         Self {
             cache_manager,
+            checksums,
             inode_number,
             type_,
             size: 0,
@@ -56,6 +78,9 @@ impl CaInode {
             offset,
             name: bytes,
             blocks: vec![],
+            inline: false,
+            compressed: false,
+            alloc_hint: 0,
         }
     }
 
@@ -64,23 +89,32 @@ impl CaInode {
         block_id: u64,
         offset: usize,
         cache_manager: Arc<CacheManager>,
-    ) -> Self {
-        let (type_, size, (_, blocks), indirect, name) = unsafe {
+        checksums: Arc<ChecksumTable>,
+    ) -> Result<Self, Error> {
+        let (type_, size, blocks, inline, compressed, name) = unsafe {
             cache_manager
                 .get(block_id)
                 .read()
-                .read(offset, |meta: &Meta| {
+                .read_checked(offset, |meta: &Meta| {
                     (
                         meta.type_(),
                         meta.size(),
                         meta.blocks(cache_manager.clone()),
-                        meta.indirect(),
+                        meta.is_inline(),
+                        meta.is_compressed(),
                         meta.name(),
                     )
-                })
+                })?
         };
-        Self {
+        let (_, blocks) = blocks?;
+        let alloc_hint = blocks
+            .iter()
+            .rev()
+            .find(|id| **id != 0)
+            .map_or(0, |id| id + 1);
+        Ok(Self {
             cache_manager,
+            checksums,
             inode_number,
             type_,
             size,
@@ -88,7 +122,72 @@ impl CaInode {
             offset,
             name,
             blocks,
+            inline,
+            compressed,
+            alloc_hint,
+        })
+    }
+
+    /// Read bytes `range` of data kept in blocks, chunk by chunk if
+    /// compressed.
+    fn read_blocks(&self, range: Range<u64>) -> Result<Vec<u8>, Error> {
+        let unit = if self.compressed {
+            CHUNK_SIZE
+        } else {
+            self.cache_manager.block_size()
+        };
+        let mut data = Vec::with_capacity((range.end - range.start) as usize);
+        for i in range.start / unit..div_round_up(range.end, unit) {
+            let piece = if self.compressed {
+                self.read_chunk(i)?
+            } else {
+                self.read_block(i)?
+            };
+            let start = range.start.max(i * unit) - i * unit;
+            let end = range.end.min((i + 1) * unit) - i * unit;
+            data.extend_from_slice(&piece[start as usize..end as usize]);
         }
+        Ok(data)
+    }
+
+    fn read_block(&self, i: u64) -> Result<Vec<u8>, Error> {
+        let id = self.blocks[i as usize];
+        // a hole reads as zero
+        if id == 0 {
+            return Ok(vec![0; self.cache_manager.block_size() as usize]);
+        }
+        let cache = self.cache_manager.get(id);
+        let cache = cache.read();
+        if self.checksums.covers(self.type_) {
+            self.checksums.verify(id, &cache)?;
+        }
+        let len = self.cache_manager.block_size() as usize;
+        Ok(unsafe { cache.read(0, |block: &DataBlock| block[..len].to_vec()) })
+    }
+
+    /// Decompress chunk `i`, up to the end of the file.
+    fn read_chunk(&self, i: u64) -> Result<Vec<u8>, Error> {
+        let chunk_blocks = Geometry::new(self.cache_manager.block_size()).chunk_blocks();
+        let first = i * chunk_blocks;
+        let ids = &self.blocks
+            [first as usize..(self.blocks.len() as u64).min(first + chunk_blocks) as usize];
+        let len = (self.size - i * CHUNK_SIZE).min(CHUNK_SIZE) as usize;
+        let stored = ids.iter().take_while(|id| **id != 0).count() as u64;
+        if stored == 0 {
+            return Ok(vec![0; len]);
+        }
+        let mut raw = vec![];
+        for block in first..first + stored {
+            raw.append(&mut self.read_block(block)?);
+        }
+        if stored == ids.len() as u64 {
+            raw.truncate(len);
+            return Ok(raw);
+        }
+        let compressed_len = u32::from_le_bytes(raw[..4].try_into().unwrap()) as usize;
+        raw.get(4..4 + compressed_len)
+            .and_then(|compressed| lz4::decompress(compressed, len))
+            .ok_or(Error::Corrupted(ids[0]))
     }
 }
 
@@ -105,35 +204,36 @@ impl Inode for CaInode {
         self.type_ == InodeType::File
     }
 
-    fn data(&self) -> Vec<u8> {
-        let mut data = Vec::with_capacity(self.size as usize);
-        if !self.blocks.is_empty() {
-            let last = self.blocks.last().unwrap();
-            for id in self.blocks.iter() {
-                unsafe {
-                    self.cache_manager
-                        .get(*id)
-                        .read()
-                        .read(0, |block: &DataBlock| {
-                            if *id == *last {
-                                let last_size = if self.size % BLOCK_SIZE != 0 {
-                                    self.size % BLOCK_SIZE
-                                } else {
-                                    BLOCK_SIZE
-                                };
-                                for i in block.iter().take(last_size as usize) {
-                                    data.push(*i);
-                                }
-                            } else {
-                                for i in block {
-                                    data.push(*i);
-                                }
-                            }
-                        });
-                }
-            }
+    fn data(&self) -> Result<Vec<u8>, Error> {
+        if self.inline {
+            // no I/O, the block of the `Meta` is cached
+            return unsafe {
+                self.cache_manager
+                    .get(self.block_id)
+                    .read()
+                    .read_checked(self.offset, |meta: &Meta| meta.inline_data())
+            };
         }
-        data
+        self.read_blocks(0..self.size)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        let end = self.size.min(offset.saturating_add(buf.len() as u64));
+        if offset >= end {
+            return Ok(0);
+        }
+        let data = if self.inline {
+            self.data()?[offset as usize..end as usize].to_vec()
+        } else {
+            self.read_blocks(offset..end)?
+        };
+        buf[..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+
+    fn physical_size(&self) -> u64 {
+        let blocks = self.blocks.iter().filter(|id| **id != 0).count() as u64;
+        blocks * self.cache_manager.block_size()
     }
 
     fn name(&self) -> String {
@@ -152,6 +252,16 @@ impl Inode for CaInode {
 
 const INODE_CACHE_SIZE: usize = 32;
 
+/// `u64::div_ceil` is not stable yet on the kernel toolchain.
+fn div_round_up(n: u64, d: u64) -> u64 {
+    let q = n / d;
+    if q * d < n {
+        q + 1
+    } else {
+        q
+    }
+}
+
 pub fn inode_number_binary(inode_number: u64) -> [u8; 10] {
     let mut repre = [0; 10];
     let bytes = inode_number.to_le_bytes();
@@ -190,12 +300,24 @@ pub fn inode_number_from(arr: [u8; 10]) -> u64 {
 }
 
 pub struct CAFS {
+    geometry: Geometry,
+    checksums: Arc<ChecksumTable>,
+    /// In-memory copy of the superblock, written back to both copies on disk.
+    super_block: RwLock<SuperBlock>,
+    read_only: bool,
+    /// Whether small files are kept inline, `FEATURE_INCOMPAT_INLINE_DATA`.
+    inline_data: bool,
+    /// Whether files may be compressed, `FEATURE_INCOMPAT_COMPRESSION`.
+    compression: bool,
     cache_manager: Arc<CacheManager>,
     inode_bitmap: Bitmap,
     data_bitmap: Bitmap,
-    inode_area_start_block: u64,
+    /// Never moves, data block numbers are relative to it in the data bitmap.
     data_area_start_block: u64,
     inode_cache: RwLock<Vec<Arc<RwLock<CaInode>>>>,
+    snapshots: RwLock<Vec<Snapshot>>,
+    /// Set when mounted on a snapshot rather than the live filesystem.
+    view: Option<SnapshotView>,
 }
 
 impl Drop for CAFS {
@@ -203,41 +325,63 @@ impl Drop for CAFS {
 }
 
 impl CAFS {
+    /// Make a new CAFS of `total_blocks` blocks of `block_size` bytes.
+    /// `block_size` is a power of two between `BLOCK_SIZE` and `MAX_BLOCK_SIZE`.
+    /// Metadata and directories are always checksummed, file data only if
+    /// `data_checksums` is set.
     pub fn init(
         block_device: Arc<RwLock<dyn BlockDevice>>,
         total_blocks: u64,
         inode_bitmap_blocks: u64,
+        block_size: u64,
+        uuid: [u8; 16],
+        label: &str,
+        data_checksums: bool,
     ) -> Arc<Self> {
         assert_eq!(0, core::mem::size_of::<Meta>() % 8);
+        assert!(label.len() <= LABEL_LENGTH_LIMIT);
 
-        let cache_manager = Arc::new(CacheManager::new(block_device));
+        let geometry = Geometry::new(block_size);
+        let cache_manager = Arc::new(CacheManager::new(block_device, block_size));
 
-        let inode_bitmap = Bitmap::new(1, inode_bitmap_blocks, cache_manager.clone());
-        let inode_num = inode_bitmap.total_count();
+        let inode_num = inode_bitmap_blocks * geometry.bitmap_bits();
         let inode_area_blocks =
-            (inode_num * core::mem::size_of::<Meta>() as u64 + BLOCK_SIZE - 1) / BLOCK_SIZE;
+            (inode_num + geometry.inodes_per_block() - 1) / geometry.inodes_per_block();
         let inode_total_blocks = inode_bitmap_blocks + inode_area_blocks;
 
-        let data_total_blocks = total_blocks - 1 - inode_total_blocks;
-        let data_bitmap_blocks = data_total_blocks / (BLOCK_BITS + 1);
-        let data_area_blocks = data_total_blocks - data_bitmap_blocks;
-        let data_bitmap = Bitmap::new(
-            1 + inode_total_blocks,
-            data_bitmap_blocks,
-            cache_manager.clone(),
-        );
-        let fs = Arc::new(Self {
-            cache_manager,
-            inode_bitmap: inode_bitmap,
-            data_bitmap: data_bitmap,
-            inode_area_start_block: 1 + inode_bitmap_blocks,
-            data_area_start_block: 1 + inode_total_blocks + data_bitmap_blocks,
-            inode_cache: Default::default(),
-        });
-        unsafe {
+        let data_total_blocks = total_blocks - RESERVED_BLOCKS - inode_total_blocks;
+        // round up, a 4 KiB bitmap block alone covers 128 MiB of data
+        let data_bitmap_blocks =
+            (data_total_blocks + geometry.bitmap_bits()) / (geometry.bitmap_bits() + 1);
+        let checksums_per_block = geometry.checksums_per_block();
+        let checksum_blocks = (data_total_blocks - data_bitmap_blocks + checksums_per_block)
+            / (checksums_per_block + 1);
+        let data_area_blocks = data_total_blocks - data_bitmap_blocks - checksum_blocks;
+        let inode_bitmap = Extent::new(RESERVED_BLOCKS, inode_bitmap_blocks);
+        let mut inode_areas = [Extent::default(); INODE_AREA_LIMIT];
+        inode_areas[0] = Extent::new(inode_bitmap.end(), inode_area_blocks);
+        let data_bitmap = Extent::new(inode_areas[0].end(), data_bitmap_blocks);
+        let checksum_table = Extent::new(data_bitmap.end(), checksum_blocks);
+        let regions = Regions {
+            inode_bitmap,
+            inode_areas,
+            inode_count: inode_num,
+            data_bitmap,
+            checksum_table,
+            data_area: Extent::new(checksum_table.end(), data_area_blocks),
+        };
+        let feature_ro_compat = if data_checksums {
+            FEATURE_RO_COMPAT_DATA_CSUM
+        } else {
+            0
+        };
+
+        let mut label_bytes = [0u8; LABEL_LENGTH_LIMIT + 1];
+        label_bytes[..label.len()].copy_from_slice(label.as_bytes());
+        let super_block = unsafe {
             // clear all blocks
             for i in 0..total_blocks {
-                fs.cache_manager
+                cache_manager
                     .get(i)
                     .write()
                     .modify(0, |data_block: &mut DataBlock| {
@@ -247,73 +391,219 @@ impl CAFS {
                     });
             }
             // initialize SuperBlock
-            fs.cache_manager
-                .get(0)
+            cache_manager
+                .get(SUPER_BLOCK_ID)
                 .write()
                 .modify(0, |super_block: &mut SuperBlock| {
                     super_block.initialize(
+                        block_size,
                         total_blocks,
-                        inode_bitmap_blocks,
-                        inode_area_blocks,
-                        data_bitmap_blocks,
-                        data_area_blocks,
+                        regions,
+                        FEATURE_INCOMPAT_INLINE_DATA | FEATURE_INCOMPAT_COMPRESSION,
+                        feature_ro_compat,
+                        uuid,
+                        label_bytes,
+                        now(),
                     );
-                });
-            // create an inode for root dir "/"
+                    *super_block
+                })
+        };
+        let fs = Arc::new(Self::from_super_block(super_block, cache_manager, false));
+        fs.inode_bitmap.format();
+        fs.data_bitmap.format();
+        fs.checksums.format();
+
+        // bits past the end of the data area are never handed out
+        let tail = fs.data_bitmap.total_count() - data_area_blocks;
+        if tail != 0 {
             assert_eq!(
-                fs.alloc_inode_meta(InodeType::Dir, "/".to_string())
-                    .read()
-                    .inode_number,
-                0
+                fs.data_bitmap
+                    .alloc_contiguous(tail, data_area_blocks)
+                    .unwrap(),
+                Some(data_area_blocks)
             );
-            fs.flush()
         }
+        // create an inode for root dir "/"
+        assert_eq!(
+            fs.alloc_inode_meta(InodeType::Dir, "/".to_string())
+                .unwrap()
+                .read()
+                .inode_number,
+            0
+        );
+        fs.flush();
         fs
     }
 
-    pub fn open(block_device: Arc<RwLock<dyn BlockDevice>>) -> Self {
-        let cache_manager = Arc::new(CacheManager::new(block_device));
-
-        // read SuperBlock
-        unsafe {
-            cache_manager
-                .get(0)
-                .read()
-                .read(0, |super_block: &SuperBlock| {
-                    assert!(super_block.is_valid(), "Error loading CAFS!");
-                    let inode_total_blocks =
-                        super_block.inode_bitmap_blocks + super_block.inode_area_blocks;
-                    let inode_bitmap =
-                        Bitmap::new(1, super_block.inode_bitmap_blocks, cache_manager.clone());
-                    let data_bitmap = Bitmap::new(
-                        1 + inode_total_blocks,
-                        super_block.data_bitmap_blocks,
-                        cache_manager.clone(),
-                    );
+    /// Mount an existing CAFS. A filesystem with read-only compatible
+    /// features this driver does not know is mounted read-only.
+    pub fn open(block_device: Arc<RwLock<dyn BlockDevice>>) -> Result<Self, Error> {
+        let super_block = Self::read_super_block(&block_device)?;
+        let unsupported = super_block.feature_incompat & !FEATURE_INCOMPAT_SUPP;
+        if unsupported != 0 {
+            return Err(Error::UnsupportedFeature(unsupported));
+        }
+        let read_only = super_block.feature_ro_compat & !FEATURE_RO_COMPAT_SUPP != 0;
+        if !super_block.is_clean() {
+            warn!("CAFS was not cleanly unmounted");
+        }
 
-                    Self {
-                        cache_manager,
-                        inode_bitmap,
-                        data_bitmap,
-                        inode_area_start_block: 1 + super_block.inode_bitmap_blocks,
-                        data_area_start_block: 1
-                            + inode_total_blocks
-                            + super_block.data_bitmap_blocks,
-                        inode_cache: Default::default(),
-                    }
-                })
+        let cache_manager = Arc::new(CacheManager::new(block_device, super_block.block_size));
+        let fs = Self::from_super_block(super_block, cache_manager, read_only);
+        fs.load_snapshots()?;
+        if !read_only {
+            let mut super_block = fs.super_block.write();
+            super_block.state = STATE_DIRTY;
+            super_block.mount_time = now();
+            super_block.mount_count += 1;
+            fs.write_super_block(&mut super_block);
         }
+        Ok(fs)
     }
 
-    pub fn alloc_inode_meta(&self, type_: InodeType, name: String) -> Arc<RwLock<CaInode>> {
-        let id = if let Some(id) = self.inode_bitmap.alloc() {
-            id
-        } else {
-            panic!("run out of inode block")
+    /// Read the primary superblock, falling back to the backup copy. The
+    /// backup sits at block 1 of whatever block size the filesystem uses.
+    fn read_super_block(block_device: &Arc<RwLock<dyn BlockDevice>>) -> Result<SuperBlock, Error> {
+        let read = |block_id: u64| {
+            let mut buf = [0u8; BLOCK_SIZE as usize];
+            block_device.read().read_block(block_id, &mut buf);
+            unsafe { core::ptr::read_unaligned(buf.as_ptr() as *const SuperBlock) }
         };
+        let primary = read(SUPER_BLOCK_ID);
+        if primary.is_valid() {
+            return Ok(primary);
+        }
+        warn!("primary superblock of CAFS is corrupted, trying the backup");
+        let mut block_size = BLOCK_SIZE;
+        while block_size <= MAX_BLOCK_SIZE {
+            let backup = read(BACKUP_SUPER_BLOCK_ID * block_size / BLOCK_SIZE);
+            if backup.is_valid() && backup.block_size == block_size {
+                return Ok(backup);
+            }
+            block_size *= 2;
+        }
+        Err(Error::BadSuperBlock)
+    }
+
+    fn from_super_block(
+        super_block: SuperBlock,
+        cache_manager: Arc<CacheManager>,
+        read_only: bool,
+    ) -> Self {
+        let regions = super_block.regions;
+        let inode_bitmap = Bitmap::new(
+            regions.inode_bitmap.start,
+            regions.inode_bitmap.blocks,
+            cache_manager.clone(),
+        );
+        let data_bitmap = Bitmap::new(
+            regions.data_bitmap.start,
+            regions.data_bitmap.blocks,
+            cache_manager.clone(),
+        );
+        let data_area_start_block = regions.data_area.start;
+        let checksums = ChecksumTable::new(
+            regions.checksum_table.start,
+            regions.checksum_table.blocks,
+            data_area_start_block,
+            super_block.feature_ro_compat & FEATURE_RO_COMPAT_DATA_CSUM != 0,
+            cache_manager.clone(),
+        );
+        Self {
+            geometry: Geometry::new(super_block.block_size),
+            checksums: Arc::new(checksums),
+            super_block: RwLock::new(super_block),
+            read_only,
+            inline_data: super_block.feature_incompat & FEATURE_INCOMPAT_INLINE_DATA != 0,
+            compression: super_block.feature_incompat & FEATURE_INCOMPAT_COMPRESSION != 0,
+            cache_manager,
+            inode_bitmap,
+            data_bitmap,
+            data_area_start_block,
+            inode_cache: Default::default(),
+            snapshots: Default::default(),
+            view: None,
+        }
+    }
+
+    /// Write `super_block` to both copies on disk right away.
+    fn write_super_block(&self, super_block: &mut SuperBlock) {
+        super_block.update_checksum();
+        for block_id in [SUPER_BLOCK_ID, BACKUP_SUPER_BLOCK_ID] {
+            let cache = self.cache_manager.get(block_id);
+            let mut cache = cache.write();
+            unsafe {
+                cache.modify(0, |block: &mut SuperBlock| *block = *super_block);
+            }
+            cache.sync();
+        }
+    }
+
+    /// Called before every change. The first change after a clean flush
+    /// marks the superblock dirty on disk.
+    fn mark_dirty(&self) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let mut super_block = self.super_block.write();
+        if super_block.is_clean() {
+            super_block.state = STATE_DIRTY;
+            self.write_super_block(&mut super_block);
+        }
+        Ok(())
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.geometry.block_size()
+    }
+
+    pub fn uuid(&self) -> [u8; 16] {
+        self.super_block.read().uuid
+    }
+
+    pub fn label(&self) -> String {
+        let label = self.super_block.read().label;
+        let len = label.iter().position(|&x| x == 0).unwrap_or(label.len());
+        String::from_utf8_lossy(&label[..len]).to_string()
+    }
+
+    /// Store the data of the inode compressed from now on, or not, and
+    /// rewrite what is there already to match.
+    pub fn set_compressed(&self, inode_number: u64, compressed: bool) -> Result<(), Error> {
+        if compressed && !self.compression {
+            return Err(Error::UnsupportedFeature(FEATURE_INCOMPAT_COMPRESSION));
+        }
+        let inode = self.cainode(inode_number)?;
+        if inode.read().compressed == compressed {
+            return Ok(());
+        }
+        let data = inode.read().data()?;
+        self.truncate(inode_number, 0)?;
+        let (block_id, offset) = self.inode_pos_of(inode_number);
+        unsafe {
+            self.cache_manager
+                .get(block_id)
+                .write()
+                .modify_checked(offset, |meta: &mut Meta| meta.set_compressed(compressed))?;
+        }
+        inode.write().compressed = compressed;
+        self.write(inode_number, &data)
+    }
+
+    pub fn alloc_inode_meta(
+        &self,
+        type_: InodeType,
+        name: String,
+    ) -> Result<Arc<RwLock<CaInode>>, Error> {
+        let id = self.inode_bitmap.alloc()?.ok_or(Error::RunOutOfInode)?;
         let (block_id, offset) = self.inode_pos_of(id);
         let meta = Arc::new(RwLock::new(CaInode::new(
             self.cache_manager.clone(),
+            self.checksums.clone(),
             id,
             type_,
             block_id,
@@ -322,26 +612,315 @@ impl CAFS {
         )));
         // add to inode_meta cache
         self.add_inode_cache(meta.clone());
-        meta
+        Ok(meta)
+    }
+
+    pub fn alloc_data(&self) -> Result<u64, Error> {
+        if let Some(id) = self.data_bitmap.alloc()? {
+            Ok(id + self.data_area_start_block)
+        } else {
+            Err(Error::NoSpace)
+        }
     }
 
-    pub fn alloc_data(&self) -> u64 {
-        if let Some(id) = self.data_bitmap.alloc() {
-            id + self.data_area_start_block
+    /// Allocate `count` data blocks in as few contiguous runs as possible,
+    /// starting the search at block `hint`.
+    pub fn alloc_data_blocks(&self, count: u64, hint: u64) -> Result<Vec<u64>, Error> {
+        let hint = hint.saturating_sub(self.data_area_start_block);
+        if let Some(extents) = self.data_bitmap.alloc_extents(count, hint)? {
+            Ok(extents
+                .into_iter()
+                .flat_map(|(start, len)| start..start + len)
+                .map(|id| id + self.data_area_start_block)
+                .collect())
         } else {
-            panic!("run out of data block")
+            Err(Error::NoSpace)
+        }
+    }
+
+    /// Return data blocks to the bitmap, freeing adjacent blocks as one range.
+    pub fn dealloc_data(&self, mut ids: Vec<u64>) -> Result<(), Error> {
+        // blocks of a snapshot stay allocated until it is deleted
+        if !self.snapshots.read().is_empty() {
+            let mut live = Vec::with_capacity(ids.len());
+            for id in ids {
+                if !self.is_frozen(id)? {
+                    live.push(id);
+                }
+            }
+            ids = live;
+        }
+        ids.sort_unstable();
+        let mut ids = ids.into_iter().map(|id| id - self.data_area_start_block);
+        if let Some(first) = ids.next() {
+            let (mut start, mut len) = (first, 1);
+            for id in ids {
+                if id == start + len {
+                    len += 1;
+                } else {
+                    self.data_bitmap.dealloc_range(start, len)?;
+                    (start, len) = (id, 1);
+                }
+            }
+            self.data_bitmap.dealloc_range(start, len)?;
         }
+        Ok(())
     }
 
+    /// Write back all cached blocks and mark the filesystem clean.
     pub fn flush(&self) {
-        self.cache_manager.flush()
+        self.cache_manager.flush();
+        if !self.read_only {
+            let mut super_block = self.super_block.write();
+            super_block.state = STATE_CLEAN;
+            super_block.write_time = now();
+            self.write_super_block(&mut super_block);
+            // the clean state reaches the disk after the blocks it covers
+            self.cache_manager.flush();
+        }
+    }
+
+    fn grow(&self, old: &SuperBlock, total_blocks: u64) -> Result<Regions, Error> {
+        let regions = self
+            .grown_regions(old, total_blocks, true)
+            .or_else(|| self.grown_regions(old, total_blocks, false))
+            .ok_or(Error::NoSpace)?;
+        let data_start = self.data_area_start_block;
+        let old_data_blocks = old.regions.data_area.blocks;
+        let data_blocks = regions.data_area.blocks;
+
+        for (old_area, area) in old.regions.inode_areas.iter().zip(regions.inode_areas) {
+            if *old_area != area {
+                for block_id in area.start..area.end() {
+                    unsafe {
+                        self.cache_manager.get(block_id).write().modify(
+                            0,
+                            |data_block: &mut DataBlock| {
+                                data_block.fill(0);
+                            },
+                        );
+                    }
+                }
+            }
+        }
+
+        // bits past the old counts were reserved, those past the new ones are
+        self.inode_bitmap
+            .relocate(regions.inode_bitmap.start, regions.inode_bitmap.blocks)?;
+        let old_inode_bits = old.regions.inode_bitmap.blocks * self.geometry.bitmap_bits();
+        let freed = old_inode_bits.min(regions.inode_count);
+        if freed > old.regions.inode_count {
+            self.inode_bitmap
+                .dealloc_range(old.regions.inode_count, freed - old.regions.inode_count)?;
+        }
+        self.inode_bitmap.reserve_from(regions.inode_count)?;
+
+        self.data_bitmap
+            .relocate(regions.data_bitmap.start, regions.data_bitmap.blocks)?;
+        let old_data_bits = old.regions.data_bitmap.blocks * self.geometry.bitmap_bits();
+        let freed = old_data_bits.min(data_blocks);
+        if freed > old_data_blocks {
+            self.data_bitmap
+                .dealloc_range(old_data_blocks, freed - old_data_blocks)?;
+        }
+        self.data_bitmap.reserve_from(data_blocks)?;
+
+        self.checksums
+            .relocate(regions.checksum_table.start, regions.checksum_table.blocks)?;
+
+        // the new metadata sits at the front of the added blocks
+        let metadata_blocks = [
+            regions.inode_bitmap,
+            regions.data_bitmap,
+            regions.checksum_table,
+        ]
+        .iter()
+        .chain(regions.inode_areas.iter())
+        .filter(|extent| extent.start >= old.total_blocks)
+        .map(|extent| extent.blocks)
+        .sum();
+        if metadata_blocks != 0 {
+            let start = old.total_blocks - data_start;
+            assert_eq!(
+                self.data_bitmap.alloc_contiguous(metadata_blocks, start)?,
+                Some(start)
+            );
+        }
+        self.free_moved(&old.regions, &regions, total_blocks)?;
+        Ok(regions)
+    }
+
+    /// Regions after growing to `total_blocks`, with or without a new inode
+    /// area. `None` if the new metadata does not fit in the added blocks.
+    fn grown_regions(
+        &self,
+        old: &SuperBlock,
+        total_blocks: u64,
+        add_inodes: bool,
+    ) -> Option<Regions> {
+        let bits = self.geometry.bitmap_bits();
+        let checksums_per_block = self.geometry.checksums_per_block();
+        let mut regions = old.regions;
+        let old_data_blocks = regions.data_area.blocks;
+        let data_blocks = total_blocks - self.data_area_start_block;
+        let mut next = old.total_blocks;
+        let mut place = |blocks: u64| {
+            next += blocks;
+            Extent::new(next - blocks, blocks)
+        };
+
+        let inode_blocks: u64 = regions.inode_areas.iter().map(|area| area.blocks).sum();
+        let added_inode_blocks = div_round_up(
+            inode_blocks * (data_blocks - old_data_blocks),
+            old_data_blocks,
+        );
+        let slot = regions.inode_areas.iter().position(|area| area.blocks == 0);
+        let new_inode_area = match slot {
+            Some(slot) if add_inodes && added_inode_blocks != 0 => {
+                regions.inode_count += added_inode_blocks * self.geometry.inodes_per_block();
+                Some((slot, added_inode_blocks))
+            }
+            _ => None,
+        };
+        let inode_bitmap_blocks = div_round_up(regions.inode_count, bits);
+        if inode_bitmap_blocks > regions.inode_bitmap.blocks {
+            regions.inode_bitmap = place(inode_bitmap_blocks);
+        }
+        if let Some((slot, blocks)) = new_inode_area {
+            regions.inode_areas[slot] = place(blocks);
+        }
+        let data_bitmap_blocks = div_round_up(data_blocks, bits);
+        if data_bitmap_blocks > regions.data_bitmap.blocks {
+            regions.data_bitmap = place(data_bitmap_blocks);
+        }
+        let checksum_blocks = div_round_up(data_blocks, checksums_per_block);
+        if checksum_blocks > regions.checksum_table.blocks {
+            regions.checksum_table = place(checksum_blocks);
+        }
+        regions.data_area.blocks = data_blocks;
+        if next <= total_blocks {
+            Some(regions)
+        } else {
+            None
+        }
+    }
+
+    fn shrink(&self, old: &SuperBlock, total_blocks: u64) -> Result<Regions, Error> {
+        let data_start = self.data_area_start_block;
+        if total_blocks <= data_start
+            || old
+                .regions
+                .inode_areas
+                .iter()
+                .any(|area| area.end() > total_blocks)
+        {
+            return Err(Error::NoSpace);
+        }
+        let mut regions = old.regions;
+        let old_data_blocks = regions.data_area.blocks;
+        let data_blocks = total_blocks - data_start;
+
+        // only metadata moved into the data area may be left past the end
+        let metadata_blocks: u64 = [
+            regions.inode_bitmap,
+            regions.data_bitmap,
+            regions.checksum_table,
+        ]
+        .iter()
+        .filter(|extent| extent.start >= data_start)
+        .map(|extent| extent.end().saturating_sub(total_blocks.max(extent.start)))
+        .sum();
+        let used = self
+            .data_bitmap
+            .used_count(data_blocks, old_data_blocks - data_blocks)?;
+        if used != metadata_blocks {
+            return Err(Error::NoSpace);
+        }
+
+        let mut moved: Vec<&mut Extent> = Vec::new();
+        for extent in [
+            &mut regions.inode_bitmap,
+            &mut regions.checksum_table,
+            &mut regions.data_bitmap,
+        ] {
+            if extent.end() > total_blocks {
+                let Some(start) = self
+                    .data_bitmap
+                    .alloc_contiguous_below(extent.blocks, data_blocks)?
+                else {
+                    for extent in moved {
+                        self.data_bitmap
+                            .dealloc_range(extent.start - data_start, extent.blocks)?;
+                    }
+                    return Err(Error::NoSpace);
+                };
+                extent.start = start + data_start;
+                moved.push(extent);
+            }
+        }
+        self.inode_bitmap
+            .relocate(regions.inode_bitmap.start, regions.inode_bitmap.blocks)?;
+        self.checksums
+            .relocate(regions.checksum_table.start, regions.checksum_table.blocks)?;
+        self.data_bitmap
+            .relocate(regions.data_bitmap.start, regions.data_bitmap.blocks)?;
+        self.data_bitmap.reserve_from(data_blocks)?;
+        regions.data_area.blocks = data_blocks;
+        self.free_moved(&old.regions, &regions, total_blocks)?;
+        Ok(regions)
+    }
+
+    /// Free the blocks below `total_blocks` of metadata moved out of the data
+    /// area.
+    fn free_moved(&self, old: &Regions, new: &Regions, total_blocks: u64) -> Result<(), Error> {
+        for (old_extent, extent) in [
+            (old.inode_bitmap, new.inode_bitmap),
+            (old.data_bitmap, new.data_bitmap),
+            (old.checksum_table, new.checksum_table),
+        ] {
+            let end = old_extent.end().min(total_blocks);
+            if old_extent != extent
+                && old_extent.start >= self.data_area_start_block
+                && end > old_extent.start
+            {
+                self.data_bitmap.dealloc_range(
+                    old_extent.start - self.data_area_start_block,
+                    end - old_extent.start,
+                )?;
+            }
+        }
+        Ok(())
     }
 
     pub fn inode_pos_of(&self, id: u64) -> (u64, usize) {
         let inode_size = core::mem::size_of::<Meta>();
-        let inodes_per_block = BLOCK_SIZE / (inode_size as u64);
-        let block_id = self.inode_area_start_block + id / inodes_per_block;
-        (block_id, (id % inodes_per_block) as usize * inode_size)
+        let inodes_per_block = self.geometry.inodes_per_block();
+        let mut block_pos = id / inodes_per_block;
+        let inode_areas = self.super_block.read().regions.inode_areas;
+        let area = inode_areas
+            .iter()
+            .find(|area| {
+                if block_pos < area.blocks {
+                    true
+                } else {
+                    block_pos -= area.blocks;
+                    false
+                }
+            })
+            .expect("inode number out of range");
+        (
+            area.start + block_pos,
+            (id % inodes_per_block) as usize * inode_size,
+        )
+    }
+
+    /// Where the `Meta` of an inode is, in the live filesystem or the
+    /// snapshot it is mounted on.
+    fn meta_pos_of(&self, inode_number: u64) -> Result<(u64, usize), Error> {
+        match &self.view {
+            Some(view) => view.inode_pos_of(inode_number, self.geometry),
+            None => Ok(self.inode_pos_of(inode_number)),
+        }
     }
 
     pub fn add_inode_cache(&self, inode_meta: Arc<RwLock<CaInode>>) {
@@ -360,13 +939,13 @@ impl CAFS {
         queue.push(inode_meta);
     }
 
-    fn cainode(&self, inode_number: u64) -> Arc<RwLock<CaInode>> {
+    fn cainode(&self, inode_number: u64) -> Result<Arc<RwLock<CaInode>>, Error> {
         let inode_cache = self.inode_cache.read();
         if let Some(cache) = inode_cache
             .iter()
             .find(|cache| cache.read().inode_number == inode_number)
         {
-            cache.clone()
+            Ok(cache.clone())
         } else {
             drop(inode_cache);
             let mut queue = self.inode_cache.write();
@@ -374,7 +953,7 @@ impl CAFS {
                 .iter()
                 .find(|cache| cache.read().inode_number == inode_number)
             {
-                cache.clone()
+                Ok(cache.clone())
             } else {
                 // substitute
                 if queue.len() == INODE_CACHE_SIZE {
@@ -388,118 +967,693 @@ impl CAFS {
                         panic!("Run out of InodeCache!");
                     }
                 }
-                let (block_id, offset) = self.inode_pos_of(inode_number);
+                let (block_id, offset) = self.meta_pos_of(inode_number)?;
                 let inode_cache = Arc::new(RwLock::new(CaInode::from(
                     inode_number,
                     block_id,
                     offset,
                     self.cache_manager.clone(),
-                )));
+                    self.checksums.clone(),
+                )?));
                 queue.push(inode_cache.clone());
-                inode_cache
+                Ok(inode_cache)
             }
         }
     }
+
+    /// Grow or shrink the file of `meta` to `new_size`. Growing only
+    /// allocates index blocks, the new range is a hole. The blocks cut off by
+    /// shrinking are added to `freed`, to free once `meta` is stored.
+    fn resize_file(
+        &self,
+        inode: &mut CaInode,
+        meta: &mut Meta,
+        new_size: u64,
+        freed: &mut Vec<u64>,
+    ) -> Result<(), Error> {
+        let curr_info = Meta::index_blocks(meta.size(), self.geometry);
+        let new_info = Meta::index_blocks(new_size, self.geometry);
+        if meta.size() < new_size {
+            let holes = self.geometry.data_blocks(new_size) - meta.data_blocks(self.geometry);
+            let index_blocks = self.alloc_data_blocks(
+                new_info.index_block_count() - curr_info.index_block_count(),
+                inode.alloc_hint,
+            )?;
+            meta.extend(
+                new_size,
+                new_info,
+                vec![0; holes as usize],
+                index_blocks,
+                self.cache_manager.clone(),
+            )?;
+        } else if meta.size() > new_size {
+            let (mut index_ids, mut data_ids) =
+                meta.shrink(new_size, self.cache_manager.clone())?;
+            freed.append(&mut data_ids);
+            freed.append(&mut index_ids);
+            // bytes past the end must read as zero if the file grows again
+            let block_size = self.geometry.block_size();
+            let tail = (new_size % block_size) as usize;
+            if tail != 0 {
+                if let Some(id) =
+                    meta.get_block_id(new_size / block_size, self.cache_manager.clone())?
+                {
+                    if id != 0 {
+                        self.zero_data(inode.type_, id, tail..block_size as usize)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Run `f` on a copy of the `Meta` of `inode_number` and store it only if
+    /// `f` succeeds. Otherwise the old `Meta` stays, and its index blocks,
+    /// which `f` may have rewritten, are put back as they were.
+    fn update_meta<V>(
+        &self,
+        inode_number: u64,
+        f: impl FnOnce(&mut Meta) -> Result<V, Error>,
+    ) -> Result<V, Error> {
+        let (block_id, offset) = self.inode_pos_of(inode_number);
+        let cache = self.cache_manager.get(block_id);
+        let mut cache = cache.write();
+        let mut old = unsafe { cache.read_checked(offset, |meta: &Meta| *meta)? };
+        let old_blocks = if old.is_inline() {
+            None
+        } else {
+            Some(old.blocks(self.cache_manager.clone())?)
+        };
+        let ret = unsafe { cache.try_modify_checked(offset, f) };
+        if let (Err(_), Some((index, data))) = (&ret, old_blocks) {
+            let info = Meta::index_blocks(old.size(), self.geometry);
+            old.forward(info, index, data, self.cache_manager.clone());
+        }
+        ret
+    }
+
+    fn fits_inline(&self, size: u64) -> bool {
+        self.inline_data && size <= INLINE_LIMIT
+    }
+
+    /// Write `data` taken out of `meta` to the start of its blocks.
+    fn write_out_of_line(
+        &self,
+        inode: &mut CaInode,
+        meta: &mut Meta,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        if !data.is_empty() {
+            let blocks =
+                self.fill_holes(inode, meta, 0..self.geometry.data_blocks(data.len() as u64))?;
+            self.write_data(inode.type_, &blocks, 0, data)?;
+        }
+        Ok(())
+    }
+
+    /// Resize a compressed file to `new_size` and store `data` from chunk
+    /// `first` on, return all data blocks. Chunks after the data must be
+    /// holes or unchanged, including the old last one if the file grows.
+    /// The blocks no longer used are added to `freed`.
+    fn rewrite_chunks(
+        &self,
+        inode: &mut CaInode,
+        meta: &mut Meta,
+        new_size: u64,
+        first: u64,
+        data: &[u8],
+        freed: &mut Vec<u64>,
+    ) -> Result<Vec<u64>, Error> {
+        // the old chunks are replaced, except the block zeroed by shrinking
+        let last = new_size / self.geometry.block_size();
+        self.unshare(inode, meta, last..last + 1)?;
+        self.resize_file(inode, meta, new_size, freed)?;
+        for (i, chunk) in data.chunks(CHUNK_SIZE as usize).enumerate() {
+            self.store_chunk(inode, meta, first + i as u64, chunk, freed)?;
+        }
+        let (_, blocks) = meta.blocks(self.cache_manager.clone())?;
+        Ok(blocks)
+    }
+
+    /// Store `data` as chunk `chunk` of `meta` in the fewest blocks: none if
+    /// all zero, compressed if that saves a block, as is otherwise. The old
+    /// blocks of the chunk are added to `freed`.
+    fn store_chunk(
+        &self,
+        inode: &mut CaInode,
+        meta: &mut Meta,
+        chunk: u64,
+        data: &[u8],
+        freed: &mut Vec<u64>,
+    ) -> Result<(), Error> {
+        let (index, mut blocks) = meta.blocks(self.cache_manager.clone())?;
+        let start = (chunk * self.geometry.chunk_blocks()) as usize;
+        let end = (start + self.geometry.chunk_blocks() as usize).min(blocks.len());
+        assert_eq!(
+            self.geometry.data_blocks(data.len() as u64),
+            (end - start) as u64
+        );
+        let mut stream = vec![];
+        if data.iter().any(|byte| *byte != 0) {
+            let compressed = lz4::compress(data);
+            if self.geometry.data_blocks(4 + compressed.len() as u64) < (end - start) as u64 {
+                stream.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+                stream.extend_from_slice(&compressed);
+            } else {
+                stream.extend_from_slice(data);
+            }
+        }
+        let count = self.geometry.data_blocks(stream.len() as u64);
+        // continue right after the data in front of the chunk
+        let hint = blocks[..start]
+            .iter()
+            .rev()
+            .find(|id| **id != 0)
+            .map_or(inode.alloc_hint, |id| id + 1);
+        // the old blocks stay in place until the new ones are written
+        let new_blocks = self.alloc_data_blocks(count, hint)?;
+        // stale bytes after the stream would end up in the checksums
+        stream.resize((count * self.geometry.block_size()) as usize, 0);
+        self.write_data(inode.type_, &new_blocks, 0, &stream)?;
+        freed.extend(blocks[start..end].iter().copied().filter(|id| *id != 0));
+        blocks[start..end].fill(0);
+        for (id, new_id) in blocks[start..].iter_mut().zip(new_blocks) {
+            *id = new_id;
+            inode.alloc_hint = inode.alloc_hint.max(new_id + 1);
+        }
+        let level_info = Meta::index_blocks(meta.size(), self.geometry);
+        meta.forward(level_info, index, blocks, self.cache_manager.clone());
+        Ok(())
+    }
+
+    /// Allocate the holes among the data blocks `range` of `meta`, zeroed, and
+    /// return all data blocks.
+    fn fill_holes(
+        &self,
+        inode: &mut CaInode,
+        meta: &mut Meta,
+        range: Range<u64>,
+    ) -> Result<Vec<u64>, Error> {
+        let (index, mut data) = meta.blocks(self.cache_manager.clone())?;
+        let range = range.start as usize..range.end as usize;
+        let holes = data[range.clone()].iter().filter(|id| **id == 0).count() as u64;
+        if holes == 0 {
+            return Ok(data);
+        }
+        // continue right after the data in front of the holes
+        let hint = data[..range.start]
+            .iter()
+            .rev()
+            .find(|id| **id != 0)
+            .map_or(inode.alloc_hint, |id| id + 1);
+        let mut new_blocks = self.alloc_data_blocks(holes, hint)?.into_iter();
+        for id in data[range].iter_mut().filter(|id| **id == 0) {
+            *id = new_blocks.next().unwrap();
+            self.zero_data(inode.type_, *id, 0..self.geometry.block_size() as usize)?;
+            inode.alloc_hint = inode.alloc_hint.max(*id + 1);
+        }
+        let level_info = Meta::index_blocks(meta.size(), self.geometry);
+        meta.forward(level_info, index, data.clone(), self.cache_manager.clone());
+        Ok(data)
+    }
+
+    fn xattrs(&self, meta: &Meta) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        if meta.xattr() == 0 {
+            return Ok(vec![]);
+        }
+        unsafe {
+            self.cache_manager
+                .get(meta.xattr())
+                .read()
+                .read_checked(0, |block: &XattrBlock| block.xattrs())
+        }
+    }
+
+    /// Store `xattrs` in a new block if the old one belongs to a snapshot.
+    fn write_xattrs(&self, meta: &mut Meta, xattrs: &[(String, Vec<u8>)]) -> Result<(), Error> {
+        if !XattrBlock::fits(xattrs, self.geometry.block_size()) {
+            return Err(Error::NoSpace);
+        }
+        let old = meta.xattr();
+        if xattrs.is_empty() {
+            meta.set_xattr(0);
+        } else if old == 0 || self.is_frozen(old)? {
+            meta.set_xattr(self.alloc_data_blocks(1, old + 1)?[0]);
+        }
+        if meta.xattr() != 0 {
+            unsafe {
+                self.cache_manager
+                    .get(meta.xattr())
+                    .write()
+                    .overwrite(0, |block: &mut XattrBlock| block.set_xattrs(xattrs));
+            }
+        }
+        if old != 0 && old != meta.xattr() {
+            self.dealloc_data(vec![old])?;
+        }
+        Ok(())
+    }
+
+    /// Copy `buf` to byte `offset` of a file whose data blocks are `blocks`,
+    /// none of them a hole.
+    fn write_data(
+        &self,
+        type_: InodeType,
+        blocks: &[u64],
+        offset: u64,
+        mut buf: &[u8],
+    ) -> Result<(), Error> {
+        let block_size = self.geometry.block_size();
+        let mut pos = offset;
+        while !buf.is_empty() {
+            let id = blocks[(pos / block_size) as usize];
+            let start = (pos % block_size) as usize;
+            let len = buf.len().min(block_size as usize - start);
+            self.modify_data(type_, id, |block| {
+                block[start..start + len].copy_from_slice(&buf[..len])
+            })?;
+            pos += len as u64;
+            buf = &buf[len..];
+        }
+        Ok(())
+    }
+
+    fn zero_data(&self, type_: InodeType, id: u64, range: Range<usize>) -> Result<(), Error> {
+        self.modify_data(type_, id, |block| block[range].fill(0))
+    }
+
+    /// Modify data block `id` of a file of type `type_`, keeping its checksum
+    /// up to date.
+    fn modify_data(
+        &self,
+        type_: InodeType,
+        id: u64,
+        f: impl FnOnce(&mut DataBlock),
+    ) -> Result<(), Error> {
+        let cache = self.cache_manager.get(id);
+        let mut cache = cache.write();
+        unsafe {
+            cache.modify(0, f);
+        }
+        if self.checksums.covers(type_) {
+            self.checksums.update(id, &cache)?;
+        }
+        Ok(())
+    }
 }
 
 impl FS for CAFS {
-    fn create(&self, parent: u64, name: String) -> Arc<RwLock<dyn Inode>> {
-        let meta = self.alloc_inode_meta(InodeType::File, name);
+    fn create(&self, parent: u64, name: String) -> Result<Arc<RwLock<dyn Inode>>, Error> {
+        self.mark_dirty()?;
+        let meta = self.alloc_inode_meta(InodeType::File, name)?;
 
         // add to dir data block
-        let parent = self.inode(parent);
+        let parent = self.cainode(parent)?;
+        // keep files of the same directory close to each other
+        meta.write().alloc_hint = parent.read().alloc_hint;
         let parent_inode_number = parent.read().inode_number();
-        let mut contents = parent.read().data();
+        let mut contents = parent.read().data()?;
         if !contents.is_empty() {
             contents.push(0);
         }
         for i in inode_number_binary(meta.read().inode_number()) {
             contents.push(i);
         }
-        self.write(parent_inode_number, &contents);
-
-        // add to inode cache
-        self.add_inode_cache(meta.clone());
-        meta
+        self.write(parent_inode_number, &contents)?;
+        Ok(meta)
     }
 
-    fn write(&self, inode_number: u64, contents: &Vec<u8>) {
+    fn write(&self, inode_number: u64, contents: &Vec<u8>) -> Result<(), Error> {
+        self.mark_dirty()?;
         let new_size = contents.len() as u64;
-        let inode = self.cainode(inode_number);
+        let inode = self.cainode(inode_number)?;
         let mut inode = inode.write();
-        let (block_id, offset) = self.inode_pos_of(inode_number);
-        unsafe {
-            let (_, blocks) =
-                self.cache_manager
-                    .get(block_id)
-                    .write()
-                    .modify(offset, |meta: &mut Meta| {
-                        let curr_info = Meta::index_blocks(meta.size());
-                        let new_info = Meta::index_blocks(new_size);
-                        if meta.size() < new_size {
-                            let mut data_blocks = vec![];
-                            let mut index_blocks = vec![];
-                            for _ in 0..(Meta::_data_blocks(new_size) - meta.data_blocks()) {
-                                data_blocks.push(self.alloc_data());
-                            }
-                            for _ in
-                                0..(new_info.index_block_count() - curr_info.index_block_count())
-                            {
-                                index_blocks.push(self.alloc_data());
-                            }
-                            meta.extend(
-                                new_size,
-                                new_info,
-                                data_blocks,
-                                index_blocks,
-                                self.cache_manager.clone(),
-                            );
-                        } else if meta.size() > new_size {
-                            let ids = meta.shrink(new_size, self.cache_manager.clone());
-                            for id in ids.0 {
-                                self.data_bitmap.dealloc(self.cache_manager.clone(), id);
-                            }
-                            for id in ids.1 {
-                                self.data_bitmap.dealloc(self.cache_manager.clone(), id);
-                            }
-                        }
-                        meta.blocks(self.cache_manager.clone())
-                    });
-            let mut contents = contents.iter().chain(iter::repeat(&0));
-            for id in &blocks {
-                self.cache_manager
-                    .get(*id)
-                    .write()
-                    .modify(0, |block: &mut DataBlock| {
-                        for i in block.iter_mut() {
-                            *i = *(contents.next().unwrap());
-                        }
-                    })
+        let mut freed = vec![];
+        let blocks = self.update_meta(inode_number, |meta: &mut Meta| {
+            meta.take_inline_data();
+            if self.fits_inline(new_size) {
+                self.resize_file(&mut inode, meta, 0, &mut freed)?;
+                meta.set_inline_data(contents);
+                return Ok(None);
+            }
+            if inode.compressed {
+                return self
+                    .rewrite_chunks(&mut inode, meta, new_size, 0, contents, &mut freed)
+                    .map(Some);
+            }
+            self.unshare(&mut inode, meta, 0..self.geometry.data_blocks(new_size))?;
+            self.resize_file(&mut inode, meta, new_size, &mut freed)?;
+            self.fill_holes(&mut inode, meta, 0..self.geometry.data_blocks(new_size))
+                .map(Some)
+        })?;
+        // no longer referred to once the new `Meta` is stored
+        self.dealloc_data(freed)?;
+        match &blocks {
+            Some(blocks) if !inode.compressed => {
+                self.write_data(inode.type_, blocks, 0, contents)?
+            }
+            _ => {}
+        }
+        inode.size = new_size;
+        inode.inline = blocks.is_none();
+        inode.blocks = blocks.unwrap_or_default();
+        Ok(())
+    }
+
+    fn write_at(&self, inode_number: u64, offset: u64, buf: &[u8]) -> Result<(), Error> {
+        self.mark_dirty()?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let end = offset + buf.len() as u64;
+        let inode = self.cainode(inode_number)?;
+        let mut inode = inode.write();
+        let new_size = inode.size.max(end);
+        let mut freed = vec![];
+        let blocks = self.update_meta(inode_number, |meta: &mut Meta| {
+            // a file that fits is inline already, or empty
+            let mut data = meta.take_inline_data();
+            if self.fits_inline(new_size) {
+                data.resize(new_size as usize, 0);
+                data[offset as usize..end as usize].copy_from_slice(buf);
+                meta.set_inline_data(&data);
+                return Ok(None);
+            }
+            if inode.compressed {
+                // from the old last chunk on if the file grows
+                let first = offset.min(inode.size.saturating_sub(1)) / CHUNK_SIZE;
+                let start = first * CHUNK_SIZE;
+                let chunks_end = div_round_up(end, CHUNK_SIZE) * CHUNK_SIZE;
+                if !inode.inline {
+                    data = inode.read_blocks(start..inode.size.min(chunks_end))?;
+                }
+                data.resize((new_size.min(chunks_end) - start) as usize, 0);
+                data[(offset - start) as usize..(end - start) as usize].copy_from_slice(buf);
+                return self
+                    .rewrite_chunks(&mut inode, meta, new_size, first, &data, &mut freed)
+                    .map(Some);
+            }
+            let first = offset / self.geometry.block_size();
+            self.unshare(&mut inode, meta, first..self.geometry.data_blocks(end))?;
+            self.resize_file(&mut inode, meta, new_size, &mut freed)?;
+            self.write_out_of_line(&mut inode, meta, &data)?;
+            self.fill_holes(&mut inode, meta, first..self.geometry.data_blocks(end))
+                .map(Some)
+        })?;
+        self.dealloc_data(freed)?;
+        match &blocks {
+            Some(blocks) if !inode.compressed => {
+                self.write_data(inode.type_, blocks, offset, buf)?
+            }
+            _ => {}
+        }
+        inode.size = new_size;
+        inode.inline = blocks.is_none();
+        inode.blocks = blocks.unwrap_or_default();
+        Ok(())
+    }
+
+    fn truncate(&self, inode_number: u64, size: u64) -> Result<(), Error> {
+        self.mark_dirty()?;
+        let inode = self.cainode(inode_number)?;
+        let mut inode = inode.write();
+        let mut freed = vec![];
+        let blocks = self.update_meta(inode_number, |meta: &mut Meta| {
+            let mut data = meta.take_inline_data();
+            if self.fits_inline(size) {
+                if meta.size() != 0 {
+                    data = inode.read_blocks(0..size)?;
+                }
+                data.resize(size as usize, 0);
+                self.resize_file(&mut inode, meta, 0, &mut freed)?;
+                meta.set_inline_data(&data);
+                return Ok(None);
+            }
+            if inode.compressed {
+                // the chunk the file now ends in, or used to
+                let keep = inode.size.min(size);
+                let first = keep.saturating_sub(1) / CHUNK_SIZE;
+                let start = first * CHUNK_SIZE;
+                if !inode.inline {
+                    data = inode.read_blocks(start..keep)?;
+                }
+                data.resize((size.min(start + CHUNK_SIZE) - start) as usize, 0);
+                return self
+                    .rewrite_chunks(&mut inode, meta, size, first, &data, &mut freed)
+                    .map(Some);
+            }
+            // the new last block gets its tail zeroed
+            let last = size / self.geometry.block_size();
+            self.unshare(&mut inode, meta, last..last + 1)?;
+            self.resize_file(&mut inode, meta, size, &mut freed)?;
+            self.write_out_of_line(&mut inode, meta, &data)?;
+            let (_, blocks) = meta.blocks(self.cache_manager.clone())?;
+            Ok(Some(blocks))
+        })?;
+        self.dealloc_data(freed)?;
+        inode.size = size;
+        inode.inline = blocks.is_none();
+        inode.blocks = blocks.unwrap_or_default();
+        Ok(())
+    }
+
+    fn punch_hole(&self, inode_number: u64, offset: u64, len: u64) -> Result<(), Error> {
+        self.mark_dirty()?;
+        let inode = self.cainode(inode_number)?;
+        let mut inode = inode.write();
+        let end = offset.saturating_add(len).min(inode.size);
+        if offset >= end {
+            return Ok(());
+        }
+        let block_size = self.geometry.block_size();
+        // blocks entirely inside the hole, the one at the end of the file
+        // counts as entirely inside if the hole reaches the end
+        let first = (offset + block_size - 1) / block_size;
+        let last = if end == inode.size {
+            self.geometry.data_blocks(end)
+        } else {
+            end / block_size
+        };
+        let mut freed = vec![];
+        let blocks = self.update_meta(inode_number, |meta: &mut Meta| {
+            if meta.is_inline() {
+                let mut data = meta.inline_data();
+                data[offset as usize..end as usize].fill(0);
+                meta.set_inline_data(&data);
+                return Ok(None);
+            }
+            if inode.compressed {
+                let first = offset / CHUNK_SIZE;
+                let start = first * CHUNK_SIZE;
+                let size = meta.size();
+                let stop = size.min(div_round_up(end, CHUNK_SIZE) * CHUNK_SIZE);
+                let mut data = inode.read_blocks(start..stop)?;
+                data[(offset - start) as usize..(end - start) as usize].fill(0);
+                return self
+                    .rewrite_chunks(&mut inode, meta, size, first, &data, &mut freed)
+                    .map(Some);
+            }
+            let edge = (end - 1) / block_size;
+            self.unshare(
+                &mut inode,
+                meta,
+                offset / block_size..offset / block_size + 1,
+            )?;
+            self.unshare(&mut inode, meta, edge..edge + 1)?;
+            let (index, mut data) = meta.blocks(self.cache_manager.clone())?;
+            if first < last {
+                for id in data[first as usize..last as usize].iter_mut() {
+                    if *id != 0 {
+                        freed.push(*id);
+                        *id = 0;
+                    }
+                }
             }
-            inode.size = new_size;
+            if !freed.is_empty() {
+                let level_info = Meta::index_blocks(meta.size(), self.geometry);
+                meta.forward(level_info, index, data.clone(), self.cache_manager.clone());
+            }
+            Ok(Some(data))
+        })?;
+        // the punched blocks go once the new `Meta` no longer refers to them
+        self.dealloc_data(freed)?;
+        let Some(blocks) = blocks else {
+            return Ok(());
+        };
+        if inode.compressed {
             inode.blocks = blocks;
+            return Ok(());
         }
+        // zero the partially covered blocks at either edge
+        let mut edges = vec![(offset, end.min((offset / block_size + 1) * block_size))];
+        if last * block_size < end && last >= first {
+            edges.push((last * block_size, end));
+        }
+        for (start, end) in edges {
+            let id = blocks[(start / block_size) as usize];
+            if id != 0 && start < end {
+                let range = (start % block_size) as usize..((end - 1) % block_size + 1) as usize;
+                self.zero_data(inode.type_, id, range)?;
+            }
+        }
+        inode.blocks = blocks;
+        Ok(())
     }
 
-    fn df(&self) -> (u64, u64) {
-        let free = self.data_bitmap.free_count();
-        let total = self.data_bitmap.total_count();
-        (free * BLOCK_SIZE, total * BLOCK_SIZE)
+    fn seek(&self, inode_number: u64, offset: u64, whence: Seek) -> Result<Option<u64>, Error> {
+        let inode = self.cainode(inode_number)?;
+        let inode = inode.read();
+        if offset >= inode.size {
+            return Ok(None);
+        }
+        if inode.inline {
+            return Ok(Some(match whence {
+                Seek::Data => offset,
+                Seek::Hole => inode.size,
+            }));
+        }
+        // a compressed chunk is data all through if any of its blocks is
+        let unit_blocks = if inode.compressed {
+            self.geometry.chunk_blocks()
+        } else {
+            1
+        };
+        let unit = unit_blocks * self.geometry.block_size();
+        let first = (offset / unit) as usize;
+        let found = inode
+            .blocks
+            .chunks(unit_blocks as usize)
+            .skip(first)
+            .position(|ids| ids.iter().any(|id| *id != 0) == (whence == Seek::Data))
+            .map(|i| ((first + i) as u64 * unit).max(offset));
+        Ok(match whence {
+            Seek::Data => found,
+            Seek::Hole => Some(found.unwrap_or(inode.size).min(inode.size)),
+        })
     }
 
-    fn inode(&self, inode_number: u64) -> Arc<RwLock<dyn Inode>> {
-        self.cainode(inode_number)
+    fn setxattr(&self, inode_number: u64, name: &str, value: &[u8]) -> Result<(), Error> {
+        if name.is_empty() || name.len() > XATTR_NAME_LIMIT {
+            return Err(Error::InvalidName);
+        }
+        self.mark_dirty()?;
+        let inode = self.cainode(inode_number)?;
+        let _inode = inode.write();
+        let (block_id, offset) = self.inode_pos_of(inode_number);
+        unsafe {
+            self.cache_manager
+                .get(block_id)
+                .write()
+                .modify_checked(offset, |meta: &mut Meta| {
+                    let mut xattrs = self.xattrs(meta)?;
+                    match xattrs.iter_mut().find(|(key, _)| key == name) {
+                        Some((_, old)) => *old = value.to_vec(),
+                        None => xattrs.push((name.to_string(), value.to_vec())),
+                    }
+                    self.write_xattrs(meta, &xattrs)
+                })?
+        }
+    }
+
+    fn getxattr(&self, inode_number: u64, name: &str) -> Result<Vec<u8>, Error> {
+        let inode = self.cainode(inode_number)?;
+        let _inode = inode.read();
+        let (block_id, offset) = self.meta_pos_of(inode_number)?;
+        let xattrs = unsafe {
+            self.cache_manager
+                .get(block_id)
+                .read()
+                .read_checked(offset, |meta: &Meta| self.xattrs(meta))??
+        };
+        xattrs
+            .into_iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value)
+            .ok_or_else(|| Error::NotExist(name.to_string()))
     }
 
-    fn sub_inodes(&self, inode_number: u64) -> Vec<u64> {
+    fn listxattr(&self, inode_number: u64) -> Result<Vec<String>, Error> {
+        let inode = self.cainode(inode_number)?;
+        let _inode = inode.read();
+        let (block_id, offset) = self.meta_pos_of(inode_number)?;
+        let xattrs = unsafe {
+            self.cache_manager
+                .get(block_id)
+                .read()
+                .read_checked(offset, |meta: &Meta| self.xattrs(meta))??
+        };
+        Ok(xattrs.into_iter().map(|(key, _)| key).collect())
+    }
+
+    fn removexattr(&self, inode_number: u64, name: &str) -> Result<(), Error> {
+        self.mark_dirty()?;
+        let inode = self.cainode(inode_number)?;
+        let _inode = inode.write();
+        let (block_id, offset) = self.inode_pos_of(inode_number);
+        unsafe {
+            self.cache_manager
+                .get(block_id)
+                .write()
+                .modify_checked(offset, |meta: &mut Meta| {
+                    let mut xattrs = self.xattrs(meta)?;
+                    let len = xattrs.len();
+                    xattrs.retain(|(key, _)| key != name);
+                    if xattrs.len() == len {
+                        return Err(Error::NotExist(name.to_string()));
+                    }
+                    self.write_xattrs(meta, &xattrs)
+                })?
+        }
+    }
+
+    fn df(&self) -> Result<(u64, u64), Error> {
+        let free = self.data_bitmap.free_count()?;
+        let total = self.super_block.read().regions.data_area.blocks;
+        let block_size = self.geometry.block_size();
+        Ok((free * block_size, total * block_size))
+    }
+
+    fn sync(&self) {
+        // clean since the last flush, nothing changed
+        if !self.super_block.read().is_clean() {
+            self.flush();
+        }
+    }
+
+    /// Bitmaps and the checksum table that no longer fit are moved into the
+    /// data area, and growing adds an inode area in proportion to the added
+    /// data blocks while a slot is left. Must not race with other writers.
+    fn resize(&self, total_blocks: u64) -> Result<(), Error> {
+        self.mark_dirty()?;
+        let old = *self.super_block.read();
+        let regions = match total_blocks.cmp(&old.total_blocks) {
+            core::cmp::Ordering::Equal => return Ok(()),
+            core::cmp::Ordering::Greater => self.grow(&old, total_blocks)?,
+            core::cmp::Ordering::Less => self.shrink(&old, total_blocks)?,
+        };
+        {
+            let mut super_block = self.super_block.write();
+            super_block.total_blocks = total_blocks;
+            super_block.regions = regions;
+            self.write_super_block(&mut super_block);
+        }
+        self.flush();
+        Ok(())
+    }
+
+    fn inode(&self, inode_number: u64) -> Result<Arc<RwLock<dyn Inode>>, Error> {
+        Ok(self.cainode(inode_number)?)
+    }
+
+    fn sub_inodes(&self, inode_number: u64) -> Result<Vec<u64>, Error> {
         let mut inodes = vec![];
-        let parent = self.inode(inode_number);
+        let parent = self.inode(inode_number)?;
         if parent.read().is_file() {
-            return inodes;
+            return Ok(inodes);
         }
-        let data = parent.read().data();
+        let data = parent.read().data()?;
         if data.is_empty() {
-            return inodes;
+            return Ok(inodes);
         }
         for sub_inode in data.split(|num| *num == 0) {
             let mut arr = [0u8; 10];
@@ -507,15 +1661,19 @@ impl FS for CAFS {
             let inode_number = inode_number_from(arr);
             inodes.push(inode_number);
         }
-        inodes
+        Ok(inodes)
     }
 }
 
 #[cfg(test)]
 mod test {
+    use crate::cafs::layout::{
+        Meta, CHUNK_SIZE, INLINE_LIMIT, SNAPSHOT_NAME_LIMIT, XATTR_NAME_LIMIT,
+    };
     use crate::cafs::{CAFS, FS};
     use crate::fake::Disk;
-    use crate::BLOCK_SIZE;
+    use crate::fs::{Inode, InodeType, Seek};
+    use crate::{BlockDevice, Error, BLOCK_SIZE};
     use spin::RwLock;
     use std::sync::Arc;
 
@@ -527,6 +1685,10 @@ mod test {
             Arc::new(RwLock::new(disk)),
             total_blocks,
             inode_bitmap_blocks,
+            BLOCK_SIZE,
+            [0; 16],
+            "",
+            false,
         )
     }
 
@@ -549,10 +1711,715 @@ mod test {
             Arc::new(RwLock::new(disk)),
             total_blocks,
             inode_bitmap_blocks,
+            BLOCK_SIZE,
+            [0; 16],
+            "",
+            false,
+        );
+        let meta = fs.create(0, "test.txt".to_string()).unwrap();
+        let inode_number = meta.read().inode_number();
+        fs.write(inode_number, &contents).unwrap();
+        assert_eq!(meta.read().data().unwrap(), contents);
+    }
+
+    #[test]
+    fn test_contiguous_write() {
+        let fs = fake_fs();
+        let free = fs.df().unwrap().0;
+        let meta = fs.create(0, "test.txt".to_string()).unwrap();
+        let inode_number = meta.read().inode_number();
+        fs.write(inode_number, &vec![1u8; 30 * BLOCK_SIZE as usize])
+            .unwrap();
+
+        let inode = fs.cainode(inode_number).unwrap();
+        let blocks = inode.read().blocks.clone();
+        assert_eq!(blocks.len(), 30);
+        assert!(blocks.windows(2).all(|w| w[1] == w[0] + 1));
+
+        fs.write(inode_number, &vec![1u8; 10]).unwrap();
+        // both the file and "/" are small enough to be inline
+        assert_eq!(fs.df().unwrap().0, free);
+    }
+
+    #[test]
+    fn test_full_disk() {
+        let total_blocks = 4 << 10;
+        let disk = Disk::new(total_blocks);
+        let fs = CAFS::init(
+            Arc::new(RwLock::new(disk)),
+            total_blocks,
+            1,
+            BLOCK_SIZE,
+            [0; 16],
+            "",
+            false,
+        );
+        let meta = fs.create(0, "big".to_string()).unwrap();
+        let inode_number = meta.read().inode_number();
+        let contents = vec![1u8; (total_blocks * BLOCK_SIZE) as usize];
+        assert!(matches!(
+            fs.write(inode_number, &contents),
+            Err(Error::NoSpace)
+        ));
+    }
+
+    #[test]
+    fn test_failed_write() {
+        let total_blocks = 4 << 10;
+        let disk = Arc::new(RwLock::new(Disk::new(total_blocks)));
+        let fs = CAFS::init(
+            disk.clone(),
+            total_blocks,
+            1,
+            BLOCK_SIZE,
+            [0; 16],
+            "",
+            false,
+        );
+        let meta = fs.create(0, "test.txt".to_string()).unwrap();
+        let inode_number = meta.read().inode_number();
+        // large enough to need index blocks
+        let contents = (0..40 * BLOCK_SIZE)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        fs.write(inode_number, &contents).unwrap();
+
+        // fails only once the new size is set
+        let big = vec![1u8; 60 * BLOCK_SIZE as usize];
+        assert!(matches!(fs.write(inode_number, &big), Err(Error::NoSpace)));
+        assert!(matches!(
+            fs.write_at(inode_number, 20 * BLOCK_SIZE, &big),
+            Err(Error::NoSpace)
+        ));
+        assert!(matches!(
+            fs.truncate(inode_number, total_blocks * BLOCK_SIZE * 1024),
+            Err(Error::NoSpace)
+        ));
+        assert_eq!(meta.read().size(), contents.len() as u64);
+        assert_eq!(meta.read().data().unwrap(), contents);
+        fs.flush();
+        drop(meta);
+        drop(fs);
+
+        let fs = CAFS::open(disk).unwrap();
+        assert_eq!(
+            fs.inode(inode_number).unwrap().read().data().unwrap(),
+            contents
         );
-        let meta = fs.create(0, "test.txt".to_string());
+    }
+
+    #[test]
+    fn test_failed_chunk_write() {
+        let total_blocks = 4 << 10;
+        let disk = Disk::new(total_blocks);
+        let fs = CAFS::init(
+            Arc::new(RwLock::new(disk)),
+            total_blocks,
+            1,
+            BLOCK_SIZE,
+            [0; 16],
+            "",
+            false,
+        );
+        let meta = fs.create(0, "test.txt".to_string()).unwrap();
         let inode_number = meta.read().inode_number();
-        fs.write(inode_number, &contents);
-        assert_eq!(meta.read().data(), contents);
+        fs.set_compressed(inode_number, true).unwrap();
+        let contents = vec![7u8; CHUNK_SIZE as usize];
+        fs.write(inode_number, &contents).unwrap();
+
+        let filler = fs.create(0, "filler".to_string()).unwrap();
+        let filler_number = filler.read().inode_number();
+        while fs.df().unwrap().0 > 2 * BLOCK_SIZE {
+            let size = filler.read().size();
+            fs.write_at(filler_number, size, &vec![1; BLOCK_SIZE as usize])
+                .unwrap();
+        }
+        let free = fs.df().unwrap().0;
+        // does not compress, so it needs more blocks than the old chunk
+        let noise = (0..CHUNK_SIZE as u32)
+            .map(|x| (x.wrapping_mul(2654435761) >> 24) as u8)
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            fs.write_at(inode_number, 0, &noise),
+            Err(Error::NoSpace)
+        ));
+        assert_eq!(fs.df().unwrap().0, free);
+        assert_eq!(meta.read().data().unwrap(), contents);
+    }
+
+    #[test]
+    fn test_block_sizes() {
+        let contents = (0..u32::MAX >> 14)
+            .flat_map(|x| x.to_le_bytes())
+            .collect::<Vec<_>>();
+
+        for block_size in [512, 1024, 2048, 4096] {
+            let total_blocks = 64 << 10;
+            let disk = Arc::new(RwLock::new(Disk::new(total_blocks)));
+            let fs = CAFS::init(
+                disk.clone(),
+                total_blocks / (block_size / BLOCK_SIZE),
+                1,
+                block_size,
+                [0; 16],
+                "",
+                false,
+            );
+            let meta = fs.create(0, "test.txt".to_string()).unwrap();
+            let inode_number = meta.read().inode_number();
+            fs.write(inode_number, &contents).unwrap();
+            fs.flush();
+            drop(meta);
+            drop(fs);
+
+            let fs = CAFS::open(disk).unwrap();
+            assert_eq!(fs.geometry.block_size(), block_size);
+            assert_eq!(fs.sub_inodes(0).unwrap(), vec![inode_number]);
+            assert_eq!(
+                fs.inode(inode_number).unwrap().read().data().unwrap(),
+                contents
+            );
+        }
+    }
+
+    #[test]
+    fn test_super_block() {
+        let disk = Arc::new(RwLock::new(Disk::new(8 << 10)));
+        let device: Arc<RwLock<dyn BlockDevice>> = disk.clone();
+        let fs = CAFS::init(
+            device.clone(),
+            8 << 10,
+            1,
+            BLOCK_SIZE,
+            [7; 16],
+            "rootfs",
+            false,
+        );
+        assert!(CAFS::read_super_block(&device).unwrap().is_clean());
+        drop(fs);
+
+        let fs = CAFS::open(device.clone()).unwrap();
+        assert_eq!(fs.uuid(), [7; 16]);
+        assert_eq!(fs.label(), "rootfs");
+        let super_block = CAFS::read_super_block(&device).unwrap();
+        assert!(!super_block.is_clean());
+        assert_eq!(super_block.mount_count, 1);
+        fs.flush();
+        assert!(CAFS::read_super_block(&device).unwrap().is_clean());
+        fs.create(0, "test.txt".to_string()).unwrap();
+        assert!(!CAFS::read_super_block(&device).unwrap().is_clean());
+        fs.flush();
+        drop(fs);
+
+        // a corrupted primary superblock falls back to the backup
+        disk.write().data[0][20] ^= 1;
+        let fs = CAFS::open(device.clone()).unwrap();
+        assert_eq!(fs.sub_inodes(0).unwrap().len(), 1);
+        // and mounting repairs it
+        assert_eq!(
+            CAFS::read_super_block(&device).unwrap().mount_count,
+            super_block.mount_count + 1
+        );
+
+        // unknown features
+        fs.super_block.write().feature_ro_compat |= 1 << 63;
+        fs.write_super_block(&mut fs.super_block.write());
+        drop(fs);
+        let fs = CAFS::open(device.clone()).unwrap();
+        assert!(fs.is_read_only());
+        assert!(matches!(
+            fs.create(0, "test.txt".to_string()),
+            Err(Error::ReadOnly)
+        ));
+        fs.super_block.write().feature_incompat |= 1 << 63;
+        fs.write_super_block(&mut fs.super_block.write());
+        drop(fs);
+        assert!(matches!(
+            CAFS::open(device.clone()),
+            Err(Error::UnsupportedFeature(bits)) if bits == 1 << 63
+        ));
+
+        disk.write().data[0][20] ^= 1;
+        disk.write().data[1][20] ^= 1;
+        assert!(matches!(CAFS::open(device), Err(Error::BadSuperBlock)));
+    }
+
+    #[test]
+    fn test_checksums() {
+        for data_checksums in [false, true] {
+            let disk = Arc::new(RwLock::new(Disk::new(8 << 10)));
+            let device: Arc<RwLock<dyn BlockDevice>> = disk.clone();
+            let fs = CAFS::init(
+                device.clone(),
+                8 << 10,
+                1,
+                BLOCK_SIZE,
+                [0; 16],
+                "",
+                data_checksums,
+            );
+            let meta = fs.create(0, "test.txt".to_string()).unwrap();
+            let inode_number = meta.read().inode_number();
+            fs.write(inode_number, &vec![1u8; 100 * BLOCK_SIZE as usize])
+                .unwrap();
+            // enough entries to move "/" out of its inode
+            for i in 0..30 {
+                fs.create(0, format!("{}", i)).unwrap();
+            }
+            let (inode_block, offset) = fs.inode_pos_of(inode_number);
+            let dir_block = fs.cainode(0).unwrap().read().blocks[0];
+            let (index, data) = unsafe {
+                fs.cache_manager
+                    .get(inode_block)
+                    .read()
+                    .read(offset, |meta: &Meta| meta.blocks(fs.cache_manager.clone()))
+                    .unwrap()
+            };
+            fs.flush();
+            drop(meta);
+            drop(fs);
+
+            let read = || {
+                CAFS::open(device.clone())
+                    .unwrap()
+                    .inode(inode_number)?
+                    .read()
+                    .data()
+            };
+            assert!(read().is_ok());
+
+            // a flipped bit in file data is only caught with data checksums
+            disk.write().data[data[50] as usize][7] ^= 1;
+            if data_checksums {
+                assert!(matches!(read(), Err(Error::Corrupted(id)) if id == data[50]));
+            } else {
+                assert!(read().is_ok());
+            }
+            disk.write().data[data[50] as usize][7] ^= 1;
+
+            // metadata and directories are always checksummed
+            for block_id in [index[0], inode_block] {
+                disk.write().data[block_id as usize][9] ^= 1;
+                assert!(matches!(read(), Err(Error::Corrupted(id)) if id == block_id));
+                disk.write().data[block_id as usize][9] ^= 1;
+            }
+            disk.write().data[dir_block as usize][3] ^= 1;
+            let fs = CAFS::open(device.clone()).unwrap();
+            assert!(matches!(fs.sub_inodes(0), Err(Error::Corrupted(id)) if id == dir_block));
+        }
+    }
+
+    #[test]
+    fn test_resize() {
+        let disk = Arc::new(RwLock::new(Disk::new(32 << 10)));
+        let device: Arc<RwLock<dyn BlockDevice>> = disk.clone();
+        let fs = CAFS::init(device.clone(), 8 << 10, 1, BLOCK_SIZE, [0; 16], "", true);
+        let contents = (0..300 << 10).map(|x| x as u8).collect::<Vec<_>>();
+        let inode = fs.create(0, "a".to_string()).unwrap();
+        let a = inode.read().inode_number();
+        fs.write(a, &contents).unwrap();
+        let (free, total) = fs.df().unwrap();
+        while fs.alloc_inode_meta(InodeType::File, "".to_string()).is_ok() {}
+
+        // bigger bitmaps and a new inode area at the front of the added blocks
+        fs.resize(32 << 10).unwrap();
+        let (grown_free, grown_total) = fs.df().unwrap();
+        assert_eq!(grown_total, total + (24 << 10) * BLOCK_SIZE);
+        assert!(grown_free > free);
+        let inode = fs
+            .alloc_inode_meta(InodeType::File, "".to_string())
+            .unwrap();
+        let (block_id, _) = fs.inode_pos_of(inode.read().inode_number());
+        assert!(block_id >= 8 << 10);
+        drop(inode);
+        drop(fs);
+
+        let fs = CAFS::open(device.clone()).unwrap();
+        assert_eq!(fs.df().unwrap(), (grown_free, grown_total));
+        assert_eq!(fs.inode(a).unwrap().read().data().unwrap(), contents);
+        let inode = fs.create(0, "b".to_string()).unwrap();
+        let b = inode.read().inode_number();
+        fs.write(b, &contents).unwrap();
+        // the new inode area can not be cut off
+        assert!(matches!(fs.resize(16 << 10), Err(Error::NoSpace)));
+        drop(fs);
+
+        let fs = CAFS::init(device.clone(), 16 << 10, 1, BLOCK_SIZE, [0; 16], "", true);
+        let inode = fs.create(0, "a".to_string()).unwrap();
+        let a = inode.read().inode_number();
+        fs.write(a, &contents).unwrap();
+        let (free, total) = fs.df().unwrap();
+        // a block in use past the new end
+        let tail = fs.alloc_data_blocks(1, (14 << 10) + 1).unwrap();
+        assert!(matches!(fs.resize(12 << 10), Err(Error::NoSpace)));
+        fs.dealloc_data(tail).unwrap();
+        fs.resize(12 << 10).unwrap();
+        assert_eq!(
+            fs.df().unwrap(),
+            (
+                free - (4 << 10) * BLOCK_SIZE,
+                total - (4 << 10) * BLOCK_SIZE
+            )
+        );
+        drop(fs);
+        let fs = CAFS::open(device).unwrap();
+        assert_eq!(fs.inode(a).unwrap().read().data().unwrap(), contents);
+        assert!(fs.alloc_data_blocks(1, 12 << 10).unwrap()[0] < 12 << 10);
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let disk = Arc::new(RwLock::new(Disk::new(20 << 10)));
+        let device: Arc<RwLock<dyn BlockDevice>> = disk.clone();
+        let fs = CAFS::init(device.clone(), 20 << 10, 2, BLOCK_SIZE, [0; 16], "", true);
+        let v1 = (0..100 << 10).map(|x| x as u8).collect::<Vec<_>>();
+        let inode = fs.create(0, "a".to_string()).unwrap();
+        let a = inode.read().inode_number();
+        drop(inode);
+        fs.write(a, &v1).unwrap();
+        let free = fs.df().unwrap().0;
+
+        fs.create_snapshot("before").unwrap();
+        assert!(matches!(
+            fs.create_snapshot("before"),
+            Err(Error::AlreadyExist(_))
+        ));
+        assert!(matches!(fs.create_snapshot(""), Err(Error::InvalidName)));
+        let long = "s".repeat(SNAPSHOT_NAME_LIMIT + 1);
+        assert!(matches!(fs.create_snapshot(&long), Err(Error::InvalidName)));
+        let taken = fs.df().unwrap().0;
+        // only the blocks written to are copied
+        fs.write_at(a, 10, b"v2").unwrap();
+        fs.create(0, "b".to_string()).unwrap();
+        let mut v2 = v1.clone();
+        v2[10..12].copy_from_slice(b"v2");
+        assert_eq!(fs.inode(a).unwrap().read().data().unwrap(), v2);
+        assert!(taken - fs.df().unwrap().0 < 10 * BLOCK_SIZE);
+        // frozen blocks are not freed
+        fs.truncate(a, 0).unwrap();
+        assert!(taken - fs.df().unwrap().0 < 10 * BLOCK_SIZE);
+        fs.flush();
+
+        let snapshot = CAFS::open_snapshot(device.clone(), "before").unwrap();
+        assert_eq!(snapshot.sub_inodes(0).unwrap(), vec![a]);
+        assert_eq!(snapshot.inode(a).unwrap().read().data().unwrap(), v1);
+        assert!(matches!(snapshot.write(a, &v2), Err(Error::ReadOnly)));
+        assert!(matches!(
+            CAFS::open_snapshot(device.clone(), "after"),
+            Err(Error::NotExist(_))
+        ));
+        drop(snapshot);
+
+        fs.rollback("before").unwrap();
+        assert_eq!(fs.sub_inodes(0).unwrap(), vec![a]);
+        assert_eq!(fs.inode(a).unwrap().read().data().unwrap(), v1);
+        assert_eq!(fs.df().unwrap().0, taken);
+        drop(fs);
+
+        let fs = CAFS::open(device).unwrap();
+        assert_eq!(fs.snapshots().len(), 1);
+        for name in ["1", "2", "3"] {
+            fs.create_snapshot(name).unwrap();
+        }
+        assert!(matches!(fs.create_snapshot("4"), Err(Error::NoSpace)));
+        for name in ["before", "1", "2", "3"] {
+            fs.delete_snapshot(name).unwrap();
+        }
+        assert!(fs.snapshots().is_empty());
+        // all but the snapshot table is back
+        assert_eq!(fs.df().unwrap().0, free - BLOCK_SIZE);
+        fs.write_at(a, 10, b"v2").unwrap();
+        assert_eq!(fs.inode(a).unwrap().read().data().unwrap(), v2);
+    }
+
+    #[test]
+    fn test_xattrs() {
+        let disk = Arc::new(RwLock::new(Disk::new(20 << 10)));
+        let device: Arc<RwLock<dyn BlockDevice>> = disk.clone();
+        let fs = CAFS::init(device.clone(), 20 << 10, 2, BLOCK_SIZE, [0; 16], "", false);
+        let inode = fs.create(0, "a".to_string()).unwrap();
+        let a = inode.read().inode_number();
+        drop(inode);
+        let free = fs.df().unwrap().0;
+        assert!(fs.listxattr(a).unwrap().is_empty());
+        assert!(matches!(
+            fs.getxattr(a, "user.hash"),
+            Err(Error::NotExist(_))
+        ));
+
+        fs.setxattr(a, "security.label", b"system_u").unwrap();
+        fs.setxattr(a, "user.hash", b"1234").unwrap();
+        fs.setxattr(a, "user.hash", b"5678").unwrap();
+        assert_eq!(fs.df().unwrap().0, free - BLOCK_SIZE);
+        assert_eq!(fs.listxattr(a).unwrap(), ["security.label", "user.hash"]);
+        assert_eq!(fs.getxattr(a, "user.hash").unwrap(), b"5678");
+        assert!(matches!(
+            fs.setxattr(a, "user.big", &[0; BLOCK_SIZE as usize]),
+            Err(Error::NoSpace)
+        ));
+        assert!(matches!(fs.setxattr(a, "", b"x"), Err(Error::InvalidName)));
+        let long = "u".repeat(XATTR_NAME_LIMIT + 1);
+        assert!(matches!(
+            fs.setxattr(a, &long, b"x"),
+            Err(Error::InvalidName)
+        ));
+        fs.write(a, &b"data".to_vec()).unwrap();
+        fs.truncate(a, 0).unwrap();
+        assert_eq!(fs.getxattr(a, "security.label").unwrap(), b"system_u");
+
+        // the snapshot keeps the old attributes
+        fs.create_snapshot("s").unwrap();
+        fs.removexattr(a, "user.hash").unwrap();
+        assert!(matches!(
+            fs.removexattr(a, "user.hash"),
+            Err(Error::NotExist(_))
+        ));
+        fs.flush();
+        let snapshot = CAFS::open_snapshot(device.clone(), "s").unwrap();
+        assert_eq!(snapshot.getxattr(a, "user.hash").unwrap(), b"5678");
+        drop(snapshot);
+        fs.delete_snapshot("s").unwrap();
+        drop(fs);
+
+        let fs = CAFS::open(device).unwrap();
+        assert_eq!(fs.listxattr(a).unwrap(), ["security.label"]);
+        fs.removexattr(a, "security.label").unwrap();
+        // all but the snapshot table is back
+        assert_eq!(fs.df().unwrap().0, free - BLOCK_SIZE);
+    }
+
+    #[test]
+    fn test_inline_data() {
+        let disk = Arc::new(RwLock::new(Disk::new(20 << 10)));
+        let device: Arc<RwLock<dyn BlockDevice>> = disk.clone();
+        let fs = CAFS::init(device.clone(), 20 << 10, 2, BLOCK_SIZE, [0; 16], "", true);
+        let free = fs.df().unwrap().0;
+        let inode = fs.create(0, "test.txt".to_string()).unwrap();
+        let a = inode.read().inode_number();
+        drop(inode);
+
+        // neither the file nor "/" takes a data block
+        fs.write(a, &b"Hello CAFS".to_vec()).unwrap();
+        fs.write_at(a, 20, b"!").unwrap();
+        fs.punch_hole(a, 0, 6).unwrap();
+        assert_eq!(fs.df().unwrap().0, free);
+        let mut expected = b"\0\0\0\0\0\0CAFS".to_vec();
+        expected.resize(21, 0);
+        expected[20] = b'!';
+        assert_eq!(fs.inode(a).unwrap().read().data().unwrap(), expected);
+        assert_eq!(fs.seek(a, 0, Seek::Hole).unwrap(), Some(21));
+
+        // growing past the inode moves the data to blocks
+        let big = INLINE_LIMIT as usize + 1;
+        fs.write_at(a, big as u64 - 1, b"?").unwrap();
+        assert_eq!(fs.df().unwrap().0, free - BLOCK_SIZE);
+        expected.resize(big, 0);
+        expected[big - 1] = b'?';
+        assert_eq!(fs.inode(a).unwrap().read().data().unwrap(), expected);
+
+        // and shrinking brings it back
+        fs.truncate(a, 8).unwrap();
+        assert_eq!(fs.df().unwrap().0, free);
+        assert_eq!(fs.inode(a).unwrap().read().data().unwrap(), &expected[..8]);
+
+        // a snapshot sees the inline data it froze
+        fs.create_snapshot("s").unwrap();
+        fs.write(a, &b"changed".to_vec()).unwrap();
+        fs.flush();
+        let snapshot = CAFS::open_snapshot(device.clone(), "s").unwrap();
+        let data = snapshot.inode(a).unwrap().read().data().unwrap();
+        assert_eq!(data, &expected[..8]);
+        drop(snapshot);
+        fs.delete_snapshot("s").unwrap();
+        drop(fs);
+
+        let fs = CAFS::open(device).unwrap();
+        assert_eq!(fs.inode(a).unwrap().read().data().unwrap(), b"changed");
+        assert_eq!(fs.sub_inodes(0).unwrap(), [a]);
+    }
+
+    #[test]
+    fn test_compression() {
+        let disk = Arc::new(RwLock::new(Disk::new(20 << 10)));
+        let device: Arc<RwLock<dyn BlockDevice>> = disk.clone();
+        let fs = CAFS::init(device.clone(), 20 << 10, 2, BLOCK_SIZE, [0; 16], "", true);
+        let inode = fs.create(0, "vmlinux".to_string()).unwrap();
+        let a = inode.read().inode_number();
+        let free = fs.df().unwrap().0;
+        let mut contents = (0..100_000u32)
+            .map(|x| (x / 7 % 13) as u8)
+            .collect::<Vec<_>>();
+        fs.set_compressed(a, true).unwrap();
+        fs.write(a, &contents).unwrap();
+        assert_eq!(inode.read().size(), contents.len() as u64);
+        assert!(inode.read().physical_size() < contents.len() as u64 / 10);
+        assert_eq!(inode.read().data().unwrap(), contents);
+        // reading across two chunks
+        let mut buf = [0; 100];
+        let offset = CHUNK_SIZE - 50;
+        assert_eq!(inode.read().read_at(offset, &mut buf).unwrap(), 100);
+        assert_eq!(buf, contents[offset as usize..offset as usize + 100]);
+
+        fs.create_snapshot("s").unwrap();
+        let frozen = contents.clone();
+        fs.write_at(a, 20_000, b"patched").unwrap();
+        contents[20_000..20_007].copy_from_slice(b"patched");
+        // growing rewrites the old last chunk, the gap is a hole
+        fs.write_at(a, 9 * CHUNK_SIZE, b"tail").unwrap();
+        contents.resize(9 * CHUNK_SIZE as usize, 0);
+        contents.extend_from_slice(b"tail");
+        fs.punch_hole(a, 0, CHUNK_SIZE + 10).unwrap();
+        contents[..CHUNK_SIZE as usize + 10].fill(0);
+        assert_eq!(fs.seek(a, 0, Seek::Data).unwrap(), Some(CHUNK_SIZE));
+        assert_eq!(fs.seek(a, 0, Seek::Hole).unwrap(), Some(0));
+        assert_eq!(
+            fs.seek(a, 100_000, Seek::Hole).unwrap(),
+            Some(7 * CHUNK_SIZE)
+        );
+        assert_eq!(inode.read().data().unwrap(), contents);
+        fs.truncate(a, 30_000).unwrap();
+        contents.truncate(30_000);
+        fs.truncate(a, 40_000).unwrap();
+        contents.resize(40_000, 0);
+        assert_eq!(inode.read().data().unwrap(), contents);
+        fs.flush();
+        let snapshot = CAFS::open_snapshot(device.clone(), "s").unwrap();
+        assert_eq!(snapshot.inode(a).unwrap().read().data().unwrap(), frozen);
+        drop(snapshot);
+        fs.delete_snapshot("s").unwrap();
+        drop(inode);
+        drop(fs);
+
+        let fs = CAFS::open(device).unwrap();
+        let inode = fs.inode(a).unwrap();
+        assert_eq!(inode.read().data().unwrap(), contents);
+        fs.set_compressed(a, false).unwrap();
+        assert_eq!(inode.read().physical_size(), 79 * BLOCK_SIZE);
+        assert_eq!(inode.read().data().unwrap(), contents);
+        fs.truncate(a, 0).unwrap();
+        // all but the snapshot table is back
+        assert_eq!(fs.df().unwrap().0, free - BLOCK_SIZE);
+    }
+
+    #[test]
+    fn test_sparse() {
+        let bs = BLOCK_SIZE;
+        let fs = fake_fs();
+        let meta = fs.create(0, "disk.img".to_string()).unwrap();
+        let inode_number = meta.read().inode_number();
+        let free = fs.df().unwrap().0;
+
+        // the gap in front of the data is not allocated
+        fs.write_at(inode_number, 50 * bs + 10, b"hello").unwrap();
+        assert_eq!(meta.read().size(), 50 * bs + 15);
+        // one data block and one block table
+        assert_eq!(fs.df().unwrap().0, free - 2 * bs);
+        let data = meta.read().data().unwrap();
+        assert!(data[..(50 * bs + 10) as usize].iter().all(|x| *x == 0));
+        assert_eq!(&data[(50 * bs + 10) as usize..], b"hello");
+
+        assert_eq!(fs.seek(inode_number, 0, Seek::Hole).unwrap(), Some(0));
+        assert_eq!(fs.seek(inode_number, 0, Seek::Data).unwrap(), Some(50 * bs));
+        assert_eq!(
+            fs.seek(inode_number, 50 * bs + 1, Seek::Hole).unwrap(),
+            Some(50 * bs + 15)
+        );
+        assert_eq!(
+            fs.seek(inode_number, 50 * bs + 15, Seek::Data).unwrap(),
+            None
+        );
+
+        // growing leaves a hole at the end
+        fs.truncate(inode_number, 90 * bs).unwrap();
+        assert_eq!(
+            fs.seek(inode_number, 50 * bs + 1, Seek::Hole).unwrap(),
+            Some(51 * bs)
+        );
+        assert_eq!(fs.seek(inode_number, 51 * bs, Seek::Data).unwrap(), None);
+        fs.write_at(inode_number, 80 * bs - 2, &[1; 4]).unwrap();
+        assert_eq!(fs.df().unwrap().0, free - 4 * bs);
+
+        // punching frees whole blocks and zeroes the edges
+        fs.punch_hole(inode_number, 50 * bs + 12, 30 * bs - 11)
+            .unwrap();
+        assert_eq!(fs.df().unwrap().0, free - 3 * bs);
+        let data = meta.read().data().unwrap();
+        assert_eq!(data.len() as u64, 90 * bs);
+        assert_eq!(
+            &data[(50 * bs + 10) as usize..(50 * bs + 15) as usize],
+            b"he\0\0\0"
+        );
+        assert_eq!(
+            &data[(80 * bs - 2) as usize..(80 * bs + 3) as usize],
+            [0, 0, 0, 1, 0]
+        );
+        assert_eq!(
+            fs.seek(inode_number, 51 * bs, Seek::Data).unwrap(),
+            Some(80 * bs)
+        );
+
+        // shrinking zeroes the tail of the last block
+        fs.truncate(inode_number, 50 * bs + 11).unwrap();
+        fs.truncate(inode_number, 51 * bs).unwrap();
+        let data = meta.read().data().unwrap();
+        assert_eq!(
+            &data[(50 * bs + 10) as usize..(50 * bs + 13) as usize],
+            b"h\0\0"
+        );
+
+        fs.punch_hole(inode_number, 0, u64::MAX).unwrap();
+        assert_eq!(fs.seek(inode_number, 0, Seek::Data).unwrap(), None);
+        assert_eq!(fs.df().unwrap().0, free - bs);
+    }
+
+    #[test]
+    fn test_failed_punch() {
+        let bs = BLOCK_SIZE;
+        let total_blocks = 4 << 10;
+        let disk = Arc::new(RwLock::new(Disk::new(total_blocks)));
+        let fs = CAFS::init(
+            disk.clone(),
+            total_blocks,
+            1,
+            BLOCK_SIZE,
+            [0; 16],
+            "",
+            false,
+        );
+        let meta = fs.create(0, "test.txt".to_string()).unwrap();
+        let inode_number = meta.read().inode_number();
+        let contents = (0..40 * bs).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        fs.write(inode_number, &contents).unwrap();
+        fs.create_snapshot("s").unwrap();
+
+        // too little space left to copy the frozen blocks at both edges
+        let filler = fs.create(0, "filler".to_string()).unwrap();
+        let filler_number = filler.read().inode_number();
+        while fs.df().unwrap().0 > 2 * bs {
+            let size = filler.read().size();
+            fs.write_at(filler_number, size, &vec![1; bs as usize])
+                .unwrap();
+        }
+        assert!(matches!(
+            fs.punch_hole(inode_number, 2 * bs + 1, 28 * bs),
+            Err(Error::NoSpace)
+        ));
+        assert_eq!(meta.read().data().unwrap(), contents);
+
+        fs.truncate(filler_number, 0).unwrap();
+        fs.punch_hole(inode_number, 2 * bs + 1, 28 * bs).unwrap();
+        let mut punched = contents.clone();
+        punched[(2 * bs + 1) as usize..(30 * bs + 1) as usize].fill(0);
+        assert_eq!(meta.read().data().unwrap(), punched);
+        fs.flush();
+        drop(meta);
+        drop(filler);
+        drop(fs);
+
+        let fs = CAFS::open(disk).unwrap();
+        assert_eq!(
+            fs.inode(inode_number).unwrap().read().data().unwrap(),
+            punched
+        );
     }
 }
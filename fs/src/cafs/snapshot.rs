@@ -0,0 +1,441 @@
+use super::bitmap::Bitmap;
+use super::cache::CacheManager;
+use super::layout::{
+    DataBlock, Extent, Geometry, Meta, SnapshotEntry, SnapshotTable, FEATURE_INCOMPAT_SUPP,
+    SNAPSHOT_LIMIT, SNAPSHOT_NAME_LIMIT,
+};
+use super::{div_round_up, CaInode, CAFS};
+use crate::{now, BlockDevice, Error};
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+use spin::RwLock;
+
+/// A snapshot of the live filesystem. The data blocks set in its data bitmap
+/// are frozen: live files copy them before a change and never free them.
+pub struct Snapshot {
+    slot: usize,
+    entry: SnapshotEntry,
+    data_bitmap: Bitmap,
+}
+
+impl Snapshot {
+    fn new(slot: usize, entry: SnapshotEntry, cache_manager: Arc<CacheManager>) -> Self {
+        let data_bitmap = Bitmap::new(
+            entry.data_bitmap.start,
+            entry.data_bitmap.blocks,
+            cache_manager,
+        );
+        Self {
+            slot,
+            entry,
+            data_bitmap,
+        }
+    }
+}
+
+/// Where a `CAFS` opened on a snapshot finds its inodes.
+pub struct SnapshotView {
+    inode_count: u64,
+    inode_bitmap: Bitmap,
+    inodes_start_block: u64,
+}
+
+impl SnapshotView {
+    fn new(entry: &SnapshotEntry, cache_manager: Arc<CacheManager>) -> Self {
+        Self {
+            inode_count: entry.inode_count,
+            inode_bitmap: Bitmap::new(
+                entry.inode_bitmap.start,
+                entry.inode_bitmap.blocks,
+                cache_manager,
+            ),
+            inodes_start_block: entry.inodes.start,
+        }
+    }
+
+    /// The `Meta` of inode `id` is the one after those of the smaller inode
+    /// numbers in use.
+    pub fn inode_pos_of(&self, id: u64, geometry: Geometry) -> Result<(u64, usize), Error> {
+        if id >= self.inode_count || !self.inode_bitmap.test(id)? {
+            return Err(Error::NotExist(id.to_string()));
+        }
+        let rank = self.inode_bitmap.used_count(0, id)?;
+        Ok(packed_pos(self.inodes_start_block, rank, geometry))
+    }
+}
+
+/// Position of the `rank`th `Meta` packed from `start_block`.
+fn packed_pos(start_block: u64, rank: u64, geometry: Geometry) -> (u64, usize) {
+    let inodes_per_block = geometry.inodes_per_block();
+    (
+        start_block + rank / inodes_per_block,
+        (rank % inodes_per_block) as usize * core::mem::size_of::<Meta>(),
+    )
+}
+
+fn test_bit(words: &[u64], bit: u64) -> bool {
+    words
+        .get((bit / 64) as usize)
+        .is_some_and(|word| word & (1 << (bit % 64)) != 0)
+}
+
+impl CAFS {
+    /// Mount snapshot `name` read-only. Writes of the live filesystem that
+    /// are not flushed yet do not matter, the blocks of a snapshot never
+    /// change.
+    pub fn open_snapshot(
+        block_device: Arc<RwLock<dyn BlockDevice>>,
+        name: &str,
+    ) -> Result<Self, Error> {
+        let super_block = Self::read_super_block(&block_device)?;
+        let unsupported = super_block.feature_incompat & !FEATURE_INCOMPAT_SUPP;
+        if unsupported != 0 {
+            return Err(Error::UnsupportedFeature(unsupported));
+        }
+        let cache_manager = Arc::new(CacheManager::new(block_device, super_block.block_size));
+        let mut fs = Self::from_super_block(super_block, cache_manager.clone(), true);
+        fs.load_snapshots()?;
+        let view = fs
+            .snapshots
+            .read()
+            .iter()
+            .find(|snapshot| snapshot.entry.name() == name)
+            .map(|snapshot| SnapshotView::new(&snapshot.entry, cache_manager))
+            .ok_or_else(|| Error::NotExist(name.to_string()))?;
+        fs.view = Some(view);
+        Ok(fs)
+    }
+
+    pub(super) fn load_snapshots(&self) -> Result<(), Error> {
+        let table = self.super_block.read().snapshot_table;
+        if table == 0 {
+            return Ok(());
+        }
+        let entries = unsafe {
+            self.cache_manager
+                .get(table)
+                .read()
+                .read_checked(0, |table: &SnapshotTable| table.entries)?
+        };
+        let mut snapshots = self.snapshots.write();
+        for (slot, entry) in entries.into_iter().enumerate() {
+            if entry.is_used() {
+                snapshots.push(Snapshot::new(slot, entry, self.cache_manager.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Names and creation times of the snapshots.
+    pub fn snapshots(&self) -> Vec<(String, u64)> {
+        self.snapshots
+            .read()
+            .iter()
+            .map(|snapshot| (snapshot.entry.name(), snapshot.entry.time))
+            .collect()
+    }
+
+    /// Take a snapshot of the live filesystem called `name`. It copies the
+    /// bitmaps and the `Meta` of the inodes in use, files share their blocks
+    /// with it until they change.
+    pub fn create_snapshot(&self, name: &str) -> Result<(), Error> {
+        if name.is_empty() || name.len() > SNAPSHOT_NAME_LIMIT {
+            return Err(Error::InvalidName);
+        }
+        self.mark_dirty()?;
+        let mut snapshots = self.snapshots.write();
+        if snapshots
+            .iter()
+            .any(|snapshot| snapshot.entry.name() == name)
+        {
+            return Err(Error::AlreadyExist(name.to_string()));
+        }
+        let slot = (0..SNAPSHOT_LIMIT)
+            .find(|slot| snapshots.iter().all(|snapshot| snapshot.slot != *slot))
+            .ok_or(Error::NoSpace)?;
+        if self.super_block.read().snapshot_table == 0 {
+            let table = self.alloc_data()?;
+            unsafe {
+                self.cache_manager
+                    .get(table)
+                    .write()
+                    .overwrite(0, |table: &mut SnapshotTable| {
+                        table.entries = Default::default();
+                    });
+            }
+            let mut super_block = self.super_block.write();
+            super_block.snapshot_table = table;
+            self.write_super_block(&mut super_block);
+        }
+
+        let inode_count = self.super_block.read().regions.inode_count;
+        let inodes = self.inode_bitmap.words()?;
+        let used = self.inode_bitmap.used_count(0, inode_count)?;
+        // one run, so that running out of space leaks nothing
+        let inode_bitmap_blocks = self.inode_bitmap.blocks();
+        let inode_blocks = div_round_up(used, self.geometry.inodes_per_block());
+        let data_bitmap_blocks = self.data_bitmap.blocks();
+        let run = self
+            .data_bitmap
+            .alloc_contiguous(inode_bitmap_blocks + inode_blocks + data_bitmap_blocks, 0)?
+            .ok_or(Error::NoSpace)?
+            + self.data_area_start_block;
+        let mut entry = SnapshotEntry {
+            name: [0; SNAPSHOT_NAME_LIMIT + 1],
+            time: now(),
+            inode_count,
+            inode_bitmap: Extent::new(run, inode_bitmap_blocks),
+            inodes: Extent::new(run + inode_bitmap_blocks, inode_blocks),
+            data_bitmap: Extent::new(run + inode_bitmap_blocks + inode_blocks, data_bitmap_blocks),
+        };
+        entry.name[..name.len()].copy_from_slice(name.as_bytes());
+
+        self.inode_bitmap
+            .copy_to(entry.inode_bitmap.start, entry.inode_bitmap.blocks)?;
+        for (rank, id) in (0..inode_count)
+            .filter(|id| test_bit(&inodes, *id))
+            .enumerate()
+        {
+            let (block_id, offset) = self.inode_pos_of(id);
+            let meta = unsafe {
+                self.cache_manager
+                    .get(block_id)
+                    .read()
+                    .read_checked(offset, |meta: &Meta| *meta)?
+            };
+            let (block_id, offset) = packed_pos(entry.inodes.start, rank as u64, self.geometry);
+            unsafe {
+                self.cache_manager
+                    .get(block_id)
+                    .write()
+                    .overwrite(offset, |copy: &mut Meta| *copy = meta);
+            }
+        }
+        // last, so that the blocks of the snapshot are frozen too
+        self.data_bitmap
+            .copy_to(entry.data_bitmap.start, entry.data_bitmap.blocks)?;
+
+        snapshots.push(Snapshot::new(slot, entry, self.cache_manager.clone()));
+        self.write_snapshot_table(&snapshots)?;
+        drop(snapshots);
+        self.flush();
+        Ok(())
+    }
+
+    /// Delete snapshot `name` and free the blocks only it used.
+    pub fn delete_snapshot(&self, name: &str) -> Result<(), Error> {
+        self.mark_dirty()?;
+        let mut snapshots = self.snapshots.write();
+        let pos = snapshots
+            .iter()
+            .position(|snapshot| snapshot.entry.name() == name)
+            .ok_or_else(|| Error::NotExist(name.to_string()))?;
+        snapshots.remove(pos);
+        self.write_snapshot_table(&snapshots)?;
+        drop(snapshots);
+        self.collect_garbage()?;
+        self.flush();
+        Ok(())
+    }
+
+    /// Bring the live filesystem back to snapshot `name`, which is kept.
+    /// Inodes of the live filesystem must not be in use.
+    pub fn rollback(&self, name: &str) -> Result<(), Error> {
+        self.mark_dirty()?;
+        let snapshots = self.snapshots.read();
+        let snapshot = snapshots
+            .iter()
+            .find(|snapshot| snapshot.entry.name() == name)
+            .ok_or_else(|| Error::NotExist(name.to_string()))?;
+        let view = SnapshotView::new(&snapshot.entry, self.cache_manager.clone());
+        let inode_count = self.super_block.read().regions.inode_count;
+        let live = self.inode_bitmap.words()?;
+        let frozen = view.inode_bitmap.words()?;
+        let mut rank = 0;
+        for id in 0..inode_count {
+            let in_use = test_bit(&live, id);
+            if id < view.inode_count && test_bit(&frozen, id) {
+                let (block_id, offset) = packed_pos(view.inodes_start_block, rank, self.geometry);
+                rank += 1;
+                let meta = unsafe {
+                    self.cache_manager
+                        .get(block_id)
+                        .read()
+                        .read_checked(offset, |meta: &Meta| *meta)?
+                };
+                let (block_id, offset) = self.inode_pos_of(id);
+                unsafe {
+                    self.cache_manager
+                        .get(block_id)
+                        .write()
+                        .overwrite(offset, |live: &mut Meta| *live = meta);
+                }
+                if !in_use {
+                    self.inode_bitmap.alloc_at(id)?;
+                }
+            } else if in_use {
+                self.inode_bitmap.dealloc(id)?;
+            }
+        }
+        drop(snapshots);
+        self.inode_cache.write().clear();
+        self.collect_garbage()?;
+        self.flush();
+        Ok(())
+    }
+
+    /// Whether data block `block_id` belongs to a snapshot.
+    pub(super) fn is_frozen(&self, block_id: u64) -> Result<bool, Error> {
+        let bit = block_id - self.data_area_start_block;
+        for snapshot in self.snapshots.read().iter() {
+            if snapshot.data_bitmap.test(bit)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Copy the frozen index blocks of `meta`, and its frozen data blocks in
+    /// `range`, before they change.
+    pub(super) fn unshare(
+        &self,
+        inode: &mut CaInode,
+        meta: &mut Meta,
+        range: Range<u64>,
+    ) -> Result<(), Error> {
+        if self.snapshots.read().is_empty() {
+            return Ok(());
+        }
+        let (mut index, mut data) = meta.blocks(self.cache_manager.clone())?;
+        let mut changed = false;
+        // the index blocks are all rewritten on every change anyway
+        let mut frozen_index = vec![];
+        for id in index.iter_mut() {
+            if self.is_frozen(*id)? {
+                frozen_index.push(id);
+            }
+        }
+        if !frozen_index.is_empty() {
+            let new_blocks = self.alloc_data_blocks(frozen_index.len() as u64, inode.alloc_hint)?;
+            for (id, new_id) in frozen_index.into_iter().zip(new_blocks) {
+                *id = new_id;
+            }
+            changed = true;
+        }
+        let end = (range.end as usize).min(data.len());
+        let start = (range.start as usize).min(end);
+        for id in data[start..end].iter_mut().filter(|id| **id != 0) {
+            if !self.is_frozen(*id)? {
+                continue;
+            }
+            let new_id = self.alloc_data_blocks(1, *id + 1)?[0];
+            let contents = {
+                let cache = self.cache_manager.get(*id);
+                let cache = cache.read();
+                if self.checksums.covers(inode.type_) {
+                    self.checksums.verify(*id, &cache)?;
+                }
+                unsafe { cache.read(0, |block: &DataBlock| *block) }
+            };
+            self.modify_data(inode.type_, new_id, |block| *block = contents)?;
+            inode.alloc_hint = inode.alloc_hint.max(new_id + 1);
+            *id = new_id;
+            changed = true;
+        }
+        if changed {
+            let level_info = Meta::index_blocks(meta.size(), self.geometry);
+            meta.forward(level_info, index, data, self.cache_manager.clone());
+        }
+        Ok(())
+    }
+
+    fn write_snapshot_table(&self, snapshots: &[Snapshot]) -> Result<(), Error> {
+        let mut entries = [SnapshotEntry::default(); SNAPSHOT_LIMIT];
+        for snapshot in snapshots {
+            entries[snapshot.slot] = snapshot.entry;
+        }
+        let table = self.super_block.read().snapshot_table;
+        unsafe {
+            self.cache_manager
+                .get(table)
+                .write()
+                .modify_checked(0, |table: &mut SnapshotTable| table.entries = entries)
+        }
+    }
+
+    /// Free the data blocks that neither the live filesystem nor a snapshot
+    /// uses any more: frozen blocks the live files let go of, and the blocks
+    /// of deleted snapshots.
+    fn collect_garbage(&self) -> Result<(), Error> {
+        let super_block = *self.super_block.read();
+        let regions = super_block.regions;
+        let data_start = self.data_area_start_block;
+        let data_blocks = regions.data_area.blocks;
+        let mut keep = vec![0u64; div_round_up(data_blocks, 64) as usize];
+        let mut mark = |block_id: u64| {
+            let bit = block_id - data_start;
+            keep[(bit / 64) as usize] |= 1 << (bit % 64);
+        };
+        // metadata moved into the data area
+        for extent in [
+            regions.inode_bitmap,
+            regions.data_bitmap,
+            regions.checksum_table,
+        ]
+        .iter()
+        .chain(regions.inode_areas.iter())
+        .filter(|extent| extent.start >= data_start)
+        {
+            (extent.start..extent.end()).for_each(&mut mark);
+        }
+        if super_block.snapshot_table != 0 {
+            mark(super_block.snapshot_table);
+        }
+        let inodes = self.inode_bitmap.words()?;
+        for id in (0..regions.inode_count).filter(|id| test_bit(&inodes, *id)) {
+            let (block_id, offset) = self.inode_pos_of(id);
+            let (xattr, (index, data)) = unsafe {
+                self.cache_manager
+                    .get(block_id)
+                    .read()
+                    .read_checked(offset, |meta: &Meta| {
+                        Ok::<_, Error>((meta.xattr(), meta.blocks(self.cache_manager.clone())?))
+                    })??
+            };
+            index
+                .into_iter()
+                .chain(data)
+                .chain([xattr])
+                .filter(|id| *id != 0)
+                .for_each(&mut mark);
+        }
+        for snapshot in self.snapshots.read().iter() {
+            for (word, frozen) in keep.iter_mut().zip(snapshot.data_bitmap.words()?) {
+                *word |= frozen;
+            }
+        }
+
+        let used = self.data_bitmap.words()?;
+        let mut run: Option<(u64, u64)> = None;
+        for bit in 0..data_blocks {
+            if test_bit(&used, bit) && !test_bit(&keep, bit) {
+                match run {
+                    Some((start, len)) if start + len == bit => run = Some((start, len + 1)),
+                    _ => {
+                        if let Some((start, len)) = run {
+                            self.data_bitmap.dealloc_range(start, len)?;
+                        }
+                        run = Some((bit, 1));
+                    }
+                }
+            }
+        }
+        if let Some((start, len)) = run {
+            self.data_bitmap.dealloc_range(start, len)?;
+        }
+        Ok(())
+    }
+}
@@ -1,15 +1,50 @@
+use crate::Error;
 use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use spin::RwLock;
 
 pub trait FS {
-    fn create(&self, parent: u64, name: String) -> Arc<RwLock<dyn Inode>>;
-    fn write(&self, inode_number: u64, contents: &Vec<u8>);
-    fn df(&self) -> (u64, u64);
+    fn create(&self, parent: u64, name: String) -> Result<Arc<RwLock<dyn Inode>>, Error>;
+    fn write(&self, inode_number: u64, contents: &Vec<u8>) -> Result<(), Error>;
+    /// Write `buf` at byte `offset`, growing the file if needed. The gap
+    /// between the old end and `offset` is left as a hole.
+    fn write_at(&self, inode_number: u64, offset: u64, buf: &[u8]) -> Result<(), Error>;
+    /// Set the size of the file. Growing it leaves a hole at the end.
+    fn truncate(&self, inode_number: u64, size: u64) -> Result<(), Error>;
+    /// Free the blocks in `[offset, offset + len)` and zero what is left of
+    /// partially covered blocks. The size of the file does not change.
+    fn punch_hole(&self, inode_number: u64, offset: u64, len: u64) -> Result<(), Error>;
+    /// Return the first offset at or after `offset` where data or a hole
+    /// starts, like `SEEK_DATA`/`SEEK_HOLE` of lseek(2). There is always a
+    /// hole at the end of a file. `None` if `offset` is not before the end, or
+    /// there is no data after it.
+    fn seek(&self, inode_number: u64, offset: u64, whence: Seek) -> Result<Option<u64>, Error>;
+    /// Set extended attribute `name` of the inode to `value`, replacing
+    /// any old value.
+    fn setxattr(&self, inode_number: u64, name: &str, value: &[u8]) -> Result<(), Error>;
+    fn getxattr(&self, inode_number: u64, name: &str) -> Result<Vec<u8>, Error>;
+    /// Names of the extended attributes of the inode.
+    fn listxattr(&self, inode_number: u64) -> Result<Vec<String>, Error>;
+    fn removexattr(&self, inode_number: u64, name: &str) -> Result<(), Error>;
+    fn df(&self) -> Result<(u64, u64), Error>;
+    /// Grow or shrink the filesystem to `total_blocks` blocks. When growing
+    /// the device must already hold them, when shrinking it may be cut down
+    /// once this returns. Fails with `Error::NoSpace` if blocks past the new
+    /// end are in use.
+    fn resize(&self, total_blocks: u64) -> Result<(), Error>;
+    /// Write back everything cached and make it durable on the device.
+    fn sync(&self);
 
-    fn inode(&self, inode_number: u64) -> Arc<RwLock<dyn Inode>>;
-    fn sub_inodes(&self, inode_number: u64) -> Vec<u64>;
+    fn inode(&self, inode_number: u64) -> Result<Arc<RwLock<dyn Inode>>, Error>;
+    fn sub_inodes(&self, inode_number: u64) -> Result<Vec<u64>, Error>;
+}
+
+/// What `FS::seek` looks for.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Seek {
+    Data,
+    Hole,
 }
 
 #[derive(PartialEq, Copy, Clone)]
@@ -23,7 +58,14 @@ pub trait Inode {
     fn inode_number(&self) -> u64;
     fn inode_type(&self) -> InodeType;
     fn is_file(&self) -> bool;
-    fn data(&self) -> Vec<u8>;
+    fn data(&self) -> Result<Vec<u8>, Error>;
+    /// Read from byte `offset` into `buf`, return the bytes read, fewer
+    /// than `buf` holds only at the end of the file.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Error>;
     fn name(&self) -> String;
+    /// Logical size in bytes.
     fn size(&self) -> u64;
+    /// Bytes of data blocks the file takes on disk, less than `size` for
+    /// holes, inline data and compressed files.
+    fn physical_size(&self) -> u64;
 }
@@ -5,6 +5,7 @@
 extern crate alloc;
 use alloc::string::String;
 use core::any::Any;
+use spin::RwLock;
 
 pub mod cafs;
 pub mod fs;
@@ -12,20 +13,49 @@ pub mod vfs;
 
 pub const PARTITION_UUID: &str = "0c421611-8e4a-464e-b683-96265fc14532";
 
+/// Size of a `BlockDevice` block, and the smallest CAFS block size.
 pub const BLOCK_SIZE: u64 = 512;
-pub const BLOCK_BITS: u64 = BLOCK_SIZE * 8;
+/// Largest CAFS block size that can be chosen at mkfs time.
+pub const MAX_BLOCK_SIZE: u64 = 4096;
 
 // TODO coverage test
 
 pub trait BlockDevice: Send + Sync + Any {
     fn read_block(&self, block_id: u64, buf: &mut [u8]);
     fn write_block(&mut self, block_id: u64, buf: &[u8]);
+    /// Make the blocks written so far durable, past any write cache.
+    fn flush(&mut self) {}
 }
 
 #[derive(Debug)]
 pub enum Error {
     NotExist(String),
+    AlreadyExist(String),
     RunOutOfInode,
+    /// Neither the primary nor the backup superblock is valid.
+    BadSuperBlock,
+    /// The filesystem uses incompatible features this driver does not know.
+    UnsupportedFeature(u64),
+    /// The filesystem is mounted read-only.
+    ReadOnly,
+    /// The checksum of the block does not match its contents.
+    Corrupted(u64),
+    /// Not enough free blocks, or blocks that would be cut off are in use.
+    NoSpace,
+    /// An empty or over-long name, such as of an extended attribute.
+    InvalidName,
+}
+
+static CLOCK: RwLock<Option<fn() -> u64>> = RwLock::new(None);
+
+/// Set the clock used for on-disk timestamps, in seconds since the Unix epoch.
+/// Timestamps are zero until a clock is set.
+pub fn set_clock(clock: fn() -> u64) {
+    *CLOCK.write() = Some(clock);
+}
+
+pub(crate) fn now() -> u64 {
+    CLOCK.read().map_or(0, |clock| clock())
 }
 
 pub mod fake {
@@ -3,25 +3,82 @@ use cafs::fake::Disk;
 use cafs::fs::FS;
 use cafs::BLOCK_SIZE;
 use spin::RwLock;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
 use std::ptr::slice_from_raw_parts;
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{env, fs, process};
 
 fn main() -> std::io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() > 2 {
+    // usage: fs [--data-csum] [--compress] [size in MiB] [block size] [label]
+    //        fs resize <image> <size in MiB>
+    //        fs snapshot <image> create|delete|rollback <name>
+    //        fs snapshot <image> list
+    let mut args: Vec<String> = env::args().collect();
+    cafs::set_clock(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    });
+    if args.get(1).map(|s| s.as_str()) == Some("resize") {
+        if args.len() != 4 {
+            process::exit(64);
+        }
+        let size = args[3].parse().expect("Wanted a number");
+        return resize_img(&args[2], size);
+    }
+    if args.get(1).map(|s| s.as_str()) == Some("snapshot") {
+        if args.len() < 4 || args.len() > 5 {
+            process::exit(64);
+        }
+        return snapshot_img(&args[2], &args[3], args.get(4).map(|s| s.as_str()));
+    }
+    let data_csum = args.iter().any(|arg| arg == "--data-csum");
+    let compress = args.iter().any(|arg| arg == "--compress");
+    args.retain(|arg| arg != "--data-csum" && arg != "--compress");
+    if args.len() > 4 {
         process::exit(64);
-    } else if args.len() == 2 {
-        create_img(args[1].parse().expect("Wanted a number"))?;
-    } else {
-        create_img(50)?;
     }
-    Ok(())
+    let size = args
+        .get(1)
+        .map_or(50, |s| s.parse().expect("Wanted a number"));
+    let block_size = args
+        .get(2)
+        .map_or(BLOCK_SIZE, |s| s.parse().expect("Wanted a number"));
+    let label = args.get(3).map_or("rootfs", |s| s.as_str());
+    create_img(size, block_size, label, data_csum, compress)
+}
+
+/// Random version 4 UUID
+fn new_uuid() -> [u8; 16] {
+    let mut uuid = [0u8; 16];
+    for chunk in uuid.chunks_mut(8) {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos(),
+        );
+        chunk.copy_from_slice(&hasher.finish().to_le_bytes());
+    }
+    uuid[6] = (uuid[6] & 0x0f) | 0x40;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+    uuid
 }
 
-fn create_img(size: usize) -> std::io::Result<()> {
+fn create_img(
+    size: usize,
+    block_size: u64,
+    label: &str,
+    data_csum: bool,
+    compress: bool,
+) -> std::io::Result<()> {
     let total_blocks = (2 * size as u64) << 10;
-    let inode_bitmap_blocks = 10;
+    // the same number of inodes whatever the block size
+    let inode_bitmap_blocks = (10 * BLOCK_SIZE / block_size).max(1);
     let disk = Disk::new(total_blocks);
     let data = slice_from_raw_parts(
         disk.data.as_ptr() as *const u8,
@@ -29,17 +86,28 @@ fn create_img(size: usize) -> std::io::Result<()> {
     );
     let fs = CAFS::init(
         Arc::new(RwLock::new(disk)),
-        total_blocks,
+        total_blocks / (block_size / BLOCK_SIZE),
         inode_bitmap_blocks,
+        block_size,
+        new_uuid(),
+        label,
+        data_csum,
     );
-    let inode = fs.create(0, "test.txt".to_string());
+    let inode = fs.create(0, "test.txt".to_string()).unwrap();
     let inode_number = inode.read().inode_number();
-    fs.write(inode_number, &Vec::from("Test File".as_bytes()));
+    fs.write(inode_number, &Vec::from("Test File".as_bytes()))
+        .unwrap();
 
     let file = fs::read("rootfs/hello").unwrap();
-    let inode = fs.create(0, "hello".to_string());
+    let inode = fs.create(0, "hello".to_string()).unwrap();
     let inode_number = inode.read().inode_number();
-    fs.write(inode_number, &file);
+    fs.set_compressed(inode_number, compress).unwrap();
+    fs.write(inode_number, &file).unwrap();
+    println!(
+        "hello: {} bytes, {} on disk",
+        inode.read().size(),
+        inode.read().physical_size()
+    );
 
     fs.flush();
 
@@ -49,6 +117,58 @@ fn create_img(size: usize) -> std::io::Result<()> {
     Ok(())
 }
 
+/// Load the image at `path` into a disk of at least `total_blocks` blocks.
+fn load_img(path: &str, total_blocks: u64) -> std::io::Result<Arc<RwLock<Disk>>> {
+    let image = fs::read(path)?;
+    let mut disk = Disk::new(total_blocks.max(image.len() as u64 / BLOCK_SIZE));
+    for (block, chunk) in disk.data.iter_mut().zip(image.chunks(BLOCK_SIZE as usize)) {
+        block[..chunk.len()].copy_from_slice(chunk);
+    }
+    Ok(Arc::new(RwLock::new(disk)))
+}
+
+/// Grow or shrink the CAFS image at `path` to `size` MiB in place.
+fn resize_img(path: &str, size: usize) -> std::io::Result<()> {
+    let total_blocks = (2 * size as u64) << 10;
+    let disk = load_img(path, total_blocks)?;
+    let fs = CAFS::open(disk.clone()).expect("Not a CAFS image");
+    let ratio = fs.block_size() / BLOCK_SIZE;
+    if let Err(err) = fs.resize(total_blocks / ratio) {
+        eprintln!("resize failed: {:?}", err);
+        process::exit(1);
+    }
+    drop(fs);
+
+    let disk = disk.read();
+    let data = disk.data[..total_blocks as usize].concat();
+    fs::write(path, data)
+}
+
+fn snapshot_img(path: &str, command: &str, name: Option<&str>) -> std::io::Result<()> {
+    let disk = load_img(path, 0)?;
+    let fs = CAFS::open(disk.clone()).expect("Not a CAFS image");
+    let result = match (command, name) {
+        ("list", None) => {
+            for (name, time) in fs.snapshots() {
+                println!("{}\t{}", name, time);
+            }
+            return Ok(());
+        }
+        ("create", Some(name)) => fs.create_snapshot(name),
+        ("delete", Some(name)) => fs.delete_snapshot(name),
+        ("rollback", Some(name)) => fs.rollback(name),
+        _ => process::exit(64),
+    };
+    if let Err(err) = result {
+        eprintln!("snapshot {} failed: {:?}", command, err);
+        process::exit(1);
+    }
+    drop(fs);
+
+    let data = disk.read().data.concat();
+    fs::write(path, data)
+}
+
 #[cfg(test)]
 mod test {
     use cafs::fake::Disk;
@@ -94,7 +214,7 @@ mod test {
             total_blocks: vec_u8_array.len() as u64,
             data: vec_u8_array,
         };
-        let cafs = VFS::new(Arc::new(RwLock::new(disk)));
+        let cafs = VFS::new(Arc::new(RwLock::new(disk))).unwrap();
         println!("{:?}", cafs.ls_root());
         let contents = cafs.read_unstable("/test.txt").unwrap();
         let str = String::from_utf8_lossy(&contents).to_string();
@@ -20,38 +20,39 @@ impl DirEntry {
         parent: Option<Weak<RwLock<DirEntry>>>,
         inode_number: u64,
         fs: Weak<dyn FS>,
-    ) -> Self {
-        let inode = fs.upgrade().unwrap().inode(inode_number);
+    ) -> Result<Self, crate::Error> {
+        let inode = fs.upgrade().unwrap().inode(inode_number)?;
         let name = inode.read().name();
         let inode_type = inode.read().inode_type();
-        Self {
+        Ok(Self {
             parent,
             name,
             inode_number,
             inode_type,
             fs,
             subdirs: vec![],
-        }
+        })
     }
 
     pub fn inode_number(&self) -> u64 {
         self.inode_number
     }
 
-    pub fn read_sub_dentry(parent_dentry: Arc<RwLock<DirEntry>>) {
+    pub fn read_sub_dentry(parent_dentry: Arc<RwLock<DirEntry>>) -> Result<(), crate::Error> {
         let fs = parent_dentry.read().fs.upgrade().unwrap();
-        let sub_inodes = fs.sub_inodes(parent_dentry.read().inode_number);
+        let sub_inodes = fs.sub_inodes(parent_dentry.read().inode_number)?;
 
         for inode_number in sub_inodes {
             let dentry = Arc::new(RwLock::new(DirEntry::new(
                 Some(Arc::downgrade(&parent_dentry)),
                 inode_number,
                 parent_dentry.read().fs.clone(),
-            )));
-            Self::read_sub_dentry(dentry.clone());
+            )?));
+            Self::read_sub_dentry(dentry.clone())?;
 
             parent_dentry.write().subdirs.push(dentry);
         }
+        Ok(())
     }
 }
 
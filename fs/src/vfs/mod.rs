@@ -2,7 +2,7 @@ mod dir_entry;
 mod path;
 
 use crate::cafs::CAFS;
-use crate::fs::FS;
+use crate::fs::{Seek, FS};
 use crate::BlockDevice;
 use alloc::string::String;
 use alloc::sync::{Arc, Weak};
@@ -20,14 +20,25 @@ pub struct VFS {
 }
 
 impl VFS {
-    pub fn new(block_device: Arc<RwLock<dyn BlockDevice>>) -> Arc<VFS> {
-        let fs: Arc<dyn FS> = Arc::new(CAFS::open(block_device));
-        let root_dentry = Arc::new(RwLock::new(DirEntry::new(None, 0, Arc::downgrade(&fs))));
-        DirEntry::read_sub_dentry(root_dentry.clone());
-        Arc::new(Self {
+    pub fn new(block_device: Arc<RwLock<dyn BlockDevice>>) -> Result<Arc<VFS>, crate::Error> {
+        Self::mount(Arc::new(CAFS::open(block_device)?))
+    }
+
+    /// Mount snapshot `name` of the CAFS on `block_device`, read-only.
+    pub fn open_snapshot(
+        block_device: Arc<RwLock<dyn BlockDevice>>,
+        name: &str,
+    ) -> Result<Arc<VFS>, crate::Error> {
+        Self::mount(Arc::new(CAFS::open_snapshot(block_device, name)?))
+    }
+
+    fn mount(fs: Arc<dyn FS>) -> Result<Arc<VFS>, crate::Error> {
+        let root_dentry = Arc::new(RwLock::new(DirEntry::new(None, 0, Arc::downgrade(&fs))?));
+        DirEntry::read_sub_dentry(root_dentry.clone())?;
+        Ok(Arc::new(Self {
             primary_partition: fs,
             dentry_cache: root_dentry,
-        })
+        }))
     }
 
     pub fn ls_root(&self) -> Vec<String> {
@@ -51,7 +62,7 @@ impl VFS {
         // create inode
         let inode_meta = self
             .primary_partition
-            .create(dir.read().inode_number(), name);
+            .create(dir.read().inode_number(), name)?;
         {
             // add to dentry cache
             let parent = Arc::downgrade(&dir);
@@ -61,7 +72,7 @@ impl VFS {
                 Some(parent),
                 inode_meta.read().inode_number(),
                 fs,
-            ))));
+            )?)));
         }
         Ok(())
     }
@@ -79,7 +90,16 @@ impl VFS {
         };
         let number = dentry.read().inode_number();
         let fs = dentry.read().fs.upgrade().unwrap();
-        Ok(fs.inode(number).read().data())
+        fs.inode(number)?.read().data()
+    }
+
+    /// Read from byte `offset` of the file into `buf`, return the bytes read.
+    pub fn read_at(&self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, crate::Error> {
+        let number = self.inode_number_of(path)?;
+        self.primary_partition
+            .inode(number)?
+            .read()
+            .read_at(offset, buf)
     }
 
     // TODO refactor write and create
@@ -92,8 +112,66 @@ impl VFS {
             Ok(d) => d,
             Err(e) => return Err(e),
         };
-        self.primary_partition
-            .write(dentry.read().inode_number(), contents);
-        Ok(())
+        let number = dentry.read().inode_number();
+        self.primary_partition.write(number, contents)
+    }
+
+    pub fn write_at(&self, path: &str, offset: u64, buf: &[u8]) -> Result<(), crate::Error> {
+        let number = self.inode_number_of(path)?;
+        self.primary_partition.write_at(number, offset, buf)
+    }
+
+    pub fn truncate(&self, path: &str, size: u64) -> Result<(), crate::Error> {
+        let number = self.inode_number_of(path)?;
+        self.primary_partition.truncate(number, size)
+    }
+
+    pub fn punch_hole(&self, path: &str, offset: u64, len: u64) -> Result<(), crate::Error> {
+        let number = self.inode_number_of(path)?;
+        self.primary_partition.punch_hole(number, offset, len)
+    }
+
+    pub fn seek(&self, path: &str, offset: u64, whence: Seek) -> Result<Option<u64>, crate::Error> {
+        let number = self.inode_number_of(path)?;
+        self.primary_partition.seek(number, offset, whence)
+    }
+
+    pub fn setxattr(&self, path: &str, name: &str, value: &[u8]) -> Result<(), crate::Error> {
+        let number = self.inode_number_of(path)?;
+        self.primary_partition.setxattr(number, name, value)
+    }
+
+    pub fn getxattr(&self, path: &str, name: &str) -> Result<Vec<u8>, crate::Error> {
+        let number = self.inode_number_of(path)?;
+        self.primary_partition.getxattr(number, name)
+    }
+
+    pub fn listxattr(&self, path: &str) -> Result<Vec<String>, crate::Error> {
+        let number = self.inode_number_of(path)?;
+        self.primary_partition.listxattr(number)
+    }
+
+    pub fn removexattr(&self, path: &str, name: &str) -> Result<(), crate::Error> {
+        let number = self.inode_number_of(path)?;
+        self.primary_partition.removexattr(number, name)
+    }
+
+    pub fn resize(&self, total_blocks: u64) -> Result<(), crate::Error> {
+        self.primary_partition.resize(total_blocks)
+    }
+
+    /// The filesystem the paths resolve in, for callers that cache by
+    /// inode number.
+    pub fn fs(&self) -> Arc<dyn FS> {
+        self.primary_partition.clone()
+    }
+
+    pub fn inode_number_of(&self, path: &str) -> Result<u64, crate::Error> {
+        let p = Self::parse_path(path);
+        let mut path = p.parents;
+        path.push(p.name);
+        let dentry = self.find_dentry(&path)?;
+        let number = dentry.read().inode_number();
+        Ok(number)
     }
 }
@@ -0,0 +1,110 @@
+//! First-fit allocator over a list of free regions sorted by address.
+//!
+//! Every free region starts with its `Node`, freed blocks are merged with
+//! the regions next to them.
+
+use core::mem::size_of;
+use core::ptr::null_mut;
+
+/// Sizes and addresses are multiples of it, so every gap holds a node.
+pub const GRANULE: usize = 16;
+
+struct Node {
+    size: usize,
+    next: *mut Node,
+}
+
+pub struct LinkedList {
+    head: *mut Node,
+    /// Bytes in the free regions.
+    free: usize,
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+impl LinkedList {
+    pub const fn new() -> Self {
+        Self {
+            head: null_mut(),
+            free: 0,
+        }
+    }
+
+    pub fn free_bytes(&self) -> usize {
+        self.free
+    }
+
+    /// Round a request up to what a block of it takes.
+    pub fn block_size(size: usize) -> usize {
+        align_up(size.max(GRANULE), GRANULE)
+    }
+
+    /// Add `size` bytes at `start` as free memory, or give a block back.
+    ///
+    /// # Safety
+    /// The memory must be unused and stay valid, `start` and `size`
+    /// multiples of `GRANULE`.
+    pub unsafe fn add(&mut self, start: usize, size: usize) {
+        debug_assert!(start % GRANULE == 0 && size % GRANULE == 0 && size >= size_of::<Node>());
+        self.free += size;
+        let mut prev: *mut Node = null_mut();
+        let mut next = self.head;
+        while !next.is_null() && (next as usize) < start {
+            prev = next;
+            next = (*next).next;
+        }
+        let node = start as *mut Node;
+        node.write(Node { size, next });
+        if !next.is_null() && start + size == next as usize {
+            (*node).size += (*next).size;
+            (*node).next = (*next).next;
+        }
+        if prev.is_null() {
+            self.head = node;
+        } else if prev as usize + (*prev).size == start {
+            (*prev).size += (*node).size;
+            (*prev).next = (*node).next;
+        } else {
+            (*prev).next = node;
+        }
+    }
+
+    /// Take a block of `size` bytes aligned to `align`, both already
+    /// rounded by the caller, null if no region fits.
+    pub fn alloc(&mut self, size: usize, align: usize) -> *mut u8 {
+        let align = align.max(GRANULE);
+        let mut prev: *mut Node = null_mut();
+        let mut node = self.head;
+        unsafe {
+            while !node.is_null() {
+                let start = node as usize;
+                let end = start + (*node).size;
+                let block = align_up(start, align);
+                if block + size <= end {
+                    // unlink, then put the gaps on both sides back
+                    let next = (*node).next;
+                    if prev.is_null() {
+                        self.head = next;
+                    } else {
+                        (*prev).next = next;
+                    }
+                    self.free -= end - start;
+                    if block > start {
+                        self.add(start, block - start);
+                    }
+                    if block + size < end {
+                        self.add(block + size, end - block - size);
+                    }
+                    return block as *mut u8;
+                }
+                prev = node;
+                node = (*node).next;
+            }
+        }
+        null_mut()
+    }
+}
+
+unsafe impl Send for LinkedList {}
@@ -0,0 +1,160 @@
+//! Caches of small objects of one size class each.
+//!
+//! A slab is a `SLAB_SIZE` aligned block starting with its header, the
+//! objects after it. Slabs with free objects are kept in a list per class,
+//! full ones are found again from the address of an object being freed.
+
+use core::mem::size_of;
+use core::ptr::null_mut;
+
+pub const SLAB_SIZE: usize = 16 * 1024;
+const MIN_CLASS: usize = 8;
+/// Objects larger than this come from the linked list allocator.
+pub const MAX_CLASS: usize = 2048;
+/// Size classes, powers of two from `MIN_CLASS` to `MAX_CLASS`.
+pub const CLASSES: usize = 9;
+
+struct Object {
+    next: *mut Object,
+}
+
+struct Slab {
+    prev: *mut Slab,
+    next: *mut Slab,
+    free: *mut Object,
+    used: usize,
+}
+
+/// The size class index of objects with `size` and `align`, if small.
+pub fn class_of(size: usize, align: usize) -> Option<usize> {
+    let size = size.max(align).max(MIN_CLASS).next_power_of_two();
+    (size <= MAX_CLASS).then(|| (size / MIN_CLASS).trailing_zeros() as usize)
+}
+
+pub fn class_size(class: usize) -> usize {
+    MIN_CLASS << class
+}
+
+#[derive(Clone, Copy)]
+pub struct Cache {
+    class: usize,
+    /// Slabs with free objects.
+    partial: *mut Slab,
+    /// Slabs owned, for the statistics.
+    pub slabs: usize,
+    /// Objects handed out.
+    pub used: usize,
+}
+
+impl Cache {
+    pub const fn new(class: usize) -> Self {
+        Self {
+            class,
+            partial: null_mut(),
+            slabs: 0,
+            used: 0,
+        }
+    }
+
+    fn first_object() -> usize {
+        // keeps the objects aligned to their size
+        size_of::<Slab>().next_power_of_two()
+    }
+
+    /// Whether `add_slab` must be called before `alloc`.
+    pub fn is_full(&self) -> bool {
+        self.partial.is_null()
+    }
+
+    /// Take an object.
+    pub fn alloc(&mut self) -> *mut u8 {
+        assert!(!self.is_full());
+        unsafe {
+            let slab = self.partial;
+            let object = (*slab).free;
+            (*slab).free = (*object).next;
+            (*slab).used += 1;
+            if (*slab).free.is_null() {
+                self.unlink(slab);
+            }
+            self.used += 1;
+            object as *mut u8
+        }
+    }
+
+    /// Give back the object at `ptr`. Return a slab that became empty,
+    /// unless it is the only one with free objects.
+    ///
+    /// # Safety
+    /// `ptr` must come from `alloc` of this cache.
+    pub unsafe fn dealloc(&mut self, ptr: *mut u8) -> Option<*mut u8> {
+        let slab = (ptr as usize & !(SLAB_SIZE - 1)) as *mut Slab;
+        let object = ptr as *mut Object;
+        let was_full = (*slab).free.is_null();
+        (*object).next = (*slab).free;
+        (*slab).free = object;
+        (*slab).used -= 1;
+        self.used -= 1;
+        if was_full {
+            self.push(slab);
+        }
+        if (*slab).used == 0 && !((*slab).prev.is_null() && (*slab).next.is_null()) {
+            self.unlink(slab);
+            self.slabs -= 1;
+            return Some(slab as *mut u8);
+        }
+        None
+    }
+
+    /// Carve the `SLAB_SIZE` aligned block at `slab` into free objects.
+    ///
+    /// # Safety
+    /// The block must be unused and stay valid.
+    pub unsafe fn add_slab(&mut self, slab: *mut u8) {
+        let slab = slab as *mut Slab;
+        let size = class_size(self.class);
+        let start = slab as usize + Self::first_object().max(size);
+        let mut free = null_mut();
+        for object in (start..slab as usize + SLAB_SIZE - size + 1)
+            .step_by(size)
+            .rev()
+        {
+            let object = object as *mut Object;
+            (*object).next = free;
+            free = object;
+        }
+        slab.write(Slab {
+            prev: null_mut(),
+            next: null_mut(),
+            free,
+            used: 0,
+        });
+        self.slabs += 1;
+        self.push(slab);
+    }
+
+    unsafe fn push(&mut self, slab: *mut Slab) {
+        (*slab).prev = null_mut();
+        (*slab).next = self.partial;
+        if !self.partial.is_null() {
+            (*self.partial).prev = slab;
+        }
+        self.partial = slab;
+    }
+
+    unsafe fn unlink(&mut self, slab: *mut Slab) {
+        let (prev, next) = ((*slab).prev, (*slab).next);
+        if prev.is_null() {
+            self.partial = next;
+        } else {
+            (*prev).next = next;
+        }
+        if !next.is_null() {
+            (*next).prev = prev;
+        }
+        (*slab).prev = null_mut();
+        (*slab).next = null_mut();
+    }
+}
+
+unsafe impl Send for Cache {}
@@ -0,0 +1,320 @@
+//! The ACPI tables the firmware describes the machine with, parsed once at
+//! boot from the RSDP the bootloader found: the CPUs, IOAPICs and ISA
+//! interrupt overrides of the MADT, the HPET, the FADT, and the PCI
+//! configuration space areas of the MCFG.
+//!
+//! The tables are read through the physical memory mapping, and copied
+//! into `Acpi`: nothing refers to them afterwards.
+
+use crate::memory::to_virt_addr;
+use alloc::vec::Vec;
+use log::{info, warn};
+
+/// Unparsed until `init`.
+static mut ACPI: Option<Acpi> = None;
+
+/// Bytes of the header every table but the RSDP starts with.
+const HEADER_SIZE: usize = 36;
+
+#[derive(Debug, Default)]
+pub struct Acpi {
+    /// Physical address of the local APICs.
+    pub lapic_address: u64,
+    /// The CPUs, the bootstrap processor among them.
+    pub cpus: Vec<Processor>,
+    pub ioapics: Vec<IoApicInfo>,
+    /// ISA interrupts not on the IOAPIC pin of their number, or not active
+    /// high and edge triggered.
+    pub overrides: Vec<InterruptOverride>,
+    /// The machine has the legacy 8259 PICs too, to mask.
+    pub pcat_compat: bool,
+    pub hpet: Option<Hpet>,
+    pub fadt: Option<Fadt>,
+    /// Memory mapped PCI configuration space, one area per segment and bus
+    /// range.
+    pub pci_segments: Vec<PciSegment>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Processor {
+    pub uid: u32,
+    pub apic_id: u32,
+    /// Else it may only be brought online later, if `online_capable`.
+    pub enabled: bool,
+    pub online_capable: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub address: u64,
+    /// The global system interrupt of its first pin.
+    pub gsi_base: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    /// The ISA interrupt.
+    pub source: u8,
+    /// The global system interrupt it is wired to.
+    pub gsi: u32,
+    /// MPS INTI flags: polarity in bits 0-1, trigger mode in bits 2-3.
+    flags: u16,
+}
+
+impl InterruptOverride {
+    pub fn active_low(&self) -> bool {
+        self.flags & 0b11 == 0b11
+    }
+
+    pub fn level_triggered(&self) -> bool {
+        (self.flags >> 2) & 0b11 == 0b11
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    /// Physical address of its registers.
+    pub address: u64,
+    pub number: u8,
+    /// Least ticks between periodic interrupts.
+    pub min_tick: u16,
+}
+
+/// What the kernel uses of the fixed description table.
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    /// The global system interrupt of ACPI events.
+    pub sci_interrupt: u16,
+    /// I/O port of the ACPI PM timer, 0 if none.
+    pub pm_timer_port: u32,
+    /// The CMOS register of the century, 0 if none.
+    pub century: u8,
+    /// An 8042 keyboard controller, per the IA-PC boot architecture flags.
+    pub has_8042: bool,
+    pub flags: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PciSegment {
+    pub address: u64,
+    pub segment: u16,
+    pub bus_start: u8,
+    pub bus_end: u8,
+}
+
+/// Parse the tables from the RSDP at the physical address `rsdp`.
+pub fn init(rsdp: Option<u64>) {
+    let acpi = rsdp
+        .ok_or("no RSDP from the bootloader")
+        .and_then(parse)
+        .unwrap_or_else(|err| panic!("failed to parse the ACPI tables: {}", err));
+    info!(
+        "ACPI: {} CPUs, {} IOAPICs, {} interrupt overrides, HPET {}, {} PCI segments",
+        acpi.cpus.len(),
+        acpi.ioapics.len(),
+        acpi.overrides.len(),
+        acpi.hpet.is_some(),
+        acpi.pci_segments.len()
+    );
+    unsafe { ACPI = Some(acpi) };
+}
+
+pub fn get() -> &'static Acpi {
+    unsafe { ACPI.as_ref().expect("ACPI tables not parsed") }
+}
+
+fn parse(rsdp: u64) -> Result<Acpi, &'static str> {
+    let mut acpi = Acpi::default();
+    for table in root_tables(rsdp)? {
+        let Some(bytes) = table_bytes(table) else {
+            warn!("ACPI table at {:#x} with a bad checksum", table);
+            continue;
+        };
+        match &bytes[..4] {
+            b"APIC" => parse_madt(bytes, &mut acpi)?,
+            b"HPET" => acpi.hpet = parse_hpet(bytes),
+            b"FACP" => acpi.fadt = parse_fadt(bytes),
+            b"MCFG" => parse_mcfg(bytes, &mut acpi),
+            _ => {}
+        }
+    }
+    if acpi.cpus.is_empty() || acpi.ioapics.is_empty() {
+        return Err("no MADT");
+    }
+    Ok(acpi)
+}
+
+/// The physical addresses of the tables the XSDT lists, or the RSDT before
+/// ACPI 2.0: the RSDP grew from 20 to 36 bytes, checksummed apart.
+fn root_tables(rsdp: u64) -> Result<Vec<u64>, &'static str> {
+    let v1 = physical(rsdp, 20);
+    if &v1[..8] != b"RSD PTR " || !checksum(v1) {
+        return Err("bad RSDP");
+    }
+    let revision = v1[15];
+    let (root, entry_size) = if revision >= 2 {
+        let v2 = physical(rsdp, 36);
+        if !checksum(v2) {
+            return Err("bad RSDP");
+        }
+        (read_u64(v2, 24), 8)
+    } else {
+        (read_u32(v1, 16) as u64, 4)
+    };
+    let root = table_bytes(root).ok_or("bad root table")?;
+    Ok(root[HEADER_SIZE..]
+        .chunks_exact(entry_size)
+        .map(|entry| match entry_size {
+            8 => read_u64(entry, 0),
+            _ => read_u32(entry, 0) as u64,
+        })
+        .collect())
+}
+
+fn parse_madt(madt: &[u8], acpi: &mut Acpi) -> Result<(), &'static str> {
+    if madt.len() < 44 {
+        return Err("bad MADT");
+    }
+    acpi.lapic_address = read_u32(madt, 36) as u64;
+    acpi.pcat_compat = read_u32(madt, 40) & 1 != 0;
+    let mut entries = &madt[44..];
+    while entries.len() >= 2 {
+        let (kind, len) = (entries[0], entries[1] as usize);
+        if len < 2 || len > entries.len() {
+            return Err("bad MADT entry");
+        }
+        let entry = &entries[..len];
+        match kind {
+            // processor local APIC
+            0 if len >= 8 => acpi.cpus.push(processor(
+                entry[2] as u32,
+                entry[3] as u32,
+                read_u32(entry, 4),
+            )),
+            1 if len >= 12 => acpi.ioapics.push(IoApicInfo {
+                id: entry[2],
+                address: read_u32(entry, 4) as u64,
+                gsi_base: read_u32(entry, 8),
+            }),
+            // on the ISA bus, the only one
+            2 if len >= 10 && entry[2] == 0 => acpi.overrides.push(InterruptOverride {
+                source: entry[3],
+                gsi: read_u32(entry, 4),
+                flags: read_u16(entry, 8),
+            }),
+            // local APIC address override
+            5 if len >= 12 => acpi.lapic_address = read_u64(entry, 4),
+            // processor local x2APIC
+            9 if len >= 16 => acpi.cpus.push(processor(
+                read_u32(entry, 12),
+                read_u32(entry, 4),
+                read_u32(entry, 8),
+            )),
+            _ => {}
+        }
+        entries = &entries[len..];
+    }
+    Ok(())
+}
+
+fn processor(uid: u32, apic_id: u32, flags: u32) -> Processor {
+    Processor {
+        uid,
+        apic_id,
+        enabled: flags & 1 != 0,
+        online_capable: flags & 2 != 0,
+    }
+}
+
+fn parse_hpet(hpet: &[u8]) -> Option<Hpet> {
+    // the address is a generic address structure, in memory space
+    (hpet.len() >= 56 && hpet[40] == 0).then(|| Hpet {
+        address: read_u64(hpet, 44),
+        number: hpet[52],
+        min_tick: read_u16(hpet, 53),
+    })
+}
+
+fn parse_fadt(fadt: &[u8]) -> Option<Fadt> {
+    // the fields up to `flags` are there since ACPI 1.0
+    (fadt.len() >= 116).then(|| Fadt {
+        sci_interrupt: read_u16(fadt, 46),
+        pm_timer_port: read_u32(fadt, 76),
+        century: fadt[108],
+        has_8042: read_u16(fadt, 109) & 2 != 0,
+        flags: read_u32(fadt, 112),
+    })
+}
+
+fn parse_mcfg(mcfg: &[u8], acpi: &mut Acpi) {
+    // after 8 reserved bytes
+    for entry in mcfg.get(44..).unwrap_or(&[]).chunks_exact(16) {
+        acpi.pci_segments.push(PciSegment {
+            address: read_u64(entry, 0),
+            segment: read_u16(entry, 8),
+            bus_start: entry[10],
+            bus_end: entry[11],
+        });
+    }
+}
+
+/// The table at the physical address `addr`, none if its checksum is bad.
+fn table_bytes(addr: u64) -> Option<&'static [u8]> {
+    let header = physical(addr, HEADER_SIZE);
+    let len = read_u32(header, 4) as usize;
+    if len < HEADER_SIZE {
+        return None;
+    }
+    let bytes = physical(addr, len);
+    checksum(bytes).then_some(bytes)
+}
+
+fn physical(addr: u64, len: usize) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts(to_virt_addr(addr).as_ptr(), len) }
+}
+
+/// All bytes of a table sum to 0.
+fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu;
+
+    #[test_case]
+    fn test_madt() {
+        let acpi = get();
+        let bsp = cpu::get(0).apic_id;
+        assert!(acpi
+            .cpus
+            .iter()
+            .any(|cpu| cpu.apic_id == bsp && cpu.enabled));
+        // all started
+        let enabled = acpi.cpus.iter().filter(|cpu| cpu.enabled).count();
+        assert_eq!(enabled.min(cpu::MAX_CPUS), cpu::online());
+        assert!(acpi.ioapics.iter().any(|ioapic| ioapic.gsi_base == 0));
+        // QEMU wires the PIT to pin 2
+        assert!(acpi.overrides.iter().any(|o| o.source == 0 && o.gsi == 2));
+    }
+
+    #[test_case]
+    fn test_checksum() {
+        assert!(checksum(&[0x10, 0xf0, 0]));
+        assert!(!checksum(&[0x10, 0xf1]));
+    }
+}
@@ -0,0 +1,135 @@
+//! Data of each CPU, found through the GS base: the kernel stack `syscall`
+//! switches to, the GDT and TSS, the local APIC.
+//!
+//! In kernel mode the GS base is the `Cpu` of the CPU, in user mode it is
+//! kept in `KernelGsBase`. `swapgs` exchanges them on the way in and out of
+//! user mode: in `syscall` and before `sysretq`, before `iretq` to user
+//! mode, and in `interrupt` for the interrupts of user mode.
+
+use crate::gdt::Tables;
+use alloc::boxed::Box;
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+use x2apic::lapic::LocalApic;
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+/// The most CPUs the kernel runs on.
+pub const MAX_CPUS: usize = 64;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_CPU: AtomicPtr<Cpu> = AtomicPtr::new(null_mut());
+
+static CPUS: [AtomicPtr<Cpu>; MAX_CPUS] = [NO_CPU; MAX_CPUS];
+/// CPUs running their scheduler, the bootstrap processor from the start.
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+/// The offsets of the first fields are the ones in `syscall_entry`.
+#[repr(C)]
+pub struct Cpu {
+    /// Its own address, at `gs:0`.
+    #[allow(dead_code)]
+    this: *const Cpu,
+    /// Top of the kernel stack `syscall` switches to, at `gs:8`.
+    #[allow(dead_code)]
+    kernel_stack: AtomicU64,
+    /// The user stack pointer in `syscall`, until pushed on the kernel
+    /// stack, at `gs:16`.
+    #[allow(dead_code)]
+    user_rsp: AtomicU64,
+    /// Numbered in the order the CPUs started, the bootstrap processor 0.
+    pub index: usize,
+    pub apic_id: u32,
+    tables: Tables,
+    /// Locked with interrupts off, see `apic::with_lapic`.
+    pub lapic: Mutex<Option<LocalApic>>,
+    /// A TLB shootdown for this CPU to do.
+    pub flush_pending: AtomicBool,
+}
+
+// Only the CPU itself touches what is not atomic or locked.
+unsafe impl Sync for Cpu {}
+
+impl Cpu {
+    /// Set the stack system calls and interrupts in user mode run on.
+    pub fn set_kernel_stack(&self, top: VirtAddr) {
+        self.tables.set_kernel_stack(top);
+        self.kernel_stack.store(top.as_u64(), Ordering::Relaxed);
+    }
+}
+
+/// Set up the data of the running CPU, the `index`th to start, and load its
+/// GDT and TSS.
+pub fn init(index: usize) {
+    let cpu = Box::leak(Box::new(Cpu {
+        this: core::ptr::null(),
+        kernel_stack: AtomicU64::new(0),
+        user_rsp: AtomicU64::new(0),
+        index,
+        apic_id: apic_id(),
+        tables: Tables::new(),
+        lapic: Mutex::new(None),
+        flush_pending: AtomicBool::new(false),
+    }));
+    let this = &*cpu as *const Cpu;
+    cpu.this = this;
+    let cpu: &'static Cpu = cpu;
+    cpu.tables.load();
+    GsBase::write(VirtAddr::from_ptr(cpu));
+    KernelGsBase::write(VirtAddr::zero());
+    CPUS[index].store(cpu as *const Cpu as *mut Cpu, Ordering::Release);
+}
+
+/// The initial APIC ID of the running CPU.
+fn apic_id() -> u32 {
+    unsafe { __cpuid(1).ebx >> 24 }
+}
+
+/// The data of the running CPU.
+pub fn current() -> &'static Cpu {
+    let cpu: *const Cpu;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) cpu, options(nostack, readonly, preserves_flags));
+        &*cpu
+    }
+}
+
+pub fn index() -> usize {
+    current().index
+}
+
+/// The CPU `index`, set up by `init`.
+pub fn get(index: usize) -> &'static Cpu {
+    let cpu = CPUS[index].load(Ordering::Acquire);
+    assert!(!cpu.is_null(), "CPU {} not started", index);
+    unsafe { &*cpu }
+}
+
+/// CPUs running their scheduler, numbered from 0.
+pub fn online() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+/// Count the CPU started last as running its scheduler.
+pub fn set_online() {
+    ONLINE.fetch_add(1, Ordering::Release);
+}
+
+/// Run the body `f` of an interrupt handler with the GS base of the kernel,
+/// swapped in if the interrupt came from user mode. Handlers that reach
+/// the data of the CPU, even through `apic::eoi`, run under it.
+pub fn interrupt<T>(frame: &InterruptStackFrame, f: impl FnOnce() -> T) -> T {
+    let from_user = frame.code_segment & 3 == 3;
+    if from_user {
+        unsafe { asm!("swapgs", options(nostack, preserves_flags)) };
+    }
+    let result = f();
+    if from_user {
+        unsafe { asm!("swapgs", options(nostack, preserves_flags)) };
+    }
+    result
+}
@@ -1,12 +1,17 @@
-use crate::interrupt::IrqVector;
+use crate::interrupt::{apic, IrqVector};
 use x2apic::ioapic::{IoApic, IrqFlags, IrqMode};
 
+/// The ISA interrupt of the keyboard.
+const ISA_IRQ: u8 = 1;
+
 pub unsafe fn init(ioapic: &mut IoApic, apic_id: u8) {
-    let mut entry = ioapic.table_entry(IrqVector::Keyboard.as_u8());
+    let (pin, flags) = apic::isa_pin(ioapic, ISA_IRQ);
+    let mut entry = ioapic.table_entry(pin);
+    entry.set_vector(IrqVector::Keyboard.with_offset() as u8);
     entry.set_mode(IrqMode::Fixed);
-    entry.set_flags(IrqFlags::MASKED);
+    entry.set_flags(flags | IrqFlags::MASKED);
     entry.set_dest(apic_id);
-    ioapic.set_table_entry(IrqVector::Keyboard.as_u8(), entry);
+    ioapic.set_table_entry(pin, entry);
 
-    ioapic.enable_irq(IrqVector::Keyboard.as_u8());
+    ioapic.enable_irq(pin);
 }
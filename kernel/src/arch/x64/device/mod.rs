@@ -1,4 +1,5 @@
-use crate::interrupt::apic::{IOAPIC, LAPIC};
+use crate::cpu;
+use crate::interrupt::apic;
 use log::info;
 
 pub mod keyboard;
@@ -6,22 +7,17 @@ pub mod pit;
 pub mod timer;
 
 pub fn init() {
+    // the bootstrap processor takes the interrupts of the IOAPIC
+    let apic_id = cpu::current().apic_id as u8;
     // set up keyboard
-    unsafe {
-        let mut ioapic = IOAPIC.as_ref().unwrap().lock();
-        let lapic = LAPIC.as_ref().unwrap().lock();
-        keyboard::init(&mut *ioapic, lapic.id() as u8);
-    }
+    apic::with_ioapic(|ioapic| unsafe { keyboard::init(ioapic, apic_id) });
     unsafe {
         pit::init();
-        let mut ioapic = IOAPIC.as_ref().unwrap().lock();
-        let lapic = LAPIC.as_ref().unwrap().lock();
-        pit::enable(&mut *ioapic, lapic.id() as u8);
+        apic::with_ioapic(|ioapic| pit::enable(ioapic, apic_id));
         info!("pit set up");
     }
     // set up timer and **also** enable interrupt
     unsafe {
-        let mut ioapic = IOAPIC.as_ref().unwrap().lock();
-        timer::init(&mut *ioapic);
+        timer::init();
     }
 }
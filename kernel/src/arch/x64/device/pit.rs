@@ -24,6 +24,9 @@ const ACCESS_LATCH: u8 = 0b00 << 4;
 const ACCESS_LOHI: u8 = 0b11 << 4;
 const SELECT_CHAN0: u8 = 0b00 << 6;
 
+/// The ISA interrupt of channel 0.
+const ISA_IRQ: u8 = 0;
+
 static mut COUNT: usize = 0;
 
 pub unsafe fn init() {
@@ -33,16 +36,18 @@ pub unsafe fn init() {
 }
 
 pub unsafe fn enable(ioapic: &mut IoApic, lapic_id: u8) {
-    let mut entry = ioapic.table_entry(IrqVector::PIT.as_u8());
+    let (pin, flags) = apic::isa_pin(ioapic, ISA_IRQ);
+    let mut entry = ioapic.table_entry(pin);
+    entry.set_vector(IrqVector::PIT.with_offset() as u8);
     entry.set_mode(IrqMode::Fixed);
-    entry.set_flags(IrqFlags::MASKED);
+    entry.set_flags(flags | IrqFlags::MASKED);
     entry.set_dest(lapic_id);
-    ioapic.set_table_entry(IrqVector::PIT.as_u8(), entry);
-    ioapic.enable_irq(IrqVector::PIT.as_u8());
+    ioapic.set_table_entry(pin, entry);
+    ioapic.enable_irq(pin);
 }
 
 pub unsafe fn disable(ioapic: &mut IoApic) {
-    ioapic.disable_irq(IrqVector::PIT.as_u8());
+    ioapic.disable_irq(apic::isa_pin(ioapic, ISA_IRQ).0);
 }
 
 pub unsafe fn read() -> u16 {
@@ -1,54 +1,70 @@
 use crate::device::pit;
-use crate::interrupt::apic::LAPIC;
+use crate::interrupt::apic;
+use crate::process::scheduler;
+use core::ptr::addr_of;
+use core::sync::atomic::{AtomicU32, Ordering};
 use log::{info, trace};
-use x2apic::ioapic::IoApic;
 use x2apic::lapic::TimerMode;
 
-pub unsafe fn init(ioapic: &mut IoApic) {
+/// The initial count of the local APIC timer for a tick, measured on the
+/// bootstrap processor.
+static TIMER_INITIAL: AtomicU32 = AtomicU32::new(0);
+
+pub unsafe fn init() {
     let pit_start = pit::count();
     x86_64::instructions::interrupts::enable();
 
     let lapic_start = {
         while pit::count() < pit_start + 2 {}
-        let lapic = LAPIC.as_ref().unwrap().lock();
-        lapic.timer_current()
+        apic::with_lapic(|lapic| lapic.timer_current())
     };
     let lapic_end = {
         // wait for 100ms
         while pit::count() < pit_start + 102 {}
-        let lapic = LAPIC.as_ref().unwrap().lock();
-        lapic.timer_current()
+        apic::with_lapic(|lapic| lapic.timer_current())
     };
-    pit::disable(&mut *ioapic);
+    apic::with_ioapic(|ioapic| pit::disable(ioapic));
     trace!("pit disabled");
 
     let count = lapic_start - lapic_end;
     trace!("lapic count {:?} in 100ms", count);
-    set_apic_timer(count);
+    let divisor = count / (HZ / 10) as u32;
+    TIMER_INITIAL.store(divisor, Ordering::Relaxed);
+    set_apic_timer(divisor);
     info!("set apic timer");
 }
 
+/// Start the timer of another CPU, at the rate measured by `init`. The
+/// local APICs share a clock.
+pub unsafe fn init_ap() {
+    set_apic_timer(TIMER_INITIAL.load(Ordering::Relaxed));
+}
+
 unsafe fn set_apic_timer(divisor: u32) {
-    let mut lapic = LAPIC.as_ref().unwrap().lock();
-    lapic.disable_timer();
-    lapic.set_timer_mode(TimerMode::Periodic);
-    lapic.set_timer_initial(divisor);
-    lapic.enable_timer();
+    apic::with_lapic(|lapic| {
+        lapic.disable_timer();
+        lapic.set_timer_mode(TimerMode::Periodic);
+        lapic.set_timer_initial(divisor);
+        lapic.enable_timer();
+    });
 }
 
+/// Timer interrupts per second.
+pub const HZ: usize = 100;
+
 static mut COUNT: usize = 0;
 
+/// Count a tick, on the bootstrap processor only.
 pub unsafe fn increment() {
     COUNT += 1;
 }
 
+/// Ticks since the timer started.
 pub fn count() -> usize {
-    unsafe { COUNT }
+    // changed by the interrupt handler
+    unsafe { core::ptr::read_volatile(addr_of!(COUNT)) }
 }
 
 pub fn sleep(sec: i32) {
-    unsafe {
-        let end = COUNT + (10 * sec) as usize;
-        while COUNT < end {}
-    }
+    scheduler::sleep(HZ * sec as usize);
 }
@@ -1,4 +1,5 @@
-use alloc::vec::Vec;
+use crate::memory::vmalloc;
+use core::cell::UnsafeCell;
 use x86_64::instructions::segmentation::{Segment, CS, SS};
 use x86_64::instructions::tables::load_tss;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
@@ -6,34 +7,68 @@ use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+const DOUBLE_FAULT_STACK_PAGES: u64 = 5;
 
-static mut GDT: Option<GlobalDescriptorTable> = None;
-static mut TSS: Option<TaskStateSegment> = None;
-
-pub fn init() {
-    // To prevent triple faults in all cases, we also set up an Interrupt Stack Table
-    // to catch double faults on a separate kernel stack.
-    let tss = unsafe {
-        TSS = Some(TaskStateSegment::new());
-        TSS.as_mut().unwrap()
-    };
-    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-        let stack = Vec::<u8>::with_capacity(4096 * 5).leak();
-        let stack_start = VirtAddr::from_ptr(stack.as_ptr());
-        let stack_end = stack_start + stack.len();
-        stack_end
-    };
-    let gdt = unsafe {
-        GDT = Some(GlobalDescriptorTable::new());
-        GDT.as_mut().unwrap()
-    };
-    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
-    gdt.load();
-    unsafe {
-        CS::set_reg(code_selector);
-        // set ss in that there is always a double fault when handler return
-        SS::set_reg(SegmentSelector { 0: 0 });
-        load_tss(tss_selector);
+static mut SELECTORS: Option<Selectors> = None;
+
+/// The segments, in the order `syscall` and `sysret` expect them: kernel
+/// data right after kernel code, user code right after user data.
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+}
+
+/// The GDT and TSS of a CPU, in its `Cpu`. The segments are the same on
+/// every CPU, the TSS is its own.
+pub(crate) struct Tables {
+    gdt: UnsafeCell<GlobalDescriptorTable>,
+    tss: UnsafeCell<TaskStateSegment>,
+}
+
+impl Tables {
+    pub fn new() -> Self {
+        // To prevent triple faults in all cases, we also set up an Interrupt Stack Table
+        // to catch double faults on a separate kernel stack.
+        let mut tss = TaskStateSegment::new();
+        // with a guard page, an overflow faults rather than corrupting memory
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+            vmalloc::alloc_stack(DOUBLE_FAULT_STACK_PAGES).expect("no double fault stack");
+        Self {
+            gdt: UnsafeCell::new(GlobalDescriptorTable::new()),
+            tss: UnsafeCell::new(tss),
+        }
     }
+
+    /// Fill the GDT and load it with the TSS, on the CPU they belong to.
+    pub fn load(&'static self) {
+        let tss = unsafe { &*self.tss.get() };
+        let gdt = unsafe { &mut *self.gdt.get() };
+        let selectors = Selectors {
+            kernel_code: gdt.add_entry(Descriptor::kernel_code_segment()),
+            kernel_data: gdt.add_entry(Descriptor::kernel_data_segment()),
+            user_data: gdt.add_entry(Descriptor::user_data_segment()),
+            user_code: gdt.add_entry(Descriptor::user_code_segment()),
+        };
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+        gdt.load();
+        unsafe {
+            SELECTORS = Some(selectors);
+            CS::set_reg(selectors.kernel_code);
+            // set ss in that there is always a double fault when handler return
+            SS::set_reg(SegmentSelector { 0: 0 });
+            load_tss(tss_selector);
+        }
+    }
+
+    /// Set the stack the CPU switches to on an interrupt in user mode.
+    pub fn set_kernel_stack(&self, top: VirtAddr) {
+        unsafe { (*self.tss.get()).privilege_stack_table[0] = top };
+    }
+}
+
+pub fn selectors() -> Selectors {
+    unsafe { SELECTORS.expect("GDT not initialized") }
 }
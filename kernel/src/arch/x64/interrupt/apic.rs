@@ -1,47 +1,120 @@
+use crate::acpi::{self, IoApicInfo};
+use crate::cpu;
 use crate::interrupt::IrqVector;
-use crate::memory::to_virt_addr;
+use crate::memory::vmalloc;
+use core::sync::atomic::{AtomicU64, Ordering};
+use log::warn;
 // TODO RWLock
 use spin::Mutex;
-use x2apic::ioapic::IoApic;
-use x2apic::lapic::{xapic_base, LocalApic, LocalApicBuilder, TimerMode};
+use x2apic::ioapic::{IoApic, IrqFlags};
+use x2apic::lapic::{LocalApic, LocalApicBuilder, TimerMode};
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
 
-pub static mut LAPIC: Option<Mutex<LocalApic>> = None;
-pub static mut IOAPIC: Option<Mutex<IoApic>> = None;
+/// Every CPU sees its own local APIC at the same address.
+static LAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+static IOAPIC: Mutex<Option<IoApic>> = Mutex::new(None);
 
 pub const IOAPIC_OFFSET: u8 = 0x20;
 
+const LAPIC_SIZE: usize = 0x400;
+const IOAPIC_SIZE: usize = 0x20;
+/// The mask registers of the 8259 PICs.
+const PIC1_DATA: u16 = 0x21;
+const PIC2_DATA: u16 = 0xa1;
+
 pub unsafe fn eoi() {
-    let mut lapic = LAPIC.as_mut().unwrap().lock();
-    lapic.end_of_interrupt();
+    with_lapic(|lapic| lapic.end_of_interrupt());
 }
 
-pub fn init() {
-    let apic_physical_address = unsafe { xapic_base() };
-    // TODO apic_physical_address 0xFEE00000 map the page and frame
-    //      check if xapic_base is already in use in mmap
-    //      if certain mapped frame in free memory region
-    let apic_virtual_address = to_virt_addr(apic_physical_address).as_u64();
-    unsafe {
-        LAPIC = Some(Mutex::new(
-            LocalApicBuilder::new()
-                .timer_vector(IrqVector::Timer.with_offset())
-                // FIXME
-                .error_vector(IrqVector::Error.with_offset())
-                .spurious_vector(IrqVector::Spurious.with_offset())
-                .set_xapic_base(apic_virtual_address)
-                .timer_initial(u32::MAX)
-                .timer_mode(TimerMode::OneShot)
-                .build()
-                .unwrap_or_else(|err| panic!("{}", err)),
-        ));
-        let mut lapic = LAPIC.as_ref().unwrap().lock();
-        lapic.enable();
-
-        // TODO check if io apic regs addr is already in use in mmap
-        // !!! Map the IOAPIC's MMIO address `addr` here !!!
-        // let ioapic = IoApic::new(addr);
-        IOAPIC = Some(Mutex::new(IoApic::new(to_virt_addr(0xFEC00000).as_u64())));
-        let mut ioapic = IOAPIC.as_ref().unwrap().lock();
-        ioapic.init(IOAPIC_OFFSET);
+/// Run `f` with the local APIC of the running CPU. Interrupts are off, the
+/// interrupt handlers use it too.
+pub fn with_lapic<T>(f: impl FnOnce(&mut LocalApic) -> T) -> T {
+    interrupts::without_interrupts(|| {
+        let mut lapic = cpu::current().lapic.lock();
+        f(lapic.as_mut().expect("local APIC not initialized"))
+    })
+}
+
+pub fn with_ioapic<T>(f: impl FnOnce(&mut IoApic) -> T) -> T {
+    interrupts::without_interrupts(|| f(IOAPIC.lock().as_mut().expect("IOAPIC not initialized")))
+}
+
+/// Send the interrupt `vector` to the CPU with the local APIC `apic_id`.
+pub fn send_ipi(vector: IrqVector, apic_id: u32) {
+    with_lapic(|lapic| unsafe { lapic.send_ipi(vector.with_offset() as u8, apic_id) });
+}
+
+/// The IOAPIC of the ISA interrupts, the one the kernel uses: devices on
+/// PCI send MSIs.
+fn ioapic_info() -> &'static IoApicInfo {
+    let ioapics = &acpi::get().ioapics;
+    ioapics
+        .iter()
+        .find(|ioapic| ioapic.gsi_base == 0)
+        .unwrap_or(&ioapics[0])
+}
+
+/// The pin of `ioapic` and flags of the ISA interrupt `irq`: the pin of
+/// that number, active high and edge triggered, unless the MADT overrides
+/// it. Overrides to the pins of other IOAPICs are ignored.
+pub fn isa_pin(ioapic: &mut IoApic, irq: u8) -> (u8, IrqFlags) {
+    let Some(rule) = acpi::get().overrides.iter().find(|o| o.source == irq) else {
+        return (irq, IrqFlags::empty());
     };
+    let base = ioapic_info().gsi_base;
+    let pins = unsafe { ioapic.max_table_entry() } as u32 + 1;
+    if !(base..base + pins).contains(&rule.gsi) {
+        warn!(
+            "ISA interrupt {} overridden to GSI {}, not on the IOAPIC",
+            irq, rule.gsi
+        );
+        return (irq, IrqFlags::empty());
+    }
+    let mut flags = IrqFlags::empty();
+    flags.set(IrqFlags::LOW_ACTIVE, rule.active_low());
+    flags.set(IrqFlags::LEVEL_TRIGGERED, rule.level_triggered());
+    ((rule.gsi - base) as u8, flags)
+}
+
+pub fn init() {
+    if acpi::get().pcat_compat {
+        // the IOAPIC takes the interrupts of the legacy PICs
+        unsafe {
+            Port::<u8>::new(PIC1_DATA).write(0xff);
+            Port::<u8>::new(PIC2_DATA).write(0xff);
+        }
+    }
+    let apic_physical_address = acpi::get().lapic_address;
+    let apic_virtual_address = vmalloc::map_mmio(PhysAddr::new(apic_physical_address), LAPIC_SIZE)
+        .expect("failed to map the local APIC")
+        .as_u64();
+    LAPIC_BASE.store(apic_virtual_address, Ordering::Relaxed);
+    init_lapic();
+
+    let ioapic_physical_address = ioapic_info().address;
+    let ioapic_virtual_address =
+        vmalloc::map_mmio(PhysAddr::new(ioapic_physical_address), IOAPIC_SIZE)
+            .expect("failed to map the IOAPIC");
+    let mut ioapic = unsafe { IoApic::new(ioapic_virtual_address.as_u64()) };
+    unsafe { ioapic.init(IOAPIC_OFFSET) };
+    *IOAPIC.lock() = Some(ioapic);
+}
+
+/// Enable the local APIC of the running CPU, its timer stopped until
+/// `timer::init` starts it.
+pub fn init_lapic() {
+    let mut lapic = LocalApicBuilder::new()
+        .timer_vector(IrqVector::Timer.with_offset())
+        // FIXME
+        .error_vector(IrqVector::Error.with_offset())
+        .spurious_vector(IrqVector::Spurious.with_offset())
+        .set_xapic_base(LAPIC_BASE.load(Ordering::Relaxed))
+        .timer_initial(u32::MAX)
+        .timer_mode(TimerMode::OneShot)
+        .build()
+        .unwrap_or_else(|err| panic!("{}", err));
+    unsafe { lapic.enable() };
+    interrupts::without_interrupts(|| *cpu::current().lapic.lock() = Some(lapic));
 }
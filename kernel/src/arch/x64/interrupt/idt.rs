@@ -1,8 +1,12 @@
 use crate::device::pit;
 use crate::interrupt::apic;
 use crate::interrupt::IrqVector;
-use crate::{device, gdt};
+use crate::process::memory::Access;
+use crate::process::{self, scheduler};
+use crate::{cpu, device, gdt, smp};
 use log::error;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::Cr2;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
 
 static mut IDT: Option<InterruptDescriptorTable> = None;
@@ -53,6 +57,8 @@ pub fn init() {
     idt[IrqVector::Keyboard.with_offset()].set_handler_fn(keyboard_interrupt_handler);
     idt[IrqVector::Error.with_offset()].set_handler_fn(apic_error_handler);
     idt[IrqVector::Spurious.with_offset()].set_handler_fn(spurious_interrupt_handler);
+    idt[IrqVector::Reschedule.with_offset()].set_handler_fn(reschedule_handler);
+    idt[IrqVector::TlbShootdown.with_offset()].set_handler_fn(tlb_shootdown_handler);
 
     // TODO A guard page is a special memory page at the bottom of a stack that
     //      makes it possible to detect stack overflows. The page is not
@@ -62,6 +68,18 @@ pub fn init() {
     idt.load();
 }
 
+/// Load the IDT on another CPU, they all share it.
+pub fn load() {
+    unsafe { IDT.as_ref().expect("IDT not initialized").load() };
+}
+
+/// Route `vector` to `handler`, for interrupts assigned at runtime like
+/// the MSI of a PCI device.
+pub fn register(vector: usize, handler: extern "x86-interrupt" fn(InterruptStackFrame)) {
+    let idt = unsafe { IDT.as_mut().unwrap() };
+    idt[vector].set_handler_fn(handler);
+}
+
 extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
     error!("EXCEPTION: DIVIDE_ERROR\n{:#?}", stack_frame);
 }
@@ -140,8 +158,29 @@ extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
-    error!("EXCEPTION: PAGE_FAULT\n{:#?}", stack_frame);
+    let addr = Cr2::read();
+    if error_code.contains(PageFaultErrorCode::USER_MODE) {
+        let access = if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            Access::Write
+        } else if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            Access::Execute
+        } else {
+            Access::Read
+        };
+        // on the kernel stack of the process, like a system call: resolving
+        // the fault takes locks, and may block
+        cpu::interrupt(&stack_frame, || {
+            interrupts::enable();
+            if !process::page_fault(addr, access) {
+                process::segfault(addr, stack_frame.instruction_pointer);
+            }
+            interrupts::disable();
+        });
+        return;
+    }
+    error!("EXCEPTION: PAGE_FAULT at {:?}\n{:#?}", addr, stack_frame);
     error!("error_code: {:#?}", error_code);
+    panic!("page fault in the kernel");
 }
 
 extern "x86-interrupt" fn x87_floating_point_handler(stack_frame: InterruptStackFrame) {
@@ -191,29 +230,48 @@ extern "x86-interrupt" fn spurious_interrupt_handler(stack_frame: InterruptStack
     error!("EXCEPTION: SPURIOUS_INTERRUPT\n{:#?}", stack_frame);
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: InterruptStackFrame) {
     // error!("EXCEPTION: TIMER_INTERRUPT");
-    unsafe {
-        device::timer::increment();
-        apic::eoi();
-    }
+    cpu::interrupt(&stack_frame, || {
+        unsafe {
+            // every CPU has a timer, the first counts the ticks
+            if cpu::index() == 0 {
+                device::timer::increment();
+            }
+            apic::eoi();
+        }
+        scheduler::tick();
+    });
+}
+
+extern "x86-interrupt" fn reschedule_handler(stack_frame: InterruptStackFrame) {
+    cpu::interrupt(&stack_frame, || {
+        unsafe { apic::eoi() };
+        scheduler::reschedule();
+    });
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+extern "x86-interrupt" fn tlb_shootdown_handler(stack_frame: InterruptStackFrame) {
+    cpu::interrupt(&stack_frame, || {
+        smp::flush_pending();
+        unsafe { apic::eoi() };
+    });
+}
+
+extern "x86-interrupt" fn keyboard_interrupt_handler(stack_frame: InterruptStackFrame) {
     use x86_64::instructions::port::Port;
 
     let mut port = Port::new(0x60);
     let _scancode: u8 = unsafe { port.read() };
     // info!("{}", scancode);
     // TODO use spin lock in interrupt handler will probably cause deadlock
-    unsafe {
+    cpu::interrupt(&stack_frame, || unsafe {
         apic::eoi();
-    }
+    });
 }
 
 #[cfg(test)]
 mod test {
-    use crate::interrupt::apic::LAPIC;
     use crate::testing::*;
     use core::arch::x86_64::{__cpuid, _rdtsc};
     use log::{debug, info};
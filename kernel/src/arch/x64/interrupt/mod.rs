@@ -4,11 +4,17 @@ pub mod idt;
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum IrqVector {
+    /// Keyboard and PIT come from the IOAPIC pins the MADT routes ISA
+    /// interrupts 1 and 0 to.
     Keyboard = 1,
     PIT = 2,
     Timer = 16,
     Error = 28,
     Spurious = 29,
+    /// Sent to a CPU to run the threads other CPUs made ready on it.
+    Reschedule = 200,
+    /// Sent to a CPU to flush pages out of its TLB.
+    TlbShootdown = 201,
 }
 
 impl IrqVector {
@@ -1,3 +1,7 @@
+pub mod acpi;
+pub mod cpu;
 pub mod device;
 pub mod gdt;
 pub mod interrupt;
+pub mod smp;
+pub mod syscall;
@@ -0,0 +1,365 @@
+//! Starting the application processors, and what the CPUs ask of each
+//! other: to run the threads made ready for them, to flush their TLBs.
+//!
+//! An application processor starts in real mode, at the page below 1 MiB
+//! its startup IPI names. The trampoline copied there switches to protected
+//! mode, then to long mode with a page table mapping the kernel and itself,
+//! and calls `ap_main` on a stack of its own.
+//!
+//! The CPUs to start are the enabled ones of the MADT.
+
+use crate::acpi;
+use crate::cpu::{self, MAX_CPUS};
+use crate::device::timer;
+use crate::interrupt::{apic, idt, IrqVector};
+use crate::memory::{self, space, to_virt_addr, vmalloc};
+use crate::process::scheduler;
+use crate::syscall;
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use log::{info, warn};
+use spin::Mutex;
+use x86_64::instructions::{interrupts, tlb};
+use x86_64::registers::control::{Cr0, Cr4, Cr4Flags};
+use x86_64::structures::paging::{Page, PhysFrame};
+use x86_64::VirtAddr;
+
+/// Pages of the stack an application processor boots and idles on.
+const AP_STACK_PAGES: u64 = 8;
+/// Timer ticks to wait for an application processor after its startup IPIs.
+const START_TICKS: usize = 10;
+/// More pages than this to flush, and a shootdown flushes the whole TLB.
+const FLUSH_PAGES_MAX: u64 = 32;
+
+// Runs at the start of a page below 1 MiB, wherever that is: it finds its
+// address from `cs`, and patches the pointers it needs with it. 32-bit mode
+// loads the registers from the data at `ap_trampoline_data`, enabling long
+// mode and the NX bit, and the far return enters 64-bit mode.
+global_asm!(
+    r#"
+    .global ap_trampoline
+    .global ap_trampoline_data
+    .global ap_trampoline_end
+
+    .code16
+ap_trampoline:
+    cli
+    cld
+    mov %cs, %ax
+    mov %ax, %ds
+    xor %ebx, %ebx
+    mov %ax, %bx
+    shl $4, %ebx
+    leal (ap_gdt - ap_trampoline)(%ebx), %eax
+    mov %eax, (ap_gdtr - ap_trampoline + 2)
+    leal (ap_protected - ap_trampoline)(%ebx), %eax
+    mov %eax, (ap_jump - ap_trampoline)
+    lgdtl (ap_gdtr - ap_trampoline)
+    mov %cr0, %eax
+    or $1, %eax
+    mov %eax, %cr0
+    ljmpl *(ap_jump - ap_trampoline)
+
+    .code32
+ap_protected:
+    mov $0x10, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    mov (ap_trampoline_data - ap_trampoline + 16)(%ebx), %eax
+    mov %eax, %cr4
+    mov (ap_trampoline_data - ap_trampoline)(%ebx), %eax
+    mov %eax, %cr3
+    mov $0xc0000080, %ecx
+    rdmsr
+    or $0x900, %eax
+    wrmsr
+    mov (ap_trampoline_data - ap_trampoline + 8)(%ebx), %eax
+    mov %eax, %cr0
+    leal (ap_long - ap_trampoline)(%ebx), %eax
+    push $0x18
+    push %eax
+    lret
+
+    .code64
+ap_long:
+    xor %eax, %eax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    mov (ap_trampoline_data - ap_trampoline + 24)(%rbx), %rsp
+    mov (ap_trampoline_data - ap_trampoline + 32)(%rbx), %rdi
+    mov (ap_trampoline_data - ap_trampoline + 40)(%rbx), %rax
+    call *%rax
+    ud2
+
+    .balign 8
+ap_gdt:
+    .quad 0
+    .quad 0x00cf9a000000ffff
+    .quad 0x00cf92000000ffff
+    .quad 0x00af9a000000ffff
+ap_gdtr:
+    .short 4 * 8 - 1
+    .long 0
+ap_jump:
+    .long 0
+    .short 0x08
+
+    .balign 8
+ap_trampoline_data:
+    .fill 6, 8, 0
+ap_trampoline_end:
+    "#,
+    options(att_syntax)
+);
+
+extern "C" {
+    static ap_trampoline: u8;
+    static ap_trampoline_data: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// What the trampoline loads, at `ap_trampoline_data`.
+#[repr(C)]
+struct TrampolineData {
+    cr3: u64,
+    cr0: u64,
+    cr4: u64,
+    stack: u64,
+    index: u64,
+    entry: u64,
+}
+
+/// Where the application processor being started is, one of the `AP_`
+/// states. It and the bootstrap processor race to leave `AP_WAITING`: to
+/// `AP_CLAIMED` as it enters `ap_main`, to `AP_ABANDONED` once `start`
+/// gives up on it.
+static AP_STATE: AtomicU8 = AtomicU8::new(AP_WAITING);
+const AP_WAITING: u8 = 0;
+const AP_CLAIMED: u8 = 1;
+/// Running its scheduler.
+const AP_ONLINE: u8 = 2;
+const AP_ABANDONED: u8 = 3;
+
+/// One shootdown at a time, the range in `FLUSH_START` and `FLUSH_PAGES`.
+static SHOOTDOWN: Mutex<()> = Mutex::new(());
+static FLUSH_START: AtomicU64 = AtomicU64::new(0);
+static FLUSH_PAGES: AtomicU64 = AtomicU64::new(0);
+/// CPUs yet to flush.
+static FLUSH_LEFT: AtomicUsize = AtomicUsize::new(0);
+
+/// Start the other CPUs, one at a time. The scheduler of the bootstrap
+/// processor must be running: the wait for each sleeps.
+pub fn init() {
+    let Some(frame) = memory::low_frame() else {
+        warn!("no memory below 1 MiB, running on one CPU");
+        return;
+    };
+    let Some(p4) = space::boot_table(frame) else {
+        warn!("no memory for the boot page table, running on one CPU");
+        return;
+    };
+    let code = to_virt_addr(frame.start_address().as_u64()).as_mut_ptr::<u8>();
+    let data = unsafe {
+        let start = &ap_trampoline as *const u8;
+        let size = &ap_trampoline_end as *const u8 as usize - start as usize;
+        core::ptr::copy_nonoverlapping(start, code, size);
+        let offset = &ap_trampoline_data as *const u8 as usize - start as usize;
+        code.add(offset) as *mut TrampolineData
+    };
+
+    let bsp = cpu::current().apic_id;
+    let mut index = 1;
+    let cpus = acpi::get()
+        .cpus
+        .iter()
+        .filter(|cpu| cpu.enabled && cpu.apic_id != bsp);
+    for apic_id in cpus.map(|cpu| cpu.apic_id) {
+        if index == MAX_CPUS {
+            warn!("more than {} CPUs, the others left halted", MAX_CPUS);
+            break;
+        }
+        let Some(stack) = vmalloc::alloc_stack(AP_STACK_PAGES) else {
+            warn!("no memory for the stack of CPU {}", index);
+            break;
+        };
+        let entry: extern "C" fn(usize) -> ! = ap_main;
+        unsafe {
+            data.write_volatile(TrampolineData {
+                cr3: p4.start_address().as_u64(),
+                cr0: Cr0::read_raw(),
+                // the trampoline enables paging with PCID off
+                cr4: (Cr4::read() - Cr4Flags::PCID).bits(),
+                stack: stack.as_u64(),
+                index: index as u64,
+                entry: entry as usize as u64,
+            });
+        }
+        if !start(apic_id, frame) {
+            // held in INIT, it no longer runs on the stack or reads the
+            // trampoline data
+            warn!("CPU with APIC ID {} did not start", apic_id);
+            vmalloc::free_stack(stack, AP_STACK_PAGES);
+            continue;
+        }
+        index += 1;
+    }
+    space::free_boot_table(p4, frame);
+    info!("{} CPUs online", cpu::online());
+}
+
+/// Send the CPU `apic_id` an INIT and up to two startup IPIs at `frame`,
+/// true once it runs its scheduler. If it does not enter the kernel in
+/// time, it is abandoned and put back in INIT: false once it has stopped.
+fn start(apic_id: u32, frame: PhysFrame) -> bool {
+    AP_STATE.store(AP_WAITING, Ordering::Release);
+    let vector = (frame.start_address().as_u64() >> 12) as u8;
+    let waiting = || AP_STATE.load(Ordering::Acquire) == AP_WAITING;
+    apic::with_lapic(|lapic| unsafe { lapic.send_init_ipi(apic_id) });
+    scheduler::sleep(2);
+    for _ in 0..2 {
+        if !waiting() {
+            break;
+        }
+        apic::with_lapic(|lapic| unsafe { lapic.send_sipi(vector, apic_id) });
+        scheduler::sleep(1);
+    }
+    let deadline = timer::count() + START_TICKS;
+    while waiting() && timer::count() < deadline {
+        scheduler::sleep(1);
+    }
+    let abandoned = AP_STATE
+        .compare_exchange(
+            AP_WAITING,
+            AP_ABANDONED,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        )
+        .is_ok();
+    if abandoned {
+        apic::with_lapic(|lapic| unsafe { lapic.send_init_ipi(apic_id) });
+        scheduler::sleep(2);
+        return false;
+    }
+    // in the kernel, it comes online
+    while AP_STATE.load(Ordering::Acquire) != AP_ONLINE {
+        scheduler::sleep(1);
+    }
+    true
+}
+
+/// Where the application processors enter the kernel from the trampoline,
+/// with interrupts off, the `index`th to start.
+extern "C" fn ap_main(index: usize) -> ! {
+    let claimed = AP_STATE
+        .compare_exchange(AP_WAITING, AP_CLAIMED, Ordering::AcqRel, Ordering::Acquire)
+        .is_ok();
+    if !claimed {
+        // too late, `start` is about to send an INIT
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+    unsafe { space::activate_kernel() };
+    cpu::init(index);
+    syscall::init();
+    idt::load();
+    apic::init_lapic();
+    unsafe { timer::init_ap() };
+    scheduler::init_ap();
+    cpu::set_online();
+    AP_STATE.store(AP_ONLINE, Ordering::Release);
+    scheduler::idle()
+}
+
+/// Tell the CPU `index` its scheduler has threads made ready from another
+/// CPU.
+pub fn reschedule(index: usize) {
+    apic::send_ipi(IrqVector::Reschedule, cpu::get(index).apic_id);
+}
+
+/// Flush `pages` pages from `start` from the TLBs of the other CPUs, and
+/// wait for them to. For changes to the kernel half, once the page table
+/// no longer maps what is to be freed.
+pub fn shootdown(start: Page, pages: u64) {
+    let online = cpu::online();
+    if online == 1 || pages == 0 {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        // another CPU may be waiting for this one to flush
+        let _guard = loop {
+            if let Some(guard) = SHOOTDOWN.try_lock() {
+                break guard;
+            }
+            flush_pending();
+            core::hint::spin_loop();
+        };
+        FLUSH_START.store(start.start_address().as_u64(), Ordering::Relaxed);
+        FLUSH_PAGES.store(pages, Ordering::Relaxed);
+        FLUSH_LEFT.store(online - 1, Ordering::Release);
+        let current = cpu::index();
+        for index in (0..online).filter(|&index| index != current) {
+            let cpu = cpu::get(index);
+            cpu.flush_pending.store(true, Ordering::Release);
+            apic::send_ipi(IrqVector::TlbShootdown, cpu.apic_id);
+        }
+        while FLUSH_LEFT.load(Ordering::Acquire) != 0 {
+            core::hint::spin_loop();
+        }
+    });
+}
+
+/// Do the shootdown asked of the running CPU, if any. Called at the
+/// shootdown interrupt, and by CPUs waiting to start one of their own.
+pub fn flush_pending() {
+    if !cpu::current().flush_pending.swap(false, Ordering::Acquire) {
+        return;
+    }
+    let start = FLUSH_START.load(Ordering::Relaxed);
+    let pages = FLUSH_PAGES.load(Ordering::Relaxed);
+    if pages > FLUSH_PAGES_MAX {
+        tlb::flush_all();
+    } else {
+        for i in 0..pages {
+            tlb::flush(VirtAddr::new(start + i * memory::PAGE_SIZE as u64));
+        }
+    }
+    FLUSH_LEFT.fetch_sub(1, Ordering::Release);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::process::thread::spawn;
+    use alloc::vec::Vec;
+
+    #[test_case]
+    fn test_threads_on_every_cpu() {
+        let online = cpu::online();
+        let handles = (0..online)
+            .map(|_| spawn("test", cpu::index))
+            .collect::<Vec<_>>();
+        let mut cpus = handles.into_iter().map(|h| h.join()).collect::<Vec<_>>();
+        cpus.sort();
+        assert_eq!(cpus, (0..online).collect::<Vec<_>>());
+    }
+
+    #[test_case]
+    fn test_shootdown() {
+        // a page the other CPUs read, then unmapped under them
+        let addr = vmalloc::vmalloc(1).unwrap();
+        unsafe { addr.as_mut_ptr::<u64>().write(42) };
+        let readers = (0..cpu::online())
+            .map(|_| {
+                spawn("test", move || unsafe {
+                    addr.as_ptr::<u64>().read_volatile()
+                })
+            })
+            .collect::<Vec<_>>();
+        assert!(readers.into_iter().all(|h| h.join() == 42));
+        vmalloc::vfree(addr);
+        assert_eq!(FLUSH_LEFT.load(Ordering::Acquire), 0);
+    }
+}
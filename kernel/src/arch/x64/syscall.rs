@@ -0,0 +1,228 @@
+//! Entering user mode with `iretq`, and coming back to the kernel through
+//! `syscall`.
+//!
+//! System calls follow the Linux convention: the number in `rax`, the
+//! arguments in `rdi`, `rsi`, `rdx`, `r10`, `r8` and `r9`, the result in
+//! `rax`. `rcx` and `r11` hold the user `rip` and `rflags` for `sysretq`.
+
+use crate::{cpu, gdt};
+use core::arch::global_asm;
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+/// The user registers at a system call, saved on the kernel stack. The
+/// ones `syscall` clobbers aside, all of them: a forked child returns from
+/// the system call with the registers of its parent.
+///
+/// The offsets of the fields are the ones in `enter_user`.
+#[repr(C)]
+#[derive(Debug, Default, Clone)]
+pub struct SyscallFrame {
+    /// The system call number, replaced by the result.
+    pub rax: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub r10: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub rbx: u64,
+    pub rbp: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rflags: u64,
+    pub rip: u64,
+    pub rsp: u64,
+}
+
+impl SyscallFrame {
+    /// The registers to start user code at `rip` with the stack at `rsp`.
+    pub fn new(rip: VirtAddr, rsp: VirtAddr) -> Self {
+        Self {
+            rflags: RFlags::INTERRUPT_FLAG.bits(),
+            rip: rip.as_u64(),
+            rsp: rsp.as_u64(),
+            ..Self::default()
+        }
+    }
+
+    pub fn args(&self) -> [u64; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+}
+
+// Interrupts are off from `syscall` until the user registers are saved on
+// the kernel stack (see `SFMask`), and from restoring them to `sysretq`:
+// nothing runs on the user stack in kernel mode. In between the system
+// call may be preempted, or block.
+//
+// The kernel stack of the running process and the user stack pointer are
+// in the `Cpu` at `gs:8` and `gs:16`.
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "swapgs",
+    "mov gs:[16], rsp",
+    "mov rsp, gs:[8]",
+    "push qword ptr gs:[16]",
+    "push rcx",
+    "push r11",
+    "push r15",
+    "push r14",
+    "push r13",
+    "push r12",
+    "push rbp",
+    "push rbx",
+    "push r9",
+    "push r8",
+    "push r10",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rax",
+    "mov rdi, rsp",
+    "sti",
+    "call {handler}",
+    "cli",
+    "pop rax",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop r10",
+    "pop r8",
+    "pop r9",
+    "pop rbx",
+    "pop rbp",
+    "pop r12",
+    "pop r13",
+    "pop r14",
+    "pop r15",
+    "pop r11",
+    "pop rcx",
+    "pop rsp",
+    "swapgs",
+    "sysretq",
+    handler = sym syscall_handler,
+);
+
+// enter_user(frame, cs, ss, context) saves the callee-saved registers and
+// the stack pointer at `context`, for leave_user(context, code) to return
+// `code` from it, and enters user mode with the registers of `frame`.
+// Interrupts are off from there, no interrupt comes in kernel mode with the
+// GS base of user mode.
+global_asm!(
+    ".global enter_user",
+    "enter_user:",
+    "cli",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rcx], rsp",
+    "push rdx",
+    "push qword ptr [rdi + 120]",
+    "push qword ptr [rdi + 104]",
+    "push rsi",
+    "push qword ptr [rdi + 112]",
+    "mov rax, [rdi]",
+    "mov rsi, [rdi + 16]",
+    "mov rdx, [rdi + 24]",
+    "mov r10, [rdi + 32]",
+    "mov r8, [rdi + 40]",
+    "mov r9, [rdi + 48]",
+    "mov rbx, [rdi + 56]",
+    "mov rbp, [rdi + 64]",
+    "mov r12, [rdi + 72]",
+    "mov r13, [rdi + 80]",
+    "mov r14, [rdi + 88]",
+    "mov r15, [rdi + 96]",
+    "mov rdi, [rdi + 8]",
+    // leave nothing of the kernel in the registers
+    "xor ecx, ecx",
+    "xor r11d, r11d",
+    "swapgs",
+    "iretq",
+    ".global leave_user",
+    "leave_user:",
+    "mov rsp, rdi",
+    "mov rax, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+);
+
+extern "C" {
+    fn syscall_entry();
+    fn enter_user(frame: *const SyscallFrame, cs: u64, ss: u64, context: *mut u64) -> i64;
+    fn leave_user(context: u64, code: i64) -> !;
+}
+
+extern "C" fn syscall_handler(frame: &mut SyscallFrame) {
+    crate::process::syscall::dispatch(frame);
+}
+
+/// Set up `syscall` on the running CPU, every CPU has its own MSRs.
+pub fn init() {
+    let selectors = gdt::selectors();
+    Star::write(
+        selectors.user_code,
+        selectors.user_data,
+        selectors.kernel_code,
+        selectors.kernel_data,
+    )
+    .expect("segments out of the order of `sysret`");
+    LStar::write(VirtAddr::new(syscall_entry as usize as u64));
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG);
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
+
+/// Set the stack system calls and interrupts in user mode run on.
+pub fn set_kernel_stack(top: VirtAddr) {
+    cpu::current().set_kernel_stack(top);
+}
+
+/// Run user code from the registers `frame`, until it calls `exit_user`
+/// with `context`, and return the code it exits with.
+///
+/// # Safety
+/// The address space of the program and its kernel stack must be active.
+pub unsafe fn run_user(frame: &SyscallFrame, context: *mut u64) -> i64 {
+    let selectors = gdt::selectors();
+    let mut frame = frame.clone();
+    // user flags only, and interrupts on
+    let mut rflags = RFlags::from_bits_truncate(frame.rflags);
+    rflags.remove(RFlags::IOPL_LOW | RFlags::IOPL_HIGH | RFlags::NESTED_TASK);
+    frame.rflags = (rflags | RFlags::INTERRUPT_FLAG).bits();
+    let enabled = interrupts::are_enabled();
+    let code = enter_user(
+        &frame,
+        selectors.user_code.0 as u64,
+        selectors.user_data.0 as u64,
+        context,
+    );
+    // left through `exit_user`, maybe with interrupts off
+    if enabled {
+        interrupts::enable();
+    }
+    code
+}
+
+/// Return `code` from the `run_user` that saved `context`, dropping the
+/// kernel stack of the system call.
+///
+/// # Safety
+/// Must be called from a system call of that `run_user`, with nothing on
+/// its kernel stack left to drop.
+pub unsafe fn exit_user(context: u64, code: i64) -> ! {
+    leave_user(context, code)
+}
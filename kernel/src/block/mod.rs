@@ -0,0 +1,151 @@
+//! Disks registered by their drivers and the partitions found on them.
+//!
+//! A disk is scanned for a GPT when it is registered, an MBR partition
+//! table if there is none. Partitions are looked up by name, GUID or type
+//! and opened as a `BlockDevice` confined to their range of sectors.
+
+mod partition;
+
+use crate::drivers::{BlockDriver, SECTOR_SIZE};
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use cafs::{BlockDevice, BLOCK_SIZE};
+use log::info;
+use spin::RwLock;
+use uguid::Guid;
+
+static DISKS: RwLock<Vec<Arc<Disk>>> = RwLock::new(Vec::new());
+static PARTITIONS: RwLock<Vec<Arc<Partition>>> = RwLock::new(Vec::new());
+
+pub struct Disk {
+    /// `disk0`, `disk1`, ... in the order of registration.
+    pub name: String,
+    pub driver: Arc<dyn BlockDriver>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionType {
+    Gpt(Guid),
+    /// The system id of an MBR entry.
+    Mbr(u8),
+}
+
+pub struct Partition {
+    /// The disk name and the partition number, e.g. `disk0p1`.
+    pub name: String,
+    /// The GPT partition name, empty on MBR disks.
+    pub label: String,
+    /// The unique partition GUID, GPT only.
+    pub guid: Option<Guid>,
+    pub type_: PartitionType,
+    pub first_lba: u64,
+    /// Inclusive.
+    pub last_lba: u64,
+    pub disk: Arc<Disk>,
+}
+
+impl Partition {
+    pub fn sectors(&self) -> u64 {
+        self.last_lba + 1 - self.first_lba
+    }
+
+    /// Open the partition as a block device.
+    pub fn device(self: &Arc<Self>) -> PartitionDevice {
+        PartitionDevice {
+            partition: self.clone(),
+        }
+    }
+}
+
+/// A partition as a block device, the blocks outside it cannot be reached.
+pub struct PartitionDevice {
+    partition: Arc<Partition>,
+}
+
+impl PartitionDevice {
+    fn lba(&self, block_id: u64) -> u64 {
+        assert!(
+            block_id < self.partition.sectors(),
+            "block {} beyond the end of {}",
+            block_id,
+            self.partition.name
+        );
+        self.partition.first_lba + block_id
+    }
+}
+
+impl BlockDevice for PartitionDevice {
+    fn read_block(&self, block_id: u64, buf: &mut [u8]) {
+        assert_eq!(buf.len(), BLOCK_SIZE as usize);
+        let lba = self.lba(block_id);
+        self.partition.disk.driver.read_block(lba, buf);
+    }
+
+    fn write_block(&mut self, block_id: u64, buf: &[u8]) {
+        assert_eq!(buf.len(), BLOCK_SIZE as usize);
+        let lba = self.lba(block_id);
+        self.partition.disk.driver.write_block(lba, buf);
+    }
+
+    fn flush(&mut self) {
+        self.partition.disk.driver.flush();
+    }
+}
+
+/// Register a disk and the partitions on it.
+pub fn register_disk(driver: Arc<dyn BlockDriver>) -> Arc<Disk> {
+    let mut disks = DISKS.write();
+    let disk = Arc::new(Disk {
+        name: format!("disk{}", disks.len()),
+        driver,
+    });
+    disks.push(disk.clone());
+    drop(disks);
+
+    let partitions = partition::scan(&disk);
+    info!(
+        "{}: {} MiB, {} partitions",
+        disk.name,
+        disk.driver.sectors() * SECTOR_SIZE as u64 >> 20,
+        partitions.len()
+    );
+    for partition in partitions.iter() {
+        info!(
+            "{}: {:?} {:?} sectors {}..={}",
+            partition.name,
+            partition.label,
+            partition.type_,
+            partition.first_lba,
+            partition.last_lba
+        );
+    }
+    PARTITIONS.write().extend(partitions);
+    disk
+}
+
+pub fn disks() -> Vec<Arc<Disk>> {
+    DISKS.read().clone()
+}
+
+pub fn partitions() -> Vec<Arc<Partition>> {
+    PARTITIONS.read().clone()
+}
+
+fn find(predicate: impl Fn(&Partition) -> bool) -> Option<Arc<Partition>> {
+    PARTITIONS.read().iter().find(|p| predicate(p)).cloned()
+}
+
+pub fn by_name(name: &str) -> Option<Arc<Partition>> {
+    find(|p| p.name == name)
+}
+
+pub fn by_partuuid(guid: Guid) -> Option<Arc<Partition>> {
+    find(|p| p.guid == Some(guid))
+}
+
+/// The first partition of type `type_`.
+pub fn by_type(type_: PartitionType) -> Option<Arc<Partition>> {
+    find(|p| p.type_ == type_)
+}
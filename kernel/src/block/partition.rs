@@ -0,0 +1,122 @@
+//! GPT and MBR partition tables.
+
+use super::{Disk, Partition, PartitionType};
+use crate::drivers::SECTOR_SIZE;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use gpt_disk_io::gpt_disk_types::BlockSize;
+use gpt_disk_io::{Disk as GptDisk, SliceBlockIo};
+use log::warn;
+use uguid::Guid;
+
+/// Protective MBR, GPT header and the usual 128 partition entries.
+const GPT_SECTORS: u64 = 34;
+const MBR_ENTRIES: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_TYPE_PROTECTIVE: u8 = 0xee;
+
+fn read(disk: &Disk, sectors: u64) -> Vec<u8> {
+    let sectors = sectors.min(disk.driver.sectors());
+    let mut data = vec![0u8; sectors as usize * SECTOR_SIZE];
+    disk.driver.read_blocks(0, &mut data);
+    data
+}
+
+/// The partitions of `disk`, from its GPT or else its MBR.
+pub fn scan(disk: &Arc<Disk>) -> Vec<Arc<Partition>> {
+    let partitions = match scan_gpt(disk) {
+        Some(partitions) => partitions,
+        None => scan_mbr(disk),
+    };
+    partitions
+        .into_iter()
+        .filter(|p| {
+            let valid = p.first_lba <= p.last_lba && p.last_lba < disk.driver.sectors();
+            if !valid {
+                warn!(
+                    "{}: sectors {}..={} outside the disk",
+                    p.name, p.first_lba, p.last_lba
+                );
+            }
+            valid
+        })
+        .map(Arc::new)
+        .collect()
+}
+
+fn scan_gpt(disk: &Arc<Disk>) -> Option<Vec<Partition>> {
+    let bs = BlockSize::BS_512;
+    let mut data = read(disk, GPT_SECTORS);
+    let mut header_data = vec![0u8; SECTOR_SIZE];
+    let header = GptDisk::new(SliceBlockIo::new(&data[..], bs))
+        .ok()?
+        .read_primary_gpt_header(&mut header_data[..])
+        .ok()?;
+    if !header.is_signature_valid() {
+        return None;
+    }
+    let layout = header.get_partition_entry_array_layout().ok()?;
+    let entries_bytes = layout.num_bytes_rounded_to_block(bs)?;
+    let end = layout.start_lba.0 + entries_bytes / SECTOR_SIZE as u64;
+    if end > GPT_SECTORS {
+        // the entries are not where they usually are
+        data = read(disk, end);
+    }
+
+    let mut gpt = GptDisk::new(SliceBlockIo::new(&data[..], bs)).ok()?;
+    let mut layout_data = vec![0u8; entries_bytes as usize];
+    let entries = gpt
+        .read_gpt_partition_entry_array(layout, &mut layout_data[..])
+        .ok()?;
+    let mut partitions = vec![];
+    for i in 0..layout.num_entries {
+        let Some(p) = entries.get_partition_entry(i) else {
+            continue;
+        };
+        let type_guid = p.partition_type_guid.0;
+        if type_guid == Guid::ZERO {
+            continue;
+        }
+        partitions.push(Partition {
+            name: format!("{}p{}", disk.name, i + 1),
+            label: p.name.to_string(),
+            guid: Some(p.unique_partition_guid),
+            type_: PartitionType::Gpt(type_guid),
+            first_lba: p.starting_lba.to_u64(),
+            last_lba: p.ending_lba.to_u64(),
+            disk: disk.clone(),
+        });
+    }
+    Some(partitions)
+}
+
+/// The primary partitions of an MBR, extended ones are not followed.
+fn scan_mbr(disk: &Arc<Disk>) -> Vec<Partition> {
+    let data = read(disk, 1);
+    if data.len() < SECTOR_SIZE || data[510..512] != MBR_SIGNATURE {
+        return vec![];
+    }
+    let mut partitions = vec![];
+    for (i, entry) in data[MBR_ENTRIES..510].chunks(MBR_ENTRY_SIZE).enumerate() {
+        let system_id = entry[4];
+        let first_lba = u32::from_le_bytes(entry[8..12].try_into().unwrap()) as u64;
+        let sectors = u32::from_le_bytes(entry[12..16].try_into().unwrap()) as u64;
+        if system_id == 0 || system_id == MBR_TYPE_PROTECTIVE || sectors == 0 {
+            continue;
+        }
+        partitions.push(Partition {
+            name: format!("{}p{}", disk.name, i + 1),
+            label: String::new(),
+            guid: None,
+            type_: PartitionType::Mbr(system_id),
+            first_lba,
+            last_lba: first_lba + sectors - 1,
+            disk: disk.clone(),
+        });
+    }
+    partitions
+}
@@ -1,27 +1,562 @@
+//! AHCI SATA driver with a request queue.
+//!
+//! Requests are queued, merged with the ones next to them on disk and
+//! issued one per command slot, as native command queuing (NCQ) commands if
+//! the disk supports them. The MSI interrupt of the controller completes
+//! them and wakes their waiters, or they are polled for without one.
+
+use crate::cpu;
 use crate::drivers::provider::Provider;
-use alloc::sync::Arc;
-use isomorphic_drivers::block::ahci::AHCI;
+use crate::drivers::{BlockDriver, SECTOR_SIZE};
+use crate::interrupt::{apic, idt};
+use alloc::collections::VecDeque;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::ptr::{read_volatile, write_volatile};
+use core::task::{Context, Poll, Waker};
+use isomorphic_drivers::provider::Provider as _;
+use log::{info, warn};
 use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::InterruptStackFrame;
+
+/// Sectors one command moves at most, merged requests included.
+pub const MAX_SECTORS: usize = 32;
+const SLOT_LIMIT: usize = 32;
+
+// generic host control, relative to the HBA
+const HBA_CAP: usize = 0x00;
+const HBA_GHC: usize = 0x04;
+const HBA_IS: usize = 0x08;
+const HBA_PI: usize = 0x0c;
+const CAP_SNCQ: u32 = 1 << 30;
+const GHC_IE: u32 = 1 << 1;
+const GHC_AE: u32 = 1 << 31;
+
+// port registers, relative to the port
+const PORT_BASE: usize = 0x100;
+const PORT_SIZE: usize = 0x80;
+const PX_CLB: usize = 0x00;
+const PX_CLBU: usize = 0x04;
+const PX_FB: usize = 0x08;
+const PX_FBU: usize = 0x0c;
+const PX_IS: usize = 0x10;
+const PX_IE: usize = 0x14;
+const PX_CMD: usize = 0x18;
+const PX_TFD: usize = 0x20;
+const PX_SSTS: usize = 0x28;
+const PX_SERR: usize = 0x30;
+const PX_SACT: usize = 0x34;
+const PX_CI: usize = 0x38;
+const CMD_ST: u32 = 1 << 0;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+/// D2H register FIS, set device bits FIS (NCQ done), task file error.
+const IS_DHRS: u32 = 1 << 0;
+const IS_SDBS: u32 = 1 << 3;
+const IS_TFES: u32 = 1 << 30;
+const TFD_ERR: u32 = 1 << 0;
+const TFD_DRQ: u32 = 1 << 3;
+const TFD_BSY: u32 = 1 << 7;
+const SSTS_DET_PRESENT: u32 = 3;
+
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_READ_FPDMA_QUEUED: u8 = 0x60;
+const ATA_WRITE_FPDMA_QUEUED: u8 = 0x61;
+const ATA_IDENTIFY: u8 = 0xec;
+
+// DMA memory of a port: command list, received FIS, command tables (FIS
+// area and one PRD entry each) and a bounce buffer per slot
+const FIS_OFFSET: usize = 0x400;
+const TABLES_OFFSET: usize = 0x1000;
+const TABLE_SIZE: usize = 0x100;
+const BUFFERS_OFFSET: usize = TABLES_OFFSET + SLOT_LIMIT * TABLE_SIZE;
+const BUFFER_SIZE: usize = MAX_SECTORS * SECTOR_SIZE;
+const DMA_SIZE: usize = BUFFERS_OFFSET + SLOT_LIMIT * BUFFER_SIZE;
+
+/// Drivers whose interrupts `interrupt_handler` serves.
+static DRIVERS: Mutex<Vec<Arc<AHCIDriver>>> = Mutex::new(Vec::new());
+
+#[derive(Debug)]
+pub struct IoError {
+    pub lba: u64,
+    /// The task file data of the port, status and error register.
+    pub task_file: u32,
+}
+
+/// A read or write of whole sectors.
+pub struct Request {
+    lba: u64,
+    sectors: usize,
+    write: bool,
+    state: Mutex<RequestState>,
+}
 
-pub struct AHCIDriver(Mutex<AHCI<Provider>>);
+struct RequestState {
+    /// What to write, or what was read.
+    data: Vec<u8>,
+    result: Option<Result<(), IoError>>,
+    waker: Option<Waker>,
+}
+
+impl Request {
+    fn new(lba: u64, sectors: usize, write: bool, data: Vec<u8>) -> Arc<Self> {
+        assert!(sectors > 0 && sectors <= MAX_SECTORS);
+        Arc::new(Self {
+            lba,
+            sectors,
+            write,
+            state: Mutex::new(RequestState {
+                data,
+                result: None,
+                waker: None,
+            }),
+        })
+    }
+
+    fn end(&self) -> u64 {
+        self.lba + self.sectors as u64
+    }
+
+    /// Called from the interrupt handler, or with interrupts off.
+    fn finish(&self, result: Result<(), IoError>, read: Option<&[u8]>) {
+        let mut state = self.state.lock();
+        if let Some(read) = read {
+            state.data.copy_from_slice(read);
+        }
+        state.result = Some(result);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn take_result(&self) -> Option<Result<Vec<u8>, IoError>> {
+        let mut state = self.state.lock();
+        let result = state.result.take()?;
+        Some(result.map(|()| core::mem::take(&mut state.data)))
+    }
+}
+
+/// A submitted request. Resolves to the sectors read, or the data written.
+pub struct Completion {
+    request: Arc<Request>,
+    driver: Arc<AHCIDriver>,
+}
+
+impl Completion {
+    /// Block until the request completes. Must not be called with
+    /// interrupts off if the driver uses them.
+    pub fn wait(self) -> Result<Vec<u8>, IoError> {
+        loop {
+            interrupts::disable();
+            if let Some(result) = self.request.take_result() {
+                interrupts::enable();
+                return result;
+            }
+            if self.driver.irq.is_some() {
+                // the completion interrupt cannot slip in before the hlt
+                interrupts::enable_and_hlt();
+            } else {
+                interrupts::enable();
+                self.driver.poll();
+            }
+        }
+    }
+}
+
+impl Future for Completion {
+    type Output = Result<Vec<u8>, IoError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.driver.irq.is_none() {
+            self.driver.poll();
+        }
+        interrupts::without_interrupts(|| match self.request.take_result() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                self.request.state.lock().waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+    }
+}
+
+/// Requests merged into one command.
+struct Command {
+    requests: Vec<Arc<Request>>,
+    lba: u64,
+    sectors: usize,
+    write: bool,
+}
+
+struct Port {
+    /// Virtual address of the port registers.
+    base: usize,
+    dma_vaddr: usize,
+    dma_paddr: usize,
+    ncq: bool,
+    slots: usize,
+    sectors: u64,
+    issued: Vec<Option<Command>>,
+    queue: VecDeque<Arc<Request>>,
+}
+
+pub struct AHCIDriver {
+    /// Handed to every `Completion`, for polling.
+    this: Weak<AHCIDriver>,
+    /// Virtual address of the HBA registers.
+    hba: usize,
+    port_index: usize,
+    irq: Option<usize>,
+    port: Mutex<Port>,
+}
+
+fn read_reg(addr: usize) -> u32 {
+    unsafe { read_volatile(addr as *const u32) }
+}
+
+fn write_reg(addr: usize, value: u32) {
+    unsafe { write_volatile(addr as *mut u32, value) }
+}
+
+impl Port {
+    fn reg(&self, offset: usize) -> u32 {
+        read_reg(self.base + offset)
+    }
+
+    fn set_reg(&self, offset: usize, value: u32) {
+        write_reg(self.base + offset, value)
+    }
+
+    fn dma<T>(&self, offset: usize) -> *mut T {
+        (self.dma_vaddr + offset) as *mut T
+    }
+
+    fn buffer(&self, slot: usize) -> &'static mut [u8] {
+        unsafe {
+            core::slice::from_raw_parts_mut(
+                self.dma(BUFFERS_OFFSET + slot * BUFFER_SIZE),
+                BUFFER_SIZE,
+            )
+        }
+    }
+
+    fn stop(&self) {
+        self.set_reg(PX_CMD, self.reg(PX_CMD) & !CMD_ST);
+        while self.reg(PX_CMD) & CMD_CR != 0 {}
+        self.set_reg(PX_CMD, self.reg(PX_CMD) & !CMD_FRE);
+        while self.reg(PX_CMD) & CMD_FR != 0 {}
+    }
+
+    fn start(&self) {
+        while self.reg(PX_TFD) & (TFD_BSY | TFD_DRQ) != 0 {}
+        self.set_reg(PX_CMD, self.reg(PX_CMD) | CMD_FRE);
+        self.set_reg(PX_CMD, self.reg(PX_CMD) | CMD_ST);
+    }
+
+    /// Point the port at its DMA memory and start it.
+    fn init(&self) {
+        self.stop();
+        unsafe {
+            core::ptr::write_bytes(self.dma::<u8>(0), 0, BUFFERS_OFFSET);
+        }
+        let paddr = self.dma_paddr as u64;
+        self.set_reg(PX_CLB, paddr as u32);
+        self.set_reg(PX_CLBU, (paddr >> 32) as u32);
+        let fis = paddr + FIS_OFFSET as u64;
+        self.set_reg(PX_FB, fis as u32);
+        self.set_reg(PX_FBU, (fis >> 32) as u32);
+        for slot in 0..SLOT_LIMIT {
+            let table = paddr + (TABLES_OFFSET + slot * TABLE_SIZE) as u64;
+            let header = self.dma::<u32>(slot * 32);
+            unsafe {
+                write_volatile(header.add(2), table as u32);
+                write_volatile(header.add(3), (table >> 32) as u32);
+            }
+        }
+        self.set_reg(PX_SERR, u32::MAX);
+        self.set_reg(PX_IS, u32::MAX);
+        self.start();
+    }
+
+    /// Fill in slot `slot` for ATA command `command` on `sectors` sectors
+    /// at `lba` and issue it.
+    fn issue_ata(&self, slot: usize, command: u8, lba: u64, sectors: usize, write: bool) {
+        let queued = command == ATA_READ_FPDMA_QUEUED || command == ATA_WRITE_FPDMA_QUEUED;
+        let bytes = sectors.max(1) * SECTOR_SIZE;
+        let header = self.dma::<u32>(slot * 32);
+        let table = self.dma::<u8>(TABLES_OFFSET + slot * TABLE_SIZE);
+        let buffer = (self.dma_paddr + BUFFERS_OFFSET + slot * BUFFER_SIZE) as u64;
+        let mut fis = [0u8; 20];
+        fis[0] = FIS_TYPE_REG_H2D;
+        // a command, not a control update
+        fis[1] = 1 << 7;
+        fis[2] = command;
+        fis[4..7].copy_from_slice(&lba.to_le_bytes()[..3]);
+        // LBA addressing
+        fis[7] = if command == ATA_IDENTIFY { 0 } else { 1 << 6 };
+        fis[8..11].copy_from_slice(&lba.to_le_bytes()[3..6]);
+        let count = (sectors as u16).to_le_bytes();
+        if queued {
+            // the count moves to the features, the tag takes its place
+            fis[3] = count[0];
+            fis[11] = count[1];
+            fis[12] = (slot as u8) << 3;
+        } else {
+            fis[12] = count[0];
+            fis[13] = count[1];
+        }
+        unsafe {
+            core::ptr::write_bytes(table, 0, TABLE_SIZE);
+            core::ptr::copy_nonoverlapping(fis.as_ptr(), table, fis.len());
+            let prd = table.add(0x80) as *mut u32;
+            write_volatile(prd, buffer as u32);
+            write_volatile(prd.add(1), (buffer >> 32) as u32);
+            write_volatile(prd.add(3), (bytes - 1) as u32 | 1 << 31);
+            // FIS length in dwords, the write bit and one PRD entry
+            write_volatile(header, 5 | (write as u32) << 6 | 1 << 16);
+            write_volatile(header.add(1), 0);
+        }
+        if queued {
+            self.set_reg(PX_SACT, 1 << slot);
+        }
+        self.set_reg(PX_CI, 1 << slot);
+    }
+
+    /// Run IDENTIFY DEVICE in slot 0 by polling, before interrupts are on.
+    fn identify(&mut self, hba_ncq: bool) {
+        self.issue_ata(0, ATA_IDENTIFY, 0, 1, false);
+        while self.reg(PX_CI) & 1 != 0 {
+            if self.reg(PX_TFD) & TFD_ERR != 0 {
+                warn!("AHCI IDENTIFY failed: {:#x}", self.reg(PX_TFD));
+                return;
+            }
+        }
+        self.set_reg(PX_IS, u32::MAX);
+        let data = self.buffer(0);
+        let word = |i: usize| u16::from_le_bytes([data[2 * i], data[2 * i + 1]]) as u64;
+        self.sectors = word(100) | word(101) << 16 | word(102) << 32 | word(103) << 48;
+        if hba_ncq && word(76) & (1 << 8) != 0 {
+            self.ncq = true;
+            self.slots = self.slots.min((word(75) & 0x1f) as usize + 1);
+        }
+    }
+
+    /// Issue queued requests while there are free slots, merging the ones
+    /// that continue a command on disk.
+    fn dispatch(&mut self) {
+        while let Some(slot) = (0..self.slots).find(|slot| self.issued[*slot].is_none()) {
+            let Some(first) = self.queue.pop_front() else {
+                return;
+            };
+            let mut command = Command {
+                lba: first.lba,
+                sectors: first.sectors,
+                write: first.write,
+                requests: vec![first],
+            };
+            while let Some(i) = self.queue.iter().position(|request| {
+                request.write == command.write
+                    && request.lba == command.lba + command.sectors as u64
+                    && command.sectors + request.sectors <= MAX_SECTORS
+            }) {
+                let request = self.queue.remove(i).unwrap();
+                command.sectors += request.sectors;
+                command.requests.push(request);
+            }
+            if command.write {
+                let buffer = self.buffer(slot);
+                let mut pos = 0;
+                for request in &command.requests {
+                    let state = request.state.lock();
+                    buffer[pos..pos + state.data.len()].copy_from_slice(&state.data);
+                    pos += state.data.len();
+                }
+            }
+            let ata = match (self.ncq, command.write) {
+                (true, false) => ATA_READ_FPDMA_QUEUED,
+                (true, true) => ATA_WRITE_FPDMA_QUEUED,
+                (false, false) => ATA_READ_DMA_EXT,
+                (false, true) => ATA_WRITE_DMA_EXT,
+            };
+            self.issue_ata(slot, ata, command.lba, command.sectors, command.write);
+            self.issued[slot] = Some(command);
+        }
+    }
+
+    /// Finish the commands the disk is done with and issue more.
+    fn complete(&mut self) {
+        let status = self.reg(PX_IS);
+        self.set_reg(PX_IS, status);
+        if status & IS_TFES != 0 {
+            // fail everything in flight and restart the port
+            let task_file = self.reg(PX_TFD);
+            warn!("AHCI task file error {:#x}", task_file);
+            for command in self.issued.iter_mut().filter_map(|slot| slot.take()) {
+                for request in command.requests {
+                    let lba = request.lba;
+                    request.finish(Err(IoError { lba, task_file }), None);
+                }
+            }
+            self.stop();
+            self.set_reg(PX_SERR, u32::MAX);
+            self.set_reg(PX_IS, u32::MAX);
+            self.start();
+        } else {
+            let busy = self.reg(PX_SACT) | self.reg(PX_CI);
+            for slot in 0..self.slots {
+                if busy & (1 << slot) != 0 || self.issued[slot].is_none() {
+                    continue;
+                }
+                let command = self.issued[slot].take().unwrap();
+                let buffer = self.buffer(slot);
+                let mut pos = 0;
+                for request in command.requests {
+                    let len = request.sectors * SECTOR_SIZE;
+                    let read = (!command.write).then(|| &buffer[pos..pos + len]);
+                    request.finish(Ok(()), read);
+                    pos += len;
+                }
+            }
+        }
+        self.dispatch();
+    }
+}
 
 impl AHCIDriver {
-    pub fn read_block(&self, block_id: u64, buf: &mut [u8]) {
-        let mut driver = self.0.lock();
-        driver.read_block(block_id as usize, buf);
+    /// Queue a read of `sectors` sectors at `lba`.
+    pub fn read(&self, lba: u64, sectors: usize) -> Completion {
+        self.submit(Request::new(
+            lba,
+            sectors,
+            false,
+            vec![0; sectors * SECTOR_SIZE],
+        ))
+    }
+
+    /// Queue a write of `data`, whole sectors, at `lba`.
+    pub fn write(&self, lba: u64, data: Vec<u8>) -> Completion {
+        assert_eq!(data.len() % SECTOR_SIZE, 0);
+        self.submit(Request::new(lba, data.len() / SECTOR_SIZE, true, data))
+    }
+
+    fn submit(&self, request: Arc<Request>) -> Completion {
+        // the interrupt handler takes the port lock too
+        interrupts::without_interrupts(|| {
+            let mut port = self.port.lock();
+            assert!(request.end() <= port.sectors || port.sectors == 0);
+            port.queue.push_back(request.clone());
+            port.dispatch();
+        });
+        Completion {
+            request,
+            driver: self.this.upgrade().unwrap(),
+        }
+    }
+
+    fn poll(&self) {
+        interrupts::without_interrupts(|| self.port.lock().complete());
+    }
+
+    fn handle_interrupt(&self) {
+        if read_reg(self.hba + HBA_IS) & (1 << self.port_index) == 0 {
+            return;
+        }
+        self.port.lock().complete();
+        write_reg(self.hba + HBA_IS, 1 << self.port_index);
+    }
+}
+
+impl BlockDriver for AHCIDriver {
+    fn read_block(&self, block_id: u64, buf: &mut [u8]) {
+        let data = self.read(block_id, 1).wait().expect("AHCI read failed");
+        buf.copy_from_slice(&data);
+    }
+
+    fn write_block(&self, block_id: u64, buf: &[u8]) {
+        self.write(block_id, buf.to_vec())
+            .wait()
+            .expect("AHCI write failed");
     }
 
-    pub fn write_block(&self, block_id: u64, buf: &[u8]) {
-        let mut driver = self.0.lock();
-        driver.write_block(block_id as usize, buf);
+    fn read_blocks(&self, block_id: u64, buf: &mut [u8]) {
+        // all in flight at once
+        let reads = buf
+            .chunks(BUFFER_SIZE)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let lba = block_id + (i * MAX_SECTORS) as u64;
+                self.read(lba, chunk.len() / SECTOR_SIZE)
+            })
+            .collect::<Vec<_>>();
+        for (read, chunk) in reads.into_iter().zip(buf.chunks_mut(BUFFER_SIZE)) {
+            chunk.copy_from_slice(&read.wait().expect("AHCI read failed"));
+        }
+    }
+
+    fn sectors(&self) -> u64 {
+        self.port.lock().sectors
     }
 }
 
-pub fn init(_irq: Option<usize>, header: usize, size: usize) -> Option<Arc<AHCIDriver>> {
-    if let Some(ahci) = AHCI::new(header, size) {
-        let driver = Arc::new(AHCIDriver(Mutex::new(ahci)));
-        Some(driver)
-    } else {
-        None
+extern "x86-interrupt" fn interrupt_handler(stack_frame: InterruptStackFrame) {
+    cpu::interrupt(&stack_frame, || {
+        for driver in DRIVERS.lock().iter() {
+            driver.handle_interrupt();
+        }
+        unsafe {
+            apic::eoi();
+        }
+    });
+}
+
+/// Take over the HBA at `header`, driving the first port with a disk.
+/// `irq` is its MSI interrupt, without one requests are polled.
+pub fn init(irq: Option<usize>, header: usize, _size: usize) -> Option<Arc<AHCIDriver>> {
+    write_reg(header + HBA_GHC, read_reg(header + HBA_GHC) | GHC_AE);
+    let cap = read_reg(header + HBA_CAP);
+    let implemented = read_reg(header + HBA_PI);
+    let port_index = (0..32).find(|i| {
+        implemented & (1 << i) != 0
+            && read_reg(header + PORT_BASE + i * PORT_SIZE + PX_SSTS) & 0xf == SSTS_DET_PRESENT
+    })?;
+    let (dma_vaddr, dma_paddr) = Provider::alloc_dma(DMA_SIZE);
+    let mut port = Port {
+        base: header + PORT_BASE + port_index * PORT_SIZE,
+        dma_vaddr,
+        dma_paddr,
+        ncq: false,
+        slots: ((cap >> 8) & 0x1f) as usize + 1,
+        sectors: 0,
+        issued: (0..SLOT_LIMIT).map(|_| None).collect(),
+        queue: VecDeque::new(),
+    };
+    port.init();
+    port.identify(cap & CAP_SNCQ != 0);
+    info!(
+        "AHCI port {}: {} sectors, {} slots, NCQ {}",
+        port_index, port.sectors, port.slots, port.ncq
+    );
+
+    let driver = Arc::new_cyclic(|this| AHCIDriver {
+        this: this.clone(),
+        hba: header,
+        port_index,
+        irq,
+        port: Mutex::new(port),
+    });
+    if let Some(irq) = irq {
+        interrupts::without_interrupts(|| DRIVERS.lock().push(driver.clone()));
+        idt::register(irq + apic::IOAPIC_OFFSET as usize, interrupt_handler);
+        let port = driver.port.lock();
+        port.set_reg(PX_IE, IS_DHRS | IS_SDBS | IS_TFES);
+        write_reg(header + HBA_GHC, read_reg(header + HBA_GHC) | GHC_IE);
     }
+    Some(driver)
 }
@@ -1,4 +1,28 @@
 #[allow(dead_code)]
 pub mod pci;
 pub mod provider;
-pub mod ahci;
\ No newline at end of file
+pub mod ahci;
+pub mod nvme;
+pub mod virtio_blk;
+
+pub const SECTOR_SIZE: usize = 512;
+
+/// A disk of 512-byte sectors, what a filesystem is mounted on.
+pub trait BlockDriver: Send + Sync {
+    fn read_block(&self, block_id: u64, buf: &mut [u8]);
+
+    fn write_block(&self, block_id: u64, buf: &[u8]);
+
+    /// Read consecutive sectors from `block_id`, as many as fill `buf`.
+    fn read_blocks(&self, block_id: u64, buf: &mut [u8]) {
+        for (i, chunk) in buf.chunks_mut(SECTOR_SIZE).enumerate() {
+            self.read_block(block_id + i as u64, chunk);
+        }
+    }
+
+    /// Make the writes completed so far durable.
+    fn flush(&self) {}
+
+    /// Sectors of the disk.
+    fn sectors(&self) -> u64;
+}
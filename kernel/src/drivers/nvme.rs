@@ -0,0 +1,471 @@
+//! NVMe driver for the first namespace of a controller.
+//!
+//! An admin queue pair sets the controller up and creates one I/O queue
+//! pair, whose commands each get a command id and a bounce buffer. The MSI
+//! interrupt of the controller completes them, or they are polled for
+//! without one.
+
+use crate::cpu;
+use crate::drivers::provider::Provider;
+use crate::drivers::{BlockDriver, SECTOR_SIZE};
+use crate::interrupt::{apic, idt};
+use crate::memory::PAGE_SIZE;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+use isomorphic_drivers::provider::Provider as _;
+use log::{info, warn};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::InterruptStackFrame;
+
+/// Sectors one command moves at most, two pages addressed by PRP1 and PRP2.
+pub const MAX_SECTORS: usize = 2 * PAGE_SIZE / SECTOR_SIZE;
+const BUFFER_SIZE: usize = MAX_SECTORS * SECTOR_SIZE;
+/// Commands in flight at once on the I/O queue.
+const SLOT_LIMIT: usize = 32;
+const ADMIN_DEPTH: u16 = 16;
+const IO_DEPTH: u16 = 64;
+const IO_QUEUE_ID: u16 = 1;
+
+// controller registers
+const REG_CAP: usize = 0x00;
+const REG_VS: usize = 0x08;
+const REG_INTMS: usize = 0x0c;
+const REG_INTMC: usize = 0x10;
+const REG_CC: usize = 0x14;
+const REG_CSTS: usize = 0x1c;
+const REG_AQA: usize = 0x24;
+const REG_ASQ: usize = 0x28;
+const REG_ACQ: usize = 0x30;
+const DOORBELLS: usize = 0x1000;
+const CC_EN: u32 = 1 << 0;
+/// 64-byte submission and 16-byte completion queue entries.
+const CC_IOSQES: u32 = 6 << 16;
+const CC_IOCQES: u32 = 4 << 20;
+const CSTS_RDY: u32 = 1 << 0;
+const CSTS_CFS: u32 = 1 << 1;
+
+const SQ_ENTRY_SIZE: usize = 64;
+const CQ_ENTRY_SIZE: usize = 16;
+
+const ADMIN_CREATE_IO_SQ: u8 = 0x01;
+const ADMIN_CREATE_IO_CQ: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+const IDENTIFY_NAMESPACE: u32 = 0x00;
+const IDENTIFY_CONTROLLER: u32 = 0x01;
+const IDENTIFY_ACTIVE_NAMESPACES: u32 = 0x02;
+/// Physically contiguous, with interrupts enabled for completion queues.
+const QUEUE_PC: u32 = 1 << 0;
+const QUEUE_IEN: u32 = 1 << 1;
+
+const IO_FLUSH: u8 = 0x00;
+const IO_WRITE: u8 = 0x01;
+const IO_READ: u8 = 0x02;
+
+/// Drivers whose interrupts `interrupt_handler` serves.
+static DRIVERS: Mutex<Vec<Arc<NvmeDriver>>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoError {
+    pub lba: u64,
+    /// Status field of the completion, without the phase bit.
+    pub status: u16,
+}
+
+fn read_reg(addr: usize) -> u32 {
+    unsafe { read_volatile(addr as *const u32) }
+}
+
+fn write_reg(addr: usize, value: u32) {
+    unsafe { write_volatile(addr as *mut u32, value) }
+}
+
+/// A submission queue and the completion queue it posts to, in one DMA
+/// allocation.
+struct QueuePair {
+    depth: u16,
+    vaddr: usize,
+    paddr: usize,
+    /// Offset of the completion queue.
+    cq: usize,
+    sq_tail: u16,
+    cq_head: u16,
+    /// Phase tag of the entries not consumed yet.
+    phase: bool,
+    /// Doorbell register of the submission queue, the completion queue one
+    /// follows after the stride.
+    doorbell: usize,
+    stride: usize,
+}
+
+impl QueuePair {
+    fn new(id: u16, depth: u16, bar: usize, stride: usize) -> Self {
+        let cq = (depth as usize * SQ_ENTRY_SIZE + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+        let size = cq + depth as usize * CQ_ENTRY_SIZE;
+        let (vaddr, paddr) = Provider::alloc_dma(size);
+        unsafe { core::ptr::write_bytes(vaddr as *mut u8, 0, size) };
+        Self {
+            depth,
+            vaddr,
+            paddr,
+            cq,
+            sq_tail: 0,
+            cq_head: 0,
+            phase: true,
+            doorbell: bar + DOORBELLS + 2 * id as usize * stride,
+            stride,
+        }
+    }
+
+    /// Post `command` and ring the doorbell.
+    fn submit(&mut self, command: [u32; 16]) {
+        let entry = self.vaddr + self.sq_tail as usize * SQ_ENTRY_SIZE;
+        unsafe { write_volatile(entry as *mut [u32; 16], command) };
+        self.sq_tail = (self.sq_tail + 1) % self.depth;
+        write_reg(self.doorbell, self.sq_tail as u32);
+    }
+
+    /// Take the next completion, its command id, status field and
+    /// command specific result.
+    fn pop(&mut self) -> Option<(u16, u16, u32)> {
+        let entry = self.vaddr + self.cq + self.cq_head as usize * CQ_ENTRY_SIZE;
+        let dw3 = read_reg(entry + 12);
+        if (dw3 & (1 << 16) != 0) != self.phase {
+            return None;
+        }
+        let result = read_reg(entry);
+        self.cq_head += 1;
+        if self.cq_head == self.depth {
+            self.cq_head = 0;
+            self.phase = !self.phase;
+        }
+        write_reg(self.doorbell + self.stride, self.cq_head as u32);
+        Some((dw3 as u16, (dw3 >> 17) as u16, result))
+    }
+
+    fn cq_paddr(&self) -> usize {
+        self.paddr + self.cq
+    }
+}
+
+fn command(opcode: u8, cid: u16, nsid: u32, prp1: usize, prp2: usize) -> [u32; 16] {
+    let mut command = [0u32; 16];
+    command[0] = opcode as u32 | (cid as u32) << 16;
+    command[1] = nsid;
+    command[6] = prp1 as u32;
+    command[7] = (prp1 as u64 >> 32) as u32;
+    command[8] = prp2 as u32;
+    command[9] = (prp2 as u64 >> 32) as u32;
+    command
+}
+
+/// The admin queue pair, used while the controller is set up.
+struct Admin {
+    queue: QueuePair,
+    /// A page for identify data.
+    page_vaddr: usize,
+    page_paddr: usize,
+}
+
+impl Admin {
+    /// Run `command` to completion, return its command specific result.
+    fn run(&mut self, command: [u32; 16]) -> Result<u32, u16> {
+        self.queue.submit(command);
+        loop {
+            if let Some((_, status, result)) = self.queue.pop() {
+                return if status == 0 { Ok(result) } else { Err(status) };
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    fn identify(&mut self, cns: u32, nsid: u32) -> Result<&[u8], u16> {
+        let mut identify = command(ADMIN_IDENTIFY, 0, nsid, self.page_paddr, 0);
+        identify[10] = cns;
+        self.run(identify)?;
+        Ok(unsafe { core::slice::from_raw_parts(self.page_vaddr as *const u8, PAGE_SIZE) })
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Slot {
+    Free,
+    Issued { lba: u64 },
+    Done(Result<(), IoError>),
+}
+
+struct Io {
+    queue: QueuePair,
+    /// A bounce buffer per command id.
+    buffers_vaddr: usize,
+    buffers_paddr: usize,
+    slots: Vec<Slot>,
+}
+
+impl Io {
+    fn buffer(&self, cid: usize) -> &'static mut [u8] {
+        let addr = self.buffers_vaddr + cid * BUFFER_SIZE;
+        unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, BUFFER_SIZE) }
+    }
+
+    /// Called from the interrupt handler, or with interrupts off.
+    fn complete(&mut self) {
+        while let Some((cid, status, _)) = self.queue.pop() {
+            let Some(Slot::Issued { lba }) = self.slots.get(cid as usize).copied() else {
+                warn!("NVMe: completion of unknown command {}", cid);
+                continue;
+            };
+            self.slots[cid as usize] = Slot::Done(match status {
+                0 => Ok(()),
+                status => Err(IoError { lba, status }),
+            });
+        }
+    }
+}
+
+pub struct NvmeDriver {
+    nsid: u32,
+    sectors: u64,
+    /// Sectors one command moves at most, bounded by the controller.
+    max_sectors: usize,
+    /// Command ids of the I/O queue.
+    slots: usize,
+    irq: Option<usize>,
+    io: Mutex<Io>,
+}
+
+impl NvmeDriver {
+    /// Sleep until the next interrupt, or poll without one. Called with
+    /// interrupts off, returns with them on.
+    fn idle(&self) {
+        if self.irq.is_some() {
+            // the completion interrupt cannot slip in before the hlt
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+            interrupts::without_interrupts(|| self.io.lock().complete());
+        }
+    }
+
+    /// Issue a command on a free command id, waiting for one if needed.
+    fn submit(&self, opcode: u8, lba: u64, data: &[u8]) -> usize {
+        assert!(data.len() <= self.max_sectors * SECTOR_SIZE);
+        loop {
+            interrupts::disable();
+            let mut io = self.io.lock();
+            if let Some(cid) = io.slots.iter().position(|slot| matches!(slot, Slot::Free)) {
+                let buffer = io.buffer(cid);
+                if opcode == IO_WRITE {
+                    buffer[..data.len()].copy_from_slice(data);
+                }
+                let paddr = io.buffers_paddr + cid * BUFFER_SIZE;
+                let prp2 = if data.len() > PAGE_SIZE {
+                    paddr + PAGE_SIZE
+                } else {
+                    0
+                };
+                let mut command = command(opcode, cid as u16, self.nsid, paddr, prp2);
+                if opcode != IO_FLUSH {
+                    command[10] = lba as u32;
+                    command[11] = (lba >> 32) as u32;
+                    command[12] = (data.len() / SECTOR_SIZE - 1) as u32;
+                }
+                io.slots[cid] = Slot::Issued { lba };
+                io.queue.submit(command);
+                drop(io);
+                interrupts::enable();
+                return cid;
+            }
+            drop(io);
+            self.idle();
+        }
+    }
+
+    /// Wait for command `cid`, copying what it read into `buf`.
+    fn wait(&self, cid: usize, buf: &mut [u8]) -> Result<(), IoError> {
+        loop {
+            interrupts::disable();
+            let mut io = self.io.lock();
+            if let Slot::Done(result) = io.slots[cid] {
+                buf.copy_from_slice(&io.buffer(cid)[..buf.len()]);
+                io.slots[cid] = Slot::Free;
+                drop(io);
+                interrupts::enable();
+                return result;
+            }
+            drop(io);
+            self.idle();
+        }
+    }
+
+    fn handle_interrupt(&self) {
+        self.io.lock().complete();
+    }
+}
+
+impl BlockDriver for NvmeDriver {
+    fn read_block(&self, block_id: u64, buf: &mut [u8]) {
+        let cid = self.submit(IO_READ, block_id, buf);
+        self.wait(cid, buf).expect("NVMe read failed");
+    }
+
+    fn write_block(&self, block_id: u64, buf: &[u8]) {
+        let cid = self.submit(IO_WRITE, block_id, buf);
+        self.wait(cid, &mut []).expect("NVMe write failed");
+    }
+
+    fn read_blocks(&self, block_id: u64, buf: &mut [u8]) {
+        let chunk_size = self.max_sectors * SECTOR_SIZE;
+        // in flight at once as far as command ids go, submitting more
+        // before waiting would wait for a command id forever
+        for (i, batch) in buf.chunks_mut(chunk_size * self.slots).enumerate() {
+            let lba = block_id + (i * self.slots * self.max_sectors) as u64;
+            let cids = batch
+                .chunks(chunk_size)
+                .enumerate()
+                .map(|(j, chunk)| self.submit(IO_READ, lba + (j * self.max_sectors) as u64, chunk))
+                .collect::<Vec<_>>();
+            for (cid, chunk) in cids.into_iter().zip(batch.chunks_mut(chunk_size)) {
+                self.wait(cid, chunk).expect("NVMe read failed");
+            }
+        }
+    }
+
+    fn flush(&self) {
+        let cid = self.submit(IO_FLUSH, 0, &[]);
+        self.wait(cid, &mut []).expect("NVMe flush failed");
+    }
+
+    fn sectors(&self) -> u64 {
+        self.sectors
+    }
+}
+
+extern "x86-interrupt" fn interrupt_handler(stack_frame: InterruptStackFrame) {
+    cpu::interrupt(&stack_frame, || {
+        for driver in DRIVERS.lock().iter() {
+            driver.handle_interrupt();
+        }
+        unsafe {
+            apic::eoi();
+        }
+    });
+}
+
+/// Reset the controller and bring it up with the admin queue pair.
+fn enable_controller(bar: usize, admin: &QueuePair) -> bool {
+    write_reg(bar + REG_CC, 0);
+    while read_reg(bar + REG_CSTS) & CSTS_RDY != 0 {
+        core::hint::spin_loop();
+    }
+    let depth = admin.depth as u32 - 1;
+    write_reg(bar + REG_AQA, depth << 16 | depth);
+    unsafe {
+        write_volatile((bar + REG_ASQ) as *mut u64, admin.paddr as u64);
+        write_volatile((bar + REG_ACQ) as *mut u64, admin.cq_paddr() as u64);
+    }
+    write_reg(bar + REG_CC, CC_EN | CC_IOSQES | CC_IOCQES);
+    loop {
+        let status = read_reg(bar + REG_CSTS);
+        if status & CSTS_CFS != 0 {
+            return false;
+        }
+        if status & CSTS_RDY != 0 {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Take over the controller at `bar`, driving its first namespace.
+/// `irq` is its MSI interrupt, without one commands are polled.
+pub fn init(irq: Option<usize>, bar: usize, _size: usize) -> Option<Arc<NvmeDriver>> {
+    let cap = unsafe { read_volatile((bar + REG_CAP) as *const u64) };
+    let max_depth = (cap & 0xffff) as u16 + 1;
+    let stride = 4 << ((cap >> 32) & 0xf);
+    let min_page_size = PAGE_SIZE << ((cap >> 48) & 0xf);
+
+    // no interrupts until the handler is in place
+    write_reg(bar + REG_INTMS, 1);
+    let (page_vaddr, page_paddr) = Provider::alloc_dma(PAGE_SIZE);
+    let mut admin = Admin {
+        queue: QueuePair::new(0, ADMIN_DEPTH.min(max_depth), bar, stride),
+        page_vaddr,
+        page_paddr,
+    };
+    if !enable_controller(bar, &admin.queue) {
+        warn!("NVMe: controller fatal status");
+        return None;
+    }
+    // the reset unmasked it again
+    write_reg(bar + REG_INTMS, 1);
+    let version = read_reg(bar + REG_VS);
+
+    // maximum data transfer size, in units of the minimum page size
+    let mdts = admin.identify(IDENTIFY_CONTROLLER, 0).ok()?[77];
+    let mut max_sectors = MAX_SECTORS;
+    if mdts != 0 {
+        max_sectors = max_sectors.min((min_page_size << mdts) / SECTOR_SIZE);
+    }
+    let namespaces = admin.identify(IDENTIFY_ACTIVE_NAMESPACES, 0).ok()?;
+    let nsid = u32::from_le_bytes(namespaces[..4].try_into().unwrap());
+    if nsid == 0 {
+        warn!("NVMe: no active namespace");
+        return None;
+    }
+    let namespace = admin.identify(IDENTIFY_NAMESPACE, nsid).ok()?;
+    let sectors = u64::from_le_bytes(namespace[..8].try_into().unwrap());
+    let format = 128 + (namespace[26] & 0xf) as usize * 4;
+    let lba_size = 1usize << namespace[format + 2];
+    if lba_size != SECTOR_SIZE {
+        warn!("NVMe: namespace {} has {}-byte blocks", nsid, lba_size);
+        return None;
+    }
+
+    let depth = IO_DEPTH.min(max_depth);
+    let queue = QueuePair::new(IO_QUEUE_ID, depth, bar, stride);
+    let size_and_id = (depth as u32 - 1) << 16 | IO_QUEUE_ID as u32;
+    let mut create_cq = command(ADMIN_CREATE_IO_CQ, 0, 0, queue.cq_paddr(), 0);
+    create_cq[10] = size_and_id;
+    // interrupt vector 0, the only one with MSI
+    create_cq[11] = QUEUE_IEN | QUEUE_PC;
+    let mut create_sq = command(ADMIN_CREATE_IO_SQ, 0, 0, queue.paddr, 0);
+    create_sq[10] = size_and_id;
+    create_sq[11] = (IO_QUEUE_ID as u32) << 16 | QUEUE_PC;
+    if let Err(status) = admin.run(create_cq).and_then(|_| admin.run(create_sq)) {
+        warn!("NVMe: failed to create the I/O queues: {:#x}", status);
+        return None;
+    }
+
+    let slots = SLOT_LIMIT.min(depth as usize - 1);
+    let (buffers_vaddr, buffers_paddr) = Provider::alloc_dma(slots * BUFFER_SIZE);
+    info!(
+        "NVMe {}.{}: namespace {}, {} sectors, queue depth {}",
+        version >> 16,
+        (version >> 8) & 0xff,
+        nsid,
+        sectors,
+        depth
+    );
+    let driver = Arc::new(NvmeDriver {
+        nsid,
+        sectors,
+        max_sectors,
+        slots,
+        irq,
+        io: Mutex::new(Io {
+            queue,
+            buffers_vaddr,
+            buffers_paddr,
+            slots: vec![Slot::Free; slots],
+        }),
+    });
+    if let Some(irq) = irq {
+        interrupts::without_interrupts(|| DRIVERS.lock().push(driver.clone()));
+        idt::register(irq + apic::IOAPIC_OFFSET as usize, interrupt_handler);
+        write_reg(bar + REG_INTMC, 1);
+    }
+    Some(driver)
+}
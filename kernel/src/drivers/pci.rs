@@ -1,21 +1,13 @@
-use crate::drivers::ahci;
-use crate::drivers::ahci::AHCIDriver;
-use crate::fs;
-use crate::memory::{to_virt_addr, PAGE_SIZE};
-use alloc::string::{String, ToString};
-use alloc::sync::Arc;
+use crate::block;
+use crate::cpu;
+use crate::drivers::{ahci, nvme, virtio_blk};
+use crate::memory::vmalloc;
 use alloc::vec;
 use alloc::vec::Vec;
-use cafs::vfs::VFS;
-use cafs::{BlockDevice, BLOCK_SIZE};
-use core::str::FromStr;
-use gpt_disk_io::gpt_disk_types::BlockSize;
-use gpt_disk_io::{Disk, SliceBlockIo};
 use log::{debug, info};
 use pci::*;
-use spin::RwLock;
-use uguid::Guid;
 use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
 
 const PCI_COMMAND: u16 = 0x04;
 const PCI_CAP_PTR: u16 = 0x34;
@@ -30,6 +22,10 @@ const PCI_MSI_DATA_64: u16 = 0x0C;
 
 const PCI_CAP_ID_MSI: u8 = 0x05;
 
+const VIRTIO_VENDOR: u16 = 0x1af4;
+const VIRTIO_BLK_LEGACY: u16 = 0x1001;
+const VIRTIO_BLK_MODERN: u16 = 0x1042;
+
 struct PortOpsImpl;
 
 impl PortOps for PortOpsImpl {
@@ -67,10 +63,55 @@ pub fn init_driver(dev: &PCIDevice) {
         if let Some(BAR::Memory(addr, len, _, _)) = dev.bars[5] {
             debug!("Found AHCI dev {:?} BAR5 {:x?}", dev, addr);
             let irq = unsafe { enable(dev.loc) };
-            assert!(len as usize <= PAGE_SIZE);
             init_sata(irq, addr, len);
         }
+    } else if dev.id.class == 0x01 && dev.id.subclass == 0x08 {
+        // NVM subclass
+        if let Some(BAR::Memory(addr, len, _, _)) = dev.bars[0] {
+            debug!("Found NVMe dev {:?} BAR0 {:x?}", dev, addr);
+            let irq = unsafe { enable(dev.loc) };
+            let vaddr = map_bar(addr, len);
+            if let Some(driver) = nvme::init(irq, vaddr, len as usize) {
+                block::register_disk(driver);
+            }
+        }
+    } else if dev.id.vendor_id == VIRTIO_VENDOR
+        && (dev.id.device_id == VIRTIO_BLK_LEGACY || dev.id.device_id == VIRTIO_BLK_MODERN)
+    {
+        debug!("Found virtio-blk dev {:?}", dev);
+        unsafe { enable(dev.loc) };
+        if let Some(driver) = virtio_blk::init(dev) {
+            block::register_disk(driver);
+        }
+    }
+}
+
+/// Map `len` bytes of registers at `addr` uncached, return where.
+pub fn map_bar(addr: u64, len: u32) -> usize {
+    vmalloc::map_mmio(PhysAddr::new(addr), len as usize)
+        .expect("failed to map BAR")
+        .as_u64() as usize
+}
+
+pub fn read_config8(loc: Location, offset: u16) -> u8 {
+    unsafe { CSpaceAccessMethod::IO.read8(&PortOpsImpl, loc, offset) }
+}
+
+pub fn read_config32(loc: Location, offset: u16) -> u32 {
+    unsafe { CSpaceAccessMethod::IO.read32(&PortOpsImpl, loc, offset) }
+}
+
+/// Offsets of the capabilities with id `cap_id` in the configuration space.
+pub fn capabilities(loc: Location, cap_id: u8) -> Vec<u16> {
+    let mut caps = vec![];
+    let mut cap_ptr = read_config8(loc, PCI_CAP_PTR) as u16;
+    while cap_ptr > 0 {
+        if read_config8(loc, cap_ptr) == cap_id {
+            caps.push(cap_ptr);
+        }
+        cap_ptr = read_config8(loc, cap_ptr + 1) as u16;
     }
+    caps
 }
 
 /// Enable the pci device and its interrupt
@@ -95,10 +136,16 @@ unsafe fn enable(loc: Location) -> Option<usize> {
         if cap_id == PCI_CAP_ID_MSI {
             let orig_ctrl = am.read32(ops, loc, cap_ptr + PCI_MSI_CTRL_CAP);
             // The manual Volume 3 Chapter 10.11 Message Signalled Interrupts
-            // 0 is (usually) the apic id of the bsp.
-            am.write32(ops, loc, cap_ptr + PCI_MSI_ADDR, 0xfee00000 | (0 << 12));
             MSI_IRQ += 1;
             let irq = MSI_IRQ;
+            // the devices take turns at the CPUs
+            let cpu = cpu::get(irq as usize % cpu::online());
+            am.write32(
+                ops,
+                loc,
+                cap_ptr + PCI_MSI_ADDR,
+                0xfee00000 | (cpu.apic_id << 12),
+            );
             assigned_irq = Some(irq as usize);
             // we offset all our irq numbers by 32
             if (orig_ctrl >> 16) & (1 << 7) != 0 {
@@ -134,76 +181,9 @@ unsafe fn enable(loc: Location) -> Option<usize> {
     assigned_irq
 }
 
-#[derive(Debug)]
-struct Partition {
-    pub starting_lba: u64,
-    pub ending_lba: u64,
-    pub name: String,
-    pub guid: Guid,
-}
-
-struct BLK {
-    offset: u64,
-    driver: Arc<AHCIDriver>,
-}
-
-impl BlockDevice for BLK {
-    fn read_block(&self, block_id: u64, buf: &mut [u8]) {
-        assert_eq!(buf.len(), BLOCK_SIZE as usize);
-        self.driver.read_block(block_id + self.offset, buf);
-    }
-
-    fn write_block(&mut self, block_id: u64, buf: &[u8]) {
-        assert_eq!(buf.len(), BLOCK_SIZE as usize);
-        self.driver.write_block(block_id + self.offset, buf);
-    }
-}
-
 fn init_sata(irq: Option<usize>, bar_addr: u64, bar_len: u32) {
-    let vaddr = to_virt_addr(bar_addr);
-    if let Some(driver) = ahci::init(irq, vaddr.as_u64() as usize, bar_len as usize) {
-        let mut gpt_data = Vec::with_capacity(2048 * 512);
-        for i in 0..2048 {
-            let mut buf = [0; 512];
-            driver.read_block(i, &mut buf[..]);
-            gpt_data.extend_from_slice(&buf);
-        }
-        let bs = BlockSize::BS_512;
-        let block_io = SliceBlockIo::new(&gpt_data[..], bs);
-        let mut disk = Disk::new(block_io).unwrap();
-
-        let mut header_data = vec![0u8; 512];
-        let header = disk.read_primary_gpt_header(&mut header_data[..]).unwrap();
-        let layout = header.get_partition_entry_array_layout().unwrap();
-        let mut layout_data = vec![0u8; layout.num_bytes_rounded_to_block(bs).unwrap() as usize];
-        let partitions_array = disk
-            .read_gpt_partition_entry_array(layout, &mut layout_data[..])
-            .unwrap();
-        let mut partitions = vec![];
-
-        for i in 0..layout.num_entries {
-            let p = partitions_array.get_partition_entry(i).unwrap();
-            let type_guid = p.partition_type_guid;
-            if type_guid.0 != Guid::from_str(cafs::PARTITION_UUID).unwrap() {
-                continue;
-            }
-            partitions.push(Partition {
-                starting_lba: p.starting_lba.clone().to_u64(),
-                ending_lba: p.ending_lba.clone().to_u64(),
-                name: p.name.to_string(),
-                guid: p.partition_type_guid.0,
-            });
-        }
-        let blk = BLK {
-            offset: partitions[0].starting_lba,
-            driver,
-        };
-        let cafs = VFS::new(Arc::new(RwLock::new(blk)));
-        unsafe {
-            fs::VFS = Some(cafs);
-        }
-        // info!("{:?}", cafs.ls_root());
-        // let contents = cafs.read_unstable("/hello").unwrap();
-        // info!("hello len: {}", contents.len());
+    let vaddr = map_bar(bar_addr, bar_len);
+    if let Some(driver) = ahci::init(irq, vaddr, bar_len as usize) {
+        block::register_disk(driver);
     }
 }
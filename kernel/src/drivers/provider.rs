@@ -1,4 +1,4 @@
-use crate::memory::frame::{alloc_range, dealloc};
+use crate::memory::frame::{alloc_range_dma32, dealloc_range};
 use crate::memory::{to_phys_addr, to_virt_addr, PAGE_SIZE};
 use isomorphic_drivers::provider;
 use log::trace;
@@ -22,9 +22,11 @@ impl provider::Provider for Provider {
     }
 }
 
+/// Below 4 GiB, for the controllers with 32-bit DMA addresses.
 #[no_mangle]
 extern "C" fn virtio_dma_alloc(pages: usize) -> PhysAddr {
-    let paddr = alloc_range(pages as u64).unwrap();
+    let paddr = alloc_range_dma32(pages as u64)
+        .unwrap_or_else(|| panic!("no {} contiguous frames below 4 GiB for DMA", pages));
     let addr = PhysAddr::new(paddr.start_address().as_u64());
     trace!("alloc DMA: paddr={:#x}, pages={}", addr, pages);
     addr
@@ -32,9 +34,6 @@ extern "C" fn virtio_dma_alloc(pages: usize) -> PhysAddr {
 
 #[no_mangle]
 extern "C" fn virtio_dma_dealloc(paddr: PhysAddr, pages: usize) {
-    let frame = PhysFrame::containing_address(paddr);
-    for i in 0..pages as u64 {
-        dealloc(frame + i);
-    }
+    dealloc_range(PhysFrame::containing_address(paddr), pages as u64);
     trace!("dealloc DMA: paddr={:#x}, pages={}", paddr, pages);
 }
@@ -0,0 +1,432 @@
+//! virtio-blk driver, over the legacy or the modern PCI transport.
+//!
+//! A single split virtqueue carries one request at a time: a header, the
+//! data in a bounce buffer and a status byte the device writes, chained in
+//! three descriptors. Completion is polled for on the used ring.
+
+use crate::drivers::pci::{capabilities, map_bar, read_config32, read_config8};
+use crate::drivers::provider::Provider;
+use crate::drivers::{BlockDriver, SECTOR_SIZE};
+use crate::memory::PAGE_SIZE;
+use alloc::sync::Arc;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+use isomorphic_drivers::provider::Provider as _;
+use log::{info, warn};
+use pci::{PCIDevice, BAR};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+/// Sectors one request moves at most.
+pub const MAX_SECTORS: usize = 32;
+
+const PCI_CAP_ID_VNDR: u8 = 0x09;
+// virtio_pci_cap, relative to the capability
+const CAP_CFG_TYPE: u16 = 3;
+const CAP_BAR: u16 = 4;
+const CAP_OFFSET: u16 = 8;
+const CAP_LENGTH: u16 = 12;
+const CAP_NOTIFY_MULTIPLIER: u16 = 16;
+const CFG_TYPE_COMMON: u8 = 1;
+const CFG_TYPE_NOTIFY: u8 = 2;
+const CFG_TYPE_DEVICE: u8 = 4;
+
+// legacy registers, relative to the I/O BAR
+const LEGACY_GUEST_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_PFN: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0c;
+const LEGACY_QUEUE_SELECT: u16 = 0x0e;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_STATUS: u16 = 0x12;
+/// Device configuration, without MSI-X.
+const LEGACY_CONFIG: u16 = 0x14;
+
+// virtio_pci_common_cfg
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0c;
+const COMMON_STATUS: usize = 0x14;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_ENABLE: usize = 0x1c;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1e;
+const COMMON_QUEUE_DESC: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 0x80;
+/// VIRTIO_F_VERSION_1, bit 32 of the features.
+const FEATURE_VERSION_1_HIGH: u32 = 1 << 0;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+const AVAIL_F_NO_INTERRUPT: u16 = 1;
+const DESC_SIZE: usize = 16;
+
+const BLK_T_IN: u32 = 0;
+const BLK_T_OUT: u32 = 1;
+const BLK_S_OK: u8 = 0;
+
+// DMA memory of a request: header, status byte and bounce buffer
+const HEADER_OFFSET: usize = 0;
+const STATUS_OFFSET: usize = 0x10;
+const BUFFER_OFFSET: usize = 0x200;
+const BUFFER_SIZE: usize = MAX_SECTORS * SECTOR_SIZE;
+const REQUEST_DMA_SIZE: usize = BUFFER_OFFSET + BUFFER_SIZE;
+
+#[derive(Debug)]
+pub struct IoError {
+    pub lba: u64,
+    /// The status byte of the request.
+    pub status: u8,
+}
+
+/// How the registers of the device are reached.
+enum Transport {
+    /// I/O port registers in BAR 0.
+    Legacy { port: u16 },
+    /// Memory-mapped structures, found through vendor capabilities.
+    Modern {
+        common: usize,
+        notify: usize,
+        notify_multiplier: u32,
+        device: usize,
+    },
+}
+
+fn mmio_read<T>(addr: usize) -> T {
+    unsafe { read_volatile(addr as *const T) }
+}
+
+fn mmio_write<T>(addr: usize, value: T) {
+    unsafe { write_volatile(addr as *mut T, value) }
+}
+
+impl Transport {
+    fn status(&self) -> u8 {
+        match *self {
+            Transport::Legacy { port } => unsafe { Port::new(port + LEGACY_STATUS).read() },
+            Transport::Modern { common, .. } => mmio_read(common + COMMON_STATUS),
+        }
+    }
+
+    fn set_status(&self, status: u8) {
+        match *self {
+            Transport::Legacy { port } => unsafe { Port::new(port + LEGACY_STATUS).write(status) },
+            Transport::Modern { common, .. } => mmio_write(common + COMMON_STATUS, status),
+        }
+    }
+
+    /// Accept no device features, only VIRTIO_F_VERSION_1 on the modern
+    /// transport. Return whether the device agreed.
+    fn negotiate(&self) -> bool {
+        match *self {
+            Transport::Legacy { port } => {
+                unsafe { Port::new(port + LEGACY_GUEST_FEATURES).write(0u32) };
+                true
+            }
+            Transport::Modern { common, .. } => {
+                mmio_write(common + COMMON_DRIVER_FEATURE_SELECT, 1u32);
+                mmio_write(common + COMMON_DRIVER_FEATURE, FEATURE_VERSION_1_HIGH);
+                mmio_write(common + COMMON_DRIVER_FEATURE_SELECT, 0u32);
+                mmio_write(common + COMMON_DRIVER_FEATURE, 0u32);
+                self.set_status(self.status() | STATUS_FEATURES_OK);
+                self.status() & STATUS_FEATURES_OK != 0
+            }
+        }
+    }
+
+    /// Select queue 0 and return its size.
+    fn queue_size(&self) -> u16 {
+        match *self {
+            Transport::Legacy { port } => unsafe {
+                Port::new(port + LEGACY_QUEUE_SELECT).write(0u16);
+                Port::new(port + LEGACY_QUEUE_SIZE).read()
+            },
+            Transport::Modern { common, .. } => {
+                mmio_write(common + COMMON_QUEUE_SELECT, 0u16);
+                mmio_read(common + COMMON_QUEUE_SIZE)
+            }
+        }
+    }
+
+    fn set_queue(&self, queue: &Queue) {
+        match *self {
+            Transport::Legacy { port } => unsafe {
+                Port::new(port + LEGACY_QUEUE_PFN).write((queue.paddr / PAGE_SIZE) as u32);
+            },
+            Transport::Modern { common, .. } => {
+                let write_addr = |offset: usize, addr: usize| {
+                    mmio_write(common + offset, addr as u32);
+                    mmio_write(common + offset + 4, (addr as u64 >> 32) as u32);
+                };
+                write_addr(COMMON_QUEUE_DESC, queue.paddr);
+                write_addr(COMMON_QUEUE_DRIVER, queue.paddr + queue.avail);
+                write_addr(COMMON_QUEUE_DEVICE, queue.paddr + queue.used);
+                mmio_write(common + COMMON_QUEUE_ENABLE, 1u16);
+            }
+        }
+    }
+
+    fn notify(&self) {
+        match *self {
+            Transport::Legacy { port } => unsafe {
+                Port::new(port + LEGACY_QUEUE_NOTIFY).write(0u16)
+            },
+            Transport::Modern {
+                common,
+                notify,
+                notify_multiplier,
+                ..
+            } => {
+                let offset: u16 = mmio_read(common + COMMON_QUEUE_NOTIFY_OFF);
+                mmio_write(notify + offset as usize * notify_multiplier as usize, 0u16);
+            }
+        }
+    }
+
+    /// Sectors of the disk, the first field of the device configuration.
+    fn capacity(&self) -> u64 {
+        let (low, high): (u32, u32) = match *self {
+            Transport::Legacy { port } => unsafe {
+                (
+                    Port::new(port + LEGACY_CONFIG).read(),
+                    Port::new(port + LEGACY_CONFIG + 4).read(),
+                )
+            },
+            Transport::Modern { device, .. } => (mmio_read(device), mmio_read(device + 4)),
+        };
+        (high as u64) << 32 | low as u64
+    }
+}
+
+/// A split virtqueue: descriptor table, available and used ring, laid out
+/// as the legacy transport wants them.
+struct Queue {
+    size: u16,
+    vaddr: usize,
+    paddr: usize,
+    /// Offsets of the rings in the queue memory.
+    avail: usize,
+    used: usize,
+    /// Next index in the available ring.
+    avail_idx: u16,
+    /// Index in the used ring seen last.
+    used_idx: u16,
+}
+
+impl Queue {
+    fn new(size: u16) -> Self {
+        let n = size as usize;
+        let avail = n * DESC_SIZE;
+        let used = (avail + 6 + 2 * n + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+        let dma_size = used + 6 + 8 * n;
+        let (vaddr, paddr) = Provider::alloc_dma(dma_size);
+        unsafe { core::ptr::write_bytes(vaddr as *mut u8, 0, dma_size) };
+        mmio_write(vaddr + avail, AVAIL_F_NO_INTERRUPT);
+        Self {
+            size,
+            vaddr,
+            paddr,
+            avail,
+            used,
+            avail_idx: 0,
+            used_idx: 0,
+        }
+    }
+
+    fn set_desc(&self, index: usize, addr: usize, len: usize, flags: u16, next: u16) {
+        let desc = self.vaddr + index * DESC_SIZE;
+        mmio_write(desc, addr as u64);
+        mmio_write(desc + 8, len as u32);
+        mmio_write(desc + 12, flags);
+        mmio_write(desc + 14, next);
+    }
+
+    /// Make the chain starting at descriptor `head` available.
+    fn push(&mut self, head: u16) {
+        let slot = self.avail_idx % self.size;
+        mmio_write(self.vaddr + self.avail + 4 + slot as usize * 2, head);
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        fence(Ordering::SeqCst);
+        mmio_write(self.vaddr + self.avail + 2, self.avail_idx);
+    }
+
+    /// Wait for the device to use the chain pushed last.
+    fn wait(&mut self) {
+        while mmio_read::<u16>(self.vaddr + self.used + 2) == self.used_idx {
+            core::hint::spin_loop();
+        }
+        fence(Ordering::SeqCst);
+        self.used_idx = self.used_idx.wrapping_add(1);
+    }
+}
+
+struct Inner {
+    queue: Queue,
+    dma_vaddr: usize,
+    dma_paddr: usize,
+}
+
+pub struct VirtioBlkDriver {
+    transport: Transport,
+    sectors: u64,
+    inner: Mutex<Inner>,
+}
+
+impl VirtioBlkDriver {
+    /// Move `buf.len() / SECTOR_SIZE` sectors at `lba` from or to `buf`.
+    fn request(&self, lba: u64, buf: &mut [u8], write: bool) -> Result<(), IoError> {
+        assert!(buf.len() % SECTOR_SIZE == 0 && buf.len() <= BUFFER_SIZE);
+        let mut inner = self.inner.lock();
+        let header = inner.dma_vaddr + HEADER_OFFSET;
+        mmio_write(header, if write { BLK_T_OUT } else { BLK_T_IN });
+        mmio_write(header + 4, 0u32);
+        mmio_write(header + 8, lba);
+        mmio_write(inner.dma_vaddr + STATUS_OFFSET, 0xffu8);
+        let buffer = (inner.dma_vaddr + BUFFER_OFFSET) as *mut u8;
+        if write {
+            unsafe { core::ptr::copy_nonoverlapping(buf.as_ptr(), buffer, buf.len()) };
+        }
+
+        let paddr = inner.dma_paddr;
+        let data_flags = if write { 0 } else { DESC_F_WRITE };
+        let queue = &mut inner.queue;
+        queue.set_desc(0, paddr + HEADER_OFFSET, 16, DESC_F_NEXT, 1);
+        queue.set_desc(
+            1,
+            paddr + BUFFER_OFFSET,
+            buf.len(),
+            DESC_F_NEXT | data_flags,
+            2,
+        );
+        queue.set_desc(2, paddr + STATUS_OFFSET, 1, DESC_F_WRITE, 0);
+        queue.push(0);
+        self.transport.notify();
+        queue.wait();
+
+        let status = mmio_read::<u8>(inner.dma_vaddr + STATUS_OFFSET);
+        if status != BLK_S_OK {
+            return Err(IoError { lba, status });
+        }
+        if !write {
+            unsafe { core::ptr::copy_nonoverlapping(buffer, buf.as_mut_ptr(), buf.len()) };
+        }
+        Ok(())
+    }
+}
+
+impl BlockDriver for VirtioBlkDriver {
+    fn read_block(&self, block_id: u64, buf: &mut [u8]) {
+        self.request(block_id, buf, false)
+            .expect("virtio-blk read failed");
+    }
+
+    fn write_block(&self, block_id: u64, buf: &[u8]) {
+        let mut data = buf.to_vec();
+        self.request(block_id, &mut data, true)
+            .expect("virtio-blk write failed");
+    }
+
+    fn read_blocks(&self, block_id: u64, buf: &mut [u8]) {
+        for (i, chunk) in buf.chunks_mut(BUFFER_SIZE).enumerate() {
+            self.read_block(block_id + (i * MAX_SECTORS) as u64, chunk);
+        }
+    }
+
+    fn sectors(&self) -> u64 {
+        self.sectors
+    }
+}
+
+/// The modern transport, if the device has all the capabilities for it.
+fn modern_transport(dev: &PCIDevice) -> Option<Transport> {
+    let (mut common, mut notify, mut device) = (None, None, None);
+    let mut notify_multiplier = 0;
+    for cap in capabilities(dev.loc, PCI_CAP_ID_VNDR) {
+        let cfg_type = read_config8(dev.loc, cap + CAP_CFG_TYPE);
+        let slot = match cfg_type {
+            CFG_TYPE_COMMON => &mut common,
+            CFG_TYPE_NOTIFY => &mut notify,
+            CFG_TYPE_DEVICE => &mut device,
+            _ => continue,
+        };
+        let bar = read_config8(dev.loc, cap + CAP_BAR) as usize;
+        let addr = match dev.bars.get(bar) {
+            Some(Some(BAR::Memory(addr, _, _, _))) => *addr,
+            _ => continue,
+        };
+        if slot.is_some() {
+            continue;
+        }
+        let offset = read_config32(dev.loc, cap + CAP_OFFSET) as u64;
+        let length = read_config32(dev.loc, cap + CAP_LENGTH);
+        *slot = Some(map_bar(addr + offset, length));
+        if cfg_type == CFG_TYPE_NOTIFY {
+            notify_multiplier = read_config32(dev.loc, cap + CAP_NOTIFY_MULTIPLIER);
+        }
+    }
+    Some(Transport::Modern {
+        common: common?,
+        notify: notify?,
+        notify_multiplier,
+        device: device?,
+    })
+}
+
+pub fn init(dev: &PCIDevice) -> Option<Arc<VirtioBlkDriver>> {
+    let transport = match (modern_transport(dev), &dev.bars[0]) {
+        (Some(transport), _) => transport,
+        (None, Some(BAR::IO(port, _))) => Transport::Legacy { port: *port as u16 },
+        _ => {
+            warn!("virtio-blk: no usable transport");
+            return None;
+        }
+    };
+
+    // reset, then the initialization sequence of the spec
+    transport.set_status(0);
+    while transport.status() != 0 {
+        core::hint::spin_loop();
+    }
+    transport.set_status(STATUS_ACKNOWLEDGE);
+    transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+    if !transport.negotiate() {
+        warn!("virtio-blk: features not accepted");
+        transport.set_status(STATUS_FAILED);
+        return None;
+    }
+    let size = transport.queue_size();
+    if size == 0 {
+        warn!("virtio-blk: queue 0 is not available");
+        transport.set_status(STATUS_FAILED);
+        return None;
+    }
+    let queue = Queue::new(size);
+    transport.set_queue(&queue);
+    transport.set_status(transport.status() | STATUS_DRIVER_OK);
+
+    let (dma_vaddr, dma_paddr) = Provider::alloc_dma(REQUEST_DMA_SIZE);
+    let sectors = transport.capacity();
+    info!(
+        "virtio-blk ({}): {} sectors, queue size {}",
+        match transport {
+            Transport::Legacy { .. } => "legacy",
+            Transport::Modern { .. } => "modern",
+        },
+        sectors,
+        size
+    );
+    Some(Arc::new(VirtioBlkDriver {
+        transport,
+        sectors,
+        inner: Mutex::new(Inner {
+            queue,
+            dma_vaddr,
+            dma_paddr,
+        }),
+    }))
+}
@@ -1,4 +1,65 @@
+pub mod page_cache;
+
+use crate::block::{self, Partition, PartitionType};
 use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use cafs::fs::{Inode, FS};
 use cafs::vfs::VFS;
+use core::str::FromStr;
+use log::{error, info};
+use spin::RwLock;
+use uguid::Guid;
 
 pub static mut VFS: Option<Arc<VFS>> = None;
+
+/// Mount the CAFS on `partition` as the filesystem.
+pub fn mount(partition: &Arc<Partition>) -> Result<(), cafs::Error> {
+    let vfs = VFS::new(Arc::new(RwLock::new(partition.device())))?;
+    unsafe {
+        VFS = Some(vfs);
+    }
+    info!("mounted {}", partition.name);
+    Ok(())
+}
+
+/// Mount the first CAFS partition of the registered disks.
+pub fn init() {
+    let type_ = PartitionType::Gpt(Guid::from_str(cafs::PARTITION_UUID).unwrap());
+    let Some(partition) = block::by_type(type_) else {
+        error!("no CAFS partition found");
+        return;
+    };
+    match mount(&partition) {
+        Ok(()) => page_cache::init(),
+        Err(e) => error!("failed to mount CAFS: {:?}", e),
+    }
+}
+
+fn vfs() -> &'static Arc<VFS> {
+    unsafe { VFS.as_ref().expect("no filesystem mounted") }
+}
+
+/// Read the whole file at `path` through the page cache.
+pub fn read_file(path: &str) -> Result<Vec<u8>, cafs::Error> {
+    let inode_number = vfs().inode_number_of(path)?;
+    let mut contents = vec![0; size(inode_number)? as usize];
+    page_cache::read(inode_number, 0, &mut contents)?;
+    Ok(contents)
+}
+
+/// The inode number of the file at `path`, created if missing and `create`.
+pub fn open(path: &str, create: bool) -> Result<u64, cafs::Error> {
+    match vfs().inode_number_of(path) {
+        Err(cafs::Error::NotExist(_)) if create => {
+            vfs().create(path)?;
+            vfs().inode_number_of(path)
+        }
+        result => result,
+    }
+}
+
+/// The size in bytes of the file `inode_number`.
+pub fn size(inode_number: u64) -> Result<u64, cafs::Error> {
+    Ok(vfs().fs().inode(inode_number)?.read().size())
+}
@@ -0,0 +1,439 @@
+//! File pages cached in frames, keyed by (inode, page index).
+//!
+//! Reads are served from cached pages, writes inside a file only dirty
+//! them until `sync`, by the `sync` thread every few seconds at the latest.
+//! A page mapped into user space shares the cached frame and stays until it
+//! is unmapped. Clean, unmapped pages are evicted with a clock sweep when
+//! the cache is full or frames run out.
+//!
+//! The cache lock is never held across filesystem I/O or frame allocation,
+//! a page written back meanwhile is marked busy instead.
+
+use crate::device::timer;
+use crate::memory::frame;
+use crate::memory::{to_virt_addr, PAGE_SIZE};
+use crate::process::{scheduler, thread};
+use alloc::collections::btree_map::Entry;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use cafs::fs::{Inode, FS};
+use cafs::Error;
+use core::ops::Bound::{Excluded, Unbounded};
+use log::warn;
+use spin::Mutex;
+use x86_64::structures::paging::PhysFrame;
+
+/// Pages the cache holds before it evicts on its own, 16 MiB.
+const CACHE_LIMIT: usize = 4096;
+/// Pages evicted at once when frames run out.
+pub const RECLAIM_BATCH: usize = 64;
+/// Seconds between the write backs of the `sync` thread.
+const SYNC_INTERVAL: usize = 5;
+
+static PAGE_CACHE: Mutex<PageCache> = Mutex::new(PageCache::new());
+
+/// Pages taken busy to write back, with their frames.
+type Taken = Vec<((u64, u64), PhysFrame)>;
+
+struct Page {
+    frame: PhysFrame,
+    dirty: bool,
+    /// Mappings into user address spaces, a mapped page is never evicted.
+    map_count: usize,
+    /// Set on every use, cleared by the clock sweep for a second chance.
+    referenced: bool,
+    /// Being written back without the cache lock: not evicted, and writes
+    /// wait so the page does not change halfway.
+    busy: bool,
+}
+
+fn bytes(frame: PhysFrame) -> &'static mut [u8] {
+    let addr = to_virt_addr(frame.start_address().as_u64());
+    unsafe { core::slice::from_raw_parts_mut(addr.as_mut_ptr(), PAGE_SIZE) }
+}
+
+struct PageCache {
+    pages: BTreeMap<(u64, u64), Page>,
+    /// The key of each page mapped into user space, by frame.
+    mapped: BTreeMap<PhysFrame, (u64, u64)>,
+    /// Where the clock sweep continues.
+    hand: (u64, u64),
+}
+
+impl PageCache {
+    const fn new() -> Self {
+        Self {
+            pages: BTreeMap::new(),
+            mapped: BTreeMap::new(),
+            hand: (0, 0),
+        }
+    }
+
+    /// Mark the dirty pages `filter` accepts busy and clean, return them to
+    /// write back. Also whether other matching pages are busy already.
+    fn take_dirty(&mut self, filter: impl Fn(&(u64, u64)) -> bool) -> (Taken, bool) {
+        let mut taken = vec![];
+        let mut busy = false;
+        for (key, page) in self.pages.iter_mut().filter(|(key, _)| filter(key)) {
+            if page.busy {
+                busy = true;
+            } else if page.dirty {
+                page.busy = true;
+                page.dirty = false;
+                taken.push((*key, page.frame));
+            }
+        }
+        (taken, busy)
+    }
+
+    /// Pick up to `count` unmapped, idle pages with a clock sweep. The clean
+    /// ones are removed and their frames returned, the dirty ones taken to
+    /// write back first.
+    fn sweep(&mut self, count: usize) -> (Vec<PhysFrame>, Taken) {
+        let keys = self
+            .pages
+            .range((Excluded(self.hand), Unbounded))
+            .chain(self.pages.range(..=self.hand))
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+        let mut clean = vec![];
+        let mut dirty = vec![];
+        // the second pass finds the pages whose reference bit got cleared
+        for key in keys.iter().chain(keys.iter()) {
+            if clean.len() + dirty.len() == count {
+                break;
+            }
+            let Some(page) = self.pages.get_mut(key) else {
+                continue;
+            };
+            if page.map_count > 0 || page.busy {
+                continue;
+            }
+            if page.referenced {
+                page.referenced = false;
+                continue;
+            }
+            if page.dirty {
+                page.busy = true;
+                page.dirty = false;
+                dirty.push((*key, page.frame));
+            } else {
+                clean.push(page.frame);
+                self.pages.remove(key);
+            }
+            self.hand = *key;
+        }
+        (clean, dirty)
+    }
+}
+
+/// Read page `index` of the file into a new frame.
+fn read_page(fs: &dyn FS, inode_number: u64, index: u64, full: bool) -> Result<PhysFrame, Error> {
+    if full {
+        reclaim_pages(fs, 1)?;
+    }
+    let frame = match frame::alloc() {
+        Some(frame) => frame,
+        None => {
+            reclaim_pages(fs, RECLAIM_BATCH)?;
+            frame::alloc().ok_or(Error::NoSpace)?
+        }
+    };
+    let bytes = bytes(frame);
+    let read = match fs
+        .inode(inode_number)
+        .and_then(|inode| inode.read().read_at(index * PAGE_SIZE as u64, bytes))
+    {
+        Ok(read) => read,
+        Err(err) => {
+            frame::dealloc(frame);
+            return Err(err);
+        }
+    };
+    // past the end of the file
+    bytes[read..].fill(0);
+    Ok(frame)
+}
+
+/// Run `f` on page `index` of the file under the cache lock, reading the
+/// page in first on a miss. A page to `write` is waited for while busy.
+fn with_page<V>(
+    fs: &dyn FS,
+    inode_number: u64,
+    index: u64,
+    write: bool,
+    f: impl FnOnce(&mut Page) -> V,
+) -> Result<V, Error> {
+    let key = (inode_number, index);
+    loop {
+        let mut cache = PAGE_CACHE.lock();
+        match cache.pages.get_mut(&key) {
+            Some(page) if write && page.busy => {
+                drop(cache);
+                scheduler::yield_now();
+            }
+            Some(page) => {
+                page.referenced = true;
+                return Ok(f(page));
+            }
+            None => {
+                let full = cache.pages.len() >= CACHE_LIMIT;
+                drop(cache);
+                let frame = read_page(fs, inode_number, index, full)?;
+                let raced = match PAGE_CACHE.lock().pages.entry(key) {
+                    Entry::Vacant(entry) => {
+                        entry.insert(Page {
+                            frame,
+                            dirty: false,
+                            map_count: 0,
+                            referenced: false,
+                            busy: false,
+                        });
+                        false
+                    }
+                    // read in by someone else meanwhile
+                    Entry::Occupied(_) => true,
+                };
+                if raced {
+                    frame::dealloc(frame);
+                }
+            }
+        }
+    }
+}
+
+/// Write back the pages taken busy, without the cache lock, then release
+/// them. Those not written are dirty again.
+fn write_back(fs: &dyn FS, pages: &Taken) -> Result<(), Error> {
+    let mut written = 0;
+    let mut result = Ok(());
+    for ((inode_number, index), frame) in pages {
+        if let Err(err) = write_page(fs, *inode_number, *index, *frame) {
+            result = Err(err);
+            break;
+        }
+        written += 1;
+    }
+    let mut cache = PAGE_CACHE.lock();
+    for (i, (key, _)) in pages.iter().enumerate() {
+        let page = cache.pages.get_mut(key).unwrap();
+        page.busy = false;
+        page.dirty |= i >= written;
+    }
+    result
+}
+
+fn write_page(fs: &dyn FS, inode_number: u64, index: u64, frame: PhysFrame) -> Result<(), Error> {
+    let size = fs.inode(inode_number)?.read().size();
+    let start = index * PAGE_SIZE as u64;
+    // the file may have been truncated meanwhile
+    if start < size {
+        let len = (size - start).min(PAGE_SIZE as u64) as usize;
+        fs.write_at(inode_number, start, &bytes(frame)[..len])?;
+    }
+    Ok(())
+}
+
+/// Write back the dirty pages of one file, or of all, waiting for those
+/// written back by others.
+fn sync_pages(fs: &dyn FS, inode_number: Option<u64>) -> Result<(), Error> {
+    loop {
+        let (pages, busy) = PAGE_CACHE
+            .lock()
+            .take_dirty(|key| inode_number.map_or(true, |number| number == key.0));
+        write_back(fs, &pages)?;
+        if !busy {
+            return Ok(());
+        }
+        scheduler::yield_now();
+    }
+}
+
+/// Evict up to `count` unmapped pages, writing back dirty ones first.
+/// Return the number evicted.
+fn reclaim_pages(fs: &dyn FS, count: usize) -> Result<usize, Error> {
+    let (clean, dirty) = PAGE_CACHE.lock().sweep(count);
+    let mut evicted = clean.len();
+    for frame in clean {
+        frame::dealloc(frame);
+    }
+    write_back(fs, &dirty)?;
+    let mut frames = vec![];
+    {
+        let mut cache = PAGE_CACHE.lock();
+        for (key, _) in dirty {
+            let page = &cache.pages[&key];
+            // used again meanwhile
+            if page.dirty || page.busy || page.referenced || page.map_count > 0 {
+                continue;
+            }
+            frames.push(page.frame);
+            cache.pages.remove(&key);
+        }
+    }
+    evicted += frames.len();
+    for frame in frames {
+        frame::dealloc(frame);
+    }
+    Ok(evicted)
+}
+
+fn partition() -> Arc<dyn FS> {
+    unsafe { super::VFS.as_ref().expect("no filesystem mounted").fs() }
+}
+
+/// Read from byte `offset` of the file into `buf` through the cache,
+/// return the bytes read.
+pub fn read(inode_number: u64, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+    let fs = partition();
+    let size = fs.inode(inode_number)?.read().size();
+    let end = size.min(offset.saturating_add(buf.len() as u64));
+    let mut pos = offset;
+    while pos < end {
+        let start = pos as usize % PAGE_SIZE;
+        let len = (end - pos).min((PAGE_SIZE - start) as u64) as usize;
+        let done = (pos - offset) as usize;
+        with_page(&*fs, inode_number, pos / PAGE_SIZE as u64, false, |page| {
+            buf[done..done + len].copy_from_slice(&bytes(page.frame)[start..start + len])
+        })?;
+        pos += len as u64;
+    }
+    Ok(end.saturating_sub(offset) as usize)
+}
+
+/// Write `buf` at byte `offset` of the file. Writes inside the file stay in
+/// the cache until `sync`, writes growing it go through so the size is
+/// right, updating the pages cached already.
+pub fn write(inode_number: u64, offset: u64, buf: &[u8]) -> Result<(), Error> {
+    let fs = partition();
+    let size = fs.inode(inode_number)?.read().size();
+    let end = offset + buf.len() as u64;
+    let mut pos = offset;
+    while pos < end {
+        let start = pos as usize % PAGE_SIZE;
+        let len = (end - pos).min((PAGE_SIZE - start) as u64) as usize;
+        let index = pos / PAGE_SIZE as u64;
+        let done = (pos - offset) as usize;
+        let copy = |page: &mut Page| {
+            bytes(page.frame)[start..start + len].copy_from_slice(&buf[done..done + len])
+        };
+        if end > size {
+            loop {
+                let mut cache = PAGE_CACHE.lock();
+                match cache.pages.get_mut(&(inode_number, index)) {
+                    Some(page) if page.busy => {
+                        drop(cache);
+                        scheduler::yield_now();
+                    }
+                    Some(page) => break copy(page),
+                    None => break,
+                }
+            }
+        } else {
+            with_page(&*fs, inode_number, index, true, |page| {
+                page.dirty = true;
+                copy(page)
+            })?;
+        }
+        pos += len as u64;
+    }
+    if end > size {
+        fs.write_at(inode_number, offset, buf)?;
+    }
+    Ok(())
+}
+
+/// Start the thread writing back the dirty pages every `SYNC_INTERVAL`
+/// seconds, once the filesystem is mounted.
+pub fn init() {
+    thread::spawn("sync", || loop {
+        scheduler::sleep(SYNC_INTERVAL * timer::HZ);
+        if let Err(e) = sync() {
+            warn!("failed to sync the page cache: {:?}", e);
+        }
+    });
+}
+
+/// Write back the dirty pages of all files, and the filesystem to the disk.
+pub fn sync() -> Result<(), Error> {
+    let fs = partition();
+    sync_pages(&*fs, None)?;
+    fs.sync();
+    Ok(())
+}
+
+/// Write back the dirty pages of one file.
+pub fn sync_inode(inode_number: u64) -> Result<(), Error> {
+    sync_pages(&*partition(), Some(inode_number))
+}
+
+/// Write back and drop the unmapped pages of the file, after it changed
+/// behind the cache, e.g. by a truncate.
+pub fn invalidate(inode_number: u64) -> Result<(), Error> {
+    let fs = partition();
+    loop {
+        sync_pages(&*fs, Some(inode_number))?;
+        let mut cache = PAGE_CACHE.lock();
+        // written to meanwhile
+        if cache
+            .pages
+            .range((inode_number, 0)..=(inode_number, u64::MAX))
+            .any(|(_, page)| page.dirty || page.busy)
+        {
+            drop(cache);
+            scheduler::yield_now();
+            continue;
+        }
+        let mut frames = vec![];
+        cache.pages.retain(|key, page| {
+            if key.0 != inode_number || page.map_count > 0 {
+                return true;
+            }
+            frames.push(page.frame);
+            false
+        });
+        drop(cache);
+        for frame in frames {
+            frame::dealloc(frame);
+        }
+        return Ok(());
+    }
+}
+
+/// Pin page `index` of the file and return its frame, to map into a user
+/// address space. Every mapping shares the same frame.
+pub fn map_page(inode_number: u64, index: u64) -> Result<PhysFrame, Error> {
+    let frame = with_page(&*partition(), inode_number, index, false, |page| {
+        page.map_count += 1;
+        page.frame
+    })?;
+    PAGE_CACHE
+        .lock()
+        .mapped
+        .insert(frame, (inode_number, index));
+    Ok(frame)
+}
+
+/// Drop a mapping of `frame` made by `map_page`, `dirty` if it was written
+/// through.
+pub fn unmap_page(frame: PhysFrame, dirty: bool) {
+    let mut cache = PAGE_CACHE.lock();
+    let key = *cache.mapped.get(&frame).expect("page was not mapped");
+    let page = cache.pages.get_mut(&key).unwrap();
+    page.map_count -= 1;
+    page.dirty |= dirty;
+    if page.map_count == 0 {
+        cache.mapped.remove(&frame);
+    }
+}
+
+/// Give back up to `count` frames under memory pressure, return how many.
+pub fn reclaim(count: usize) -> Result<usize, Error> {
+    // nothing cached, maybe nothing mounted either
+    if PAGE_CACHE.lock().pages.is_empty() {
+        return Ok(0);
+    }
+    reclaim_pages(&*partition(), count)
+}
@@ -14,6 +14,7 @@ mod allocator;
 pub mod arch;
 pub use crate::arch::*;
 
+pub mod block;
 pub mod drivers;
 pub mod fs;
 mod logger;
@@ -63,11 +64,17 @@ pub fn init(boot_info: &'static mut BootInfo) {
 
     // ! The order cannot be changed.
     memory::init(boot_info.physical_memory_offset, &boot_info.memory_map);
-    gdt::init();
+    logger::map_framebuffer();
+    acpi::init(boot_info.rsdp_addr);
+    cpu::init(0);
+    syscall::init();
     interrupt::idt::init();
     interrupt::apic::init();
     device::init();
+    process::scheduler::init();
+    smp::init();
     drivers::pci::init();
+    fs::init();
 
     process::init();
 }
@@ -0,0 +1,79 @@
+//! Files of the filesystem.
+
+use crate::io;
+use crate::syscall::{self, check, Errno};
+
+/// Create the file `open` does not find.
+const O_CREAT: u64 = 0o100;
+
+/// Where `File::seek` moves to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+/// An open file, closed when dropped.
+pub struct File {
+    fd: u64,
+}
+
+impl File {
+    /// Open the file at `path`.
+    pub fn open(path: &str) -> Result<Self, Errno> {
+        Self::open_with(path, 0)
+    }
+
+    /// Open the file at `path`, created if missing.
+    pub fn create(path: &str) -> Result<Self, Errno> {
+        Self::open_with(path, O_CREAT)
+    }
+
+    fn open_with(path: &str, flags: u64) -> Result<Self, Errno> {
+        let ret = unsafe {
+            syscall::syscall3(
+                syscall::OPEN,
+                path.as_ptr() as u64,
+                path.len() as u64,
+                flags,
+            )
+        };
+        check(ret).map(|fd| Self { fd })
+    }
+
+    pub fn fd(&self) -> u64 {
+        self.fd
+    }
+
+    /// Read at the offset, return the bytes read, 0 at the end.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        io::read(self.fd, buf)
+    }
+
+    /// Write at the offset, return the bytes written.
+    pub fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+        io::write(self.fd, buf)
+    }
+
+    /// Move the offset, return where it is.
+    pub fn seek(&self, pos: SeekFrom) -> Result<u64, Errno> {
+        let (offset, whence) = match pos {
+            SeekFrom::Start(offset) => (offset, 0),
+            SeekFrom::Current(offset) => (offset as u64, 1),
+            SeekFrom::End(offset) => (offset as u64, 2),
+        };
+        check(unsafe { syscall::syscall3(syscall::SEEK, self.fd, offset, whence) })
+    }
+}
+
+/// Write the dirty file pages and the filesystem to the disk.
+pub fn sync() -> Result<(), Errno> {
+    check(unsafe { syscall::syscall0(syscall::SYNC) }).map(|_| ())
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        unsafe { syscall::syscall1(syscall::CLOSE, self.fd) };
+    }
+}
@@ -0,0 +1,73 @@
+//! Reading and writing descriptors, and printing to the console.
+
+use crate::syscall::{self, check, Errno};
+use core::fmt::{self, Write};
+
+pub const STDIN: u64 = 0;
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+/// Read from `fd` into `buf`, return the bytes read, 0 at the end.
+pub fn read(fd: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+    let ret =
+        unsafe { syscall::syscall3(syscall::READ, fd, buf.as_mut_ptr() as u64, buf.len() as u64) };
+    check(ret).map(|read| read as usize)
+}
+
+/// Write `buf` to `fd`, return the bytes written.
+pub fn write(fd: u64, buf: &[u8]) -> Result<usize, Errno> {
+    let ret =
+        unsafe { syscall::syscall3(syscall::WRITE, fd, buf.as_ptr() as u64, buf.len() as u64) };
+    check(ret).map(|written| written as usize)
+}
+
+/// Write all of `buf` to `fd`.
+pub fn write_all(fd: u64, mut buf: &[u8]) -> Result<(), Errno> {
+    while !buf.is_empty() {
+        match write(fd, buf)? {
+            0 => return Err(Errno::EIO),
+            written => buf = &buf[written..],
+        }
+    }
+    Ok(())
+}
+
+/// A descriptor written to with `write!`.
+pub struct Writer(pub u64);
+
+impl Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write_all(self.0, s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(fd: u64, args: fmt::Arguments) {
+    let _ = Writer(fd).write_fmt(args);
+}
+
+/// Print to standard output.
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::io::_print($crate::io::STDOUT, format_args!($($arg)*)));
+}
+
+/// Print to standard output, with a newline.
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+/// Print to standard error.
+#[macro_export]
+macro_rules! eprint {
+    ($($arg:tt)*) => ($crate::io::_print($crate::io::STDERR, format_args!($($arg)*)));
+}
+
+/// Print to standard error, with a newline.
+#[macro_export]
+macro_rules! eprintln {
+    () => ($crate::eprint!("\n"));
+    ($($arg:tt)*) => ($crate::eprint!("{}\n", format_args!($($arg)*)));
+}
@@ -1 +1,27 @@
+//! The library canyon programs are written against: system call wrappers,
+//! printing, and the runtime that starts them.
+//!
+//! A program is a `#![no_std]`, `#![no_main]` binary defining
+//!
+//! ```ignore
+//! #[no_mangle]
+//! fn main() -> i32 {
+//!     libcanyon::println!("Hello, canyon!");
+//!     0
+//! }
+//! ```
+//!
+//! linked as a static executable, e.g. with `-C relocation-model=static`.
+
 #![no_std]
+
+extern crate alloc;
+
+pub mod fs;
+pub mod io;
+pub mod memory;
+pub mod process;
+mod rt;
+pub mod syscall;
+
+pub use syscall::Errno;
@@ -0,0 +1,31 @@
+//! Memory from the kernel: the heap end and anonymous mappings.
+
+use crate::syscall::{self, check, Errno};
+
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+pub const PROT_EXEC: u64 = 4;
+
+/// Map `len` bytes of zeroed memory with the protection `prot`, return
+/// its address.
+pub fn mmap(len: usize, prot: u64) -> Result<*mut u8, Errno> {
+    let ret = unsafe { syscall::syscall3(syscall::MMAP, 0, len as u64, prot) };
+    check(ret).map(|addr| addr as *mut u8)
+}
+
+/// Unmap `len` bytes at `addr`.
+///
+/// # Safety
+/// Nothing may use the memory afterwards.
+pub unsafe fn munmap(addr: *mut u8, len: usize) -> Result<(), Errno> {
+    check(syscall::syscall2(syscall::MUNMAP, addr as u64, len as u64)).map(|_| ())
+}
+
+/// Move the end of the heap to `end`, or only get it if null. Return
+/// where it is, unmoved if it could not.
+///
+/// # Safety
+/// Nothing may use the memory given back.
+pub unsafe fn brk(end: *mut u8) -> *mut u8 {
+    syscall::syscall1(syscall::BRK, end as u64) as *mut u8
+}
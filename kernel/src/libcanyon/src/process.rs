@@ -0,0 +1,58 @@
+//! Processes: exiting, spawning or forking them, waiting for them.
+
+use crate::syscall::{self, check, Errno};
+
+/// Wait for any child.
+const ANY_CHILD: u64 = u64::MAX;
+
+/// End the process with `code`.
+pub fn exit(code: i32) -> ! {
+    unsafe { syscall::syscall1(syscall::EXIT, code as i64 as u64) };
+    unreachable!("process still running after exit");
+}
+
+pub fn getpid() -> u64 {
+    unsafe { syscall::syscall0(syscall::GETPID) }
+}
+
+/// The pid of the parent, 0 for the kernel.
+pub fn getppid() -> u64 {
+    unsafe { syscall::syscall0(syscall::GETPPID) }
+}
+
+/// Block the process for `ms` milliseconds.
+pub fn sleep(ms: u64) {
+    unsafe { syscall::syscall1(syscall::SLEEP, ms) };
+}
+
+/// Run the executable at `path` in a new process, return its pid.
+pub fn spawn(path: &str) -> Result<u64, Errno> {
+    check(unsafe { syscall::syscall2(syscall::SPAWN, path.as_ptr() as u64, path.len() as u64) })
+}
+
+/// Copy the process. Return the pid of the child in the parent, and 0 in
+/// the child.
+pub fn fork() -> Result<u64, Errno> {
+    check(unsafe { syscall::syscall0(syscall::FORK) })
+}
+
+/// Replace the program with the executable at `path`, return only on an
+/// error.
+pub fn exec(path: &str) -> Errno {
+    let ret = unsafe { syscall::syscall2(syscall::EXEC, path.as_ptr() as u64, path.len() as u64) };
+    check(ret).expect_err("returned from exec")
+}
+
+/// Wait for the child `pid`, or any if none, to exit. Return its pid and
+/// exit code.
+pub fn wait(pid: Option<u64>) -> Result<(u64, i64), Errno> {
+    let mut code = 0i64;
+    let ret = unsafe {
+        syscall::syscall2(
+            syscall::WAIT,
+            pid.unwrap_or(ANY_CHILD),
+            &mut code as *mut i64 as u64,
+        )
+    };
+    check(ret).map(|pid| (pid, code))
+}
@@ -0,0 +1,104 @@
+//! The runtime: the entry point calling `main`, the panic handler and the
+//! heap allocator.
+
+use crate::{eprintln, memory, process};
+use core::alloc::{GlobalAlloc, Layout};
+use core::arch::global_asm;
+use core::cell::UnsafeCell;
+use core::panic::PanicInfo;
+use core::ptr;
+
+/// The exit code of a program that panicked.
+const PANIC_CODE: i32 = 101;
+/// The heap grows by at least this much.
+const HEAP_GROWTH: usize = 64 * 1024;
+
+extern "Rust" {
+    /// The `main` of the program, returning its exit code.
+    fn main() -> i32;
+}
+
+// The kernel starts the program with `rsp` at argc, unaligned for a call
+// until the end of `_start`.
+global_asm!(
+    ".global _start",
+    "_start:",
+    "xor ebp, ebp",
+    "and rsp, -16",
+    "call {start}",
+    "ud2",
+    start = sym start,
+);
+
+extern "C" fn start() -> ! {
+    process::exit(unsafe { main() })
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    eprintln!("{}", info);
+    process::exit(PANIC_CODE)
+}
+
+/// A bump allocator on the heap of `brk`, freeing only the last block.
+struct Heap(UnsafeCell<Inner>);
+
+struct Inner {
+    /// Where the next block goes.
+    next: usize,
+    /// The end of the heap.
+    end: usize,
+    /// Blocks allocated and not freed.
+    count: usize,
+    /// The start of the heap, to go back to when all are freed.
+    start: usize,
+}
+
+// A process has one thread.
+unsafe impl Sync for Heap {}
+
+#[global_allocator]
+static HEAP: Heap = Heap(UnsafeCell::new(Inner {
+    next: 0,
+    end: 0,
+    count: 0,
+    start: 0,
+}));
+
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let heap = &mut *self.0.get();
+        if heap.end == 0 {
+            heap.start = memory::brk(ptr::null_mut()) as usize;
+            heap.next = heap.start;
+            heap.end = heap.start;
+        }
+        let Some(block) = heap.next.checked_add(layout.align() - 1) else {
+            return ptr::null_mut();
+        };
+        let block = block & !(layout.align() - 1);
+        let Some(block_end) = block.checked_add(layout.size()) else {
+            return ptr::null_mut();
+        };
+        if block_end > heap.end {
+            let end = block_end.max(heap.end + HEAP_GROWTH);
+            if memory::brk(end as *mut u8) as usize != end {
+                return ptr::null_mut();
+            }
+            heap.end = end;
+        }
+        heap.next = block_end;
+        heap.count += 1;
+        block as *mut u8
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let heap = &mut *self.0.get();
+        heap.count -= 1;
+        if heap.count == 0 {
+            heap.next = heap.start;
+        } else if ptr as usize + layout.size() == heap.next {
+            heap.next = ptr as usize;
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//! The raw system calls. The kernel takes the number in `rax`, the
+//! arguments in `rdi`, `rsi` and `rdx`, and returns the result in `rax`,
+//! an error as its negated `Errno`.
+
+use core::arch::asm;
+
+pub const READ: u64 = 0;
+pub const WRITE: u64 = 1;
+pub const OPEN: u64 = 2;
+pub const CLOSE: u64 = 3;
+pub const SEEK: u64 = 8;
+pub const MMAP: u64 = 9;
+pub const MUNMAP: u64 = 11;
+pub const BRK: u64 = 12;
+pub const SLEEP: u64 = 35;
+pub const GETPID: u64 = 39;
+pub const SPAWN: u64 = 56;
+pub const FORK: u64 = 57;
+pub const EXEC: u64 = 59;
+pub const EXIT: u64 = 60;
+pub const WAIT: u64 = 61;
+pub const GETPPID: u64 = 110;
+pub const SYNC: u64 = 162;
+
+/// An error of a system call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Errno(pub u64);
+
+impl Errno {
+    pub const ENOENT: Errno = Errno(2);
+    pub const EIO: Errno = Errno(5);
+    pub const ENOEXEC: Errno = Errno(8);
+    pub const EBADF: Errno = Errno(9);
+    pub const ECHILD: Errno = Errno(10);
+    pub const ENOMEM: Errno = Errno(12);
+    pub const EFAULT: Errno = Errno(14);
+    pub const EINVAL: Errno = Errno(22);
+    pub const ESPIPE: Errno = Errno(29);
+    pub const ENOSYS: Errno = Errno(38);
+}
+
+/// The largest error number, results above `-MAX_ERRNO` are values.
+const MAX_ERRNO: u64 = 4095;
+
+/// Split the result of a system call into its value or error.
+pub fn check(ret: u64) -> Result<u64, Errno> {
+    if ret > u64::MAX - MAX_ERRNO {
+        Err(Errno(ret.wrapping_neg()))
+    } else {
+        Ok(ret)
+    }
+}
+
+/// # Safety
+/// The system call must not break what the program assumes of its memory.
+pub unsafe fn syscall0(nr: u64) -> u64 {
+    syscall3(nr, 0, 0, 0)
+}
+
+/// # Safety
+/// As for `syscall0`.
+pub unsafe fn syscall1(nr: u64, a0: u64) -> u64 {
+    syscall3(nr, a0, 0, 0)
+}
+
+/// # Safety
+/// As for `syscall0`.
+pub unsafe fn syscall2(nr: u64, a0: u64, a1: u64) -> u64 {
+    syscall3(nr, a0, a1, 0)
+}
+
+/// # Safety
+/// As for `syscall0`, and pointers given must be valid for the call.
+pub unsafe fn syscall3(nr: u64, a0: u64, a1: u64, a2: u64) -> u64 {
+    let ret;
+    // `syscall` puts the user `rip` and `rflags` in `rcx` and `r11`
+    asm!(
+        "syscall",
+        inlateout("rax") nr => ret,
+        in("rdi") a0,
+        in("rsi") a1,
+        in("rdx") a2,
+        lateout("rcx") _,
+        lateout("r11") _,
+        options(nostack),
+    );
+    ret
+}
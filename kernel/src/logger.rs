@@ -1,3 +1,4 @@
+use crate::memory::vmalloc;
 use alloc::format;
 use bootloader_lib::GraphicInfo;
 use core::fmt::Write;
@@ -7,6 +8,7 @@ use noto_sans_mono_bitmap::{get_bitmap, BitmapChar, BitmapHeight, FontWeight};
 use spin::Mutex;
 use uart_16550::SerialPort;
 use uefi::proto::console::gop::PixelFormat;
+use x86_64::PhysAddr;
 
 pub static mut LOGGER: Option<LockedLogger> = None;
 // TODO move to `drivers`
@@ -26,6 +28,27 @@ pub fn init(info: GraphicInfo) {
     log::set_max_level(log::STATIC_MAX_LEVEL);
 }
 
+/// Move the framebuffer from the identity mapping of the bootloader to the
+/// kernel half, which the address spaces of user programs share.
+pub fn map_framebuffer() {
+    let logger = unsafe { LOGGER.as_ref().unwrap() };
+    let (addr, len) = {
+        let logger = logger.0.lock();
+        (logger.framebuffer.as_ptr() as u64, logger.framebuffer.len())
+    };
+    // not under the lock, a panic logs
+    let virt = vmalloc::map_mmio(PhysAddr::new(addr), len).expect("failed to map the framebuffer");
+    logger.0.lock().framebuffer =
+        unsafe { &mut *ptr::slice_from_raw_parts_mut(virt.as_mut_ptr(), len) };
+}
+
+/// Write `s` as is, for the output of user programs.
+pub fn print(s: &str) {
+    if let Some(logger) = unsafe { LOGGER.as_ref() } {
+        let _ = logger.0.lock().write_str(s);
+    }
+}
+
 pub struct LockedLogger(Mutex<Logger>);
 
 impl LockedLogger {
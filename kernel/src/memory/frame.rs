@@ -1,33 +1,110 @@
+//! Buddy allocator of physical frames.
+//!
+//! Free blocks of 2^order frames are kept in a list per order and zone,
+//! linked through the blocks themselves in the physical memory mapping.
+//! A byte per frame holds the order of the free block starting there, for
+//! a freed block to find its buddy. Blocks are aligned to their size, so a
+//! range serves DMA that wants alignment too.
+
+use super::{to_virt_addr, PAGE_SIZE};
 use alloc::vec::Vec;
 use core::fmt::{Debug, Formatter};
 use spin::Mutex;
 use x86_64::structures::paging::PhysFrame;
+use x86_64::PhysAddr;
+
+/// Blocks of 1 up to 4096 frames, 16 MiB.
+const ORDERS: usize = 13;
+/// Order byte of frames not starting a free block.
+const NOT_FREE: u8 = 0xff;
+/// End of the list, frame numbers are used as links.
+const NONE: u64 = u64::MAX;
+/// The first frame above 4 GiB.
+const DMA32_LIMIT: u64 = 1 << 20;
+
+static FRAME: Mutex<FrameAllocator> = Mutex::new(FrameAllocator::empty());
 
-static mut FRAME: Option<Mutex<FrameAllocator>> = None;
+/// Take over the free `ranges`, the first frames of one of them hold the
+/// order bytes.
+///
+/// # Safety
+/// The ranges must be unused and mapped in the physical memory mapping.
+pub unsafe fn init_frame(mut ranges: Vec<MemoryRange>) {
+    // frame 0 is never handed out, a null physical address is suspicious
+    ranges.retain_mut(|range| {
+        if range.start.start_address().as_u64() == 0 {
+            range.start += 1;
+            range.pages -= 1;
+        }
+        range.pages > 0
+    });
+    let frames = ranges
+        .iter()
+        .map(|range| number(range.end_frame()) + 1)
+        .max()
+        .unwrap_or(0);
+    let meta_pages = (frames + PAGE_SIZE as u64 - 1) / PAGE_SIZE as u64;
+    let meta = ranges
+        .iter_mut()
+        .find(|range| range.pages > meta_pages)
+        .expect("no memory for the frame allocator");
+    let orders = to_virt_addr(meta.start.start_address().as_u64()).as_mut_ptr();
+    meta.start += meta_pages;
+    meta.pages -= meta_pages;
 
-pub unsafe fn init_frame(vec: Vec<MemoryRange>) {
-    FRAME = Some(Mutex::new(FrameAllocator::new(vec)));
+    let mut fa = FrameAllocator::new(0, orders, frames);
+    for range in ranges.iter() {
+        fa.free_range(number(range.start), range.pages, true);
+    }
+    *FRAME.lock() = fa;
 }
 
 pub fn alloc() -> Option<PhysFrame> {
-    unsafe {
-        let mut fa = FRAME.as_ref().unwrap().lock();
-        fa.allocate()
-    }
+    alloc_range(1)
 }
 
+/// Allocate `pages` contiguous frames aligned to `pages` rounded up to a
+/// power of two. Memory below 4 GiB is used last.
 pub fn alloc_range(pages: u64) -> Option<PhysFrame> {
-    unsafe {
-        let mut fa = FRAME.as_ref().unwrap().lock();
-        fa.alloc_range(pages)
-    }
+    let mut fa = FRAME.lock();
+    fa.alloc_range(pages, Zone::Normal)
+        .or_else(|| fa.alloc_range(pages, Zone::Dma32))
+        .map(frame)
+}
+
+/// Like `alloc_range`, below 4 GiB for devices with 32-bit DMA addresses.
+pub fn alloc_range_dma32(pages: u64) -> Option<PhysFrame> {
+    FRAME.lock().alloc_range(pages, Zone::Dma32).map(frame)
 }
 
 pub fn dealloc(addr: PhysFrame) {
-    unsafe {
-        let mut fa = FRAME.as_ref().unwrap().lock();
-        fa.deallocate(addr)
-    }
+    dealloc_range(addr, 1);
+}
+
+/// Free `pages` frames from `start`, not necessarily allocated together.
+pub fn dealloc_range(start: PhysFrame, pages: u64) {
+    FRAME.lock().free_range(number(start), pages, false);
+}
+
+/// Frames in each zone, and how many of them are free.
+pub fn stats() -> [ZoneStats; 2] {
+    let fa = FRAME.lock();
+    [Zone::Dma32, Zone::Normal].map(|zone| {
+        let area = &fa.zones[zone as usize];
+        ZoneStats {
+            zone,
+            total: area.total,
+            free: area.free,
+        }
+    })
+}
+
+fn number(frame: PhysFrame) -> u64 {
+    frame.start_address().as_u64() / PAGE_SIZE as u64
+}
+
+fn frame(number: u64) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(number * PAGE_SIZE as u64))
 }
 
 #[derive(Eq, PartialEq)]
@@ -61,125 +138,183 @@ impl Drop for Frame {
     }
 }
 
-// TODO bitmap allocator
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    /// Below 4 GiB.
+    Dma32,
+    Normal,
+}
+
+impl Zone {
+    fn of(number: u64) -> Self {
+        if number < DMA32_LIMIT {
+            Zone::Dma32
+        } else {
+            Zone::Normal
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ZoneStats {
+    pub zone: Zone,
+    /// In frames.
+    pub total: u64,
+    pub free: u64,
+}
+
+/// The links of a free block, in its first frame.
+struct Node {
+    prev: u64,
+    next: u64,
+}
+
+#[derive(Clone, Copy)]
+struct FreeArea {
+    /// First free block of each order.
+    heads: [u64; ORDERS],
+    total: u64,
+    free: u64,
+}
+
 pub struct FrameAllocator {
-    segments: Vec<MemoryRange>,
+    zones: [FreeArea; 2],
+    /// The first frame covered.
+    base: u64,
+    /// Order of the free block starting at each frame.
+    orders: *mut u8,
+    frames: u64,
 }
 
+unsafe impl Send for FrameAllocator {}
+
 impl FrameAllocator {
-    pub fn new(vec: Vec<MemoryRange>) -> FrameAllocator {
-        FrameAllocator { segments: vec }
+    const fn empty() -> Self {
+        Self {
+            zones: [FreeArea {
+                heads: [NONE; ORDERS],
+                total: 0,
+                free: 0,
+            }; 2],
+            base: 0,
+            orders: core::ptr::null_mut(),
+            frames: 0,
+        }
     }
 
-    fn allocate(&mut self) -> Option<PhysFrame> {
-        if self.segments.len() == 0 {
-            return None;
-        }
-        let addr = self.segments[0].start;
-        if self.segments[0].pages == 1 {
-            self.segments.remove(0);
-        } else {
-            self.segments[0].start += 1;
-            self.segments[0].pages -= 1;
+    /// An allocator of no frames yet, for frames `base..base + frames`
+    /// whose order bytes are at `orders`.
+    ///
+    /// # Safety
+    /// `orders` must be valid for `frames` bytes and unused elsewhere.
+    pub unsafe fn new(base: u64, orders: *mut u8, frames: u64) -> Self {
+        core::ptr::write_bytes(orders, NOT_FREE, frames as usize);
+        Self {
+            base,
+            orders,
+            frames,
+            ..Self::empty()
         }
-        return Some(addr);
     }
 
-    fn allocate_frame(&mut self) -> Option<Frame> {
-        self.allocate()
-            .and_then(|phys_frame| Some(Frame(phys_frame)))
+    fn covers(&self, number: u64) -> bool {
+        number >= self.base && number - self.base < self.frames
     }
 
-    fn alloc_range(&mut self, pages: u64) -> Option<PhysFrame> {
-        let idx = self
-            .segments
-            .iter()
-            .enumerate()
-            .find(|(_, seg)| seg.pages >= pages);
-        return if idx.is_none() {
-            None
-        } else {
-            let id = idx.unwrap().0;
-            let addr = self.segments[id].start;
-            if self.segments[id].pages == pages {
-                self.segments.remove(id);
-            } else {
-                self.segments[id].start += pages;
-                self.segments[id].pages -= pages;
-            }
-            Some(addr)
-        };
+    fn order(&self, number: u64) -> u8 {
+        assert!(self.covers(number));
+        unsafe { *self.orders.add((number - self.base) as usize) }
     }
 
-    fn deallocate(&mut self, addr: PhysFrame) {
-        if self.segments.len() == 0 {
-            self.segments.push(MemoryRange {
-                start: addr,
-                pages: 1,
-            });
-            return;
+    fn set_order(&mut self, number: u64, order: u8) {
+        assert!(self.covers(number));
+        unsafe { *self.orders.add((number - self.base) as usize) = order }
+    }
+
+    fn node(number: u64) -> &'static mut Node {
+        let addr = to_virt_addr(number * PAGE_SIZE as u64);
+        unsafe { &mut *addr.as_mut_ptr() }
+    }
+
+    fn push(&mut self, number: u64, order: usize) {
+        let head = &mut self.zones[Zone::of(number) as usize].heads[order];
+        if *head != NONE {
+            Self::node(*head).prev = number;
         }
-        let not = 0;
-        let left = 1;
-        let right = 2;
-        let mut ok = not;
-        let mut index = -1;
-        for (i, segment) in self.segments.iter_mut().enumerate() {
-            if segment.end_frame() + 1 < addr {
-                continue;
-            }
-            if segment.end_frame() + 1 == addr {
-                index = i as i32;
-                ok = right;
-                segment.pages += 1;
-                break;
-            }
-            if segment.start == addr + 1 {
-                index = i as i32;
-                ok = left;
-                segment.start = addr;
-                segment.pages += 1;
-                break;
-            }
-            if segment.start > addr + 1 {
-                index = i as i32;
-                break;
-            }
+        *Self::node(number) = Node {
+            prev: NONE,
+            next: *head,
+        };
+        *head = number;
+        self.set_order(number, order as u8);
+    }
+
+    fn remove(&mut self, number: u64, order: usize) {
+        let Node { prev, next } = *Self::node(number);
+        if prev == NONE {
+            self.zones[Zone::of(number) as usize].heads[order] = next;
+        } else {
+            Self::node(prev).next = next;
         }
-        if ok == not && index != -1 {
-            self.segments.insert(
-                index as usize,
-                MemoryRange {
-                    start: addr,
-                    pages: 1,
-                },
-            );
-            return;
+        if next != NONE {
+            Self::node(next).prev = prev;
         }
-        if ok == not && index == -1 {
-            self.segments.push(MemoryRange {
-                start: addr,
-                pages: 1,
-            });
-            return;
+        self.set_order(number, NOT_FREE);
+    }
+
+    fn alloc_block(&mut self, order: usize, zone: Zone) -> Option<u64> {
+        let area = &self.zones[zone as usize];
+        let found = (order..ORDERS).find(|&o| area.heads[o] != NONE)?;
+        let number = area.heads[found];
+        self.remove(number, found);
+        // split, keeping the lower half
+        for o in (order..found).rev() {
+            self.push(number + (1 << o), o);
         }
-        // ok
-        if ok == left && index != 0 {
-            if self.segments[(index - 1) as usize].end_frame() + 1
-                == self.segments[index as usize].start
-            {
-                self.segments[(index - 1) as usize].pages += self.segments[index as usize].pages;
-                self.segments.remove(index as usize);
+        self.zones[zone as usize].free -= 1 << order;
+        Some(number)
+    }
+
+    fn free_block(&mut self, mut number: u64, mut order: usize) {
+        assert_eq!(self.order(number), NOT_FREE, "frame freed twice");
+        let zone = Zone::of(number);
+        self.zones[zone as usize].free += 1 << order;
+        while order + 1 < ORDERS {
+            let buddy = number ^ (1 << order);
+            if !self.covers(buddy) || self.order(buddy) != order as u8 {
+                break;
             }
+            self.remove(buddy, order);
+            number = number.min(buddy);
+            order += 1;
+        }
+        self.push(number, order);
+    }
+
+    fn alloc_range(&mut self, pages: u64, zone: Zone) -> Option<u64> {
+        let order = pages.max(1).next_power_of_two().trailing_zeros() as usize;
+        if order >= ORDERS {
+            return None;
         }
-        if ok == right && (index as usize) != self.segments.len() - 1 {
-            if self.segments[index as usize].end_frame() + 1
-                == self.segments[(index + 1) as usize].start
-            {
-                self.segments[index as usize].pages += self.segments[(index + 1) as usize].pages;
-                self.segments.remove((index + 1) as usize);
+        let number = self.alloc_block(order, zone)?;
+        // the rest of the block is not needed
+        self.free_range(number + pages, (1 << order) - pages, false);
+        Some(number)
+    }
+
+    /// Free frames as the largest aligned blocks they make up, counting
+    /// them into their zone if they are `new`.
+    fn free_range(&mut self, mut number: u64, mut pages: u64, new: bool) {
+        while pages > 0 {
+            let order = (number.trailing_zeros() as usize)
+                .min(63 - pages.leading_zeros() as usize)
+                .min(ORDERS - 1);
+            if new {
+                self.zones[Zone::of(number) as usize].total += 1 << order;
             }
+            self.free_block(number, order);
+            number += 1 << order;
+            pages -= 1 << order;
         }
     }
 }
@@ -188,153 +323,36 @@ impl FrameAllocator {
 mod test {
     use super::*;
     use alloc::vec;
-    use x86_64::structures::paging::{PhysFrame, Size4KiB};
-    use x86_64::PhysAddr;
 
     #[test_case]
-    fn case_1() {
-        let mut fa = FrameAllocator {
-            segments: vec![
-                MemoryRange {
-                    start: PhysFrame::containing_address(PhysAddr::new(1 << 12)),
-                    pages: 10,
-                },
-                MemoryRange {
-                    start: PhysFrame::containing_address(PhysAddr::new(200 << 12)),
-                    pages: 1,
-                },
-                MemoryRange {
-                    start: PhysFrame::containing_address(PhysAddr::new(203 << 12)),
-                    pages: 218,
-                },
-                MemoryRange {
-                    start: PhysFrame::containing_address(PhysAddr::new(500 << 12)),
-                    pages: 101,
-                },
-                MemoryRange {
-                    start: PhysFrame::containing_address(PhysAddr::new(602 << 12)),
-                    pages: 1,
-                },
-                MemoryRange {
-                    start: PhysFrame::containing_address(PhysAddr::new(1000 << 12)),
-                    pages: 31,
-                },
-            ],
-        };
+    fn test_buddy() {
+        let frames = 256;
+        let start = alloc_range(frames).unwrap();
+        let base = number(start);
+        let orders = vec![0u8; frames as usize].leak();
+        let mut fa = unsafe { FrameAllocator::new(base, orders.as_mut_ptr(), frames) };
+        fa.free_range(base, frames, true);
+        let zone = Zone::of(base);
+        assert_eq!(fa.zones[zone as usize].free, frames);
+        assert_eq!(fa.zones[zone as usize].heads[8], base);
 
-        fa.allocate();
-        assert_eq!(
-            fa.segments[0],
-            MemoryRange {
-                start: PhysFrame::containing_address(PhysAddr::new(2 << 12)),
-                pages: 9,
-            }
-        );
-
-        fa.deallocate(PhysFrame::containing_address(PhysAddr::new(11 << 12)));
-        assert_eq!(
-            fa.segments[0],
-            MemoryRange {
-                start: PhysFrame::containing_address(PhysAddr::new(2 << 12)),
-                pages: 10,
-            }
-        );
-
-        fa.deallocate(PhysFrame::containing_address(PhysAddr::new(1 << 12)));
-        assert_eq!(
-            fa.segments[0],
-            MemoryRange {
-                start: PhysFrame::containing_address(PhysAddr::new(1 << 12)),
-                pages: 11,
-            }
-        );
-
-        fa.deallocate(PhysFrame::containing_address(PhysAddr::new(0)));
-        assert_eq!(
-            fa.segments[0],
-            MemoryRange {
-                start: PhysFrame::containing_address(PhysAddr::new(0)),
-                pages: 12,
-            }
-        );
-        fa.allocate();
-        fa.allocate();
-        fa.deallocate(PhysFrame::containing_address(PhysAddr::new(0)));
-        assert_eq!(
-            fa.segments[0],
-            MemoryRange {
-                start: PhysFrame::containing_address(PhysAddr::new(0)),
-                pages: 1,
-            }
-        );
-        assert_eq!(
-            fa.segments[1],
-            MemoryRange {
-                start: PhysFrame::containing_address(PhysAddr::new(2 << 12)),
-                pages: 10,
-            }
-        );
-        fa.allocate();
-
-        fa.deallocate(PhysFrame::containing_address(PhysAddr::new(199 << 12)));
-        assert_eq!(
-            fa.segments[1],
-            MemoryRange {
-                start: PhysFrame::containing_address(PhysAddr::new(199 << 12)),
-                pages: 2,
-            }
-        );
-
-        fa.segments[1].start = PhysFrame::containing_address(PhysAddr::new(200 << 12));
-        fa.segments[1].pages -= 1;
-        fa.deallocate(PhysFrame::containing_address(PhysAddr::new(201 << 12)));
-        assert_eq!(
-            fa.segments[1],
-            MemoryRange {
-                start: PhysFrame::containing_address(PhysAddr::new(200 << 12)),
-                pages: 2,
-            }
-        );
-
-        fa.deallocate(PhysFrame::containing_address(PhysAddr::new(202 << 12)));
-        assert_eq!(
-            fa.segments[1],
-            MemoryRange {
-                start: PhysFrame::containing_address(PhysAddr::new(200 << 12)),
-                pages: 221,
-            }
-        );
-        assert_eq!(
-            fa.segments[2],
-            MemoryRange {
-                start: PhysFrame::containing_address(PhysAddr::new(500 << 12)),
-                pages: 101,
-            }
-        );
-        fa.deallocate(PhysFrame::containing_address(PhysAddr::new(450 << 12)));
-        fa.deallocate(PhysFrame::containing_address(PhysAddr::new(601 << 12)));
-        assert_eq!(
-            fa.segments[3],
-            MemoryRange {
-                start: PhysFrame::containing_address(PhysAddr::new(500 << 12)),
-                pages: 103,
-            }
-        );
-        assert_eq!(
-            fa.segments[4],
-            MemoryRange {
-                start: PhysFrame::containing_address(PhysAddr::new(1000 << 12)),
-                pages: 31,
-            }
-        );
-
-        fa.deallocate(PhysFrame::containing_address(PhysAddr::new(2000 << 12)));
-        assert_eq!(
-            fa.segments[fa.segments.len() - 1],
-            MemoryRange {
-                start: PhysFrame::containing_address(PhysAddr::new(2000 << 12)),
-                pages: 1,
-            }
-        );
+        let a = fa.alloc_range(3, zone).unwrap();
+        let b = fa.alloc_range(1, zone).unwrap();
+        let c = fa.alloc_range(16, zone).unwrap();
+        assert_eq!(a % 4, 0);
+        assert_eq!(b, a + 3);
+        assert_eq!(c % 16, 0);
+        assert_eq!(fa.zones[zone as usize].free, frames - 20);
+        assert!(fa.alloc_range(256, zone).is_none());
+
+        fa.free_range(a, 3, false);
+        fa.free_range(c, 16, false);
+        fa.free_range(b, 1, false);
+        // merged back into one block
+        assert_eq!(fa.zones[zone as usize].free, frames);
+        assert_eq!(fa.zones[zone as usize].total, frames);
+        assert_eq!(fa.zones[zone as usize].heads[8], base);
+        assert_eq!(fa.alloc_range(256, zone), Some(base));
+        dealloc_range(start, frames);
     }
 }
@@ -1,5 +1,7 @@
 pub mod frame;
 pub mod page;
+pub mod space;
+pub mod vmalloc;
 
 use alloc::vec::Vec;
 use frame::MemoryRange;
@@ -11,22 +13,45 @@ use crate::memory::frame::init_frame;
 
 static mut PHYSICAL_MEMORY_OFFSET: u64 = 0;
 static mut KERNEL_P4_TABLE: u64 = 0;
+static mut LOW_FRAME: Option<PhysFrame> = None;
 
 pub const PAGE_SIZE: usize = 1 << 12;
+/// Memory below is left out of the frame allocator: real mode code runs
+/// there, see `low_frame`.
+const LOW_MEMORY_END: u64 = 0x10_0000;
 
 pub fn init(offset: u64, descriptors: &Vec<&MemoryDescriptor>) {
+    let conventional = descriptors
+        .iter()
+        .filter(|x| x.ty == MemoryType::CONVENTIONAL)
+        .map(|x| (x.phys_start, x.phys_start + x.page_count * PAGE_SIZE as u64));
     unsafe {
         PHYSICAL_MEMORY_OFFSET = offset;
         KERNEL_P4_TABLE = Cr3::read().0.start_address().as_u64();
-        init_frame(descriptors
-            .iter()
-            .filter(|x| x.ty == MemoryType::CONVENTIONAL)
-            .map(|x| MemoryRange {
-                start: PhysFrame::containing_address(PhysAddr::new(x.phys_start)),
-                pages: x.page_count,
+        // past page 0, the real mode interrupt vectors
+        LOW_FRAME = conventional.clone().find_map(|(start, end)| {
+            let start = start.max(PAGE_SIZE as u64);
+            (start < end.min(LOW_MEMORY_END))
+                .then(|| PhysFrame::containing_address(PhysAddr::new(start)))
+        });
+        init_frame(conventional
+            .filter_map(|(start, end)| {
+                let start = start.max(LOW_MEMORY_END);
+                (start < end).then(|| MemoryRange {
+                    start: PhysFrame::containing_address(PhysAddr::new(start)),
+                    pages: (end - start) / PAGE_SIZE as u64,
+                })
             })
             .collect::<Vec<MemoryRange>>());
+        page::init(PhysAddr::new(KERNEL_P4_TABLE), offset);
     }
+    vmalloc::init();
+}
+
+/// A free frame below 1 MiB, for the real mode code starting the other
+/// CPUs.
+pub fn low_frame() -> Option<PhysFrame> {
+    unsafe { LOW_FRAME }
 }
 
 pub fn to_virt_addr(phys: u64) -> VirtAddr {
@@ -0,0 +1,159 @@
+//! Mapping pages in the kernel page table, the one the bootloader left.
+
+use super::{frame, to_virt_addr, PAGE_SIZE};
+use crate::smp;
+use spin::Mutex;
+use x86_64::structures::paging::mapper::{FlagUpdateError, MapToError, UnmapError};
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB,
+    Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+
+/// Pages `unmap_range` unmaps before a TLB shootdown.
+const UNMAP_BATCH: usize = 32;
+
+/// Uncached, for device registers.
+pub const MMIO_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::NO_CACHE)
+    .union(PageTableFlags::WRITE_THROUGH)
+    .union(PageTableFlags::NO_EXECUTE);
+
+/// Kernel data.
+pub const DATA_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::NO_EXECUTE);
+
+/// Page tables come from the frame allocator.
+pub(super) struct Frames;
+
+unsafe impl FrameAllocator<Size4KiB> for Frames {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        frame::alloc()
+    }
+}
+
+pub(super) fn table(frame: PhysFrame) -> &'static mut PageTable {
+    unsafe { &mut *to_virt_addr(frame.start_address().as_u64()).as_mut_ptr() }
+}
+
+/// # Safety
+/// `p4` must be the active page table, with all of physical memory
+/// mapped at the physical memory offset.
+pub(super) unsafe fn init(p4: PhysAddr, offset: u64) {
+    let p4 = table(PhysFrame::containing_address(p4));
+    *MAPPER.lock() = Some(OffsetPageTable::new(p4, VirtAddr::new(offset)));
+}
+
+fn with_mapper<T>(f: impl FnOnce(&mut OffsetPageTable<'static>) -> T) -> T {
+    f(MAPPER.lock().as_mut().expect("page tables not initialized"))
+}
+
+/// Give the level 4 entry of `addr` a level 3 table if it has none. The
+/// kernel half of every address space is copied from these entries, so
+/// the tables must exist before the first copy.
+pub fn populate_p4_entry(addr: VirtAddr) {
+    with_mapper(|mapper| {
+        let entry = &mut mapper.level_4_table()[addr.p4_index()];
+        if entry.is_unused() {
+            let frame = frame::alloc().expect("no frame for a page table");
+            table(frame).zero();
+            entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        }
+    })
+}
+
+/// Map `pages` pages from `start` to fresh zeroed frames.
+pub fn map_range(
+    start: Page,
+    pages: u64,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    for i in 0..pages {
+        let result = frame::alloc()
+            .ok_or(MapToError::FrameAllocationFailed)
+            .and_then(|frame| {
+                let addr = to_virt_addr(frame.start_address().as_u64());
+                unsafe { core::ptr::write_bytes(addr.as_mut_ptr::<u8>(), 0, PAGE_SIZE) };
+                map_frames(start + i, frame, 1, flags).map_err(|err| {
+                    frame::dealloc(frame);
+                    err
+                })
+            });
+        if let Err(err) = result {
+            // leave nothing behind
+            unmap_range(start, i, true).unwrap();
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// Map `pages` pages from `start` to the frames from `frame` on.
+pub fn map_frames(
+    start: Page,
+    frame: PhysFrame,
+    pages: u64,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    with_mapper(|mapper| {
+        for i in 0..pages {
+            unsafe { mapper.map_to(start + i, frame + i, flags, &mut Frames)? }.flush();
+        }
+        Ok(())
+    })
+}
+
+/// Unmap `pages` pages from `start`, giving their frames back if `free`.
+/// The frames wait for the other CPUs to drop the pages from their TLBs, a
+/// batch at a time: the heap may be what is unmapped, nothing is
+/// allocated.
+pub fn unmap_range(start: Page, pages: u64, free: bool) -> Result<(), UnmapError> {
+    let mut frames = [None; UNMAP_BATCH];
+    let mut done = 0;
+    while done < pages {
+        let batch = (pages - done).min(UNMAP_BATCH as u64);
+        let result = with_mapper(|mapper| {
+            for i in 0..batch {
+                let (frame, flush) = mapper.unmap(start + done + i)?;
+                flush.flush();
+                frames[i as usize] = Some(frame);
+            }
+            Ok(())
+        });
+        smp::shootdown(start + done, batch);
+        for frame in frames.iter_mut().filter_map(Option::take) {
+            if free {
+                frame::dealloc(frame);
+            }
+        }
+        result?;
+        done += batch;
+    }
+    Ok(())
+}
+
+/// Change the flags of `pages` mapped pages from `start`.
+pub fn protect(start: Page, pages: u64, flags: PageTableFlags) -> Result<(), FlagUpdateError> {
+    let result = with_mapper(|mapper| {
+        for i in 0..pages {
+            unsafe { mapper.update_flags(start + i, flags)? }.flush();
+        }
+        Ok(())
+    });
+    smp::shootdown(start, pages);
+    result
+}
+
+pub fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    with_mapper(|mapper| mapper.translate_addr(addr))
+}
+
+/// Pages covering `size` bytes from `addr`.
+pub fn pages_of(addr: u64, size: usize) -> u64 {
+    let end = addr + size as u64;
+    (end + PAGE_SIZE as u64 - 1) / PAGE_SIZE as u64 - addr / PAGE_SIZE as u64
+}
@@ -0,0 +1,500 @@
+//! Address spaces of user programs. The lower half belongs to the program,
+//! the kernel half is the one of the kernel page table: its level 4 entries
+//! are copied, so every address space shares the kernel's level 3 tables.
+//!
+//! A duplicated address space shares its frames with the original, until
+//! either writes to them: writable pages become read-only and `COW` in
+//! both, and get a frame of their own with `unshare`.
+//!
+//! Pages of a file map the frames of the page cache, read-only or `COW`:
+//! the file is never written through them. The last mapping of such a
+//! frame gives it back to the page cache instead of freeing it.
+//!
+//! An address space is only active on the CPU its process runs on, so
+//! changes to the user half flush the local TLB only. The kernel half is
+//! shared by all CPUs, `page` shoots down its changes on the others.
+
+use super::page::{table, Frames};
+use super::{frame, to_virt_addr, KERNEL_P4_TABLE, PAGE_SIZE};
+use crate::fs::page_cache;
+use alloc::collections::BTreeMap;
+use spin::Mutex;
+use x86_64::instructions::tlb;
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::paging::mapper::{FlagUpdateError, MapToError, TranslateResult};
+use x86_64::structures::paging::{
+    Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// The first level 4 entry of the kernel half.
+const KERNEL_P4_START: usize = 256;
+/// The end of the user half.
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+
+/// Marks a page sharing its frame that is to be copied when written.
+pub const COW: PageTableFlags = PageTableFlags::BIT_9;
+/// Marks a page mapping a frame of the page cache.
+pub const CACHED: PageTableFlags = PageTableFlags::BIT_10;
+
+/// Frames shared by duplicated address spaces, with the number of their
+/// mappings beyond the first.
+static SHARED: Mutex<BTreeMap<PhysFrame, u64>> = Mutex::new(BTreeMap::new());
+
+/// Flags of the tables above user pages, the pages themselves restrict.
+const TABLE_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::USER_ACCESSIBLE);
+
+pub struct AddressSpace {
+    p4: PhysFrame,
+}
+
+impl AddressSpace {
+    /// An address space with an empty user half.
+    pub fn new() -> Option<Self> {
+        let p4 = frame::alloc()?;
+        let kernel = table(kernel_p4());
+        let new = table(p4);
+        new.zero();
+        for i in KERNEL_P4_START..512 {
+            new[i] = kernel[i].clone();
+        }
+        Some(Self { p4 })
+    }
+
+    /// A copy of the user half, sharing the frames copy-on-write.
+    pub fn duplicate(&mut self) -> Option<Self> {
+        let new = Self::new()?;
+        let (from, to) = (table(self.p4), table(new.p4));
+        let result = (|| {
+            for (i, entry) in from.iter_mut().enumerate().take(KERNEL_P4_START) {
+                if !entry.is_unused() {
+                    // on failure, `new` frees what was copied
+                    let copy = copy_table(entry.frame().unwrap(), 3)?;
+                    to[i].set_frame(copy, entry.flags());
+                }
+            }
+            Some(())
+        })();
+        // pages of this one may have become read-only, even on failure
+        if Cr3::read().0 == self.p4 {
+            tlb::flush_all();
+        }
+        result.map(|_| new)
+    }
+
+    fn mapper(&self) -> OffsetPageTable<'static> {
+        unsafe { OffsetPageTable::new(table(self.p4), to_virt_addr(0)) }
+    }
+
+    /// Map `pages` pages from `start` to fresh zeroed frames, accessible
+    /// from user mode. On an error the pages mapped before stay, they go
+    /// with the address space.
+    pub fn map_range(
+        &mut self,
+        start: Page,
+        pages: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        assert!(
+            (start + pages).start_address().as_u64() <= USER_END,
+            "mapping the kernel half"
+        );
+        let mut mapper = self.mapper();
+        let flags = flags | PageTableFlags::USER_ACCESSIBLE;
+        for i in 0..pages {
+            let frame = alloc_frame().ok_or(MapToError::FrameAllocationFailed)?;
+            let addr = to_virt_addr(frame.start_address().as_u64());
+            unsafe { core::ptr::write_bytes(addr.as_mut_ptr::<u8>(), 0, PAGE_SIZE) };
+            match unsafe {
+                mapper.map_to_with_table_flags(start + i, frame, flags, TABLE_FLAGS, &mut Frames)
+            } {
+                // not necessarily the active address space, nothing to flush
+                Ok(flush) => flush.ignore(),
+                Err(err) => {
+                    frame::dealloc(frame);
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Map `page` to `frame` of the page cache, pinned for it with
+    /// `page_cache::map_page`. A writable page becomes `COW`, written it
+    /// gets a copy.
+    pub fn map_cached(
+        &mut self,
+        page: Page,
+        frame: PhysFrame,
+        mut flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        assert!(
+            page.start_address().as_u64() < USER_END,
+            "mapping the kernel half"
+        );
+        if flags.contains(PageTableFlags::WRITABLE) {
+            flags.remove(PageTableFlags::WRITABLE);
+            flags.insert(COW);
+        }
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | CACHED;
+        let mut mapper = self.mapper();
+        unsafe { mapper.map_to_with_table_flags(page, frame, flags, TABLE_FLAGS, &mut Frames) }?
+            .ignore();
+        Ok(())
+    }
+
+    /// Unmap what is mapped of `pages` pages from `start`, freeing the
+    /// frames.
+    pub fn unmap_range(&mut self, start: Page, pages: u64) {
+        assert!(
+            (start + pages).start_address().as_u64() <= USER_END,
+            "unmapping the kernel half"
+        );
+        let mut mapper = self.mapper();
+        for i in 0..pages {
+            let Some(flags) = self.flags(start + i) else {
+                continue;
+            };
+            if let Ok((frame, flush)) = mapper.unmap(start + i) {
+                flush.flush();
+                release(frame, flags);
+            }
+        }
+    }
+
+    /// Flags of `page`, if mapped.
+    pub fn flags(&self, page: Page) -> Option<PageTableFlags> {
+        match self.mapper().translate(page.start_address()) {
+            TranslateResult::Mapped { flags, .. } => Some(flags),
+            _ => None,
+        }
+    }
+
+    /// Change the flags of the mapped `page`.
+    pub fn protect(&mut self, page: Page, flags: PageTableFlags) -> Result<(), FlagUpdateError> {
+        let flags = flags | PageTableFlags::USER_ACCESSIBLE;
+        unsafe { self.mapper().update_flags(page, flags)? }.flush();
+        Ok(())
+    }
+
+    /// Give `page` a frame of its own if it shares one or maps the page
+    /// cache, writable if it is `COW`. None if not mapped, or out of memory.
+    pub fn unshare(&mut self, page: Page) -> Option<()> {
+        let mut mapper = self.mapper();
+        let frame = mapper.translate_page(page).ok()?;
+        let old_flags = self.flags(page)?;
+        let mut flags = old_flags - CACHED;
+        let cow = flags.contains(COW);
+        if cow {
+            flags.remove(COW);
+            flags.insert(PageTableFlags::WRITABLE);
+        }
+        if !old_flags.contains(CACHED) && !SHARED.lock().contains_key(&frame) {
+            if cow {
+                unsafe { mapper.update_flags(page, flags) }.ok()?.flush();
+            }
+            return Some(());
+        }
+        // copied before dropping the share, the others may write it then
+        let copy = copy_frame(frame)?;
+        mapper.unmap(page).ok()?.1.flush();
+        unsafe { mapper.map_to_with_table_flags(page, copy, flags, TABLE_FLAGS, &mut Frames) }
+            .expect("page mapped again")
+            .flush();
+        release(frame, old_flags);
+        Some(())
+    }
+
+    /// Copy `bytes` to `addr` of the user half, through the physical memory
+    /// mapping so that read-only pages can be filled. Shared frames are
+    /// copied first. Fails with the first address not mapped.
+    pub fn write(&mut self, addr: VirtAddr, bytes: &[u8]) -> Result<(), VirtAddr> {
+        // all mapped
+        self.copy(addr, bytes.len(), |_, _, _| {})?;
+        if let Some(last) = bytes.len().checked_sub(1) {
+            let first = Page::containing_address(addr);
+            let last = Page::containing_address(addr + last as u64);
+            for page in Page::range_inclusive(first, last) {
+                self.unshare(page).ok_or(page.start_address().max(addr))?;
+            }
+        }
+        self.copy(addr, bytes.len(), |virt, offset, len| unsafe {
+            core::ptr::copy_nonoverlapping(bytes[offset..].as_ptr(), virt, len)
+        })
+    }
+
+    /// Copy from `addr` of the user half to `buf`.
+    pub fn read(&self, addr: VirtAddr, buf: &mut [u8]) -> Result<(), VirtAddr> {
+        self.copy(addr, buf.len(), |virt, offset, len| unsafe {
+            core::ptr::copy_nonoverlapping(virt, buf[offset..].as_mut_ptr(), len)
+        })
+    }
+
+    /// Call `f` with the kernel address of each piece of `len` bytes from
+    /// `addr` within a page, its offset in the bytes and its length.
+    fn copy(
+        &self,
+        addr: VirtAddr,
+        len: usize,
+        mut f: impl FnMut(*mut u8, usize, usize),
+    ) -> Result<(), VirtAddr> {
+        if addr
+            .as_u64()
+            .checked_add(len as u64)
+            .map_or(true, |end| end > USER_END)
+        {
+            return Err(addr);
+        }
+        let mapper = self.mapper();
+        let mut offset = 0;
+        while offset < len {
+            let virt = addr + offset as u64;
+            let phys = mapper.translate_addr(virt).ok_or(virt)?;
+            let piece = (PAGE_SIZE - virt.as_u64() as usize % PAGE_SIZE).min(len - offset);
+            f(to_virt_addr(phys.as_u64()).as_mut_ptr(), offset, piece);
+            offset += piece;
+        }
+        Ok(())
+    }
+
+    /// Switch to this address space.
+    ///
+    /// # Safety
+    /// The kernel must not be using memory of the user half of the
+    /// address space it leaves.
+    pub unsafe fn activate(&self) {
+        switch_to(self.p4);
+    }
+
+    /// The level 4 table, for `switch_to`.
+    pub fn p4(&self) -> PhysFrame {
+        self.p4
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert_ne!(Cr3::read().0, self.p4, "dropping the active address space");
+        for entry in table(self.p4).iter().take(KERNEL_P4_START) {
+            if !entry.is_unused() {
+                free_table(entry.frame().unwrap(), 3);
+            }
+        }
+        frame::dealloc(self.p4);
+    }
+}
+
+/// Free the page table at `frame` of `level`, with the tables and frames
+/// under it.
+fn free_table(frame: PhysFrame, level: u8) {
+    for entry in table(frame).iter().filter(|entry| !entry.is_unused()) {
+        let child = entry.frame().expect("huge page in a user address space");
+        if level > 1 {
+            free_table(child, level - 1);
+        } else {
+            release(child, entry.flags());
+        }
+    }
+    frame::dealloc(frame);
+}
+
+/// Copy the page table at `frame` of `level`, with the tables under it.
+/// The pages share their frames, writable ones become `COW` in both.
+fn copy_table(frame: PhysFrame, level: u8) -> Option<PhysFrame> {
+    let copy = frame::alloc()?;
+    let new = table(copy);
+    new.zero();
+    for (i, entry) in table(frame).iter_mut().enumerate() {
+        if entry.is_unused() {
+            continue;
+        }
+        let child = entry.frame().expect("huge page in a user address space");
+        if level == 1 {
+            let mut flags = entry.flags();
+            if flags.contains(PageTableFlags::WRITABLE) {
+                flags.remove(PageTableFlags::WRITABLE);
+                flags.insert(COW);
+                entry.set_flags(flags);
+            }
+            share(child);
+            new[i].set_frame(child, flags);
+            continue;
+        }
+        match copy_table(child, level - 1) {
+            Some(child) => new[i].set_frame(child, entry.flags()),
+            None => {
+                free_table(copy, level);
+                return None;
+            }
+        }
+    }
+    Some(copy)
+}
+
+fn copy_frame(frame: PhysFrame) -> Option<PhysFrame> {
+    let copy = alloc_frame()?;
+    let from = to_virt_addr(frame.start_address().as_u64());
+    let to = to_virt_addr(copy.start_address().as_u64());
+    unsafe {
+        core::ptr::copy_nonoverlapping(from.as_ptr::<u8>(), to.as_mut_ptr::<u8>(), PAGE_SIZE)
+    };
+    Some(copy)
+}
+
+/// A frame for a user page, evicting pages of the page cache for it when
+/// out of memory.
+fn alloc_frame() -> Option<PhysFrame> {
+    frame::alloc().or_else(|| {
+        page_cache::reclaim(page_cache::RECLAIM_BATCH).ok()?;
+        frame::alloc()
+    })
+}
+
+/// Count one more mapping of the user `frame`.
+fn share(frame: PhysFrame) {
+    *SHARED.lock().entry(frame).or_insert(0) += 1;
+}
+
+/// Drop a mapping of the user `frame` with `flags`, freeing it with the
+/// last, or giving it back to the page cache if `CACHED`.
+fn release(frame: PhysFrame, flags: PageTableFlags) {
+    let mut shared = SHARED.lock();
+    match shared.get_mut(&frame) {
+        Some(1) => {
+            shared.remove(&frame);
+        }
+        Some(count) => *count -= 1,
+        None => {
+            drop(shared);
+            if flags.contains(CACHED) {
+                page_cache::unmap_page(frame, false);
+            } else {
+                frame::dealloc(frame);
+            }
+        }
+    }
+}
+
+fn kernel_p4() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(unsafe { KERNEL_P4_TABLE }))
+}
+
+/// A page table for the other CPUs to turn on paging with: below 4 GiB,
+/// they load it in 32-bit mode, with the kernel half of the kernel page
+/// table and `frame`, the code they run, mapped to itself.
+pub fn boot_table(frame: PhysFrame) -> Option<PhysFrame> {
+    let p4 = frame::alloc_range_dma32(1)?;
+    let (kernel, new) = (table(kernel_p4()), table(p4));
+    new.zero();
+    for i in KERNEL_P4_START..512 {
+        new[i] = kernel[i].clone();
+    }
+    let mut mapper = unsafe { OffsetPageTable::new(new, to_virt_addr(0)) };
+    let page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    match unsafe { mapper.map_to(page, frame, flags, &mut Frames) } {
+        // not active anywhere yet
+        Ok(flush) => flush.ignore(),
+        Err(_) => {
+            free_boot_table(p4, frame);
+            return None;
+        }
+    }
+    Some(p4)
+}
+
+/// Free the tables of `boot_table`, once no CPU uses them.
+pub fn free_boot_table(p4: PhysFrame, frame: PhysFrame) {
+    let addr = VirtAddr::new(frame.start_address().as_u64());
+    let mut current = p4;
+    for index in [addr.p4_index(), addr.p3_index(), addr.p2_index()] {
+        let next = table(current)[index].frame();
+        frame::dealloc(current);
+        match next {
+            Ok(next) => current = next,
+            Err(_) => return,
+        }
+    }
+    frame::dealloc(current);
+}
+
+/// Switch back to the kernel page table.
+///
+/// # Safety
+/// The kernel must not be using memory of the user half it leaves.
+pub unsafe fn activate_kernel() {
+    switch_to(kernel_p4());
+}
+
+/// Switch to the address space of the level 4 table `p4`.
+///
+/// # Safety
+/// As for `AddressSpace::activate`, and `p4` must be the table of a live
+/// address space.
+pub unsafe fn switch_to(p4: PhysFrame) {
+    // reloading flushes the TLB
+    if Cr3::read().0 != p4 {
+        Cr3::write(p4, Cr3Flags::empty());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_address_space() {
+        let free = |stats: [frame::ZoneStats; 2]| stats.iter().map(|zone| zone.free).sum::<u64>();
+        let before = free(frame::stats());
+        let mut space = AddressSpace::new().unwrap();
+        let start = Page::containing_address(VirtAddr::new(0x40_0000));
+        space.map_range(start, 2, PageTableFlags::PRESENT).unwrap();
+        assert!(space
+            .flags(start + 1)
+            .unwrap()
+            .contains(PageTableFlags::USER_ACCESSIBLE));
+        assert!(space.flags(start + 2).is_none());
+
+        // across the page boundary
+        let addr = start.start_address() + PAGE_SIZE as u64 - 3;
+        space.write(addr, b"canyon").unwrap();
+        let mut buf = [0; 8];
+        space.read(addr - 1u64, &mut buf).unwrap();
+        assert_eq!(&buf, b"\0canyon\0");
+        let end = (start + 2).start_address();
+        assert_eq!(space.read(end - 1u64, &mut buf), Err(end));
+        assert!(space.read(VirtAddr::new(USER_END - 4), &mut buf).is_err());
+
+        // the kernel half is there too
+        let kernel = VirtAddr::new(test_address_space as usize as u64);
+        assert_eq!(
+            space.mapper().translate_addr(kernel),
+            super::page::translate(kernel)
+        );
+
+        // a copy, sharing the frames until written
+        space
+            .protect(start, PageTableFlags::PRESENT | PageTableFlags::WRITABLE)
+            .unwrap();
+        let mut copy = space.duplicate().unwrap();
+        for space in [&space, &copy] {
+            let flags = space.flags(start).unwrap();
+            assert!(flags.contains(COW) && !flags.contains(PageTableFlags::WRITABLE));
+            assert!(!space.flags(start + 1).unwrap().contains(COW));
+        }
+        copy.write(addr, b"CANYON").unwrap();
+        assert!(copy
+            .flags(start)
+            .unwrap()
+            .contains(PageTableFlags::WRITABLE));
+        space.read(addr - 1u64, &mut buf).unwrap();
+        assert_eq!(&buf, b"\0canyon\0");
+        copy.read(addr - 1u64, &mut buf).unwrap();
+        assert_eq!(&buf, b"\0CANYON\0");
+        drop(copy);
+        drop(space);
+        assert_eq!(free(frame::stats()), before);
+    }
+}
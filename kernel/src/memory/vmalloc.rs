@@ -0,0 +1,152 @@
+//! Kernel virtual address space for mappings made at runtime: device
+//! registers, stacks and buffers that need not be physically contiguous.
+//!
+//! Every area has an unmapped guard page below it, so running off either
+//! end of an area faults instead of reaching its neighbour.
+
+use super::page::{self, DATA_FLAGS, MMIO_FLAGS};
+use super::PAGE_SIZE;
+use alloc::collections::BTreeMap;
+use spin::Mutex;
+use x86_64::structures::paging::{Page, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// One level 4 entry, 512 GiB.
+const VMALLOC_START: u64 = 0xffff_a000_0000_0000;
+const VMALLOC_PAGES: u64 = 1 << 27;
+
+static AREAS: Mutex<Areas> = Mutex::new(Areas {
+    free: BTreeMap::new(),
+    used: BTreeMap::new(),
+});
+
+struct Area {
+    pages: u64,
+    /// Whether the frames were allocated for the area, to free with it.
+    owned: bool,
+}
+
+/// Ranges of page numbers in the vmalloc region.
+struct Areas {
+    /// Free ranges, by first page.
+    free: BTreeMap<u64, u64>,
+    /// Areas handed out, by first page after the guard page.
+    used: BTreeMap<u64, Area>,
+}
+
+impl Areas {
+    /// Reserve `pages` pages after a guard page, return the first.
+    fn reserve(&mut self, pages: u64) -> Option<u64> {
+        let (&start, &len) = self.free.iter().find(|(_, &len)| len > pages)?;
+        self.free.remove(&start);
+        if len > pages + 1 {
+            self.free.insert(start + pages + 1, len - pages - 1);
+        }
+        Some(start + 1)
+    }
+
+    /// Give back the `pages` pages from `start` and the guard page below.
+    fn release(&mut self, start: u64, pages: u64) {
+        let (mut start, mut len) = (start - 1, pages + 1);
+        if let Some(next) = self.free.remove(&(start + len)) {
+            len += next;
+        }
+        if let Some((&prev, &prev_len)) = self.free.range(..start).next_back() {
+            if prev + prev_len == start {
+                self.free.remove(&prev);
+                start = prev;
+                len += prev_len;
+            }
+        }
+        self.free.insert(start, len);
+    }
+}
+
+fn page(number: u64) -> Page {
+    Page::containing_address(VirtAddr::new(number * PAGE_SIZE as u64))
+}
+
+pub(super) fn init() {
+    page::populate_p4_entry(VirtAddr::new(VMALLOC_START));
+    AREAS
+        .lock()
+        .free
+        .insert(VMALLOC_START / PAGE_SIZE as u64, VMALLOC_PAGES);
+}
+
+/// Map `pages` pages of fresh zeroed memory.
+pub fn vmalloc(pages: u64) -> Option<VirtAddr> {
+    let mut areas = AREAS.lock();
+    let start = areas.reserve(pages)?;
+    if page::map_range(page(start), pages, DATA_FLAGS).is_err() {
+        areas.release(start, pages);
+        return None;
+    }
+    areas.used.insert(start, Area { pages, owned: true });
+    Some(page(start).start_address())
+}
+
+/// Map the device registers of `size` bytes at `addr`, uncached.
+pub fn map_mmio(addr: PhysAddr, size: usize) -> Option<VirtAddr> {
+    let pages = page::pages_of(addr.as_u64(), size);
+    let mut areas = AREAS.lock();
+    let start = areas.reserve(pages)?;
+    let frame = PhysFrame::containing_address(addr);
+    if page::map_frames(page(start), frame, pages, MMIO_FLAGS).is_err() {
+        // stops at the page that failed, after the ones mapped before it
+        let _ = page::unmap_range(page(start), pages, false);
+        areas.release(start, pages);
+        return None;
+    }
+    areas.used.insert(
+        start,
+        Area {
+            pages,
+            owned: false,
+        },
+    );
+    Some(page(start).start_address() + addr.as_u64() % PAGE_SIZE as u64)
+}
+
+/// Unmap the area at `addr`, from `vmalloc` or `map_mmio`.
+pub fn vfree(addr: VirtAddr) {
+    let start = addr.as_u64() / PAGE_SIZE as u64;
+    let mut areas = AREAS.lock();
+    let area = areas.used.remove(&start).expect("vfree of an unknown area");
+    page::unmap_range(page(start), area.pages, area.owned).unwrap();
+    areas.release(start, area.pages);
+}
+
+/// Allocate a stack of `pages` pages with a guard page below, return its
+/// top.
+pub fn alloc_stack(pages: u64) -> Option<VirtAddr> {
+    vmalloc(pages).map(|bottom| bottom + pages * PAGE_SIZE as u64)
+}
+
+/// Free a stack from `alloc_stack` by its top.
+pub fn free_stack(top: VirtAddr, pages: u64) {
+    vfree(top - pages * PAGE_SIZE as u64);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_vmalloc() {
+        let a = vmalloc(4).unwrap();
+        let b = vmalloc(1).unwrap();
+        // the guard page of `b` sits between them
+        assert_eq!(b, a + 5 * PAGE_SIZE as u64);
+        let bytes = unsafe { core::slice::from_raw_parts_mut(a.as_mut_ptr::<u8>(), 4 * PAGE_SIZE) };
+        assert!(bytes.iter().all(|&b| b == 0));
+        bytes.fill(0x5a);
+        assert!(page::translate(a + 4 * PAGE_SIZE as u64).is_none());
+        vfree(a);
+        assert!(page::translate(a).is_none());
+        // the hole is used again
+        assert_eq!(vmalloc(4), Some(a));
+        vfree(a);
+        vfree(b);
+    }
+}
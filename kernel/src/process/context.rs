@@ -0,0 +1,74 @@
+//! Switching between kernel stacks.
+//!
+//! A thread that is not running has its callee-saved registers pushed on its
+//! stack, under the address to return to, and the stack pointer saved in its
+//! `Context`. The caller-saved ones were saved by the compiler around the
+//! call to `switch`, or by the interrupt handler it was called from.
+
+use core::arch::global_asm;
+use core::mem::size_of;
+use x86_64::VirtAddr;
+
+// context_switch(old: *mut u64, new: u64)
+global_asm!(
+    ".global context_switch",
+    "context_switch:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+);
+
+extern "C" {
+    fn context_switch(old: *mut u64, new: u64);
+}
+
+/// The saved stack pointer of a thread not running.
+#[derive(Debug, Default)]
+pub struct Context {
+    rsp: u64,
+}
+
+impl Context {
+    /// The context of a thread that has not run yet, to start in `entry`
+    /// on the stack ending at `top`.
+    pub fn new(top: VirtAddr, entry: extern "C" fn() -> !) -> Self {
+        let frame = [
+            // r15, r14, r13, r12, rbx, rbp
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            entry as usize as u64,
+            // where `entry` would return to, keeps the stack aligned as
+            // after a call
+            0,
+        ];
+        let rsp = top.as_u64() - size_of::<[u64; 8]>() as u64;
+        unsafe { (rsp as *mut [u64; 8]).write(frame) };
+        Self { rsp }
+    }
+}
+
+/// Save the running thread in `old`, continue the one in `new`. Returns
+/// when switched back to `old`.
+///
+/// # Safety
+/// Interrupts must be off, and `new` must be a context saved by `switch`
+/// or made by `Context::new` whose stack is still there.
+pub unsafe fn switch(old: *mut Context, new: *const Context) {
+    context_switch(&mut (*old).rsp, (*new).rsp);
+}
@@ -1,128 +1,225 @@
-use log::info;
-use x86_64::structures::paging::mapper::{MapToError, UnmapError};
-use x86_64::structures::paging::*;
-use x86_64::PhysAddr;
-use xmas_elf::program;
+use super::memory::{Kind, Memory, Vma, MMAP_START};
+use super::Error;
+use crate::fs::page_cache;
+use crate::memory::space::{AddressSpace, USER_END};
+use crate::memory::PAGE_SIZE;
+use alloc::vec;
+use alloc::vec::Vec;
+use log::debug;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+use xmas_elf::header::{Class, Machine, Type};
+use xmas_elf::program::{self, ProgramHeader};
 use xmas_elf::ElfFile;
 
+/// Where the pages of a segment come from: its bytes in the file, zeroes
+/// after them.
+#[derive(Debug, Clone, Copy)]
+pub struct Segment {
+    inode_number: u64,
+    /// The address of the segment.
+    start: u64,
+    /// Where it is in the file, and how much of it.
+    offset: u64,
+    file_size: u64,
+}
+
+impl Segment {
+    /// The inode and page index of the page of the file `page` maps whole,
+    /// to share its frame in the page cache. None if the segment covers
+    /// only part of `page`, or not from the start of a page of the file.
+    pub fn file_page(&self, page: Page) -> Option<(u64, u64)> {
+        let page_start = page.start_address().as_u64();
+        let offset = page_start.checked_sub(self.start)? + self.offset;
+        let whole = page_start + PAGE_SIZE as u64 <= self.start + self.file_size;
+        (whole && offset % PAGE_SIZE as u64 == 0)
+            .then_some((self.inode_number, offset / PAGE_SIZE as u64))
+    }
+
+    /// Copy the bytes of the segment within the mapped `page` to it, for
+    /// the pages `file_page` does not share.
+    pub fn fill(&self, space: &mut AddressSpace, page: Page) -> Result<(), Error> {
+        let page_start = page.start_address().as_u64();
+        let from = page_start.max(self.start);
+        let to = (page_start + PAGE_SIZE as u64).min(self.start + self.file_size);
+        if from >= to {
+            return Ok(());
+        }
+        let mut bytes = [0; PAGE_SIZE];
+        let bytes = &mut bytes[..(to - from) as usize];
+        let read = page_cache::read(self.inode_number, self.offset + from - self.start, bytes)?;
+        if read < bytes.len() {
+            return Err(Error::Elf("segment out of the file"));
+        }
+        space
+            .write(VirtAddr::new(from), bytes)
+            .expect("page not mapped");
+        Ok(())
+    }
+}
+
+/// A `PT_LOAD` segment checked.
+struct Load {
+    segment: Segment,
+    end: u64,
+    /// `WRITABLE` and `NO_EXECUTE` as the segment allows.
+    flags: PageTableFlags,
+}
+
+impl Load {
+    fn first_page(&self) -> Page {
+        Page::containing_address(VirtAddr::new(self.segment.start))
+    }
+
+    fn last_page(&self) -> Page {
+        Page::containing_address(VirtAddr::new(self.end - 1))
+    }
+}
+
+/// Read the headers of the executable `inode_number` of `size` bytes: the
+/// ELF header and the program headers.
+pub fn read_headers(inode_number: u64, size: u64) -> Result<Vec<u8>, Error> {
+    let mut headers = vec![0; size.min(PAGE_SIZE as u64) as usize];
+    page_cache::read(inode_number, 0, &mut headers)?;
+    let elf = ElfFile::new(&headers).map_err(Error::Elf)?;
+    let pt2 = &elf.header.pt2;
+    let end = pt2.ph_offset() + pt2.ph_count() as u64 * pt2.ph_entry_size() as u64;
+    if end > size {
+        return Err(Error::Elf("program headers out of the file"));
+    }
+    if end > headers.len() as u64 {
+        headers = vec![0; end as usize];
+        page_cache::read(inode_number, 0, &mut headers)?;
+    }
+    Ok(headers)
+}
+
+/// Add the `PT_LOAD` segments of the static executable `inode_number` of
+/// `size` bytes, with the headers `elf`, to `memory`. Return its entry point
+/// and the end of its highest segment.
+///
+/// The pages of a segment are read from the file as they are first
+/// touched, but for the ones shared by two segments, filled now.
 pub fn map_elf(
     elf: &ElfFile,
-    // page_table: &mut impl Mapper<Size4KiB>,
-    // frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
-    let user_start = PhysAddr::new(elf.input.as_ptr() as u64);
-    info!("{:?}", user_start);
-    for segment in elf.program_iter() {
-        // map_segment(&segment, user_start, page_table, frame_allocator)?;
+    inode_number: u64,
+    size: u64,
+    memory: &mut Memory,
+) -> Result<(VirtAddr, VirtAddr), Error> {
+    let header = &elf.header;
+    if header.pt1.class() != Class::SixtyFour
+        || header.pt2.machine().as_machine() != Machine::X86_64
+    {
+        return Err(Error::Elf("not an x86_64 ELF"));
     }
-    Ok(())
+    if header.pt2.type_().as_type() != Type::Executable {
+        return Err(Error::Elf("not a static executable"));
+    }
+    let entry = header.pt2.entry_point();
+    if entry >= USER_END {
+        return Err(Error::Elf("entry point in the kernel half"));
+    }
+    let mut loads: Vec<Load> = Vec::new();
+    for header in elf.program_iter() {
+        if header.get_type() == Ok(program::Type::Load) && header.mem_size() > 0 {
+            loads.push(check_segment(&header, inode_number, size, loads.last())?);
+        }
+    }
+
+    for (i, load) in loads.iter().enumerate() {
+        let mut first = load.first_page();
+        let mut last = load.last_page();
+        let shared_before = i > 0 && loads[i - 1].last_page() == first;
+        if shared_before {
+            first += 1;
+        }
+        if let Some(next) = loads.get(i + 1) {
+            if next.first_page() == last {
+                if shared_before && first > last {
+                    return Err(Error::Elf("page shared by three segments"));
+                }
+                map_shared(memory, last, load, next)?;
+                last -= 1;
+            }
+        }
+        if first <= last {
+            memory.insert(Vma {
+                start: first.start_address().as_u64(),
+                end: (last + 1).start_address().as_u64(),
+                flags: load.flags,
+                kind: Kind::File(load.segment),
+            });
+        }
+    }
+    let end = loads.last().map_or(0, |load| load.end);
+    Ok((VirtAddr::new(entry), VirtAddr::new(end)))
+}
+
+/// Check the segment of `header` is below the mmap area and in the file,
+/// after the segment `before`.
+fn check_segment(
+    header: &ProgramHeader,
+    inode_number: u64,
+    size: u64,
+    before: Option<&Load>,
+) -> Result<Load, Error> {
+    debug!("Mapping segment: {:#x?}", header);
+    let start = header.virtual_addr();
+    let (file_size, mem_size) = (header.file_size(), header.mem_size());
+    let end = start
+        .checked_add(mem_size)
+        .filter(|&end| end <= MMAP_START)
+        .ok_or(Error::Elf("segment over the mmap area"))?;
+    if before.map_or(false, |before| start < before.end) {
+        return Err(Error::Elf("segments overlapping or out of order"));
+    }
+    header
+        .offset()
+        .checked_add(file_size)
+        .filter(|&data_end| file_size <= mem_size && data_end <= size)
+        .ok_or(Error::Elf("segment out of the file"))?;
+
+    let flags = header.flags();
+    let mut page_flags = PageTableFlags::empty();
+    if flags.is_write() {
+        page_flags |= PageTableFlags::WRITABLE;
+    }
+    if !flags.is_execute() {
+        page_flags |= PageTableFlags::NO_EXECUTE;
+    }
+    let segment = Segment {
+        inode_number,
+        start,
+        offset: header.offset(),
+        file_size,
+    };
+    Ok(Load {
+        segment,
+        end,
+        flags: page_flags,
+    })
 }
 
-// fn map_segment(
-//     segment: &program::ProgramHeader,
-//     kernel_start: PhysAddr,
-//     page_table: &mut impl Mapper<Size4KiB>,
-//     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-// ) -> Result<(), MapToError<Size4KiB>> {
-//     if segment.get_type().unwrap() != program::Type::Load {
-//         return Ok(());
-//     }
-//     debug!("Mapping segment: {:#x?}", segment);
-//
-//     let mem_size = segment.mem_size(); // Size in bytes of the segment in memory
-//     let file_size = segment.file_size(); // Size in bytes of the segment in the file image
-//     let file_offset = segment.offset() & !0xfff; // Offset of the segment in the file image
-//                                                  // 4K aligned
-//     let phys_start_addr = kernel_start + file_offset;
-//     let virt_start_addr = VirtAddr::new(segment.virtual_addr());
-//
-//     let start_page = Page::containing_address(virt_start_addr);
-//     let start_frame = PhysFrame::containing_address(phys_start_addr);
-//     let end_frame = PhysFrame::containing_address(phys_start_addr + file_size - 1u64);
-//
-//     let flags = segment.flags();
-//     let mut page_table_flags = PageTableFlags::PRESENT;
-//     if !flags.is_execute() {
-//         page_table_flags |= PageTableFlags::NO_EXECUTE
-//     };
-//     if flags.is_write() {
-//         page_table_flags |= PageTableFlags::WRITABLE
-//     };
-//
-//     for frame in PhysFrame::range_inclusive(start_frame, end_frame) {
-//         let offset = frame - start_frame;
-//         let page = start_page + offset;
-//         unsafe {
-//             page_table
-//                 .map_to(page, frame, page_table_flags, frame_allocator)?
-//                 .flush();
-//         }
-//     }
-//
-//     if mem_size > file_size {
-//         // .bss section (or similar), which needs to be zeroed
-//         let zero_start = virt_start_addr + file_size;
-//         let zero_end = virt_start_addr + mem_size;
-//         if zero_start.as_u64() & 0xfff != 0 {
-//             // A part of the last mapped frame needs to be zeroed. This is
-//             // not possible since it could already contains parts of the next
-//             // segment. Thus, we need to copy it before zeroing.
-//             // i.e. maybe there is another page point to this frame
-//
-//             let new_frame = frame_allocator
-//                 .allocate_frame()
-//                 .ok_or(MapToError::FrameAllocationFailed)?;
-//
-//             type PageArray = [u64; Size4KiB::SIZE as usize / 8];
-//
-//             let last_page = Page::containing_address(virt_start_addr + file_size - 1u64);
-//             let last_page_ptr = end_frame.start_address().as_u64() as *mut PageArray;
-//             let temp_page_ptr = new_frame.start_address().as_u64() as *mut PageArray;
-//
-//             unsafe {
-//                 // copy contents
-//                 temp_page_ptr.write(last_page_ptr.read());
-//             }
-//
-//             // remap last page
-//             if let Err(e) = page_table.unmap(last_page.clone()) {
-//                 return Err(match e {
-//                     UnmapError::ParentEntryHugePage => MapToError::ParentEntryHugePage,
-//                     UnmapError::PageNotMapped => unreachable!(),
-//                     UnmapError::InvalidFrameAddress(_) => unreachable!(),
-//                 });
-//             }
-//             unsafe {
-//                 page_table
-//                     .map_to(last_page, new_frame, page_table_flags, frame_allocator)?
-//                     .flush();
-//             }
-//         }
-//
-//         // Map additional frames.
-//         let start_page: Page =
-//             Page::containing_address(VirtAddr::new(align_up(zero_start.as_u64(), Size4KiB::SIZE)));
-//         let end_page = Page::containing_address(zero_end);
-//         for page in Page::range_inclusive(start_page, end_page) {
-//             let frame = frame_allocator
-//                 .allocate_frame()
-//                 .ok_or(MapToError::FrameAllocationFailed)?;
-//             unsafe {
-//                 page_table
-//                     .map_to(page, frame, page_table_flags, frame_allocator)?
-//                     .flush();
-//             }
-//         }
-//
-//         // zero bss
-//         unsafe {
-//             core::ptr::write_bytes(
-//                 zero_start.as_mut_ptr::<u8>(),
-//                 0,
-//                 (mem_size - file_size) as usize,
-//             );
-//         }
-//     }
-//
-//     Ok(())
-// }
+/// Map the `page` shared by the end of `first` and the start of `second`
+/// now, allowing what both do.
+fn map_shared(memory: &mut Memory, page: Page, first: &Load, second: &Load) -> Result<(), Error> {
+    let mut flags = (first.flags | second.flags) & PageTableFlags::WRITABLE;
+    if first.flags.contains(PageTableFlags::NO_EXECUTE)
+        && second.flags.contains(PageTableFlags::NO_EXECUTE)
+    {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    memory
+        .space
+        .map_range(page, 1, flags | PageTableFlags::PRESENT)
+        .map_err(|_| Error::NoMemory)?;
+    first.segment.fill(&mut memory.space, page)?;
+    second.segment.fill(&mut memory.space, page)?;
+    memory.insert(Vma {
+        start: page.start_address().as_u64(),
+        end: (page + 1).start_address().as_u64(),
+        flags,
+        kind: Kind::Anonymous,
+    });
+    Ok(())
+}
@@ -0,0 +1,161 @@
+//! The files a process has open, by descriptor.
+
+use super::syscall::Errno;
+use crate::fs::{self, page_cache};
+use crate::logger;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use log::warn;
+use spin::Mutex;
+
+pub enum File {
+    /// Output to the screen and the serial port, nothing to read.
+    Console,
+    Inode {
+        inode_number: u64,
+        offset: Mutex<u64>,
+    },
+}
+
+/// Where `seek` counts from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Whence {
+    Set,
+    Current,
+    End,
+}
+
+impl TryFrom<u64> for Whence {
+    type Error = Errno;
+
+    fn try_from(value: u64) -> Result<Self, Errno> {
+        match value {
+            0 => Ok(Whence::Set),
+            1 => Ok(Whence::Current),
+            2 => Ok(Whence::End),
+            _ => Err(Errno::EINVAL),
+        }
+    }
+}
+
+impl File {
+    pub fn open(inode_number: u64) -> Self {
+        File::Inode {
+            inode_number,
+            offset: Mutex::new(0),
+        }
+    }
+
+    /// Read at the offset, return the bytes read, 0 at the end.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        match self {
+            File::Console => Ok(0),
+            File::Inode {
+                inode_number,
+                offset,
+            } => {
+                let mut offset = offset.lock();
+                let read = page_cache::read(*inode_number, *offset, buf)?;
+                *offset += read as u64;
+                Ok(read)
+            }
+        }
+    }
+
+    /// Write at the offset.
+    pub fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+        match self {
+            File::Console => logger::print(&String::from_utf8_lossy(buf)),
+            File::Inode {
+                inode_number,
+                offset,
+            } => {
+                let mut offset = offset.lock();
+                page_cache::write(*inode_number, *offset, buf)?;
+                *offset += buf.len() as u64;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    /// Move the offset, return where it is.
+    pub fn seek(&self, delta: i64, whence: Whence) -> Result<u64, Errno> {
+        let File::Inode {
+            inode_number,
+            offset,
+        } = self
+        else {
+            return Err(Errno::ESPIPE);
+        };
+        let mut offset = offset.lock();
+        let base = match whence {
+            Whence::Set => 0,
+            Whence::Current => *offset,
+            Whence::End => fs::size(*inode_number)?,
+        };
+        *offset = base.checked_add_signed(delta).ok_or(Errno::EINVAL)?;
+        Ok(*offset)
+    }
+
+    /// Write back the pages left dirty through the file.
+    pub fn sync(&self) -> Result<(), Errno> {
+        if let File::Inode { inode_number, .. } = self {
+            page_cache::sync_inode(*inode_number)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for File {
+    /// The last descriptor of the file is closed, or the last process
+    /// holding it exited: write back what it left dirty.
+    fn drop(&mut self) {
+        if let File::Inode { inode_number, .. } = self {
+            if let Err(e) = page_cache::sync_inode(*inode_number) {
+                warn!("failed to sync inode {}: {:?}", inode_number, e);
+            }
+        }
+    }
+}
+
+/// Cloned for a forked process, sharing the open files and their offsets.
+#[derive(Clone)]
+pub struct FileTable {
+    files: Vec<Option<Arc<File>>>,
+}
+
+impl FileTable {
+    /// A table with standard input, output and error open.
+    pub fn new() -> Self {
+        let console = Arc::new(File::Console);
+        Self {
+            files: vec![Some(console.clone()), Some(console.clone()), Some(console)],
+        }
+    }
+
+    pub fn get(&self, fd: u64) -> Result<Arc<File>, Errno> {
+        let file = self.files.get(fd as usize).and_then(Option::as_ref);
+        file.cloned().ok_or(Errno::EBADF)
+    }
+
+    /// Add `file` at the lowest free descriptor, return it.
+    pub fn insert(&mut self, file: Arc<File>) -> u64 {
+        match self.files.iter().position(Option::is_none) {
+            Some(fd) => {
+                self.files[fd] = Some(file);
+                fd as u64
+            }
+            None => {
+                self.files.push(Some(file));
+                self.files.len() as u64 - 1
+            }
+        }
+    }
+
+    pub fn remove(&mut self, fd: u64) -> Result<Arc<File>, Errno> {
+        let slot = self.files.get_mut(fd as usize).ok_or(Errno::EBADF)?;
+        slot.take().ok_or(Errno::EBADF)
+    }
+}
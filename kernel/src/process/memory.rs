@@ -0,0 +1,345 @@
+//! The memory of a process: the areas of its address space it may use,
+//! their pages mapped as they are first touched.
+
+use super::elf::Segment;
+use super::syscall::Errno;
+use super::Error;
+use crate::fs::page_cache;
+use crate::memory::space::{AddressSpace, COW, USER_END};
+use crate::memory::PAGE_SIZE;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::{align_down, align_up, VirtAddr};
+
+/// Top of the user stack, the highest page of the user half left unmapped.
+pub const USER_STACK_TOP: u64 = 0x7fff_ffff_f000;
+/// Pages of the stack at the start, it grows down from there on faults.
+const USER_STACK_PAGES: u64 = 16;
+/// The most the stack grows to.
+const USER_STACK_MAX: u64 = 8 << 20;
+/// Where `mmap` starts mapping, the heap grows up to it.
+pub const MMAP_START: u64 = 0x1000_0000_0000;
+
+const PAGE: u64 = PAGE_SIZE as u64;
+
+/// How the memory is accessed, at a fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+/// What the pages of an area start as.
+#[derive(Debug, Clone, Copy)]
+pub enum Kind {
+    /// Zeroed.
+    Anonymous,
+    /// Zeroed, and growing down on faults below it.
+    Stack,
+    /// Part of a segment of the program.
+    File(Segment),
+}
+
+/// A virtual memory area: pages alike from `start` to `end`.
+#[derive(Debug, Clone)]
+pub struct Vma {
+    pub start: u64,
+    pub end: u64,
+    /// The flags of its pages: `WRITABLE` and `NO_EXECUTE`.
+    pub flags: PageTableFlags,
+    pub kind: Kind,
+}
+
+pub struct Memory {
+    pub space: AddressSpace,
+    /// The areas, by start, none overlapping.
+    vmas: BTreeMap<u64, Vma>,
+    /// The start of the heap, after the program, and its end.
+    brk_start: u64,
+    brk: u64,
+    /// Where the next `mmap` maps.
+    mmap_next: u64,
+}
+
+impl Memory {
+    /// An empty user half, but for the stack.
+    pub fn new() -> Option<Self> {
+        let mut memory = Self {
+            space: AddressSpace::new()?,
+            vmas: BTreeMap::new(),
+            brk_start: 0,
+            brk: 0,
+            mmap_next: MMAP_START,
+        };
+        memory.insert(Vma {
+            start: USER_STACK_TOP - USER_STACK_PAGES * PAGE,
+            end: USER_STACK_TOP,
+            flags: PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+            kind: Kind::Stack,
+        });
+        Some(memory)
+    }
+
+    /// Start the heap at `end`, the end of the program.
+    pub fn set_brk_start(&mut self, end: u64) {
+        self.brk_start = align_up(end, PAGE);
+        self.brk = self.brk_start;
+    }
+
+    /// A copy, sharing the pages copy-on-write.
+    pub fn fork(&mut self) -> Option<Self> {
+        Some(Self {
+            space: self.space.duplicate()?,
+            vmas: self.vmas.clone(),
+            brk_start: self.brk_start,
+            brk: self.brk,
+            mmap_next: self.mmap_next,
+        })
+    }
+
+    /// Add `vma`, over no other.
+    pub fn insert(&mut self, vma: Vma) {
+        assert!(vma.start < vma.end && vma.end <= USER_END);
+        assert!(self.free(vma.start, vma.end), "overlapping areas");
+        self.vmas.insert(vma.start, vma);
+    }
+
+    /// The area `addr` is in.
+    pub fn find(&self, addr: u64) -> Option<&Vma> {
+        let (_, vma) = self.vmas.range(..=addr).next_back()?;
+        (addr < vma.end).then_some(vma)
+    }
+
+    /// Whether no area is in `start..end`.
+    fn free(&self, start: u64, end: u64) -> bool {
+        let before = self.vmas.range(..end).next_back();
+        before.map_or(true, |(_, vma)| vma.end <= start)
+    }
+
+    /// Remove the areas in `start..end`, splitting the ones across, and
+    /// unmap their pages.
+    fn remove(&mut self, start: u64, end: u64) {
+        let overlapping: Vec<u64> = self
+            .vmas
+            .range(..end)
+            .filter(|(_, vma)| vma.end > start)
+            .map(|(&key, _)| key)
+            .collect();
+        for key in overlapping {
+            let vma = self.vmas.remove(&key).unwrap();
+            if vma.start < start {
+                let before = Vma {
+                    end: start,
+                    ..vma.clone()
+                };
+                self.vmas.insert(before.start, before);
+            }
+            if vma.end > end {
+                let after = Vma { start: end, ..vma };
+                self.vmas.insert(after.start, after);
+            }
+        }
+        let first = Page::containing_address(VirtAddr::new(start));
+        self.space.unmap_range(first, (end - start) / PAGE);
+    }
+
+    /// Resolve a fault at `addr` of the user half: map the page if in an
+    /// area, or copy it if written copy-on-write. False if the access is not
+    /// allowed.
+    pub fn fault(&mut self, addr: VirtAddr, access: Access) -> bool {
+        let addr = addr.as_u64();
+        if addr >= USER_END || (self.find(addr).is_none() && !self.grow_stack(addr)) {
+            return false;
+        }
+        let vma = self.find(addr).unwrap().clone();
+        let allowed = match access {
+            Access::Read => true,
+            Access::Write => vma.flags.contains(PageTableFlags::WRITABLE),
+            Access::Execute => !vma.flags.contains(PageTableFlags::NO_EXECUTE),
+        };
+        if !allowed {
+            return false;
+        }
+        let page = Page::containing_address(VirtAddr::new(addr));
+        match self.space.flags(page) {
+            None => self.populate(&vma, page).is_ok(),
+            Some(flags) if access == Access::Write && !flags.contains(PageTableFlags::WRITABLE) => {
+                flags.contains(COW) && self.space.unshare(page).is_some()
+            }
+            // mapped since, the kernel faulting it in
+            Some(_) => true,
+        }
+    }
+
+    /// Map the page of `vma` for the first time. A page of a segment that
+    /// is a whole page of the file maps its frame in the page cache.
+    fn populate(&mut self, vma: &Vma, page: Page) -> Result<(), Error> {
+        if let Kind::File(segment) = vma.kind {
+            if let Some((inode_number, index)) = segment.file_page(page) {
+                let frame = page_cache::map_page(inode_number, index)?;
+                return self.space.map_cached(page, frame, vma.flags).map_err(|_| {
+                    page_cache::unmap_page(frame, false);
+                    Error::NoMemory
+                });
+            }
+        }
+        self.space
+            .map_range(page, 1, vma.flags | PageTableFlags::PRESENT)
+            .map_err(|_| Error::NoMemory)?;
+        match vma.kind {
+            Kind::File(segment) => segment.fill(&mut self.space, page),
+            Kind::Anonymous | Kind::Stack => Ok(()),
+        }
+    }
+
+    /// Grow the stack down to `addr`, if just below it.
+    fn grow_stack(&mut self, addr: u64) -> bool {
+        let Some((&key, stack)) = self.vmas.range(addr..).next() else {
+            return false;
+        };
+        let start = align_down(addr, PAGE);
+        // a guard page between it and the area below
+        if !matches!(stack.kind, Kind::Stack)
+            || stack.end - start > USER_STACK_MAX
+            || !self.free(start.saturating_sub(PAGE), key)
+        {
+            return false;
+        }
+        let mut stack = self.vmas.remove(&key).unwrap();
+        stack.start = start;
+        self.vmas.insert(start, stack);
+        true
+    }
+
+    /// Fault in the pages of `len` bytes from `addr`, for the kernel to
+    /// access them like the program would.
+    pub fn prepare(&mut self, addr: VirtAddr, len: usize, access: Access) -> Result<(), Errno> {
+        let Some(last) = len.checked_sub(1) else {
+            return Ok(());
+        };
+        let end = addr
+            .as_u64()
+            .checked_add(last as u64)
+            .filter(|&end| end < USER_END)
+            .ok_or(Errno::EFAULT)?;
+        let first = Page::containing_address(addr);
+        let last = Page::containing_address(VirtAddr::new(end));
+        for page in Page::range_inclusive(first, last) {
+            if !self.fault(page.start_address().max(addr), access) {
+                return Err(Errno::EFAULT);
+            }
+        }
+        Ok(())
+    }
+
+    /// Add an area of `len` bytes of zeroed memory after the last one, with
+    /// a guard page between them, return its address.
+    pub fn mmap(&mut self, len: u64, flags: PageTableFlags) -> Result<u64, Errno> {
+        if len == 0 {
+            return Err(Errno::EINVAL);
+        }
+        let size = len.checked_add(PAGE - 1).ok_or(Errno::ENOMEM)? & !(PAGE - 1);
+        let start = self.mmap_next;
+        let stack_bottom = USER_STACK_TOP - USER_STACK_MAX;
+        let end = start
+            .checked_add(size)
+            .filter(|&end| end + PAGE <= stack_bottom)
+            .ok_or(Errno::ENOMEM)?;
+        self.insert(Vma {
+            start,
+            end,
+            flags,
+            kind: Kind::Anonymous,
+        });
+        self.mmap_next = end + PAGE;
+        Ok(start)
+    }
+
+    /// Remove `len` bytes from `addr`, mapped by `mmap`.
+    pub fn munmap(&mut self, addr: u64, len: u64) -> Result<(), Errno> {
+        let end = addr
+            .checked_add(len)
+            .map(|end| align_up(end, PAGE))
+            .filter(|&end| addr % PAGE == 0 && addr >= MMAP_START && end <= self.mmap_next)
+            .ok_or(Errno::EINVAL)?;
+        self.remove(addr, end);
+        Ok(())
+    }
+
+    /// Move the end of the heap to `end`, return where it is, unmoved if it
+    /// could not.
+    pub fn brk(&mut self, end: u64) -> u64 {
+        if end < self.brk_start || end > MMAP_START {
+            return self.brk;
+        }
+        let mapped = align_up(self.brk, PAGE);
+        let wanted = align_up(end, PAGE);
+        if wanted > mapped {
+            if !self.free(mapped, wanted) {
+                return self.brk;
+            }
+            let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+            // the heap is one area
+            let start = match self.find(mapped.wrapping_sub(1)) {
+                Some(heap) if mapped > self.brk_start && heap.end == mapped => heap.start,
+                _ => mapped,
+            };
+            self.vmas.remove(&start);
+            self.insert(Vma {
+                start,
+                end: wanted,
+                flags,
+                kind: Kind::Anonymous,
+            });
+        } else if wanted < mapped {
+            self.remove(wanted, mapped);
+        }
+        self.brk = end;
+        end
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_faults() {
+        let mut memory = Memory::new().unwrap();
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let addr = memory.mmap(2 * PAGE, flags).unwrap();
+        let page = Page::containing_address(VirtAddr::new(addr));
+        assert!(memory.space.flags(page).is_none());
+        assert!(memory.fault(VirtAddr::new(addr + 8), Access::Write));
+        assert!(memory.space.flags(page).is_some());
+        assert!(!memory.fault(VirtAddr::new(addr), Access::Execute));
+        // the guard page after it
+        assert!(!memory.fault(VirtAddr::new(addr + 2 * PAGE), Access::Read));
+
+        // the stack grows down
+        let bottom = USER_STACK_TOP - USER_STACK_PAGES * PAGE;
+        assert!(memory.fault(VirtAddr::new(bottom - 8), Access::Write));
+        assert_eq!(memory.find(bottom - 8).unwrap().start, bottom - PAGE);
+
+        // the heap
+        memory.set_brk_start(0x40_0000);
+        assert_eq!(memory.brk(0x40_0010), 0x40_0010);
+        assert!(memory.fault(VirtAddr::new(0x40_0008), Access::Write));
+        assert_eq!(memory.brk(0x40_0000), 0x40_0000);
+        assert!(!memory.fault(VirtAddr::new(0x40_0008), Access::Read));
+
+        // copy-on-write
+        memory.space.write(VirtAddr::new(addr), b"canyon").unwrap();
+        let mut child = memory.fork().unwrap();
+        assert!(child.fault(VirtAddr::new(addr), Access::Write));
+        child.space.write(VirtAddr::new(addr), b"CANYON").unwrap();
+        let mut buf = [0; 6];
+        memory.space.read(VirtAddr::new(addr), &mut buf).unwrap();
+        assert_eq!(&buf, b"canyon");
+
+        memory.munmap(addr, 2 * PAGE).unwrap();
+        assert!(!memory.fault(VirtAddr::new(addr), Access::Read));
+    }
+}
@@ -1,63 +1,233 @@
 mod context;
 pub mod elf;
-mod thread;
+pub mod file;
+pub mod memory;
+pub mod scheduler;
+pub mod syscall;
+pub mod table;
+pub mod thread;
 
+use self::file::FileTable;
+use self::memory::{Access, Memory, USER_STACK_TOP};
+use self::syscall::Errno;
 use crate::fs;
-use alloc::string::String;
-use alloc::sync::{Arc, Weak};
-use alloc::vec::Vec;
-use core::cell::RefCell;
-use core::mem;
-use log::info;
+use crate::memory::space;
+use crate::memory::vmalloc;
+use crate::syscall::SyscallFrame;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU64, Ordering};
+use log::{error, info, warn};
 use spin::Mutex;
-use xmas_elf::header::HeaderPt2_;
-use xmas_elf::{ElfFile, P64};
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::{PhysAddr, VirtAddr};
+use xmas_elf::ElfFile;
 
-static mut PROCESS_COUNT: Mutex<u64> = Mutex::new(0);
+/// Pages of the kernel stack of a process, for its system calls and
+/// interrupts.
+const KERNEL_STACK_PAGES: u64 = 8;
+/// The exit code of a process ended by a fault, the one shells report for
+/// `SIGSEGV`.
+const SEGFAULT_CODE: i64 = 139;
+
+static NEXT_PID: AtomicU64 = AtomicU64::new(1);
 
 pub fn next() -> u64 {
-    unsafe {
-        let mut pc = PROCESS_COUNT.lock();
-        *pc += 1;
-        *pc - 1
-    }
+    NEXT_PID.fetch_add(1, Ordering::Relaxed)
 }
 
 pub fn init() {
-    let contents = unsafe { fs::VFS.as_ref().unwrap().read_unstable("/hello").unwrap() };
-    info!("hello len: {}", contents.len());
-    assert!((18446620929482082386 & (mem::align_of::<HeaderPt2_<P64>>() - 1)) == 0);
-    // let elf = ElfFile::new(contents.as_slice()).expect("failed to parse ELF");
-    // elf::map_elf(&elf);
+    if let Err(e) = spawn("/hello", None) {
+        error!("failed to load /hello: {:?}", e);
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Fs(cafs::Error),
+    /// Not an executable the kernel runs.
+    Elf(&'static str),
+    NoMemory,
+}
+
+impl From<cafs::Error> for Error {
+    fn from(e: cafs::Error) -> Self {
+        Error::Fs(e)
+    }
 }
 
 pub struct Process {
-    pid: usize,
-    name: String,
-    page_table: u64,
-}
-
-pub struct Stack {
-    pid: usize,
-}
-
-// pub struct PCB {
-//     pub pid: u64,
-//     pub stack: Stack,
-//     inner: RefCell<PCBInner>,
-// }
-
-// pub struct PCBInner {
-//     pub trap_ctx_ppn: PhysPageNum,
-//     pub base_size: usize,
-//     pub ctx: ProcessContext,
-//     pub status: ProcessStatus,
-//     pub memory_set: MemorySet,
-//     pub parent: Option<Weak<PCB>>,
-//     pub children: Vec<Arc<PCB>>,
-//     pub exit_code: i32,
-// }
-
-struct ProcessList {
-    ready: Vec<Arc<Process>>,
+    pub pid: u64,
+    pub name: String,
+    /// The level 4 table of the address space, for the scheduler to switch
+    /// to without locking.
+    p4: AtomicU64,
+    memory: Mutex<Memory>,
+    kernel_stack: VirtAddr,
+    /// The kernel stack pointer saved by `run`, for `exit` to return to.
+    context: AtomicU64,
+    files: Mutex<FileTable>,
+}
+
+/// A program loaded into memory of its own.
+struct Image {
+    memory: Memory,
+    entry: VirtAddr,
+}
+
+impl Image {
+    /// The registers to start the program with: the stack is zeroed, argc,
+    /// the ends of argv, envp and the auxiliary vector read as empty.
+    fn frame(&self) -> SyscallFrame {
+        SyscallFrame::new(self.entry, VirtAddr::new(USER_STACK_TOP - 64))
+    }
+}
+
+/// Load the executable at `path`: its headers now, the rest as touched.
+fn load_image(path: &str) -> Result<Image, Error> {
+    let inode_number = fs::open(path, false)?;
+    let size = fs::size(inode_number)?;
+    let headers = elf::read_headers(inode_number, size)?;
+    let elf = ElfFile::new(&headers).map_err(Error::Elf)?;
+    let mut memory = Memory::new().ok_or(Error::NoMemory)?;
+    let (entry, end) = elf::map_elf(&elf, inode_number, size, &mut memory)?;
+    memory.set_brk_start(end.as_u64());
+    Ok(Image { memory, entry })
+}
+
+/// Run the executable at `path` in a new process, a child of `parent`,
+/// return its pid.
+pub fn spawn(path: &str, parent: Option<u64>) -> Result<u64, Error> {
+    let image = load_image(path)?;
+    let frame = image.frame();
+    let process = Process::new(path.to_string(), image.memory, FileTable::new())?;
+    Ok(start(process, parent, frame))
+}
+
+/// Run `process`, a child of `parent`, in a thread of its own from the
+/// user registers `frame`. Return its pid.
+fn start(process: Process, parent: Option<u64>, frame: SyscallFrame) -> u64 {
+    let pid = process.pid;
+    let name = process.name.clone();
+    table::insert(pid, parent);
+    thread::spawn(&name.clone(), move || {
+        let code = Arc::new(process).run(&frame);
+        if table::exit(pid, code) {
+            info!("{} exited with {} (pid {})", name, code, pid);
+        }
+    });
+    pid
+}
+
+impl Process {
+    fn new(name: String, memory: Memory, files: FileTable) -> Result<Self, Error> {
+        let kernel_stack = vmalloc::alloc_stack(KERNEL_STACK_PAGES).ok_or(Error::NoMemory)?;
+        Ok(Self {
+            pid: next(),
+            name,
+            p4: AtomicU64::new(memory.space.p4().start_address().as_u64()),
+            memory: Mutex::new(memory),
+            kernel_stack,
+            context: AtomicU64::new(0),
+            files: Mutex::new(files),
+        })
+    }
+
+    /// Run the process in user mode on the current thread from the
+    /// registers `frame`, until it exits. Return its exit code.
+    pub fn run(self: Arc<Self>, frame: &SyscallFrame) -> i64 {
+        let thread = scheduler::current();
+        thread.set_process(Some(self.clone()));
+        let context = self.context.as_ptr();
+        drop(self);
+        let code = unsafe { crate::syscall::run_user(frame, context) };
+        // the last reference, freeing the memory and closing the files
+        thread.set_process(None);
+        code
+    }
+
+    /// The level 4 table of the address space.
+    pub fn p4(&self) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(self.p4.load(Ordering::Relaxed)))
+    }
+
+    /// A copy of the process, with its memory copy-on-write and its open
+    /// files shared.
+    fn fork(&self) -> Result<Self, Error> {
+        let files = self.files.lock().clone();
+        let memory = self.memory.lock().fork().ok_or(Error::NoMemory)?;
+        Self::new(self.name.clone(), memory, files)
+    }
+
+    /// Replace the program with the executable at `path`, return the
+    /// registers to start it with. The open files stay.
+    fn exec(&self, path: &str) -> Result<SyscallFrame, Error> {
+        let image = load_image(path)?;
+        let frame = image.frame();
+        let p4 = image.memory.space.p4();
+        let old = interrupts::without_interrupts(|| {
+            self.p4
+                .store(p4.start_address().as_u64(), Ordering::Relaxed);
+            unsafe { space::switch_to(p4) };
+            core::mem::replace(&mut *self.memory.lock(), image.memory)
+        });
+        // no longer active
+        drop(old);
+        Ok(frame)
+    }
+
+    /// Copy from `addr` in user memory to `buf`.
+    fn read_user(&self, addr: u64, buf: &mut [u8]) -> Result<(), Errno> {
+        let addr = VirtAddr::try_new(addr).map_err(|_| Errno::EFAULT)?;
+        let mut memory = self.memory.lock();
+        memory.prepare(addr, buf.len(), Access::Read)?;
+        memory.space.read(addr, buf).map_err(|_| Errno::EFAULT)
+    }
+
+    /// Copy `bytes` to `addr` in user memory.
+    fn write_user(&self, addr: u64, bytes: &[u8]) -> Result<(), Errno> {
+        let addr = VirtAddr::try_new(addr).map_err(|_| Errno::EFAULT)?;
+        let mut memory = self.memory.lock();
+        memory.prepare(addr, bytes.len(), Access::Write)?;
+        memory.space.write(addr, bytes).map_err(|_| Errno::EFAULT)
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        vmalloc::free_stack(self.kernel_stack, KERNEL_STACK_PAGES);
+    }
+}
+
+/// The process of the current thread, in a system call.
+fn current() -> Arc<Process> {
+    scheduler::current()
+        .process()
+        .expect("system call without a process")
+}
+
+/// Resolve a page fault of the current process in user mode at `addr`,
+/// false if it was not allowed to access it.
+pub fn page_fault(addr: VirtAddr, access: Access) -> bool {
+    let Some(process) = scheduler::current().process() else {
+        return false;
+    };
+    let resolved = process.memory.lock().fault(addr, access);
+    resolved
+}
+
+/// End the current process for a fault at `addr` it made at `rip` in user
+/// mode.
+pub fn segfault(addr: VirtAddr, rip: VirtAddr) -> ! {
+    let context = {
+        let process = current();
+        warn!(
+            "{} (pid {}) segfault at {:?}, rip {:?}",
+            process.name, process.pid, addr, rip
+        );
+        process.context.load(Ordering::Relaxed)
+    };
+    // nothing left on this stack to drop
+    unsafe { crate::syscall::exit_user(context, SEGFAULT_CODE) }
 }
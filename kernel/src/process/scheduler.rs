@@ -0,0 +1,339 @@
+//! Round-robin scheduling of the kernel threads. The timer interrupt
+//! preempts the running thread at the end of its time slice.
+//!
+//! Every CPU has a scheduler of its own, and a thread stays on the CPU it
+//! was added to: new threads take turns at the CPUs. A thread woken from
+//! another CPU is queued there, and the reschedule interrupt tells its CPU.
+//!
+//! The schedulers are locked in the timer interrupt, so always with
+//! interrupts off. Threads and processes are dropped with interrupts on:
+//! freeing them takes locks a preempted thread may hold.
+
+use super::context;
+use super::thread::{State, Thread};
+use crate::cpu::{self, MAX_CPUS};
+use crate::device::timer;
+use crate::memory::space;
+use crate::{smp, syscall};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Timer ticks a thread runs for while others are ready.
+const TIME_SLICE: u64 = 5;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+
+/// The scheduler of each CPU, by index.
+static SCHEDULERS: [Mutex<Option<Scheduler>>; MAX_CPUS] = [NO_SCHEDULER; MAX_CPUS];
+/// Counts the threads added, to spread them over the CPUs.
+static NEXT_CPU: AtomicUsize = AtomicUsize::new(0);
+
+struct Scheduler {
+    current: Arc<Thread>,
+    ready: VecDeque<Arc<Thread>>,
+    /// Threads in `sleep`, with the tick to wake them at.
+    sleeping: Vec<(usize, Arc<Thread>)>,
+    /// Runs when no other thread is ready.
+    idle: Arc<Thread>,
+    /// Threads that exited, to free once off their stacks.
+    dead: Vec<Arc<Thread>>,
+    /// Ticks left of the time slice of the current thread.
+    slice: u64,
+}
+
+impl Scheduler {
+    fn wake(&mut self, thread: Arc<Thread>) {
+        thread.set_state(State::Ready);
+        self.ready.push_back(thread);
+    }
+}
+
+/// Run `f` with the scheduler of the CPU `index`.
+fn with_cpu<T>(index: usize, f: impl FnOnce(&mut Scheduler) -> T) -> T {
+    interrupts::without_interrupts(|| {
+        f(SCHEDULERS[index]
+            .lock()
+            .as_mut()
+            .expect("scheduler not initialized"))
+    })
+}
+
+/// Run `f` with the scheduler of the running CPU.
+fn with_scheduler<T>(f: impl FnOnce(&mut Scheduler) -> T) -> T {
+    with_cpu(cpu::index(), f)
+}
+
+/// Make the running code the boot thread, and start scheduling on the
+/// bootstrap processor.
+pub fn init() {
+    let idle = Thread::with_entry(String::from("idle"), Box::new(|| idle()))
+        .expect("no memory for the idle thread");
+    install(Arc::new(Thread::boot()), Arc::new(idle));
+}
+
+/// Start scheduling on an application processor, the running code its
+/// idle thread.
+pub fn init_ap() {
+    let boot = Arc::new(Thread::boot());
+    install(boot.clone(), boot);
+}
+
+fn install(current: Arc<Thread>, idle: Arc<Thread>) {
+    let index = cpu::index();
+    current.set_cpu(index);
+    idle.set_cpu(index);
+    interrupts::without_interrupts(|| {
+        *SCHEDULERS[index].lock() = Some(Scheduler {
+            current,
+            ready: VecDeque::new(),
+            sleeping: Vec::new(),
+            idle,
+            dead: Vec::new(),
+            slice: TIME_SLICE,
+        });
+    });
+}
+
+/// What the idle thread runs: free the threads that exited, and halt until
+/// the next interrupt.
+pub fn idle() -> ! {
+    loop {
+        reap();
+        interrupts::enable_and_hlt();
+    }
+}
+
+pub fn current() -> Arc<Thread> {
+    with_scheduler(|s| s.current.clone())
+}
+
+/// Make `thread` ready to run, on the next CPU in turn.
+pub(super) fn add(thread: Arc<Thread>) {
+    reap();
+    let index = NEXT_CPU.fetch_add(1, Ordering::Relaxed) % cpu::online();
+    thread.set_cpu(index);
+    with_cpu(index, |s| s.wake(thread));
+    if index != cpu::index() {
+        smp::reschedule(index);
+    }
+}
+
+/// Free the threads that exited.
+fn reap() {
+    let dead = with_scheduler(|s| core::mem::take(&mut s.dead));
+    drop(dead);
+}
+
+/// Switch to the address space of `thread`, and the kernel stack its
+/// process enters the kernel on.
+pub(super) fn activate(thread: &Thread) {
+    match thread.process() {
+        Some(process) => unsafe {
+            space::switch_to(process.p4());
+            syscall::set_kernel_stack(process.kernel_stack);
+        },
+        None => unsafe { space::activate_kernel() },
+    }
+}
+
+/// Give the CPU to the next ready thread, the current one becoming
+/// `state`. Interrupts must be off. A thread blocking is marked `Blocked`
+/// before whoever wakes it can find it, and keeps running if woken before
+/// it gets here.
+fn schedule(state: State) {
+    let (old, new) = {
+        let mut guard = SCHEDULERS[cpu::index()].lock();
+        let s = guard.as_mut().expect("scheduler not initialized");
+        let current = s.current.clone();
+        if state == State::Blocked && current.state() != State::Blocked {
+            s.ready.retain(|thread| !Arc::ptr_eq(thread, &current));
+            current.set_state(State::Running);
+            return;
+        }
+        match state {
+            State::Ready if !Arc::ptr_eq(&current, &s.idle) => s.ready.push_back(current.clone()),
+            State::Exited => s.dead.push(current.clone()),
+            _ => {}
+        }
+        let next = s.ready.pop_front().unwrap_or_else(|| s.idle.clone());
+        s.slice = TIME_SLICE;
+        if Arc::ptr_eq(&next, &current) {
+            return;
+        }
+        current.set_state(state);
+        next.set_state(State::Running);
+        s.current = next.clone();
+        (current, next)
+    };
+    activate(&new);
+    let (old_context, new_context) = (old.context(), new.context());
+    // not the last references: the scheduler holds `new`, and `old` is
+    // held by whoever is to wake or free it. This stack may never resume.
+    drop(old);
+    drop(new);
+    unsafe { context::switch(old_context, new_context) };
+}
+
+/// Called at every timer interrupt: wake the threads whose sleep is over,
+/// and preempt the current one at the end of its time slice.
+pub fn tick() {
+    let preempt = {
+        let mut guard = SCHEDULERS[cpu::index()].lock();
+        let Some(s) = guard.as_mut() else {
+            return;
+        };
+        let now = timer::count();
+        let mut i = 0;
+        while i < s.sleeping.len() {
+            if s.sleeping[i].0 <= now {
+                let (_, thread) = s.sleeping.swap_remove(i);
+                s.wake(thread);
+            } else {
+                i += 1;
+            }
+        }
+        s.slice = s.slice.saturating_sub(1);
+        !s.ready.is_empty() && (s.slice == 0 || Arc::ptr_eq(&s.current, &s.idle))
+    };
+    if preempt {
+        schedule(State::Ready);
+    }
+}
+
+/// Called at the reschedule interrupt: another CPU made threads ready here,
+/// run them if idle.
+pub fn reschedule() {
+    let idle = with_scheduler(|s| !s.ready.is_empty() && Arc::ptr_eq(&s.current, &s.idle));
+    if idle {
+        schedule(State::Ready);
+    }
+}
+
+/// Where a new thread starts, right after the switch to it.
+pub(super) fn started() {
+    interrupts::enable();
+    reap();
+}
+
+/// Let the other ready threads run.
+pub fn yield_now() {
+    interrupts::without_interrupts(|| schedule(State::Ready));
+}
+
+/// Block the current thread for `ticks` timer ticks.
+pub fn sleep(ticks: usize) {
+    interrupts::without_interrupts(|| {
+        let wake = timer::count() + ticks;
+        with_scheduler(|s| {
+            s.current.set_state(State::Blocked);
+            s.sleeping.push((wake, s.current.clone()));
+        });
+        schedule(State::Blocked);
+    });
+}
+
+/// Mark the current thread blocked, before releasing the lock whoever is
+/// to wake it takes. Then `block`.
+pub(super) fn prepare_block() {
+    current().set_state(State::Blocked);
+}
+
+/// Block the current thread until woken with `wake`, if not woken since
+/// `prepare_block`. Interrupts must be off from `prepare_block` on.
+pub(super) fn block() {
+    schedule(State::Blocked);
+}
+
+/// Make the blocked `thread` ready again, on its CPU. Nothing if it is not
+/// blocked.
+pub(super) fn wake(thread: Arc<Thread>) {
+    let index = thread.cpu();
+    let woken = with_cpu(index, |s| {
+        let blocked = thread.state() == State::Blocked;
+        if blocked {
+            s.wake(thread);
+        }
+        blocked
+    });
+    if woken && index != cpu::index() {
+        smp::reschedule(index);
+    }
+}
+
+/// Block the current thread until `thread` exits.
+pub(super) fn join(thread: &Arc<Thread>) {
+    interrupts::without_interrupts(|| {
+        let current = current();
+        assert!(!Arc::ptr_eq(thread, &current), "thread joining itself");
+        current.set_state(State::Blocked);
+        if thread.add_joiner(current.clone()) {
+            schedule(State::Blocked);
+        } else {
+            current.set_state(State::Running);
+        }
+    });
+}
+
+/// End the current thread, waking the threads joining it.
+pub fn exit() -> ! {
+    let current = current();
+    current.set_process(None);
+    interrupts::disable();
+    let joiners = current.exit();
+    joiners.into_iter().for_each(wake);
+    drop(current);
+    schedule(State::Exited);
+    unreachable!("exited thread scheduled again");
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::thread::spawn;
+    use super::*;
+    use alloc::vec;
+
+    #[test_case]
+    fn test_threads() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let handles = (0..3)
+            .map(|i| {
+                let log = log.clone();
+                spawn("test", move || {
+                    for _ in 0..2 {
+                        interrupts::without_interrupts(|| log.lock().push(i));
+                        yield_now();
+                    }
+                    i * 10
+                })
+            })
+            .collect::<Vec<_>>();
+        let results = handles.into_iter().map(|h| h.join()).collect::<Vec<_>>();
+        assert_eq!(results, vec![0, 10, 20]);
+        let mut log = log.lock().clone();
+        log.sort();
+        assert_eq!(log, vec![0, 0, 1, 1, 2, 2]);
+    }
+
+    #[test_case]
+    fn test_preempt_and_sleep() {
+        let start = timer::count();
+        // never yields, the timer takes the CPU from it
+        let spinner = spawn("spinner", move || while timer::count() < start + 20 {});
+        sleep(5);
+        assert!(timer::count() >= start + 5);
+        // preempted, or running on another CPU
+        assert!(matches!(
+            spinner.thread().state(),
+            State::Ready | State::Running
+        ));
+        spinner.join();
+        assert!(timer::count() >= start + 20);
+    }
+}
@@ -0,0 +1,311 @@
+//! The system calls, dispatched by number.
+//!
+//! The numbers are the ones of the alike Linux calls, so that static Linux
+//! programs as simple as `/hello` run, but the arguments are canyon's:
+//! paths are a pointer and a length, `sleep` takes milliseconds. Keep them
+//! in sync with `libcanyon`.
+
+use super::file::{File, Whence};
+use super::{current, scheduler, table, Process};
+use crate::device::timer;
+use crate::fs::page_cache;
+use crate::memory::PAGE_SIZE;
+use crate::syscall::{self, SyscallFrame};
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::sync::atomic::Ordering;
+use log::warn;
+use x86_64::structures::paging::PageTableFlags;
+
+pub mod nr {
+    pub const READ: u64 = 0;
+    pub const WRITE: u64 = 1;
+    pub const OPEN: u64 = 2;
+    pub const CLOSE: u64 = 3;
+    pub const SEEK: u64 = 8;
+    pub const MMAP: u64 = 9;
+    pub const MUNMAP: u64 = 11;
+    pub const BRK: u64 = 12;
+    pub const SLEEP: u64 = 35;
+    pub const GETPID: u64 = 39;
+    pub const SPAWN: u64 = 56;
+    pub const FORK: u64 = 57;
+    pub const EXEC: u64 = 59;
+    pub const EXIT: u64 = 60;
+    pub const WAIT: u64 = 61;
+    pub const GETPPID: u64 = 110;
+    pub const SYNC: u64 = 162;
+}
+
+/// Create the file `open` does not find.
+const O_CREAT: u64 = 0o100;
+const PROT_WRITE: u64 = 2;
+const PROT_EXEC: u64 = 4;
+/// The longest path taken.
+const PATH_MAX: u64 = 4096;
+/// `wait` for any child.
+const ANY_CHILD: u64 = u64::MAX;
+
+/// Errors, returned negated in `rax`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum Errno {
+    ENOENT = 2,
+    EIO = 5,
+    ENOEXEC = 8,
+    EBADF = 9,
+    ECHILD = 10,
+    ENOMEM = 12,
+    EFAULT = 14,
+    EINVAL = 22,
+    ESPIPE = 29,
+    ENOSYS = 38,
+}
+
+impl From<cafs::Error> for Errno {
+    fn from(e: cafs::Error) -> Self {
+        match e {
+            cafs::Error::NotExist(_) => Errno::ENOENT,
+            cafs::Error::InvalidName => Errno::EINVAL,
+            _ => Errno::EIO,
+        }
+    }
+}
+
+impl From<super::Error> for Errno {
+    fn from(e: super::Error) -> Self {
+        match e {
+            super::Error::Fs(e) => e.into(),
+            super::Error::Elf(_) => Errno::ENOEXEC,
+            super::Error::NoMemory => Errno::ENOMEM,
+        }
+    }
+}
+
+type Handler = fn(&mut SyscallFrame) -> Result<u64, Errno>;
+
+const TABLE_SIZE: usize = 256;
+
+static TABLE: [Option<Handler>; TABLE_SIZE] = table();
+
+const fn table() -> [Option<Handler>; TABLE_SIZE] {
+    let mut table: [Option<Handler>; TABLE_SIZE] = [None; TABLE_SIZE];
+    table[nr::READ as usize] = Some(read);
+    table[nr::WRITE as usize] = Some(write);
+    table[nr::OPEN as usize] = Some(open);
+    table[nr::CLOSE as usize] = Some(close);
+    table[nr::SEEK as usize] = Some(seek);
+    table[nr::MMAP as usize] = Some(mmap);
+    table[nr::MUNMAP as usize] = Some(munmap);
+    table[nr::BRK as usize] = Some(brk);
+    table[nr::SLEEP as usize] = Some(sleep);
+    table[nr::GETPID as usize] = Some(getpid);
+    table[nr::SPAWN as usize] = Some(spawn);
+    table[nr::FORK as usize] = Some(fork);
+    table[nr::EXEC as usize] = Some(exec);
+    table[nr::EXIT as usize] = Some(exit);
+    table[nr::WAIT as usize] = Some(wait);
+    table[nr::GETPPID as usize] = Some(getppid);
+    table[nr::SYNC as usize] = Some(sync);
+    table
+}
+
+/// Run the system call in `frame`, leave the result in `rax`.
+pub fn dispatch(frame: &mut SyscallFrame) {
+    let handler = TABLE.get(frame.rax as usize).copied().flatten();
+    let result = match handler {
+        Some(handler) => handler(frame),
+        None => {
+            warn!("unknown system call {}", frame.rax);
+            Err(Errno::ENOSYS)
+        }
+    };
+    frame.rax = match result {
+        Ok(value) => value,
+        Err(errno) => (-(errno as i64)) as u64,
+    };
+}
+
+/// Copy `len` bytes from user memory at `addr` in pieces of up to a page,
+/// for `f` to consume, until it takes less than given. Return the bytes
+/// taken.
+fn copy_in(
+    process: &Process,
+    addr: u64,
+    len: u64,
+    mut f: impl FnMut(&[u8]) -> Result<usize, Errno>,
+) -> Result<u64, Errno> {
+    let mut bytes = [0; PAGE_SIZE];
+    let mut done = 0;
+    while done < len {
+        let piece = &mut bytes[..(len - done).min(PAGE_SIZE as u64) as usize];
+        process.read_user(addr + done, piece)?;
+        let taken = f(piece)?;
+        done += taken as u64;
+        if taken < piece.len() {
+            break;
+        }
+    }
+    Ok(done)
+}
+
+fn read_path(process: &Process, addr: u64, len: u64) -> Result<String, Errno> {
+    if len > PATH_MAX {
+        return Err(Errno::EINVAL);
+    }
+    let mut path = alloc::vec![0; len as usize];
+    process.read_user(addr, &mut path)?;
+    String::from_utf8(path).map_err(|_| Errno::EINVAL)
+}
+
+fn read(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let [fd, addr, len, ..] = frame.args();
+    let process = current();
+    let file = process.files.lock().get(fd)?;
+    let mut bytes = [0; PAGE_SIZE];
+    let mut done = 0;
+    while done < len {
+        let piece = &mut bytes[..(len - done).min(PAGE_SIZE as u64) as usize];
+        let read = file.read(piece)?;
+        process.write_user(addr + done, &piece[..read])?;
+        done += read as u64;
+        if read < piece.len() {
+            break;
+        }
+    }
+    Ok(done)
+}
+
+fn write(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let [fd, addr, len, ..] = frame.args();
+    let process = current();
+    let file = process.files.lock().get(fd)?;
+    copy_in(&process, addr, len, |bytes| file.write(bytes))
+}
+
+fn open(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let [addr, len, flags, ..] = frame.args();
+    let process = current();
+    let path = read_path(&process, addr, len)?;
+    let inode_number = crate::fs::open(&path, flags & O_CREAT != 0)?;
+    let file = Arc::new(File::open(inode_number));
+    let fd = process.files.lock().insert(file);
+    Ok(fd)
+}
+
+fn close(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let [fd, ..] = frame.args();
+    let file = current().files.lock().remove(fd)?;
+    // not under the lock of the table, and an error fails the close
+    file.sync()?;
+    drop(file);
+    Ok(0)
+}
+
+fn seek(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let [fd, offset, whence, ..] = frame.args();
+    let file = current().files.lock().get(fd)?;
+    file.seek(offset as i64, Whence::try_from(whence)?)
+}
+
+/// Map anonymous zeroed memory, the address is up to the kernel.
+fn mmap(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let [_, len, prot, ..] = frame.args();
+    if len == 0 {
+        return Err(Errno::EINVAL);
+    }
+    let mut flags = PageTableFlags::empty();
+    if prot & PROT_WRITE != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if prot & PROT_EXEC == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    current().memory.lock().mmap(len, flags)
+}
+
+fn munmap(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let [addr, len, ..] = frame.args();
+    let process = current();
+    let result = process.memory.lock().munmap(addr, len);
+    result.map(|_| 0)
+}
+
+/// Move the end of the heap to the address given, unless 0. Return where
+/// it is, unmoved if it could not.
+fn brk(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let [end, ..] = frame.args();
+    let process = current();
+    let brk = process.memory.lock().brk(end);
+    Ok(brk)
+}
+
+fn sleep(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let [ms, ..] = frame.args();
+    let ticks = ms.saturating_mul(timer::HZ as u64) / 1000;
+    scheduler::sleep(ticks.max(1) as usize);
+    Ok(0)
+}
+
+fn getpid(_: &mut SyscallFrame) -> Result<u64, Errno> {
+    Ok(current().pid)
+}
+
+/// The pid of the parent, 0 for the kernel.
+fn getppid(_: &mut SyscallFrame) -> Result<u64, Errno> {
+    Ok(table::parent(current().pid).unwrap_or(0))
+}
+
+/// Write the dirty file pages and the filesystem to the disk.
+fn sync(_: &mut SyscallFrame) -> Result<u64, Errno> {
+    page_cache::sync()?;
+    Ok(0)
+}
+
+/// Run the executable at the path in a new process, return its pid.
+fn spawn(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let [addr, len, ..] = frame.args();
+    let process = current();
+    let path = read_path(&process, addr, len)?;
+    Ok(super::spawn(&path, Some(process.pid))?)
+}
+
+/// Copy the process. Return the pid of the child in the parent, and 0 in
+/// the child.
+fn fork(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let process = current();
+    let child = process.fork()?;
+    let mut child_frame = frame.clone();
+    child_frame.rax = 0;
+    Ok(super::start(child, Some(process.pid), child_frame))
+}
+
+/// Replace the program of the process with the executable at the path.
+fn exec(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let [addr, len, ..] = frame.args();
+    let process = current();
+    let path = read_path(&process, addr, len)?;
+    // return to the start of the new program
+    *frame = process.exec(&path)?;
+    Ok(0)
+}
+
+fn exit(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let [code, ..] = frame.args();
+    // nothing left on this stack to drop
+    let context = current().context.load(Ordering::Relaxed);
+    unsafe { syscall::exit_user(context, code as i64) }
+}
+
+/// Wait for the child with the pid, or any, to exit, and reap it. Return
+/// its pid, with its exit code stored at the address given unless 0.
+fn wait(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let [pid, addr, ..] = frame.args();
+    let process = current();
+    let pid = (pid != ANY_CHILD).then_some(pid);
+    let (pid, code) = table::wait(process.pid, pid)?;
+    if addr != 0 {
+        process.write_user(addr, &code.to_ne_bytes())?;
+    }
+    Ok(pid)
+}
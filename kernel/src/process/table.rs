@@ -0,0 +1,147 @@
+//! The process table: the parent and children of every process, and the
+//! exit codes of the ones exited and not yet waited for, the zombies.
+//!
+//! The table is locked with interrupts off, as `wait` checks it before
+//! blocking. Orphans are adopted by the kernel, which reaps them as they
+//! exit.
+
+use super::scheduler;
+use super::syscall::Errno;
+use super::thread::Thread;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+static TABLE: Mutex<BTreeMap<u64, Entry>> = Mutex::new(BTreeMap::new());
+
+struct Entry {
+    /// None for the children of the kernel.
+    parent: Option<u64>,
+    children: Vec<u64>,
+    /// The exit code of a zombie.
+    exit_code: Option<i64>,
+    /// The thread blocked in `wait` for a child.
+    waiter: Option<Arc<Thread>>,
+}
+
+fn with_table<T>(f: impl FnOnce(&mut BTreeMap<u64, Entry>) -> T) -> T {
+    interrupts::without_interrupts(|| f(&mut TABLE.lock()))
+}
+
+/// Add the process `pid`, a child of `parent`.
+pub(super) fn insert(pid: u64, parent: Option<u64>) {
+    with_table(|table| {
+        if let Some(parent) = parent {
+            let entry = table.get_mut(&parent).expect("parent not in the table");
+            entry.children.push(pid);
+        }
+        table.insert(
+            pid,
+            Entry {
+                parent,
+                children: Vec::new(),
+                exit_code: None,
+                waiter: None,
+            },
+        );
+    });
+}
+
+/// Make the process `pid` a zombie exited with `code`, and wake its parent,
+/// or reap it if it has none. Its children go to the kernel. Return whether
+/// it was reaped.
+pub(super) fn exit(pid: u64, code: i64) -> bool {
+    with_table(|table| {
+        let entry = table.get_mut(&pid).expect("process not in the table");
+        entry.exit_code = Some(code);
+        let parent = entry.parent;
+        for child in core::mem::take(&mut entry.children) {
+            let child_entry = table.get_mut(&child).expect("child not in the table");
+            if child_entry.exit_code.is_some() {
+                table.remove(&child);
+            } else {
+                child_entry.parent = None;
+            }
+        }
+        match parent {
+            Some(parent) => {
+                let parent = table.get_mut(&parent).expect("parent not in the table");
+                if let Some(waiter) = parent.waiter.take() {
+                    scheduler::wake(waiter);
+                }
+                false
+            }
+            None => {
+                table.remove(&pid);
+                true
+            }
+        }
+    })
+}
+
+/// Block until the child `pid` of `parent`, or any if none, is a zombie,
+/// and reap it. Return its pid and exit code.
+pub(super) fn wait(parent: u64, pid: Option<u64>) -> Result<(u64, i64), Errno> {
+    loop {
+        let reaped = interrupts::without_interrupts(|| {
+            let mut table = TABLE.lock();
+            let mut children = table[&parent]
+                .children
+                .iter()
+                .filter(|&&child| pid.map_or(true, |pid| pid == child))
+                .peekable();
+            if children.peek().is_none() {
+                return Some(Err(Errno::ECHILD));
+            }
+            let zombie = children.find_map(|&child| Some(child).zip(table[&child].exit_code));
+            if let Some((child, code)) = zombie {
+                table.remove(&child);
+                let entry = table.get_mut(&parent).unwrap();
+                entry.children.retain(|&other| other != child);
+                return Some(Ok((child, code)));
+            }
+            table.get_mut(&parent).unwrap().waiter = Some(scheduler::current());
+            scheduler::prepare_block();
+            drop(table);
+            scheduler::block();
+            None
+        });
+        if let Some(result) = reaped {
+            return result;
+        }
+    }
+}
+
+/// The parent of the process `pid`, none for the kernel.
+pub fn parent(pid: u64) -> Option<u64> {
+    with_table(|table| table.get(&pid).and_then(|entry| entry.parent))
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::next;
+    use super::*;
+
+    #[test_case]
+    fn test_wait_and_orphans() {
+        let (a, b, c) = (next(), next(), next());
+        insert(a, None);
+        insert(b, Some(a));
+        insert(c, Some(b));
+        assert_eq!(wait(a, Some(c)), Err(Errno::ECHILD));
+
+        // `c` goes to the kernel, `b` stays a zombie until waited for
+        assert!(!exit(b, 3));
+        assert_eq!(parent(c), None);
+        assert_eq!(wait(a, None), Ok((b, 3)));
+        assert_eq!(wait(a, None), Err(Errno::ECHILD));
+
+        assert!(exit(c, 0));
+        assert!(exit(a, 0));
+        assert!(with_table(|table| [a, b, c]
+            .iter()
+            .all(|pid| !table.contains_key(pid))));
+    }
+}
@@ -1,3 +1,199 @@
-pub struct Process {
-    tid: usize,
+//! Kernel threads: a stack, the context saved on it, and what the thread
+//! runs, in user mode too.
+
+use super::context::Context;
+use super::{scheduler, Process};
+use crate::memory::vmalloc;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::VirtAddr;
+
+const STACK_PAGES: u64 = 8;
+
+static NEXT_TID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Ready,
+    Running,
+    /// Sleeping, or waiting for another thread.
+    Blocked,
+    Exited,
+}
+
+pub struct Thread {
+    pub tid: u64,
+    pub name: String,
+    /// Top of the stack, none for the boot thread running on the stack
+    /// from the bootloader.
+    stack: Option<VirtAddr>,
+    context: UnsafeCell<Context>,
+    /// The index of the CPU whose scheduler runs it.
+    cpu: AtomicUsize,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    state: State,
+    entry: Option<Box<dyn FnOnce() + Send>>,
+    /// Threads blocked in `join` of this one.
+    joiners: Vec<Arc<Thread>>,
+    /// The process the thread runs in user mode.
+    process: Option<Arc<Process>>,
+}
+
+// The context is only touched by the scheduler, with interrupts off.
+unsafe impl Sync for Thread {}
+
+impl Thread {
+    fn new(name: String, stack: Option<VirtAddr>, context: Context) -> Self {
+        Self {
+            tid: NEXT_TID.fetch_add(1, Ordering::Relaxed),
+            name,
+            stack,
+            context: UnsafeCell::new(context),
+            cpu: AtomicUsize::new(0),
+            inner: Mutex::new(Inner {
+                state: State::Ready,
+                entry: None,
+                joiners: Vec::new(),
+                process: None,
+            }),
+        }
+    }
+
+    /// The thread already running, the one the kernel booted on.
+    pub(super) fn boot() -> Self {
+        let thread = Self::new(String::from("boot"), None, Context::default());
+        thread.set_state(State::Running);
+        thread
+    }
+
+    /// A thread to run `entry` on a stack of its own.
+    pub(super) fn with_entry(name: String, entry: Box<dyn FnOnce() + Send>) -> Option<Self> {
+        let stack = vmalloc::alloc_stack(STACK_PAGES)?;
+        let thread = Self::new(name, Some(stack), Context::new(stack, start));
+        thread.with_inner(|inner| inner.entry = Some(entry));
+        Some(thread)
+    }
+
+    /// The scheduler locks `inner` in the timer interrupt, so interrupts are
+    /// off while it is locked.
+    fn with_inner<T>(&self, f: impl FnOnce(&mut Inner) -> T) -> T {
+        interrupts::without_interrupts(|| f(&mut self.inner.lock()))
+    }
+
+    pub fn state(&self) -> State {
+        self.with_inner(|inner| inner.state)
+    }
+
+    pub(super) fn set_state(&self, state: State) {
+        self.with_inner(|inner| inner.state = state);
+    }
+
+    pub fn cpu(&self) -> usize {
+        self.cpu.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn set_cpu(&self, index: usize) {
+        self.cpu.store(index, Ordering::Relaxed);
+    }
+
+    pub(super) fn context(&self) -> *mut Context {
+        self.context.get()
+    }
+
+    pub fn process(&self) -> Option<Arc<Process>> {
+        self.with_inner(|inner| inner.process.clone())
+    }
+
+    /// Set the process the thread runs in user mode, and switch to its
+    /// address space.
+    pub fn set_process(&self, process: Option<Arc<Process>>) {
+        let old = interrupts::without_interrupts(|| {
+            let old = self.with_inner(|inner| core::mem::replace(&mut inner.process, process));
+            scheduler::activate(self);
+            old
+        });
+        // with interrupts on, and its address space no longer active
+        drop(old);
+    }
+
+    /// Add `thread` to wake when this one exits, false if it has exited.
+    pub(super) fn add_joiner(&self, thread: Arc<Thread>) -> bool {
+        self.with_inner(|inner| {
+            if inner.state == State::Exited {
+                return false;
+            }
+            inner.joiners.push(thread);
+            true
+        })
+    }
+
+    /// Mark the thread exited, return the threads to wake.
+    pub(super) fn exit(&self) -> Vec<Arc<Thread>> {
+        self.with_inner(|inner| {
+            inner.state = State::Exited;
+            core::mem::take(&mut inner.joiners)
+        })
+    }
+}
+
+impl Drop for Thread {
+    fn drop(&mut self) {
+        if let Some(stack) = self.stack {
+            vmalloc::free_stack(stack, STACK_PAGES);
+        }
+    }
+}
+
+/// Where a new thread starts, switched to with interrupts off.
+extern "C" fn start() -> ! {
+    scheduler::started();
+    let entry = scheduler::current().with_inner(|inner| inner.entry.take());
+    entry.expect("thread started twice")();
+    scheduler::exit()
+}
+
+/// A thread to wait for, and the result of its closure.
+pub struct JoinHandle<T> {
+    thread: Arc<Thread>,
+    result: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn thread(&self) -> &Arc<Thread> {
+        &self.thread
+    }
+
+    /// Block until the thread exits, return what its closure returned.
+    pub fn join(self) -> T {
+        scheduler::join(&self.thread);
+        let result = self.result.lock().take();
+        result.expect("thread exited without a result")
+    }
+}
+
+/// Run `f` in a new kernel thread.
+pub fn spawn<F, T>(name: &str, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let result = Arc::new(Mutex::new(None));
+    let slot = result.clone();
+    let entry = Box::new(move || {
+        let value = f();
+        *slot.lock() = Some(value);
+    });
+    let thread = Thread::with_entry(String::from(name), entry).expect("no memory for a thread");
+    let thread = Arc::new(thread);
+    scheduler::add(thread.clone());
+    JoinHandle { thread, result }
 }